@@ -1,29 +1,109 @@
+use core::ops::Range;
+
 #[derive(Clone, defmt::Format, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub enum Cap {
     Endpoint(Endpoint),
     Listen(Listen),
     Connect(Connect),
     Notification,
+    /// Allows a thread to make privileged syscalls, like [`crate::SyscallFn::Reboot`]
+    Admin,
+    /// Delivers the interrupt with the given IRQ number to the thread
+    ///
+    /// Each interrupt is delivered as a message sent to [`crate::INTERRUPT_ADDR`], with the IRQ number as its body.
+    /// The interrupt stays masked until the thread receives with [`crate::INTERRUPT_ADDR`] in its mask again.
+    DirectInterrupt(u8),
+    /// Listens on a port as one of several shards
+    ///
+    /// Unlike [`Cap::Listen`], this adds the thread to the port's listeners rather than replacing them.
+    /// Connections to the port are handed out to each shard in turn.
+    ListenShard(Listen),
+    /// Allows the thread to wait on and post to a counting semaphore shared with other threads
+    ///
+    /// See [`crate::SyscallFn::SemWait`] and [`crate::SyscallFn::SemPost`]
+    Semaphore(SemaphoreId),
+    /// Allows the thread's task to map a range of flash shared with other tasks, such as a lookup table
+    ///
+    /// The range is mapped read and execute only with [`crate::SyscallFn::MapShared`].
+    SharedFlash {
+        range: Range<usize>,
+    },
+    /// Allows the thread to set notification bits on the target thread, a lighter alternative to a message
+    ///
+    /// See [`crate::SyscallFn::Notify`] and [`crate::SyscallFn::WaitNotify`]
+    Notify(super::ThreadRef),
+    /// Lets the thread handle an interrupt itself, by waiting for it and acknowledging it
+    ///
+    /// The interrupt is masked each time it fires, until the thread acknowledges it. See [`crate::SyscallFn::WaitIrq`]
+    /// and [`crate::SyscallFn::AckIrq`]
+    Irq {
+        irq_number: u16,
+    },
+    /// A heap private to the thread's task, which it allocates from with [`crate::SyscallFn::Alloc`]
+    ///
+    /// The heap is inside the task's own RAM, so running out of it can't affect other tasks.
+    Heap {
+        base: usize,
+        size: usize,
+    },
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, defmt::Format, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Endpoint {
     pub tcb_ref: super::ThreadRef,
     pub addr: usize,
     pub disposable: bool,
+    /// Sends fail with [`crate::Error::QueueFull`] once this many messages sent to `addr` are waiting to be received,
+    /// 0 means no limit
+    pub max_queue_depth: u8,
 }
 
 pub type PortId = [u8; 16];
 
-#[derive(Clone, Copy, defmt::Format, Debug)]
+/// Identifies a semaphore, threads holding a [`Cap::Semaphore`] with the same id share its count
+pub type SemaphoreId = usize;
+
+/// Formats a [`PortId`] as a string when it is valid UTF-8, ignoring trailing zero padding
+///
+/// Falls back to the raw bytes for any other port
+pub struct PortName<'a>(pub &'a PortId);
+
+impl defmt::Format for PortName<'_> {
+    fn format(&self, f: defmt::Formatter) {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        match core::str::from_utf8(&self.0[..len]) {
+            Ok(name) => defmt::write!(f, "{=str}", name),
+            Err(_) => defmt::write!(f, "{=[u8]}", self.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct Listen {
     pub port: PortId,
 }
-#[derive(Clone, Copy, defmt::Format, Debug)]
+
+impl defmt::Format for Listen {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Listen {{ port: {} }}", PortName(&self.port))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct Connect {
     pub port: PortId,
 }
+
+impl defmt::Format for Connect {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Connect {{ port: {} }}", PortName(&self.port))
+    }
+}
@@ -15,6 +15,13 @@ mycelium_bitfield::bitfield! {
     }
 }
 
+/// The endpoint address interrupts claimed with [`Cap::DirectInterrupt`] are delivered to
+pub const INTERRUPT_ADDR: usize = 0x40000000;
+
+/// Passed as the last argument to [`SyscallFn::Recv`] to match messages sent to *any* of the addresses in the mask,
+/// rather than only those whose address contains every bit of it
+pub const RECV_ANY: usize = 1;
+
 #[derive(Debug, defmt::Format, PartialEq)]
 #[repr(u8)]
 pub enum SyscallFn {
@@ -26,10 +33,34 @@ pub enum SyscallFn {
     Panik = 0x5,
     Connect = 0x6,
     Listen = 0x7,
+    Reboot = 0x8,
+    ReadMemory = 0x9,
+    WriteMemory = 0xA,
+    Timestamp = 0xB,
+    MapDevice = 0xC,
+    Unmap = 0xD,
+    SendVec = 0xE,
+    GetTaskInfo = 0xF,
+    SemWait = 0x10,
+    SemPost = 0x11,
+    PollCaps = 0x12,
+    Yield = 0x13,
+    Sleep = 0x14,
+    Spawn = 0x15,
+    Revoke = 0x16,
+    MapShared = 0x17,
+    Notify = 0x18,
+    WaitNotify = 0x19,
+    Stats = 0x1A,
+    StackUsage = 0x1B,
+    WaitIrq = 0x1C,
+    AckIrq = 0x1D,
+    SendCap = 0x1E,
+    Alloc = 0x1F,
 }
 
 impl FromBits<u32> for SyscallFn {
-    const BITS: u32 = 3;
+    const BITS: u32 = 5;
     type Error = &'static str;
 
     fn try_from_bits(bits: u32) -> Result<Self, Self::Error> {
@@ -42,6 +73,30 @@ impl FromBits<u32> for SyscallFn {
             bits if bits == Self::Panik as u8 => Ok(Self::Panik),
             bits if bits == Self::Connect as u8 => Ok(Self::Connect),
             bits if bits == Self::Listen as u8 => Ok(Self::Listen),
+            bits if bits == Self::Reboot as u8 => Ok(Self::Reboot),
+            bits if bits == Self::ReadMemory as u8 => Ok(Self::ReadMemory),
+            bits if bits == Self::WriteMemory as u8 => Ok(Self::WriteMemory),
+            bits if bits == Self::Timestamp as u8 => Ok(Self::Timestamp),
+            bits if bits == Self::MapDevice as u8 => Ok(Self::MapDevice),
+            bits if bits == Self::Unmap as u8 => Ok(Self::Unmap),
+            bits if bits == Self::SendVec as u8 => Ok(Self::SendVec),
+            bits if bits == Self::GetTaskInfo as u8 => Ok(Self::GetTaskInfo),
+            bits if bits == Self::SemWait as u8 => Ok(Self::SemWait),
+            bits if bits == Self::SemPost as u8 => Ok(Self::SemPost),
+            bits if bits == Self::PollCaps as u8 => Ok(Self::PollCaps),
+            bits if bits == Self::Yield as u8 => Ok(Self::Yield),
+            bits if bits == Self::Sleep as u8 => Ok(Self::Sleep),
+            bits if bits == Self::Spawn as u8 => Ok(Self::Spawn),
+            bits if bits == Self::Revoke as u8 => Ok(Self::Revoke),
+            bits if bits == Self::MapShared as u8 => Ok(Self::MapShared),
+            bits if bits == Self::Notify as u8 => Ok(Self::Notify),
+            bits if bits == Self::WaitNotify as u8 => Ok(Self::WaitNotify),
+            bits if bits == Self::Stats as u8 => Ok(Self::Stats),
+            bits if bits == Self::StackUsage as u8 => Ok(Self::StackUsage),
+            bits if bits == Self::WaitIrq as u8 => Ok(Self::WaitIrq),
+            bits if bits == Self::AckIrq as u8 => Ok(Self::AckIrq),
+            bits if bits == Self::SendCap as u8 => Ok(Self::SendCap),
+            bits if bits == Self::Alloc as u8 => Ok(Self::Alloc),
             _ => Err("expected valid syscall fn identifier"),
         }
     }
@@ -95,6 +150,7 @@ pub enum SyscallReturnType {
     Short,
     Page,
     Copy,
+    Notification,
 }
 
 mycelium_bitfield::bitfield! {
@@ -107,6 +163,34 @@ mycelium_bitfield::bitfield! {
 }
 
 impl SyscallReturn {
+    /// A successful return where `len` bytes were copied into the caller's buffer
+    #[inline]
+    pub fn copy(len: u64) -> Self {
+        SyscallReturn::new()
+            .with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy)
+            .with(SyscallReturn::SYSCALL_LEN, len)
+    }
+
+    /// A successful return carrying a page at `ptr`
+    #[inline]
+    pub fn page(ptr: u64) -> Self {
+        SyscallReturn::new()
+            .with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Page)
+            .with(SyscallReturn::SYSCALL_PTR, ptr)
+    }
+
+    /// A successful return whose value is packed into `SYSCALL_PTR` and `SYSCALL_LEN`
+    #[inline]
+    pub fn short() -> Self {
+        SyscallReturn::new().with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Short)
+    }
+
+    /// Returns `err` to the caller, equivalent to `SyscallReturn::from(err)`
+    #[inline]
+    pub fn error(err: Error) -> Self {
+        err.into()
+    }
+
     pub fn split(self) -> (u32, u32) {
         ((self.0 >> 32) as u32, self.0 as u32)
     }
@@ -122,7 +206,7 @@ impl From<Error> for SyscallReturn {
 }
 
 impl FromBits<u64> for SyscallReturnType {
-    const BITS: u32 = 2;
+    const BITS: u32 = 3;
     type Error = &'static str;
 
     fn try_from_bits(bits: u64) -> Result<Self, &'static str> {
@@ -131,6 +215,7 @@ impl FromBits<u64> for SyscallReturnType {
             bits if bits == Self::Short as u8 => Ok(Self::Short),
             bits if bits == Self::Copy as u8 => Ok(Self::Copy),
             bits if bits == Self::Page as u8 => Ok(Self::Page),
+            bits if bits == Self::Notification as u8 => Ok(Self::Notification),
             _ => Err("expected valid syscall fn identifier"),
         }
     }
@@ -140,7 +225,7 @@ impl FromBits<u64> for SyscallReturnType {
     }
 }
 
-#[derive(Debug, Format, Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 pub enum Error {
     ReturnTypeMismatch,
@@ -149,6 +234,18 @@ pub enum Error {
     PortNotOpen,
     InvalidCap,
     InvalidLoan,
+    /// Returned by a non-blocking call that would otherwise have blocked
+    WouldBlock,
+    /// Returned when a blocking call's timeout expires first
+    Timeout,
+    /// Returned when a typed IPC message can't be encoded or decoded, see `k5-ipc`
+    InvalidMessage,
+    /// Returned when a queue in the kernel is at capacity, like the threads waiting on a semaphore
+    QueueFull,
+    /// Returned when a call isn't valid in the context it was made from, like the idle thread sleeping
+    NotSupported,
+    /// Returned when the caller isn't allowed to do what it asked, like spawning a thread above its own priority
+    PermissionDenied,
     Unknown(u8),
 }
 
@@ -161,6 +258,12 @@ impl From<u8> for Error {
             4 => Error::PortNotOpen,
             5 => Error::InvalidCap,
             6 => Error::InvalidLoan,
+            7 => Error::WouldBlock,
+            8 => Error::Timeout,
+            9 => Error::InvalidMessage,
+            10 => Error::QueueFull,
+            11 => Error::NotSupported,
+            12 => Error::PermissionDenied,
             code => Error::Unknown(code),
         }
     }
@@ -175,15 +278,66 @@ impl From<Error> for u8 {
             Error::PortNotOpen => 4,
             Error::InvalidCap => 5,
             Error::InvalidLoan => 6,
+            Error::WouldBlock => 7,
+            Error::Timeout => 8,
+            Error::InvalidMessage => 9,
+            Error::QueueFull => 10,
+            Error::NotSupported => 11,
+            Error::PermissionDenied => 12,
             Error::Unknown(code) => code,
         }
     }
 }
 
+impl Format for Error {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Error::ReturnTypeMismatch => {
+                defmt::write!(f, "ReturnTypeMismatch: syscall returned an unexpected type")
+            }
+            Error::BadAccess => {
+                defmt::write!(f, "BadAccess: memory or task outside of the caller's reach")
+            }
+            Error::BufferOverflow => {
+                defmt::write!(f, "BufferOverflow: buffer or table is too small")
+            }
+            Error::PortNotOpen => defmt::write!(f, "PortNotOpen: nothing is listening on the port"),
+            Error::InvalidCap => {
+                defmt::write!(f, "InvalidCap: capability is missing or the wrong type")
+            }
+            Error::InvalidLoan => defmt::write!(f, "InvalidLoan: memory can't be loaned"),
+            Error::WouldBlock => defmt::write!(f, "WouldBlock: call would have blocked"),
+            Error::Timeout => defmt::write!(f, "Timeout: timed out while blocked"),
+            Error::InvalidMessage => {
+                defmt::write!(f, "InvalidMessage: message can't be encoded or decoded")
+            }
+            Error::QueueFull => defmt::write!(f, "QueueFull: queue is at capacity"),
+            Error::NotSupported => {
+                defmt::write!(f, "NotSupported: call isn't valid in this context")
+            }
+            Error::PermissionDenied => {
+                defmt::write!(f, "PermissionDenied: caller isn't allowed to do that")
+            }
+            Error::Unknown(code) => defmt::write!(f, "Unknown({}): unrecognized error code", code),
+        }
+    }
+}
+
 #[derive(Clone, Copy, defmt::Format, Debug)]
 #[repr(C)]
 pub struct CapRef(pub usize);
 
+impl CapRef {
+    /// A cap ref that never points to a capability, the kernel rejects it in every syscall
+    pub const fn null() -> Self {
+        CapRef(0)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0 == 0
+    }
+}
+
 impl Deref for CapRef {
     type Target = usize;
 
@@ -199,6 +353,7 @@ impl From<usize> for CapRef {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, defmt::Format, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct ThreadRef(pub usize);
 
@@ -222,10 +377,64 @@ pub struct CapListEntry {
     pub desc: Cap,
 }
 
+/// A task's memory layout, as returned by [`SyscallFn::GetTaskInfo`]
+#[derive(Format, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TaskInfo {
+    /// The task's name, padded with zeros and truncated to 32 bytes
+    pub name: [u8; 32],
+    /// The number of threads currently running in the task
+    pub thread_count: u8,
+    pub flash_start: usize,
+    pub flash_len: usize,
+    pub ram_start: usize,
+    pub ram_len: usize,
+}
+
+/// A thread's scheduling statistics, as returned by [`SyscallFn::Stats`]
+///
+/// The counters start at zero when the thread is spawned, and wrap rather than saturate.
+#[derive(Format, Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TaskStats {
+    /// Number of scheduler ticks the thread was running for
+    pub cpu_ticks: u64,
+    /// Number of messages the thread has sent and received
+    pub ipc_count: u32,
+    /// Total length of the bodies of those messages
+    pub ipc_bytes: u64,
+    /// Number of times the scheduler has switched to the thread
+    pub context_switches: u32,
+}
+
+/// Passed as the timeout of [`SyscallFn::Recv`] to return [`Error::WouldBlock`] instead of waiting
+///
+/// A timeout of 0 waits forever, any other value is the number of ticks to wait for.
+pub const RECV_NO_WAIT: usize = usize::MAX;
+
+/// The maximum number of ports a thread can wait for with a single [`SyscallFn::PollCaps`]
+pub const MAX_POLL_PORTS: usize = 4;
+
+/// The maximum number of buffers that can be gathered into one message by [`SyscallFn::SendVec`]
+pub const MAX_SEND_VEC: usize = 4;
+
+/// The longest task name the kernel includes in a log frame, longer names are truncated
+pub const MAX_LOG_NAME_LEN: usize = 32;
+
+/// One buffer in a [`SyscallFn::SendVec`] scatter list
+#[derive(Format, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct IoSlice {
+    pub addr: usize,
+    pub len: usize,
+}
+
 #[derive(Format)]
 #[repr(C)]
 pub struct RecvResp {
     pub cap: Option<CapRef>,
+    /// The endpoint address the message was sent to
+    pub addr: usize,
     pub inner: RecvRespInner,
 }
 
@@ -233,5 +442,19 @@ pub struct RecvResp {
 #[repr(C)]
 pub enum RecvRespInner {
     Copy(usize),
-    Page { addr: usize, len: usize },
+    Page {
+        addr: usize,
+        len: usize,
+    },
+    /// The thread was woken by a notification rather than a message, `bits` are the pending notification bits
+    Notification {
+        bits: u32,
+    },
+    /// The message delegated a capability, sent with [`SyscallFn::SendCap`], which has been added to the receiving
+    /// thread's capabilities as `cap`
+    ///
+    /// Nothing is copied into the receive buffer, whatever its length.
+    Cap {
+        cap: CapRef,
+    },
 }
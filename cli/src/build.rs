@@ -16,8 +16,8 @@ use goblin::{elf64::program_header::PT_LOAD, Object};
 use serde::Deserialize;
 use std::fmt::Write;
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, HashMap},
+    env, fs,
     ops::Range,
     path::{Path, PathBuf},
     process::{Command, Stdio},
@@ -40,10 +40,41 @@ pub struct Config {
     pub flash_probe: flash::FlashConfig,
     pub tasks: Vec<Task>,
     pub regions: HashMap<String, MemorySection>,
+    #[serde(default)]
     stack_size: Option<usize>,
+    #[serde(default)]
     stack_space_size: Option<usize>,
     pub kernel: Kernel,
     platform: Platform,
+    #[serde(default)]
+    default_features: Vec<String>,
+    #[serde(default)]
+    kernel_features: Vec<String>,
+    #[serde(default)]
+    global_caps: GlobalCaps,
+    /// Baud rate of the serial port logs are read from, it is detected when unset
+    #[serde(default)]
+    pub serial_baud: Option<u32>,
+    /// Fails the build if the kernel and tasks take more than this many bytes of flash, including alignment padding
+    #[serde(default)]
+    pub max_total_flash: Option<usize>,
+}
+
+/// Caps given to every task, in addition to the caps derived from their interfaces
+#[derive(Debug, Deserialize, Default)]
+pub struct GlobalCaps {
+    #[serde(default)]
+    listen: Vec<String>,
+    #[serde(default)]
+    connect: Vec<String>,
+}
+
+impl GlobalCaps {
+    fn caps(&self) -> impl Iterator<Item = codegen::CapDesc> + '_ {
+        let listen = self.listen.iter().cloned().map(codegen::CapDesc::Listen);
+        let connect = self.connect.iter().cloned().map(codegen::CapDesc::Connect);
+        listen.chain(connect)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -58,6 +89,26 @@ pub struct Task {
     pub stack_size: usize,
     #[serde(default)]
     pub stack_space_size: usize,
+    /// Bytes of thread-local storage reserved at the top of each of the task's stacks
+    ///
+    /// Rounded up to the platform's stack alignment, see [`Platform::stack_align`]
+    #[serde(default)]
+    pub tls_size: usize,
+    /// Cargo features to enable for this task, overrides `default_features` when set
+    #[serde(default)]
+    pub features: Option<Vec<String>>,
+    /// Static caps for the task, derived from the `interfaces/` directories of every task in the app
+    #[serde(skip)]
+    pub caps: Vec<codegen::CapDesc>,
+    /// Linker used to link the task, overrides the kernel's `linker` when set
+    #[serde(default)]
+    pub linker: Option<String>,
+    /// Fails the build if the task takes more than this many bytes of flash
+    #[serde(default)]
+    pub max_flash_size: Option<usize>,
+    /// Fails the build if the task takes more than this many bytes of RAM, including its stack space
+    #[serde(default)]
+    pub max_ram_size: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,15 +116,25 @@ pub struct Kernel {
     pub crate_path: PathBuf,
     #[serde(default)]
     stack_size: usize,
+    #[serde(default)]
     pub(crate) sizes: HashMap<String, usize>,
+    #[serde(default)]
     linker_script: Option<PathBuf>,
+    /// Linker used to link tasks, defaults to [`Platform::default_linker`]
+    #[serde(default)]
+    linker: Option<String>,
+    #[serde(skip)]
+    features: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Copy, Clone)]
 pub enum Platform {
+    /// 32-bit RISC-V parts, built with the kernel's `rv32` feature
     RV32,
     AwD1,
     ArmV8m,
+    /// Cortex-M4F/M7F parts, built with the kernel's `cortex_m7m` feature
+    ArmV7m,
 }
 
 impl Platform {
@@ -83,28 +144,76 @@ impl Platform {
             Platform::AwD1 => {
                 todo!()
             }
-            Platform::ArmV8m => KERN_LINK_BYTES,
+            Platform::ArmV8m | Platform::ArmV7m => KERN_LINK_BYTES,
         }
     }
 
     pub(crate) fn task_rlink(&self) -> &'static [u8] {
         match self {
             Platform::AwD1 | Platform::RV32 => RV_TASK_RLINK_BYTES,
-            Platform::ArmV8m => ARM_TASK_RLINK_BYTES,
+            Platform::ArmV8m | Platform::ArmV7m => ARM_TASK_RLINK_BYTES,
         }
     }
 
     pub(crate) fn task_link(&self) -> &'static [u8] {
         match self {
             Platform::AwD1 | Platform::RV32 => RV_TASK_LINK_BYTES,
-            Platform::ArmV8m => ARM_TASK_LINK_BYTES,
+            Platform::ArmV8m | Platform::ArmV7m => ARM_TASK_LINK_BYTES,
+        }
+    }
+
+    /// The linker used for tasks when `linker` isn't set in `app.toml`
+    pub(crate) fn default_linker(&self) -> &'static str {
+        match self {
+            Platform::RV32 => "riscv32-unknown-elf-ld",
+            Platform::AwD1 => "riscv64-unknown-elf-ld",
+            Platform::ArmV8m | Platform::ArmV7m => "arm-none-eabi-ld",
         }
     }
 
     pub(crate) fn relocate(&self) -> bool {
         match self {
             Platform::RV32 | Platform::AwD1 => false,
-            Platform::ArmV8m => true,
+            Platform::ArmV8m | Platform::ArmV7m => true,
+        }
+    }
+
+    /// Cargo config passed to every task build with `--config`
+    ///
+    /// On ARM the kernel passes each thread's TLS base in `r9`, which AAPCS otherwise treats as a general-purpose
+    /// register. Every crate in the task is built with it reserved, so `userspace::thread_local_ptr` can read it.
+    pub(crate) fn task_cargo_config(&self) -> Option<&'static str> {
+        match self {
+            Platform::RV32 | Platform::AwD1 => None,
+            Platform::ArmV8m | Platform::ArmV7m => Some(
+                r#"target.'cfg(target_arch = "arm")'.rustflags = ["-C", "target-feature=+reserve-r9"]"#,
+            ),
+        }
+    }
+
+    /// The alignment the platform's ABI requires of the stack pointer, 8 bytes for AAPCS and 16 for RISC-V
+    pub(crate) fn stack_align(&self) -> usize {
+        match self {
+            Platform::RV32 | Platform::AwD1 => 16,
+            Platform::ArmV8m | Platform::ArmV7m => 8,
+        }
+    }
+
+    /// Returns the size and alignment of a task region that holds `len` bytes
+    ///
+    /// ARMv7-M's MPU only supports regions that are a power of two in size and aligned to it. Regions of at least
+    /// 256 bytes can disable eighths of themselves, so they only need to be a multiple of an eighth.
+    pub(crate) fn region_layout(&self, len: usize) -> (usize, usize) {
+        match self {
+            Platform::ArmV7m => {
+                let block = len.next_power_of_two().max(32);
+                if block >= 256 {
+                    (align_up(len, block / 8), block)
+                } else {
+                    (block, block)
+                }
+            }
+            _ => (align_up(len, 32), 32),
         }
     }
 }
@@ -114,6 +223,10 @@ impl Platform {
 pub enum MemoryRole {
     None,
     Stack,
+    /// Flash shared between tasks, see `KernelBuilder::shared_flash`
+    ///
+    /// It isn't divided between tasks like other regions, instead every task's linker script gets it as `SHARED`.
+    Shared,
 }
 
 impl Default for MemoryRole {
@@ -128,8 +241,78 @@ pub enum TaskSource {
     Crate { crate_path: PathBuf },
 }
 
+/// A partial [`Config`], layered over a full one with [`Config::merge`]
+///
+/// Every field is optional, so the overlay only needs the settings it changes.
+#[derive(Debug, Deserialize, Default)]
+pub struct ConfigOverlay {
+    #[serde(flatten, default)]
+    pub flash_probe: Option<flash::FlashConfig>,
+    #[serde(default)]
+    pub tasks: Vec<Task>,
+    #[serde(default)]
+    pub regions: HashMap<String, MemorySection>,
+    #[serde(default)]
+    stack_size: Option<usize>,
+    #[serde(default)]
+    stack_space_size: Option<usize>,
+    #[serde(default)]
+    pub kernel: Option<Kernel>,
+    #[serde(default)]
+    platform: Option<Platform>,
+    #[serde(default)]
+    default_features: Option<Vec<String>>,
+    #[serde(default)]
+    kernel_features: Option<Vec<String>>,
+    #[serde(default)]
+    global_caps: Option<GlobalCaps>,
+    #[serde(default)]
+    pub serial_baud: Option<u32>,
+    #[serde(default)]
+    pub max_total_flash: Option<usize>,
+}
+
 impl Config {
-    pub fn build(&mut self, app_path: &Path) -> Result<PathBuf> {
+    /// Layers `other` over this config
+    ///
+    /// Tasks and regions in `other` replace the ones with the same name, and are added otherwise. Other fields
+    /// are replaced only when they are set in `other`.
+    pub fn merge(&mut self, other: ConfigOverlay) {
+        if let Some(flash_probe) = other.flash_probe {
+            self.flash_probe = flash_probe;
+        }
+        for task in other.tasks {
+            match self.tasks.iter_mut().find(|t| t.name == task.name) {
+                Some(existing) => *existing = task,
+                None => self.tasks.push(task),
+            }
+        }
+        self.regions.extend(other.regions);
+        self.stack_size = other.stack_size.or(self.stack_size);
+        self.stack_space_size = other.stack_space_size.or(self.stack_space_size);
+        if let Some(kernel) = other.kernel {
+            self.kernel = kernel;
+        }
+        if let Some(platform) = other.platform {
+            self.platform = platform;
+        }
+        if let Some(default_features) = other.default_features {
+            self.default_features = default_features;
+        }
+        if let Some(kernel_features) = other.kernel_features {
+            self.kernel_features = kernel_features;
+        }
+        if let Some(global_caps) = other.global_caps {
+            self.global_caps = global_caps;
+        }
+        self.serial_baud = other.serial_baud.or(self.serial_baud);
+        self.max_total_flash = other.max_total_flash.or(self.max_total_flash);
+    }
+
+    /// Makes every crate, linker script and linker path in the config absolute, relative to `app_path`
+    ///
+    /// Linkers given as a bare name are left alone, since they are looked up in `$PATH`.
+    pub fn resolve_paths(&mut self, app_path: &Path) -> Result<()> {
         if self.kernel.crate_path.is_relative() {
             self.kernel.crate_path =
                 fs::canonicalize(app_path.join(self.kernel.crate_path.clone()))?;
@@ -139,6 +322,25 @@ impl Config {
                 *linker_path = fs::canonicalize(app_path.join(linker_path.clone()))?;
             }
         }
+        resolve_linker(&mut self.kernel.linker, app_path)?;
+        for task in &mut self.tasks {
+            let TaskSource::Crate { crate_path } = &mut task.source;
+            if crate_path.is_relative() {
+                *crate_path = fs::canonicalize(app_path.join(crate_path.clone()))?;
+            }
+            resolve_linker(&mut task.linker, app_path)?;
+        }
+        Ok(())
+    }
+
+    /// The directory the final image is written to
+    pub fn target_path(&self) -> PathBuf {
+        self.kernel.crate_path.join("target")
+    }
+
+    pub fn build(&mut self, app_path: &Path) -> Result<PathBuf> {
+        self.resolve_paths(app_path)?;
+        self.kernel.features = self.kernel_features.clone();
 
         for task in &mut self.tasks {
             if task.stack_size == 0 {
@@ -151,12 +353,28 @@ impl Config {
                     .stack_space_size
                     .ok_or_else(|| anyhow!("missing default stack space size"))?;
             }
-            match task.source {
-                TaskSource::Crate { ref mut crate_path } => {
-                    if crate_path.is_relative() {
-                        *crate_path = fs::canonicalize(app_path.join(crate_path.clone()))?;
-                    }
-                }
+            // the stack starts right below the TLS, so rounding keeps the stack pointer aligned
+            task.tls_size = align_up(task.tls_size, self.platform.stack_align());
+            if task.tls_size >= task.stack_size {
+                return Err(anyhow!(
+                    "{:?} has a tls_size larger than its stack",
+                    task.name
+                ));
+            }
+            if task.features.is_none() {
+                task.features = Some(self.default_features.clone());
+            }
+            if task.linker.is_none() {
+                task.linker = Some(
+                    self.kernel
+                        .linker
+                        .clone()
+                        .unwrap_or_else(|| self.platform.default_linker().to_string()),
+                );
+            }
+            // tasks are only linked separately when they are relocated
+            if self.platform.relocate() {
+                check_linker(task.linker.as_deref().unwrap_or_default())?;
             }
         }
         let mut task_by_name = HashMap::new();
@@ -168,18 +386,30 @@ impl Config {
         if !task_by_name.contains_key("idle") {
             return Err(anyhow!("missing idle task"));
         }
+        if self
+            .regions
+            .values()
+            .filter(|r| r.role == MemoryRole::Shared)
+            .count()
+            > 1
+        {
+            return Err(anyhow!("only one region can have the shared role"));
+        }
+        self.assign_static_caps(&task_by_name)?;
 
-        let target_path = self.kernel.crate_path.join("target");
+        let target_path = self.target_path();
         let mut builder: Box<dyn ImageBuilder<Image = SRecImage>> = match self.platform {
             // Platform::AwD1 => Box::new(D1ImageBuilder::new(
             //     self.regions.clone(),
             //     self.platform,
             //     &self.kernel,
+            //     self.max_total_flash,
             // )?),
             _ => Box::new(SRecImageBuilder::new(
                 self.regions.clone(),
                 self.platform,
                 &self.kernel,
+                self.max_total_flash,
             )),
         };
         for task in &self.tasks {
@@ -190,6 +420,53 @@ impl Config {
         img.write(&target_path)?;
         Ok(target_path)
     }
+
+    /// Gives each task a connect cap for every interface it uses, and a listen cap if any other task uses its interface
+    ///
+    /// Ports are named after the task that listens on them. Every task also receives the caps in `global_caps`,
+    /// caps a task already has are not duplicated.
+    fn assign_static_caps(&mut self, task_by_name: &HashMap<String, usize>) -> Result<()> {
+        let mut listeners = vec![];
+        for task in &mut self.tasks {
+            let TaskSource::Crate { crate_path } = &task.source;
+            let dir = crate_path.join("interfaces");
+            if !dir.is_dir() {
+                continue;
+            }
+            for port in codegen::interface_tasks(&dir)? {
+                let listener = *task_by_name.get(&port).ok_or_else(|| {
+                    anyhow!(
+                        "{:?} uses an interface for missing task {:?}",
+                        task.name,
+                        port
+                    )
+                })?;
+                if codegen::port_id(&port).is_none() {
+                    return Err(anyhow!("port name {:?} is longer than 16 bytes", port));
+                }
+                task.caps.push(codegen::CapDesc::Connect(port));
+                if !listeners.contains(&listener) {
+                    listeners.push(listener);
+                }
+            }
+        }
+        for i in listeners {
+            let task = &mut self.tasks[i];
+            task.caps.push(codegen::CapDesc::Listen(task.name.clone()));
+        }
+        for cap in self.global_caps.caps() {
+            let (codegen::CapDesc::Listen(port) | codegen::CapDesc::Connect(port)) = &cap;
+            if codegen::port_id(port).is_none() {
+                return Err(anyhow!("port name {:?} is longer than 16 bytes", port));
+            }
+            for task in &mut self.tasks {
+                if !task.caps.contains(&cap) {
+                    task.caps.push(cap.clone());
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Kernel {
@@ -225,7 +502,14 @@ impl Kernel {
         let task_list = codegen::TaskList { tasks };
         let task_list_path = target_dir.join("task_list.json");
         fs::write(task_list_path.clone(), serde_json::to_vec(&task_list)?)?;
-        build_crate(&self.crate_path, Some(&task_list_path), "link.x", false)
+        build_crate(
+            &self.crate_path,
+            Some(&task_list_path),
+            "link.x",
+            false,
+            Some(&self.features),
+            None,
+        )
     }
 }
 
@@ -234,13 +518,24 @@ fn build_crate(
     task_list: Option<&Path>,
     link_name: &str,
     reloc: bool,
+    features: Option<&[String]>,
+    cargo_config: Option<&str>,
 ) -> Result<PathBuf> {
     let target_dir = crate_path.join("target");
     let mut cmd = Command::new("cargo");
     cmd.current_dir(&crate_path)
         .arg("rustc")
-        .args(&["--message-format", "json-diagnostic-rendered-ansi"])
-        .arg("--")
+        .args(&["--message-format", "json-diagnostic-rendered-ansi"]);
+    if let Some(features) = features {
+        if !features.is_empty() {
+            cmd.arg("--features").arg(features.join(","));
+        }
+    }
+    // unlike the rustc args below, config applies to every crate in the build, not just the final one
+    if let Some(config) = cargo_config {
+        cmd.arg("--config").arg(config);
+    }
+    cmd.arg("--")
         .arg("-C")
         .arg(format!("link-arg=-T{link_name}"))
         .arg("-L")
@@ -326,7 +621,69 @@ impl Task {
                 plat.task_link()
             },
         )?;
-        build_crate(crate_path, None, link_name, reloc)
+        build_crate(
+            crate_path,
+            None,
+            link_name,
+            reloc,
+            self.features.as_deref(),
+            plat.task_cargo_config(),
+        )
+    }
+
+    /// Reads the named entrypoints from `[package.metadata.k5.entrypoints]` in the task's `Cargo.toml`
+    ///
+    /// Each entry maps a name to the symbol of a `#[no_mangle]` function in the task
+    pub fn entrypoint_symbols(&self) -> Result<BTreeMap<String, String>> {
+        let TaskSource::Crate { crate_path } = &self.source;
+        let metadata = cargo_metadata::MetadataCommand::new()
+            .manifest_path(crate_path.join("Cargo.toml"))
+            .no_deps()
+            .exec()?;
+        let package = metadata
+            .root_package()
+            .ok_or_else(|| anyhow!("no package found for {}", self.name))?;
+        match package.metadata.pointer("/k5/entrypoints") {
+            Some(entrypoints) => Ok(serde_json::from_value(entrypoints.clone())?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Returns an error with a bar for each budget if the task's sizes are over `max_flash_size` or `max_ram_size`
+    ///
+    /// `sizes` are the ranges returned by [`get_elf_size`]. The regions in `flash` count towards the flash budget,
+    /// and every other region counts towards the RAM budget.
+    pub(crate) fn check_budget(
+        &self,
+        sizes: &HashMap<String, Range<usize>>,
+        flash: &[String],
+    ) -> Result<()> {
+        let used = |in_flash: bool| -> usize {
+            sizes
+                .iter()
+                .filter(|(name, _)| flash.contains(*name) == in_flash)
+                .map(|(_, range)| range.len())
+                .sum()
+        };
+        let budgets = [
+            ("flash", used(true), self.max_flash_size),
+            ("ram", used(false), self.max_ram_size),
+        ];
+        if budgets
+            .iter()
+            .all(|(_, used, limit)| limit.map_or(true, |limit| *used <= limit))
+        {
+            return Ok(());
+        }
+        let bars: Vec<_> = budgets
+            .iter()
+            .filter_map(|(label, used, limit)| Some(budget_bar(label, *used, (*limit)?)))
+            .collect();
+        Err(anyhow!(
+            "{:?} is over its size budget:\n{}",
+            self.name,
+            bars.join("\n")
+        ))
     }
 
     pub fn link(
@@ -345,7 +702,11 @@ impl Task {
         )?;
 
         fs::write(target_dir.join("link.x"), link_script)?;
-        let status = Command::new("arm-none-eabi-ld")
+        let linker = self
+            .linker
+            .as_deref()
+            .ok_or_else(|| anyhow!("no linker set for {:?}", self.name))?;
+        let status = Command::new(linker)
             .current_dir(target_dir)
             .arg(reloc_elf)
             .arg("-o")
@@ -364,6 +725,65 @@ impl Task {
     }
 }
 
+/// Linkers that take the same arguments as GNU ld, suggested when the configured linker can't be found
+const LINKER_ALTERNATIVES: &[&str] = &[
+    "arm-none-eabi-ld",
+    "arm-none-eabi-ld.bfd",
+    "riscv32-unknown-elf-ld",
+    "riscv64-unknown-elf-ld",
+    "riscv64-linux-gnu-ld",
+    "ld.lld",
+];
+
+/// Checks that `linker` exists, either as a path or by searching `$PATH`
+fn check_linker(linker: &str) -> Result<()> {
+    if find_executable(linker) {
+        return Ok(());
+    }
+    let found: Vec<_> = LINKER_ALTERNATIVES
+        .iter()
+        .copied()
+        .filter(|l| *l != linker && find_executable(l))
+        .collect();
+    if found.is_empty() {
+        Err(anyhow!("linker {:?} not found in $PATH", linker))
+    } else {
+        Err(anyhow!(
+            "linker {:?} not found in $PATH, set `linker` in app.toml to one of the installed linkers: {}",
+            linker,
+            found.join(", ")
+        ))
+    }
+}
+
+/// Makes a relative `linker` path absolute, relative to `app_path`
+///
+/// Tasks are linked from their target directory, so a relative path would otherwise resolve against it.
+fn resolve_linker(linker: &mut Option<String>, app_path: &Path) -> Result<()> {
+    if let Some(name) = linker {
+        let path = Path::new(name);
+        if path.components().count() > 1 && path.is_relative() {
+            let path = fs::canonicalize(app_path.join(path))
+                .map_err(|err| anyhow!("linker {:?} not found: {}", name, err))?;
+            *name = path
+                .into_os_string()
+                .into_string()
+                .map_err(|path| anyhow!("linker path {:?} isn't valid utf-8", path))?;
+        }
+    }
+    Ok(())
+}
+
+fn find_executable(name: &str) -> bool {
+    let path = Path::new(name);
+    if path.components().count() > 1 {
+        return path.is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
 pub(crate) fn get_elf_size(
     elf: &Path,
     regions: &HashMap<String, MemorySection>,
@@ -377,8 +797,8 @@ pub(crate) fn get_elf_size(
     };
     let mut sizes = HashMap::new();
     let mut add_section = |start, size| {
-        println!("add_section: {:x} {:x}", start, size);
-        for (name, region) in regions.iter() {
+        // the shared region is linked into every task as-is, so it doesn't count towards any task's size
+        for (name, region) in regions.iter().filter(|(_, r)| r.role != MemoryRole::Shared) {
             if region.contains(start) || region.contains(start + size) {
                 let end = start + size;
                 let range = sizes.entry(name.clone()).or_insert(start..end);
@@ -390,7 +810,6 @@ pub(crate) fn get_elf_size(
         false
     };
     for header in &elf.program_headers {
-        println!("{:?}", header);
         add_section(header.p_vaddr as usize, header.p_memsz as usize);
         if header.p_vaddr != header.p_paddr
             && !add_section(header.p_paddr as usize, header.p_filesz as usize)
@@ -409,6 +828,78 @@ pub(crate) fn get_elf_size(
     Ok(sizes)
 }
 
+/// Renders `used` bytes against a `limit` as a bar, like `flash [#######---] 7000/10000 bytes (70%)`
+///
+/// Budgets that are exceeded are marked with `over by`.
+pub(crate) fn budget_bar(label: &str, used: usize, limit: usize) -> String {
+    const WIDTH: usize = 30;
+    let filled = if limit == 0 {
+        WIDTH
+    } else {
+        (used * WIDTH / limit).min(WIDTH)
+    };
+    let percent = if limit == 0 { 100 } else { used * 100 / limit };
+    let mut bar = format!(
+        "{:<6}[{}{}] {}/{} bytes ({}%)",
+        label,
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        used,
+        limit,
+        percent
+    );
+    if used > limit {
+        write!(&mut bar, ", over by {} bytes", used - limit).unwrap();
+    }
+    bar
+}
+
+/// Returns the address range of the `.text` section in `elf`, if it has one
+pub(crate) fn get_text_range(elf: &Path) -> Result<Option<Range<usize>>> {
+    let elf = fs::read(elf)?;
+    let elf = if let Object::Elf(e) = Object::parse(&elf)? {
+        e
+    } else {
+        return Err(anyhow!("object must be an elf"));
+    };
+    Ok(elf
+        .section_headers
+        .iter()
+        .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".text"))
+        .map(|sh| sh.sh_addr as usize..(sh.sh_addr + sh.sh_size) as usize))
+}
+
+/// Looks up each entrypoint symbol in `elf`, and returns its offset from `base`
+pub(crate) fn get_entrypoint_offsets(
+    elf: &Path,
+    symbols: &BTreeMap<String, String>,
+    base: usize,
+) -> Result<BTreeMap<String, usize>> {
+    if symbols.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let elf = fs::read(elf)?;
+    let elf = if let Object::Elf(e) = Object::parse(&elf)? {
+        e
+    } else {
+        return Err(anyhow!("object must be an elf"));
+    };
+    symbols
+        .iter()
+        .map(|(name, symbol)| {
+            let sym = elf
+                .syms
+                .iter()
+                .find(|s| elf.strtab.get_at(s.st_name) == Some(symbol.as_str()))
+                .ok_or_else(|| anyhow!("entrypoint symbol {:?} not found", symbol))?;
+            let offset = (sym.st_value as usize)
+                .checked_sub(base)
+                .ok_or_else(|| anyhow!("entrypoint {:?} is outside of the flash region", name))?;
+            Ok((name.clone(), offset))
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct TaskLoc {
     pub(crate) regions: HashMap<String, MemorySection>,
@@ -428,12 +919,15 @@ impl TaskLoc {
                 section.address = section.address + stack_size;
                 section.size = section.size - stack_size;
             }
+            let name = if section.role == MemoryRole::Shared {
+                "SHARED".to_string()
+            } else {
+                name.to_uppercase()
+            };
             writeln!(
                 &mut file,
                 "{} : ORIGIN = {:#010x}, LENGTH = {:#010x}",
-                name.to_uppercase(),
-                section.address,
-                section.size
+                name, section.address, section.size
             )?;
         }
         file += "}";
@@ -451,7 +945,7 @@ pub struct MemorySection {
 }
 
 impl MemorySection {
-    fn contains(&self, addr: usize) -> bool {
+    pub(crate) fn contains(&self, addr: usize) -> bool {
         addr >= self.address && addr <= (self.address + self.size)
     }
 }
@@ -475,26 +969,29 @@ impl SRecWriter {
             }
             let data =
                 &image[header.p_offset as usize..(header.p_offset + header.p_filesz) as usize];
-            let mut addr = header.p_paddr as u32;
-            for chunk in data.chunks(250) {
-                self.buf.push(srec::Record::S3(srec::Data {
-                    address: srec::Address32(addr),
-                    data: chunk.to_vec(),
-                }));
-                addr += chunk.len() as u32;
-            }
+            self.write_slice(header.p_paddr, data)?;
         }
         Ok(elf.header.e_entry as usize)
     }
 
-    pub(crate) fn write_slice(&mut self, mut addr: usize, buf: &[u8]) {
-        for chunk in buf.chunks(250) {
+    /// Writes `buf` at `addr`, which must fit in the 32-bit addresses of S3 records
+    ///
+    /// 64-bit targets are supported as long as the image is loaded below 4GiB, which is true for the D1's DRAM
+    pub(crate) fn write_slice(&mut self, addr: impl TryInto<u32>, buf: &[u8]) -> Result<()> {
+        let addr: u32 = addr
+            .try_into()
+            .map_err(|_| anyhow!("64-bit address cannot be encoded in SREC S3 record"))?;
+        for (i, chunk) in buf.chunks(250).enumerate() {
+            let addr = u32::try_from(i * 250)
+                .ok()
+                .and_then(|offset| addr.checked_add(offset))
+                .ok_or_else(|| anyhow!("64-bit address cannot be encoded in SREC S3 record"))?;
             self.buf.push(srec::Record::S3(srec::Data {
-                address: srec::Address32(addr as u32),
+                address: srec::Address32(addr),
                 data: chunk.to_vec(),
             }));
-            addr += chunk.len()
         }
+        Ok(())
     }
 
     pub(crate) fn finalize(&mut self) -> String {
@@ -523,3 +1020,163 @@ pub const fn align_up(addr: usize, align: usize) -> usize {
         (addr | align_mask) + 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<T: serde::de::DeserializeOwned>(toml: &str) -> T {
+        let mut config = config::Config::default();
+        config
+            .merge(config::File::from_str(toml, config::FileFormat::Toml))
+            .unwrap();
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_merge_keeps_unset_fields() {
+        let mut config: Config = parse(
+            r#"
+            flash_tool = "probe"
+            chip = "STM32H743ZITx"
+            platform = "ArmV7m"
+            serial_baud = 115200
+            kernel_features = ["defmt"]
+
+            [kernel]
+            crate_path = "kernel"
+
+            [regions.flash]
+            address = 0x08000000
+            size = 0x100000
+
+            [[tasks]]
+            name = "foo"
+            crate_path = "foo"
+            secure = false
+            "#,
+        );
+        let overlay: ConfigOverlay = parse(
+            r#"
+            max_total_flash = 0x8000
+
+            [regions.ram]
+            address = 0x20000000
+            size = 0x20000
+
+            [[tasks]]
+            name = "foo"
+            crate_path = "foo-dev"
+            secure = false
+            "#,
+        );
+        config.merge(overlay);
+        assert!(matches!(
+            &config.flash_probe,
+            flash::FlashConfig::Probe(probe) if probe.chip.as_deref() == Some("STM32H743ZITx")
+        ));
+        assert!(matches!(config.platform, Platform::ArmV7m));
+        assert_eq!(config.kernel.crate_path, Path::new("kernel"));
+        assert_eq!(config.kernel_features, ["defmt"]);
+        assert_eq!(config.serial_baud, Some(115200));
+        assert_eq!(config.max_total_flash, Some(0x8000));
+        assert_eq!(config.regions.len(), 2);
+        assert_eq!(config.tasks.len(), 1);
+        let TaskSource::Crate { crate_path } = &config.tasks[0].source;
+        assert_eq!(crate_path, Path::new("foo-dev"));
+
+        config.merge(parse(
+            r#"
+            platform = "ArmV8m"
+            kernel_features = []
+            "#,
+        ));
+        assert!(matches!(config.platform, Platform::ArmV8m));
+        assert!(config.kernel_features.is_empty());
+        assert_eq!(config.kernel.crate_path, Path::new("kernel"));
+    }
+
+    #[test]
+    fn test_resolve_linker() {
+        let dir = tempdir::TempDir::new("k5-linker").unwrap();
+        fs::create_dir(dir.path().join("tools")).unwrap();
+        fs::write(dir.path().join("tools/ld"), "").unwrap();
+        let app_path = fs::canonicalize(dir.path()).unwrap();
+
+        let mut linker = Some("tools/ld".to_string());
+        resolve_linker(&mut linker, &app_path).unwrap();
+        assert_eq!(
+            linker.as_deref().map(Path::new),
+            Some(&*app_path.join("tools/ld"))
+        );
+
+        let mut linker = Some("arm-none-eabi-ld".to_string());
+        resolve_linker(&mut linker, &app_path).unwrap();
+        assert_eq!(linker.as_deref(), Some("arm-none-eabi-ld"));
+
+        let mut linker = Some("tools/missing-ld".to_string());
+        assert!(resolve_linker(&mut linker, &app_path).is_err());
+    }
+
+    #[test]
+    fn test_budget_bar() {
+        assert_eq!(
+            budget_bar("flash", 7000, 10000),
+            "flash [#####################---------] 7000/10000 bytes (70%)"
+        );
+        assert_eq!(
+            budget_bar("ram", 300, 200),
+            "ram   [##############################] 300/200 bytes (150%), over by 100 bytes"
+        );
+        assert_eq!(
+            budget_bar("ram", 0, 0),
+            "ram   [##############################] 0/0 bytes (100%)"
+        );
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let task: Task = parse(
+            r#"
+            name = "foo"
+            crate_path = "foo"
+            secure = false
+            max_flash_size = 0x1000
+            max_ram_size = 0x800
+            "#,
+        );
+        let flash = ["flash".to_string()];
+        let sizes = |flash_len: usize, ram_len: usize| {
+            HashMap::from([
+                ("flash".to_string(), 0x0800_0000..0x0800_0000 + flash_len),
+                ("ram".to_string(), 0x2000_0000..0x2000_0000 + ram_len / 2),
+                ("stack".to_string(), 0x2001_0000..0x2001_0000 + ram_len / 2),
+            ])
+        };
+        assert!(task.check_budget(&sizes(0x1000, 0x800), &flash).is_ok());
+
+        let err = task
+            .check_budget(&sizes(0x1001, 0x800), &flash)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("over by 1 bytes"), "{}", err);
+        assert!(err.contains("ram   ["), "{}", err);
+
+        let err = task
+            .check_budget(&sizes(0x1000, 0x802), &flash)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("2050/2048 bytes"), "{}", err);
+
+        let unlimited: Task = parse(
+            r#"
+            name = "bar"
+            crate_path = "bar"
+            secure = false
+            "#,
+        );
+        assert!(unlimited
+            .check_budget(&sizes(0x10_0000, 0x10_0000), &flash)
+            .is_ok());
+    }
+}
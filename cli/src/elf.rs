@@ -1,6 +1,6 @@
 // from probe-run/src/elf.rs
 #![allow(dead_code)]
-use std::{collections::HashSet, convert::TryInto, env, ops::Deref};
+use std::{collections::HashSet, env, ops::Deref};
 
 use color_eyre::{
     eyre::{anyhow, bail},
@@ -48,7 +48,7 @@ impl<'file> Elf<'file> {
         })
     }
 
-    pub fn main_fn_address(&self) -> u32 {
+    pub fn main_fn_address(&self) -> u64 {
         self.symbols.main_fn_address
     }
 
@@ -56,7 +56,7 @@ impl<'file> Elf<'file> {
         self.symbols.program_uses_heap
     }
 
-    pub fn rtt_buffer_address(&self) -> Option<u32> {
+    pub fn rtt_buffer_address(&self) -> Option<u64> {
         self.symbols.rtt_buffer_address
     }
 }
@@ -170,10 +170,11 @@ fn extract_vector_table(elf: &ObjectFile) -> Result<VectorTable> {
 //     Ok(debug_frame)
 // }
 
+/// Symbol addresses are stored as `u64`, so both 32-bit ARM and 64-bit RISC-V ELFs can be read
 struct Symbols {
-    rtt_buffer_address: Option<u32>,
+    rtt_buffer_address: Option<u64>,
     program_uses_heap: bool,
-    main_fn_address: u32,
+    main_fn_address: u64,
 }
 
 fn extract_symbols(elf: &ObjectFile) -> Result<Symbols> {
@@ -187,7 +188,7 @@ fn extract_symbols(elf: &ObjectFile) -> Result<Symbols> {
             Err(_) => continue,
         };
 
-        let address = symbol.address().try_into().expect("expected 32-bit ELF");
+        let address = symbol.address();
         match name {
             "main" => main_fn_address = Some(address & !1), // clear thumb bit,
             "_SEGGER_RTT" => rtt_buffer_address = Some(address),
@@ -217,3 +218,17 @@ pub struct VectorTable {
     // entry 3: HardFault handler
     pub hard_fault: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_symbols_elf64() {
+        let elf =
+            ObjectFile::parse(&include_bytes!("../tests/fixtures/rv64-symbols.o")[..]).unwrap();
+        let symbols = extract_symbols(&elf).unwrap();
+        assert_eq!(symbols.main_fn_address, 0x1_0000_0000);
+        assert_eq!(symbols.rtt_buffer_address, Some(0x1_0000_1000));
+    }
+}
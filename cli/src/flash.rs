@@ -1,15 +1,15 @@
 use std::{
     fs,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    thread,
 };
 
-use color_eyre::Result;
-use colored::Colorize;
+use color_eyre::{eyre::anyhow, Result};
 use kdam::{tqdm, Column, RichProgress};
 use probe_rs::{
     flashing::{DownloadOptions, FlashProgress, ProgressEvent},
-    DebugProbeSelector, Session as ProbeSession, WireProtocol,
+    DebugProbeSelector, Probe, Session as ProbeSession, WireProtocol,
 };
 use probe_rs_cli_util::common_options::ProbeOptions;
 use serde::Deserialize;
@@ -26,6 +26,8 @@ pub enum FlashConfig {
     Xfel {
         flash: xfel::InternalFlash,
         base_addr: usize,
+        #[serde(default)]
+        xfel: xfel::XfelConfig,
     },
     Probe(#[serde(default)] ProbeConfig),
 }
@@ -73,6 +75,10 @@ pub struct ProbeConfig {
     )]
     #[serde(default)]
     pub allow_erase_all: bool,
+    /// Flashes every connected probe in parallel, rather than a single probe
+    #[structopt(long = "multi-flash")]
+    #[serde(default)]
+    pub multi_flash: bool,
 }
 
 // impl FlashConfig {
@@ -127,19 +133,35 @@ impl From<ProbeConfig> for ProbeOptions {
     }
 }
 
+/// Flashes the app, to every connected probe if `multi_flash` is set
+pub fn flash_all(config: &Config) -> Result<()> {
+    match &config.flash_probe {
+        FlashConfig::Probe(probe) if probe.multi_flash => probe
+            .clone()
+            .multi_flash(&config.kernel.crate_path.join("target").join("final.ihex")),
+        _ => flash(config).map(|_| ()),
+    }
+}
+
 pub fn flash(config: &Config) -> Result<Session> {
     let target = config.kernel.crate_path.join("target");
     match &config.flash_probe {
-        FlashConfig::Xfel { flash, base_addr } => {
-            let device = XfelDevice::connect(*flash).map_err(|err| {
-                println!("{} {}", " Hint ".on_red().white(), "Remember to put the device into FEL mode, this can be done by holding down the FEL button and hitting reset.");
-               err
-            })?;
+        FlashConfig::Xfel {
+            flash,
+            base_addr,
+            xfel,
+        } => {
+            let device = XfelDevice::connect(*flash, xfel)?;
             let bin = target.join("final.bin");
             device.write_flash(*base_addr, &bin)?;
             Ok(Session::Xfel(device))
         }
         FlashConfig::Probe(probe) => {
+            if probe.multi_flash {
+                return Err(anyhow!(
+                    "multi_flash is only supported when flashing, not when attaching to a device"
+                ));
+            }
             let probe = probe.clone();
             let ihex = target.join("final.ihex");
             probe.flash(ihex).map(Session::Probe)
@@ -147,6 +169,14 @@ pub fn flash(config: &Config) -> Result<Session> {
     }
 }
 
+/// Attaches to the probe configured in `app.toml` without flashing or resetting the target
+pub fn attach_probe_session(config: &Config) -> Result<ProbeSession> {
+    match &config.flash_probe {
+        FlashConfig::Probe(probe) => probe.clone().attach(),
+        FlashConfig::Xfel { .. } => Err(anyhow!("attaching is only supported with a probe")),
+    }
+}
+
 pub enum Session {
     Xfel(XfelDevice),
     Probe(ProbeSession),
@@ -157,69 +187,140 @@ impl ProbeConfig {
         crate::print_header("Flashing");
         let config: ProbeOptions = self.into();
         let mut session = config.simple_attach()?;
-        let mut bin = fs::File::open(ihex)?;
-        let mut loader = session.target().flash_loader();
-        loader.load_hex_data(&mut bin)?;
-        let mut download_option = DownloadOptions::default();
-        //download_option.keep_unwritten_bytes = config.restore_unwritten;
-        download_option.dry_run = config.dry_run;
-        download_option.do_chip_erase = true;
-        // download_option.disable_double_buffering = config.disable_double_buffering;
-
-        let pb = Arc::new(Mutex::new(RichProgress::new(
-            tqdm!(),
-            vec![
-                Column::Bar,
-                Column::Percentage(1),
-                Column::Text("•".to_string(), None),
-                Column::CountTotal,
-                Column::Text("•".to_string(), None),
-                Column::RemainingTime,
-            ],
-        )));
         kdam::term::init();
-        let total_sector_size = Arc::new(Mutex::new(0));
-        let total_page_size = Arc::new(Mutex::new(0));
-        let total_fill_size = Arc::new(Mutex::new(0));
-        let progress = FlashProgress::new(move |event| match event {
-            ProgressEvent::Initialized { flash_layout } => {
-                *total_page_size.lock().unwrap() =
-                    flash_layout.pages().iter().map(|s| s.size()).sum();
-                *total_sector_size.lock().unwrap() =
-                    flash_layout.sectors().iter().map(|s| s.size()).sum();
-                *total_fill_size.lock().unwrap() =
-                    flash_layout.fills().iter().map(|s| s.size()).sum();
-            }
-            ProgressEvent::StartedFilling => {
-                let mut pb = pb.lock().unwrap();
-                pb.reset(Some(*total_fill_size.lock().unwrap() as usize))
-            }
-            ProgressEvent::StartedErasing => {
-                let mut pb = pb.lock().unwrap();
-                pb.reset(Some(*total_sector_size.lock().unwrap() as usize))
-            }
-            ProgressEvent::StartedProgramming => {
-                let mut pb = pb.lock().unwrap();
-                pb.reset(Some(*total_page_size.lock().unwrap() as usize))
-            }
+        let pb = progress_bar(tqdm!());
+        download(&mut session, &ihex, config.dry_run, pb)?;
+        Ok(session)
+    }
 
-            ProgressEvent::PageFilled { size, .. } => {
-                let mut pb = pb.lock().unwrap();
-                pb.update(size as usize);
-            }
-            ProgressEvent::SectorErased { size, .. } => {
-                let mut pb = pb.lock().unwrap();
-                pb.update(size as usize);
-            }
-            ProgressEvent::PageProgrammed { size, .. } => {
-                let mut pb = pb.lock().unwrap();
-                pb.update(size as usize);
-            }
-            _ => {}
-        });
-        download_option.progress = Some(&progress);
-        loader.commit(&mut session, download_option)?;
+    /// Attaches to the target without flashing or resetting it
+    pub fn attach(self) -> Result<ProbeSession> {
+        let config: ProbeOptions = self.into();
+        Ok(config.simple_attach()?)
+    }
 
-        Ok(session)
+    /// Flashes every connected probe in parallel, each probe is attached to with the rest of this config
+    ///
+    /// Every probe is attempted even if some fail, an error is returned if any of them failed.
+    pub fn multi_flash(self, ihex: &Path) -> Result<()> {
+        crate::print_header("Flashing");
+        let probes = Probe::list_all();
+        if probes.is_empty() {
+            return Err(anyhow!("no probes found"));
+        }
+        kdam::term::init();
+        let handles: Vec<_> = probes
+            .into_iter()
+            .enumerate()
+            .map(|(i, info)| {
+                let name = info
+                    .serial_number
+                    .clone()
+                    .unwrap_or_else(|| info.identifier.clone());
+                let mut config: ProbeOptions = self.clone().into();
+                config.probe_selector = Some(DebugProbeSelector {
+                    vendor_id: info.vendor_id,
+                    product_id: info.product_id,
+                    serial_number: info.serial_number,
+                });
+                let pb = progress_bar(tqdm!(desc = name.clone(), position = i as u16));
+                let ihex = ihex.to_path_buf();
+                let handle = thread::spawn(move || -> Result<()> {
+                    let mut session = config.simple_attach()?;
+                    download(&mut session, &ihex, config.dry_run, pb)
+                });
+                (name, handle)
+            })
+            .collect();
+        let mut failed = 0;
+        for (name, handle) in handles {
+            match handle.join() {
+                Ok(Ok(())) => println!("{}: flashed", name),
+                Ok(Err(err)) => {
+                    failed += 1;
+                    println!("{}: failed: {:?}", name, err);
+                }
+                Err(_) => {
+                    failed += 1;
+                    println!("{}: failed: flashing thread panicked", name);
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("failed to flash {} probe(s)", failed));
+        }
+        Ok(())
     }
 }
+
+fn progress_bar(bar: kdam::Bar) -> Arc<Mutex<RichProgress>> {
+    Arc::new(Mutex::new(RichProgress::new(
+        bar,
+        vec![
+            Column::Bar,
+            Column::Percentage(1),
+            Column::Text("•".to_string(), None),
+            Column::CountTotal,
+            Column::Text("•".to_string(), None),
+            Column::RemainingTime,
+        ],
+    )))
+}
+
+/// Writes `ihex` to the target's flash, reporting progress to `pb`
+fn download(
+    session: &mut ProbeSession,
+    ihex: &Path,
+    dry_run: bool,
+    pb: Arc<Mutex<RichProgress>>,
+) -> Result<()> {
+    let mut bin = fs::File::open(ihex)?;
+    let mut loader = session.target().flash_loader();
+    loader.load_hex_data(&mut bin)?;
+    let mut download_option = DownloadOptions::default();
+    //download_option.keep_unwritten_bytes = config.restore_unwritten;
+    download_option.dry_run = dry_run;
+    download_option.do_chip_erase = true;
+    // download_option.disable_double_buffering = config.disable_double_buffering;
+
+    let total_sector_size = Arc::new(Mutex::new(0));
+    let total_page_size = Arc::new(Mutex::new(0));
+    let total_fill_size = Arc::new(Mutex::new(0));
+    let progress = FlashProgress::new(move |event| match event {
+        ProgressEvent::Initialized { flash_layout } => {
+            *total_page_size.lock().unwrap() = flash_layout.pages().iter().map(|s| s.size()).sum();
+            *total_sector_size.lock().unwrap() =
+                flash_layout.sectors().iter().map(|s| s.size()).sum();
+            *total_fill_size.lock().unwrap() = flash_layout.fills().iter().map(|s| s.size()).sum();
+        }
+        ProgressEvent::StartedFilling => {
+            let mut pb = pb.lock().unwrap();
+            pb.reset(Some(*total_fill_size.lock().unwrap() as usize))
+        }
+        ProgressEvent::StartedErasing => {
+            let mut pb = pb.lock().unwrap();
+            pb.reset(Some(*total_sector_size.lock().unwrap() as usize))
+        }
+        ProgressEvent::StartedProgramming => {
+            let mut pb = pb.lock().unwrap();
+            pb.reset(Some(*total_page_size.lock().unwrap() as usize))
+        }
+
+        ProgressEvent::PageFilled { size, .. } => {
+            let mut pb = pb.lock().unwrap();
+            pb.update(size as usize);
+        }
+        ProgressEvent::SectorErased { size, .. } => {
+            let mut pb = pb.lock().unwrap();
+            pb.update(size as usize);
+        }
+        ProgressEvent::PageProgrammed { size, .. } => {
+            let mut pb = pb.lock().unwrap();
+            pb.update(size as usize);
+        }
+        _ => {}
+    });
+    download_option.progress = Some(&progress);
+    loader.commit(session, download_option)?;
+    Ok(())
+}
@@ -0,0 +1,455 @@
+//! A GDB remote serial protocol server, for debugging tasks through a probe
+//!
+//! Registers are those of the thread the selected task last ran on, found through the kernel's
+//! `__k5_debug_info` symbol. When the core is halted in that thread they are read from the core, otherwise
+//! they are rebuilt from the thread's saved state and the exception frame on its stack. Memory, breakpoints,
+//! and stepping go straight to the core, so they aren't limited to the selected task.
+use std::{
+    marker::PhantomData,
+    mem,
+    net::{TcpListener, TcpStream},
+    num::NonZeroUsize,
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{eyre::anyhow, Result};
+use colored::Colorize;
+use gdbstub::{
+    arch::{Arch, RegId, Registers, SingleStepGdbBehavior},
+    common::Signal,
+    conn::ConnectionExt,
+    stub::{run_blocking, DisconnectReason, GdbStub, SingleThreadStopReason},
+    target::{
+        ext::{
+            base::{
+                singlethread::{
+                    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps,
+                    SingleThreadSingleStep, SingleThreadSingleStepOps,
+                },
+                BaseOps,
+            },
+            breakpoints::{Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps},
+        },
+        Target, TargetError, TargetResult,
+    },
+};
+use object::{Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface as _, RegisterId, Session};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the core is checked for a halt while it is running
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// The version of `__k5_debug_info` this understands, see `DebugInfo` in the kernel's `arch/cortex_m.rs`
+const DEBUG_INFO_VERSION: u32 = 1;
+/// The core register ID of xPSR, after r0-r15
+const XPSR: u16 = 16;
+
+/// A Cortex-M target description with just the core registers, which is all GDB needs to unwind task code
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+/// Waits for GDB to connect on `port`, and serves it until it disconnects
+///
+/// `task` is the index of the task to debug in the kernel's task table, if it is `None` the running thread is
+/// debugged instead. `kernel_elf` is used to find `__k5_debug_info`.
+pub fn serve(
+    session: &mut Session,
+    kernel_elf: &[u8],
+    task: Option<usize>,
+    port: u16,
+) -> Result<()> {
+    let elf = object::File::parse(kernel_elf)?;
+    let debug_info_addr = elf
+        .symbols()
+        .find(|s| s.name() == Ok("__k5_debug_info"))
+        .ok_or_else(|| anyhow!("`__k5_debug_info` not found, is the kernel built for Cortex-M?"))?
+        .address() as u32;
+
+    let mut core = session.core(0)?;
+    core.reset_and_halt(TIMEOUT)?;
+    let debug_info = DebugInfo::read(&mut core, debug_info_addr)?;
+    if let Some(task) = task {
+        if task >= debug_info.task_capacity as usize {
+            return Err(anyhow!(
+                "the kernel only tracks {} tasks",
+                debug_info.task_capacity
+            ));
+        }
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!(
+        "{}",
+        format!("waiting for GDB on localhost:{}", port).bold()
+    );
+    let (stream, _) = listener.accept()?;
+    println!("{}", "GDB connected".bold());
+
+    let mut target = K5Target {
+        core,
+        debug_info,
+        task,
+        stepping: false,
+    };
+    match GdbStub::new(stream).run_blocking::<EventLoop<'_>>(&mut target) {
+        Ok(DisconnectReason::Disconnect) => println!("{}", "GDB disconnected".bold()),
+        Ok(reason) => println!("{}", format!("GDB session ended: {:?}", reason).bold()),
+        Err(err) => return Err(anyhow!("GDB session failed: {}", err)),
+    }
+    Ok(())
+}
+
+/// The host's copy of the kernel's `DebugInfo`
+struct DebugInfo {
+    task_capacity: u32,
+    current_tcb: u32,
+    task_tcbs: u32,
+}
+
+impl DebugInfo {
+    fn read(core: &mut Core, addr: u32) -> Result<Self> {
+        let mut words = [0u32; 4];
+        core.read_32(addr, &mut words)?;
+        let [version, task_capacity, current_tcb, task_tcbs] = words;
+        if version != DEBUG_INFO_VERSION {
+            return Err(anyhow!(
+                "unsupported debug info version {}, expected {}",
+                version,
+                DEBUG_INFO_VERSION
+            ));
+        }
+        Ok(Self {
+            task_capacity,
+            current_tcb,
+            task_tcbs,
+        })
+    }
+}
+
+/// Where the debugged thread's registers currently are
+enum RegSource {
+    /// The thread is running on the core
+    Core,
+    /// The thread's registers were saved to the TCB at this address when the kernel was entered
+    Saved(u32),
+}
+
+struct K5Target<'a> {
+    core: Core<'a>,
+    debug_info: DebugInfo,
+    task: Option<usize>,
+    /// Set while single stepping, so the next halt is reported as a completed step rather than a breakpoint
+    stepping: bool,
+}
+
+impl<'a> K5Target<'a> {
+    fn reg_source(&mut self) -> TargetResult<RegSource, Self> {
+        let current = self.read_word(self.debug_info.current_tcb)?;
+        let tcb = match self.task {
+            Some(task) => self.read_word(self.debug_info.task_tcbs + task as u32 * 4)?,
+            None => current,
+        };
+        // the TCB is null until the thread has run for the first time
+        if tcb == 0 {
+            return Err(TargetError::NonFatal);
+        }
+        let xpsr = self
+            .core
+            .read_core_reg(RegisterId(XPSR))
+            .map_err(TargetError::Fatal)?;
+        // the exception number in IPSR is 0 in thread mode, which only ever runs the current thread
+        if tcb == current && xpsr & 0x1FF == 0 {
+            Ok(RegSource::Core)
+        } else {
+            Ok(RegSource::Saved(tcb))
+        }
+    }
+
+    fn read_word(&mut self, addr: u32) -> TargetResult<u32, Self> {
+        self.core.read_word_32(addr).map_err(TargetError::Fatal)
+    }
+
+    /// Rebuilds a thread's registers from its `SavedThreadState` and the exception frame on its stack
+    fn read_saved_registers(&mut self, tcb: u32, regs: &mut CortexMRegs) -> TargetResult<(), Self> {
+        // r4-r11, then the PSP and EXC_RETURN
+        let mut saved = [0u32; 10];
+        self.core
+            .read_32(tcb, &mut saved)
+            .map_err(TargetError::Fatal)?;
+        let (psp, exc_return) = (saved[8], saved[9]);
+        // r0-r3, r12, lr, pc, and xPSR
+        let mut frame = [0u32; 8];
+        self.core
+            .read_32(psp, &mut frame)
+            .map_err(|_| TargetError::NonFatal)?;
+        // bit 4 of EXC_RETURN is clear when the frame includes the FPU registers
+        let mut frame_len = if exc_return & (1 << 4) == 0 {
+            0x68
+        } else {
+            0x20
+        };
+        // bit 9 of the stacked xPSR is set when the stack was realigned to 8 bytes
+        if frame[7] & (1 << 9) != 0 {
+            frame_len += 4;
+        }
+        regs.r[..4].copy_from_slice(&frame[..4]);
+        regs.r[4..12].copy_from_slice(&saved[..8]);
+        regs.r[12] = frame[4];
+        regs.sp = psp + frame_len;
+        regs.lr = frame[5];
+        regs.pc = frame[6];
+        regs.xpsr = frame[7];
+        Ok(())
+    }
+}
+
+impl<'a> Target for K5Target<'a> {
+    type Arch = CortexM;
+    type Error = probe_rs::Error;
+
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<'a> SingleThreadBase for K5Target<'a> {
+    fn read_registers(&mut self, regs: &mut CortexMRegs) -> TargetResult<(), Self> {
+        match self.reg_source()? {
+            RegSource::Core => {
+                let mut values = [0u32; 17];
+                for (id, value) in values.iter_mut().enumerate() {
+                    *value = self
+                        .core
+                        .read_core_reg(RegisterId(id as u16))
+                        .map_err(TargetError::Fatal)?;
+                }
+                regs.r.copy_from_slice(&values[..13]);
+                regs.sp = values[13];
+                regs.lr = values[14];
+                regs.pc = values[15];
+                regs.xpsr = values[16];
+                Ok(())
+            }
+            RegSource::Saved(tcb) => self.read_saved_registers(tcb, regs),
+        }
+    }
+
+    fn write_registers(&mut self, regs: &CortexMRegs) -> TargetResult<(), Self> {
+        // writing a saved thread's registers would mean rewriting its stack, so only the running thread is writable
+        if !matches!(self.reg_source()?, RegSource::Core) {
+            return Err(TargetError::NonFatal);
+        }
+        let values = regs
+            .r
+            .iter()
+            .chain([regs.sp, regs.lr, regs.pc, regs.xpsr].iter());
+        for (id, value) in values.enumerate() {
+            self.core
+                .write_core_reg(RegisterId(id as u16), *value)
+                .map_err(TargetError::Fatal)?;
+        }
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
+        // GDB often reads past the end of memory while unwinding, which shouldn't end the session
+        self.core
+            .read_8(start_addr, data)
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        self.core
+            .write_8(start_addr, data)
+            .map_err(|_| TargetError::NonFatal)
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<'a> SingleThreadResume for K5Target<'a> {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.core.run()
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<'a> SingleThreadSingleStep for K5Target<'a> {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.stepping = true;
+        self.core.step().map(|_| ())
+    }
+}
+
+impl<'a> Breakpoints for K5Target<'a> {
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl<'a> HwBreakpoint for K5Target<'a> {
+    fn add_hw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        // running out of breakpoint units is reported to GDB as an unsupported breakpoint
+        Ok(self.core.set_hw_breakpoint(addr).is_ok())
+    }
+
+    fn remove_hw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        Ok(self.core.clear_hw_breakpoint(addr).is_ok())
+    }
+}
+
+struct EventLoop<'a>(PhantomData<&'a ()>);
+
+impl<'a> run_blocking::BlockingEventLoop for EventLoop<'a> {
+    type Target = K5Target<'a>;
+    type Connection = TcpStream;
+    type StopReason = SingleThreadStopReason<u32>;
+
+    fn wait_for_stop_reason(
+        target: &mut K5Target<'a>,
+        conn: &mut TcpStream,
+    ) -> Result<
+        run_blocking::Event<Self::StopReason>,
+        run_blocking::WaitForStopReasonError<probe_rs::Error, std::io::Error>,
+    > {
+        loop {
+            if conn
+                .peek()
+                .map_err(run_blocking::WaitForStopReasonError::Connection)?
+                .is_some()
+            {
+                let byte = conn
+                    .read()
+                    .map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                return Ok(run_blocking::Event::IncomingData(byte));
+            }
+            if target
+                .core
+                .core_halted()
+                .map_err(run_blocking::WaitForStopReasonError::Target)?
+            {
+                let reason = if mem::take(&mut target.stepping) {
+                    SingleThreadStopReason::DoneStep
+                } else {
+                    SingleThreadStopReason::HwBreak(())
+                };
+                return Ok(run_blocking::Event::TargetStopped(reason));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn on_interrupt(
+        target: &mut K5Target<'a>,
+    ) -> Result<Option<Self::StopReason>, probe_rs::Error> {
+        target.core.halt(TIMEOUT)?;
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+enum CortexM {}
+
+impl Arch for CortexM {
+    type Usize = u32;
+    type Registers = CortexMRegs;
+    type BreakpointKind = usize;
+    type RegId = CortexMRegId;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+
+    fn single_step_gdb_behavior() -> SingleStepGdbBehavior {
+        SingleStepGdbBehavior::Optional
+    }
+}
+
+/// The core registers, in the order of [`TARGET_XML`]
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CortexMRegs {
+    r: [u32; 13],
+    sp: u32,
+    lr: u32,
+    pc: u32,
+    xpsr: u32,
+}
+
+impl Registers for CortexMRegs {
+    type ProgramCounter = u32;
+
+    fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        let regs = self
+            .r
+            .iter()
+            .chain([self.sp, self.lr, self.pc, self.xpsr].iter());
+        for reg in regs {
+            for byte in reg.to_le_bytes() {
+                write_byte(Some(byte));
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != 17 * 4 {
+            return Err(());
+        }
+        let mut values = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        for r in self.r.iter_mut() {
+            *r = values.next().unwrap();
+        }
+        self.sp = values.next().unwrap();
+        self.lr = values.next().unwrap();
+        self.pc = values.next().unwrap();
+        self.xpsr = values.next().unwrap();
+        Ok(())
+    }
+}
+
+/// A register's index in [`TARGET_XML`]
+#[derive(Debug, Clone, Copy)]
+struct CortexMRegId(u16);
+
+impl RegId for CortexMRegId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        (id <= XPSR as usize).then(|| (CortexMRegId(id as u16), NonZeroUsize::new(4)))
+    }
+}
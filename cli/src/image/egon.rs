@@ -19,11 +19,16 @@ pub struct D1ImageBuilder {
 }
 
 impl D1ImageBuilder {
+    /// Creates a builder that places the egon header at the start of the `flash` region
+    ///
+    /// Tasks are built by an inner [`SRecImageBuilder`], which enforces their size budgets. The header is
+    /// taken out of `max_total_flash` up front, since it isn't part of any task or the kernel.
     #[allow(dead_code)]
     pub(crate) fn new(
         mut regions: HashMap<String, MemorySection>,
         platform: Platform,
         kern: &Kernel,
+        max_total_flash: Option<usize>,
     ) -> Result<Self> {
         let flash_region = regions
             .get_mut("flash")
@@ -32,7 +37,12 @@ impl D1ImageBuilder {
         flash_region.address += D1_HEADER_SIZE;
         flash_region.size -= D1_HEADER_SIZE;
         Ok(Self {
-            srec: SRecImageBuilder::new(regions, platform, kern),
+            srec: SRecImageBuilder::new(
+                regions,
+                platform,
+                kern,
+                max_total_flash.map(|limit| limit.saturating_sub(D1_HEADER_SIZE)),
+            ),
             flash_base_addr,
         })
     }
@@ -84,7 +94,7 @@ impl ImageBuilder for D1ImageBuilder {
         head.checksum = checksum;
         self.srec
             .output
-            .write_slice(self.flash_base_addr, bytemuck::bytes_of(&head));
+            .write_slice(self.flash_base_addr, bytemuck::bytes_of(&head))?;
         self.srec.build()
     }
 }
@@ -115,22 +125,63 @@ pub struct HeadData {
     string_pool: [u32; 13],
 }
 
+/// The JAL opcode with `rd` set to `x0`, so the return address is discarded
+const JAL_X0: u32 = 0x0000_006f;
+
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(transparent)]
 struct RVJumpInst(u32);
 
 impl RVJumpInst {
-    const fn new(_addr: usize) -> Self {
-        // let addr = addr as u32;
-        // // source uboot:
-        // // https://github.com/u-boot/u-boot/blob/aef6839747b5b01e3d1d32d16e712d42a6702b88/tools/sunxi_egon.c#L135
-        // // basically generates a valid jump inst in rv64
-        // let value = 0x0000006f
-        //     | ((addr & 0x00100000) << 11)
-        //     | ((addr & 0x000007fe) << 20)
-        //     | ((addr & 0x00000800) << 9)
-        //     | ((addr & 0x000ff000) << 0);
-        // Self(value)
-        Self(0x00_00_a0_85)
+    /// Encodes `jal x0, offset`, which jumps `offset` bytes forward from the start of the header
+    ///
+    /// This follows U-Boot's `sunxi_egon` tool:
+    /// https://github.com/u-boot/u-boot/blob/aef6839747b5b01e3d1d32d16e712d42a6702b88/tools/sunxi_egon.c#L135
+    /// JAL scatters its immediate as `imm[20|10:1|11|19:12]` across bits 31..12 of the instruction.
+    const fn new(offset: usize) -> Self {
+        // imm[20] is the sign bit, so forward jumps are limited to just under 1MiB
+        assert!(
+            offset % 2 == 0 && offset < 1 << 20,
+            "jump offset must be even and below 1MiB"
+        );
+        let offset = offset as u32;
+        let value = JAL_X0
+            | ((offset & 0x0000_07fe) << 20) // imm[10:1] -> inst[30:21]
+            | ((offset & 0x0000_0800) << 9) // imm[11] -> inst[20]
+            | (offset & 0x000f_f000); // imm[19:12] -> inst[19:12]
+        Self(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes the target of a JAL instruction, relative to the instruction's address
+    fn jal_offset(inst: u32) -> i32 {
+        assert_eq!(inst & 0xfff, JAL_X0, "not a `jal x0` instruction");
+        let imm = (((inst >> 31) & 0x1) << 20)
+            | (((inst >> 21) & 0x3ff) << 1)
+            | (((inst >> 20) & 0x1) << 11)
+            | (((inst >> 12) & 0xff) << 12);
+        // sign extend from bit 20
+        ((imm << 11) as i32) >> 11
+    }
+
+    #[test]
+    fn test_jump_inst() {
+        // encodings from `llvm-mc -triple=riscv64 -mattr=-c -show-encoding` for `jal x0, <offset>`
+        for (offset, encoding) in [
+            (96, 0x0600_006f),
+            (2048, 0x0010_006f),
+            (4096, 0x0000_106f),
+            (12346, 0x03a0_306f),
+            (0xf_fffe, 0x7fff_f06f),
+        ] {
+            let inst = RVJumpInst::new(offset).0;
+            assert_eq!(inst, encoding, "offset {offset}");
+            assert_eq!(jal_offset(inst), offset as i32);
+        }
+        assert_eq!(jal_offset(DEFAULT_HEAD.jump_inst.0), D1_HEADER_SIZE as i32);
     }
 }
@@ -11,7 +11,8 @@ mod egon;
 pub use egon::*;
 
 use crate::build::{
-    align_up, get_elf_size, Kernel, MemoryRole, MemorySection, Platform, SRecWriter, Task, TaskLoc,
+    align_up, budget_bar, get_elf_size, get_entrypoint_offsets, get_text_range, Kernel, MemoryRole,
+    MemorySection, Platform, SRecWriter, Task, TaskLoc,
 };
 
 pub(crate) trait ImageBuilder {
@@ -31,6 +32,9 @@ pub struct SRecImageBuilder {
     platform: Platform,
     codegen_tasks: Vec<codegen::Task>,
     output: SRecWriter,
+    /// The regions holding the tasks' code, which count towards `max_total_flash`
+    flash_regions: Vec<String>,
+    max_total_flash: Option<usize>,
 }
 
 impl SRecImageBuilder {
@@ -38,6 +42,7 @@ impl SRecImageBuilder {
         regions: HashMap<String, MemorySection>,
         platform: Platform,
         kern: &Kernel,
+        max_total_flash: Option<usize>,
     ) -> Self {
         let mut current_locs = regions.clone();
         for (name, size) in kern.sizes.iter() {
@@ -50,6 +55,8 @@ impl SRecImageBuilder {
             platform,
             codegen_tasks: vec![],
             output: SRecWriter::default(),
+            flash_regions: vec![],
+            max_total_flash,
         }
     }
 }
@@ -58,6 +65,20 @@ impl ImageBuilder for SRecImageBuilder {
     type Image = SRecImage;
 
     fn kernel(&mut self, kern: &Kernel) -> Result<()> {
+        // the kernel's regions are reserved up front, so every byte of flash is allocated by now
+        if let Some(limit) = self.max_total_flash {
+            let used: usize = self
+                .flash_regions
+                .iter()
+                .map(|name| self.current_locs[name].address - self.regions[name].address)
+                .sum();
+            if used > limit {
+                return Err(anyhow!(
+                    "the image is over its size budget:\n{}",
+                    budget_bar("flash", used, limit)
+                ));
+            }
+        }
         let kernel_path = kern.build(
             self.platform,
             self.regions.clone(),
@@ -99,23 +120,36 @@ impl ImageBuilder for SRecImageBuilder {
             )?
         };
         let sizes = get_elf_size(&size_elf, &self.regions, task.stack_space_size)?;
+        if let Some(text) = get_text_range(&size_elf)? {
+            if let Some((name, _)) = self.regions.iter().find(|(_, r)| r.contains(text.start)) {
+                if !self.flash_regions.contains(name) {
+                    self.flash_regions.push(name.clone());
+                }
+            }
+        }
+        task.check_budget(&sizes, &self.flash_regions)?;
         let regions: HashMap<_, _> = sizes
-            .clone()
-            .into_iter()
+            .iter()
             .map(|(name, range)| {
-                (
-                    name.clone(),
-                    MemorySection {
-                        size: align_up(range.len(), 32),
-                        ..self.current_locs[&name]
-                    },
-                )
+                let (size, align) = self.platform.region_layout(range.len());
+                let loc = self.current_locs.get_mut(name).unwrap();
+                loc.address = align_up(loc.address, align);
+                let region = MemorySection { size, ..*loc };
+                loc.address += size;
+                (name.clone(), region)
             })
             .collect();
-        for (name, size) in sizes.iter() {
-            let loc = &mut self.current_locs.get_mut(name).unwrap();
-            loc.address += align_up(size.len(), 32);
-        }
+        // the task's own regions plus the shared region, which it can only access through a cap
+        let link_regions: HashMap<_, _> = regions
+            .clone()
+            .into_iter()
+            .chain(
+                self.regions
+                    .iter()
+                    .filter(|(_, r)| r.role == MemoryRole::Shared)
+                    .map(|(name, r)| (name.clone(), r.clone())),
+            )
+            .collect();
         println!("{:?}", self.current_locs);
         let elf = if let Some(reloc) = &reloc {
             let output_elf = task.target_dir().join("final.elf");
@@ -123,7 +157,7 @@ impl ImageBuilder for SRecImageBuilder {
                 &reloc,
                 &output_elf,
                 &TaskLoc {
-                    regions: regions.clone(),
+                    regions: link_regions,
                 },
                 self.platform.task_link(),
             )?;
@@ -133,7 +167,7 @@ impl ImageBuilder for SRecImageBuilder {
             let elf = task.build(
                 self.platform,
                 Some(&TaskLoc {
-                    regions: regions.clone(),
+                    regions: link_regions,
                 }),
                 false,
                 false,
@@ -143,6 +177,12 @@ impl ImageBuilder for SRecImageBuilder {
         };
 
         let entrypoint = self.output.write(&elf)?;
+        let flash_base = regions
+            .values()
+            .find(|r| r.contains(entrypoint))
+            .map(|r| r.address)
+            .ok_or_else(|| anyhow!("entrypoint for {} is outside of its regions", task.name))?;
+        let entrypoints = get_entrypoint_offsets(&elf, &task.entrypoint_symbols()?, flash_base)?;
         let stack_region = regions
             .values()
             .find(|r| r.role == MemoryRole::Stack)
@@ -158,6 +198,10 @@ impl ImageBuilder for SRecImageBuilder {
                 .values()
                 .map(|r| r.address..r.address + r.size)
                 .collect(),
+            entrypoints,
+            caps: task.caps.clone(),
+            text: get_text_range(&elf)?,
+            tls_size: task.tls_size,
         });
         Ok(())
     }
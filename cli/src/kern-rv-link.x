@@ -1,3 +1,13 @@
+/* Memory layout generated by the CLI from `app.toml`, which names the regions FLASH and RAM */
+INCLUDE memory.x
+
+REGION_ALIAS("REGION_TEXT", FLASH)
+REGION_ALIAS("REGION_RODATA", FLASH)
+REGION_ALIAS("REGION_DATA", RAM)
+REGION_ALIAS("REGION_BSS", RAM)
+REGION_ALIAS("REGION_HEAP", RAM)
+REGION_ALIAS("REGION_STACK", RAM)
+
 PROVIDE(_stext = ORIGIN(REGION_TEXT));
 PROVIDE(_stack_start = ORIGIN(REGION_STACK) + LENGTH(REGION_STACK));
 PROVIDE(_max_hart_id = 0);
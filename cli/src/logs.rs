@@ -1,34 +1,66 @@
 use std::{
     fs,
-    io::{self, Read},
+    io::{self, BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::{build::Config, elf::Elf};
+use crate::{build::Config, elf::Elf, xfel::XfelDevice};
 use color_eyre::{eyre::anyhow, Result};
 use colored::Colorize;
 use defmt_decoder::{DecodeError, Frame, Locations};
-use probe_rs::{Core, MemoryInterface as _, Session};
+use probe_rs::{config::MemoryRegion, Core, MemoryInterface as _, Session};
 use probe_rs_rtt::{Rtt, ScanRegion, UpChannel};
 use serialport::{SerialPort, SerialPortType};
 use signal_hook::consts::signal;
 
 const TIMEOUT: Duration = Duration::from_secs(2);
+/// Baud rates tried, in order, when the serial baud rate isn't configured
+const BAUD_RATES: &[u32] = &[9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
+/// How long to wait for the `LOG_START` marker at each baud rate
+const BAUD_DETECT_WINDOW: Duration = Duration::from_secs(2);
 
-fn attach_rtt(elf: &Elf, session: &mut Session) -> Result<UpChannel> {
-    let mem_map = session.target().memory_map.clone();
-    let mut core = session.core(0)?;
-    let rtt_buffer_address = elf
-        .rtt_buffer_address()
-        .ok_or_else(|| anyhow!("rtt buffer not available"))?;
-    let scan_region = ScanRegion::Exact(rtt_buffer_address);
+/// The task ID the kernel logs right before it resets the target
+const REBOOT_TASK_ID: usize = 0xFF;
+/// The task ID the kernel logs memory dumps with, in debug builds
+///
+/// Each frame starts with the dumped task's ID, followed by the little-endian `u64` address of the data
+const MEMORY_DUMP_TASK_ID: usize = 0xFE;
+/// The task ID the kernel logs a thread's scheduling stats with
+///
+/// Each frame starts with the task's ID, followed by the little-endian fields of `abi::TaskStats`
+const STATS_TASK_ID: usize = 0xFD;
+/// The task ID the kernel logs at boot when it finds crashes recorded before the last reset
+///
+/// The frame holds the little-endian `u32` number of crashes, the kernel logs a warning for each one after it
+const CRASH_LOG_TASK_ID: usize = 0xFC;
+/// The task ID the kernel logs how full its tables are with, once when it starts
+///
+/// The frame holds the fields of `kernel::KernelDiagnostics` in order, each as a little-endian `u32`
+const DIAGNOSTICS_TASK_ID: usize = 0xFB;
+/// IDs from this one up are the kernel's own frames, which never include a task name
+///
+/// Their IDs have [`TASK_NAME_FLAG`]'s bit set, so they have to be ruled out before the flag is checked
+const RESERVED_TASK_IDS: usize = 0xFB;
+/// Set in the task ID of frames that include the sending task's name, prefixed by its length
+const TASK_NAME_FLAG: usize = 0x80;
+/// The pattern the kernel paints task stacks with before a task starts
+const STACK_PAINT: u32 = 0xdeadf00d;
+/// Stack usage, as a percent of a thread's stack size, above which we warn
+const STACK_WARN_PERCENT: usize = 80;
+
+fn attach_rtt(
+    core: &mut Core,
+    mem_map: &[MemoryRegion],
+    scan_region: &ScanRegion,
+) -> Result<UpChannel> {
     for _ in 0..50 {
-        let mut rtt = match Rtt::attach_region(&mut core, &mem_map, &scan_region) {
+        let mut rtt = match Rtt::attach_region(core, mem_map, scan_region) {
             Err(probe_rs_rtt::Error::ControlBlockNotFound) => continue,
             rtt => rtt?,
         };
@@ -41,10 +73,47 @@ fn attach_rtt(elf: &Elf, session: &mut Session) -> Result<UpChannel> {
     Err(anyhow!("failed to attach rtt"))
 }
 
-pub fn print_logs(config: &Config, kernel_path: PathBuf, source: LogSource) -> Result<()> {
-    let mut elf = fs::File::open(kernel_path)?;
-    let mut elf_data = vec![];
-    elf.read_to_end(&mut elf_data)?;
+/// When to check each thread's stack high-watermark, see [`print_logs`]
+#[derive(Clone, Copy, Debug)]
+pub struct StackCheck {
+    /// Time since the logs started before the first check
+    pub after: Duration,
+    /// How often to check again after the first check, if at all
+    pub every: Option<Duration>,
+}
+
+/// Prints logs from the target until it halts or the user exits
+///
+/// If `check_stack` is set, each thread's stack high-watermark is checked after `check_stack.after`, and then every
+/// `check_stack.every` if that is set.
+/// Memory dumps are always displayed, if `mem_filter` is set only the matching part of dumps are shown.
+/// Scheduling stats are only displayed if `show_stats` is set.
+///
+/// When attaching to a running target with [`LogSource::Attach`], missing ELFs aren't an error. Instead logs are
+/// printed as raw frames, since they can't be decoded.
+pub fn print_logs(
+    config: &Config,
+    kernel_path: PathBuf,
+    source: LogSource,
+    check_stack: Option<StackCheck>,
+    mem_filter: Option<MemoryFilter>,
+    show_stats: bool,
+) -> Result<()> {
+    let build = match load_build(config, &kernel_path) {
+        Ok(build) => Some(build),
+        Err(err) if matches!(source, LogSource::Attach(_)) => {
+            println!(
+                "{}",
+                format!("failed to load build, printing raw logs: {}", err).yellow()
+            );
+            None
+        }
+        Err(err) => return Err(err),
+    };
+    let (task_list, task_elf_data) = match build {
+        Some((task_list, task_elf_data)) => (task_list.tasks, task_elf_data),
+        None => (vec![], vec![]),
+    };
     let task_name_width = config
         .tasks
         .iter()
@@ -52,18 +121,6 @@ pub fn print_logs(config: &Config, kernel_path: PathBuf, source: LogSource) -> R
         .max()
         .unwrap_or_default()
         + 2;
-    let mut task_elf_data = config
-        .tasks
-        .iter()
-        .map(|t| {
-            let path = t.target_dir().join("final.elf");
-            let mut elf = fs::File::open(path)?;
-            let mut elf_data = vec![];
-            elf.read_to_end(&mut elf_data)?;
-            Ok(elf_data)
-        })
-        .collect::<Result<Vec<_>>>()?;
-    task_elf_data.insert(0, elf_data.clone());
     let task_elfs = task_elf_data
         .iter()
         .map(|elf_data| {
@@ -71,21 +128,25 @@ pub fn print_logs(config: &Config, kernel_path: PathBuf, source: LogSource) -> R
             Ok(elf)
         })
         .collect::<Result<Vec<_>>>()?;
-    let mut task_decoders = task_elfs
-        .iter()
-        .map(|elf| {
-            let table = elf
-                .defmt_table
-                .as_ref()
-                .ok_or_else(|| anyhow!("missing defmt table for task"))?;
-            Ok(table.new_stream_decoder())
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let new_decoders = || {
+        task_elfs
+            .iter()
+            .map(|elf| {
+                let table = elf
+                    .defmt_table
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("missing defmt table for task"))?;
+                Ok(table.new_stream_decoder())
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+    let mut task_decoders = new_decoders()?;
     let mut task_names: Vec<_> = config.tasks.iter().map(|t| t.name.clone()).collect();
     task_names.insert(0, "kern".to_string());
-    let elf = Elf::parse(&elf_data).map_err(|e| anyhow!(e))?;
-    let mut log_session = source.attach(&elf)?;
+    let mut log_session = source.attach(task_elfs.first())?;
     let mut was_halted = false;
+    let start = Instant::now();
+    let mut check_stack = check_stack;
     let current_dir = std::env::current_dir().unwrap();
     let exit = Arc::new(AtomicBool::new(false));
     let sig_id = signal_hook::flag::register(signal::SIGINT, exit.clone())?;
@@ -93,10 +154,54 @@ pub fn print_logs(config: &Config, kernel_path: PathBuf, source: LogSource) -> R
     reader.find_start_marker(&mut log_session)?;
     while !exit.load(Ordering::Relaxed) {
         reader.read(&mut log_session)?;
-        while let Some((task_id, buf)) = reader.frame()? {
-            let elf = &task_elfs[task_id];
+        if let Some(check) = check_stack {
+            if start.elapsed() >= check.after {
+                log_session.check_stacks(&task_list)?;
+                check_stack = check.every.map(|every| StackCheck {
+                    after: start.elapsed() + every,
+                    every: Some(every),
+                });
+            }
+        }
+        while let Some((task_id, name, buf)) = reader.frame()? {
+            if task_id == REBOOT_TASK_ID {
+                println!("{}", "target rebooting, reconnecting...".bold());
+                log_session.reattach()?;
+                reader = LogReader::default();
+                reader.find_start_marker(&mut log_session)?;
+                task_decoders = new_decoders()?;
+                break;
+            }
+            if task_id == MEMORY_DUMP_TASK_ID {
+                print_memory_dump(&buf, &task_names, mem_filter.as_ref())?;
+                continue;
+            }
+            if task_id == STATS_TASK_ID {
+                if show_stats {
+                    print_stats(&buf, &task_names)?;
+                }
+                continue;
+            }
+            if task_id == CRASH_LOG_TASK_ID {
+                print_crash_banner(&buf)?;
+                continue;
+            }
+            if task_id == DIAGNOSTICS_TASK_ID {
+                print_diagnostics(&buf)?;
+                continue;
+            }
+            // frames that name their task are matched by name, rather than relying on the config's task order
+            let task_id = name
+                .and_then(|name| task_names.iter().position(|n| *n == name))
+                .unwrap_or(task_id);
+            let (elf, decoder) = match (task_elfs.get(task_id), task_decoders.get_mut(task_id)) {
+                (Some(elf), Some(decoder)) => (elf, decoder),
+                _ => {
+                    print_raw_frame(task_id, &buf, &task_names);
+                    continue;
+                }
+            };
             let task_name = &task_names[task_id];
-            let decoder = &mut task_decoders[task_id];
             decoder.received(&buf);
             loop {
                 match decoder.decode() {
@@ -140,6 +245,38 @@ pub fn print_logs(config: &Config, kernel_path: PathBuf, source: LogSource) -> R
     Ok(())
 }
 
+/// Reads the task list and the ELF of the kernel and every task, the kernel's ELF is first
+fn load_build(config: &Config, kernel_path: &Path) -> Result<(codegen::TaskList, Vec<Vec<u8>>)> {
+    let task_list: codegen::TaskList =
+        serde_json::from_slice(&fs::read(kernel_path.with_file_name("task_list.json"))?)?;
+    let mut elf_data = vec![fs::read(kernel_path)?];
+    for task in &config.tasks {
+        elf_data.push(fs::read(task.target_dir().join("final.elf"))?);
+    }
+    Ok((task_list, elf_data))
+}
+
+/// Prints a log frame that can't be decoded without the task's ELF
+fn print_raw_frame(task_id: usize, frame: &[u8], task_names: &[String]) {
+    let task_name = task_names
+        .get(task_id)
+        .cloned()
+        .unwrap_or_else(|| format!("task {}", task_id));
+    let bytes = frame
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    println!(
+        "{} {}",
+        format!(" {} ", task_name)
+            .bold()
+            .white()
+            .on_truecolor(0, 142, 245),
+        bytes
+    );
+}
+
 fn start_program(sess: &mut Session, elf: &Elf) -> Result<()> {
     let mut core = sess.core(0)?;
 
@@ -156,34 +293,163 @@ fn start_program(sess: &mut Session, elf: &Elf) -> Result<()> {
 /// Set rtt to blocking mode
 fn set_rtt_to_blocking(
     core: &mut Core,
-    main_fn_address: u32,
-    rtt_buffer_address: u32,
+    main_fn_address: u64,
+    rtt_buffer_address: u64,
 ) -> Result<()> {
     // set and wait for a hardware breakpoint at the beginning of `fn main()`
-    core.set_hw_breakpoint(main_fn_address as u64)?;
+    core.set_hw_breakpoint(main_fn_address)?;
     core.run()?;
     core.wait_for_core_halted(Duration::from_secs(5))?;
 
     // calculate address of up-channel-flags inside the rtt control block
-    const OFFSET: u32 = 44;
+    const OFFSET: u64 = 44;
     let rtt_buffer_address = rtt_buffer_address + OFFSET;
 
     // read flags
     let channel_flags = &mut [0];
-    core.read_32(rtt_buffer_address as u64, channel_flags)?;
+    core.read_32(rtt_buffer_address, channel_flags)?;
     // modify flags to blocking
     const MODE_MASK: u32 = 0b11;
     const MODE_BLOCK_IF_FULL: u32 = 0b10;
     let modified_channel_flags = (channel_flags[0] & !MODE_MASK) | MODE_BLOCK_IF_FULL;
     // write flags back
-    core.write_word_32(rtt_buffer_address as u64, modified_channel_flags)?;
+    core.write_word_32(rtt_buffer_address, modified_channel_flags)?;
 
     // clear the breakpoint we set before
-    core.clear_hw_breakpoint(main_fn_address as u64)?;
+    core.clear_hw_breakpoint(main_fn_address)?;
 
     Ok(())
 }
 
+/// Restricts which memory dumps are displayed to a single task and address range
+pub struct MemoryFilter {
+    pub task: String,
+    pub addr: u64,
+    pub len: u64,
+}
+
+fn print_memory_dump(
+    frame: &[u8],
+    task_names: &[String],
+    filter: Option<&MemoryFilter>,
+) -> Result<()> {
+    if frame.len() < 9 {
+        return Err(anyhow!("memory dump frame too short"));
+    }
+    let task_name = task_names
+        .get(frame[0] as usize)
+        .ok_or_else(|| anyhow!("memory dump for unknown task {}", frame[0]))?;
+    let mut addr = u64::from_le_bytes(frame[1..9].try_into().unwrap());
+    let mut data = &frame[9..];
+    if let Some(filter) = filter {
+        if &filter.task != task_name {
+            return Ok(());
+        }
+        let start = addr.max(filter.addr);
+        let end = (addr + data.len() as u64).min(filter.addr + filter.len);
+        if start >= end {
+            return Ok(());
+        }
+        data = &data[(start - addr) as usize..(end - addr) as usize];
+        addr = start;
+    }
+    for line in data.chunks(16) {
+        let bytes = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!(
+            "{} {:#010x}: {}",
+            format!(" {} ", task_name)
+                .bold()
+                .white()
+                .on_truecolor(0, 142, 245),
+            addr,
+            bytes
+        );
+        addr += line.len() as u64;
+    }
+    Ok(())
+}
+
+fn print_stats(frame: &[u8], task_names: &[String]) -> Result<()> {
+    if frame.len() < 25 {
+        return Err(anyhow!("stats frame too short"));
+    }
+    let task_name = task_names
+        .get(frame[0] as usize)
+        .ok_or_else(|| anyhow!("stats for unknown task {}", frame[0]))?;
+    let cpu_ticks = u64::from_le_bytes(frame[1..9].try_into().unwrap());
+    let ipc_count = u32::from_le_bytes(frame[9..13].try_into().unwrap());
+    let ipc_bytes = u64::from_le_bytes(frame[13..21].try_into().unwrap());
+    let context_switches = u32::from_le_bytes(frame[21..25].try_into().unwrap());
+    println!(
+        "{} {}",
+        format!(" {} ", task_name)
+            .bold()
+            .white()
+            .on_truecolor(0, 142, 245),
+        format!(
+            "{} ticks, {} messages ({} bytes), {} context switches",
+            cpu_ticks, ipc_count, ipc_bytes, context_switches
+        )
+        .dimmed()
+    );
+    Ok(())
+}
+
+/// Prints how full each of the kernel's tables is, in yellow if any of them is more than 80% full
+fn print_diagnostics(frame: &[u8]) -> Result<()> {
+    if frame.len() < 24 {
+        return Err(anyhow!("diagnostics frame too short"));
+    }
+    let fields: Vec<_> = frame[..24]
+        .chunks(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    let tables = [
+        ("threads", fields[0], fields[1]),
+        ("tasks", fields[2], fields[3]),
+        ("registry", fields[4], fields[5]),
+    ];
+    let text = tables
+        .iter()
+        .map(|(name, used, capacity)| format!("{} {}/{}", name, used, capacity))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let nearly_full = tables
+        .iter()
+        .any(|(_, used, capacity)| *used as u64 * 5 > *capacity as u64 * 4);
+    let banner = " kernel tables ".bold().white();
+    if nearly_full {
+        println!("{} {}", banner.on_yellow(), text.yellow());
+    } else {
+        println!("{} {}", banner.on_truecolor(0, 142, 245), text.dimmed());
+    }
+    Ok(())
+}
+
+/// Prints a banner flagging the crashes the kernel found from before the last reset
+fn print_crash_banner(frame: &[u8]) -> Result<()> {
+    let count = frame
+        .get(..4)
+        .ok_or_else(|| anyhow!("crash log frame too short"))?;
+    let count = u32::from_le_bytes(count.try_into().unwrap());
+    let plural = if count == 1 { "" } else { "es" };
+    println!(
+        "{}",
+        format!(
+            " target crashed before the last reset, {} task crash{} recorded ",
+            count, plural
+        )
+        .bold()
+        .white()
+        .on_red()
+    );
+    Ok(())
+}
+
 fn print_location(file: &str, line: u32, module_path: &str) -> io::Result<()> {
     let mod_path = module_path;
     let loc = format!("{}:{}", file, line);
@@ -221,41 +487,137 @@ fn location_info(
 
 pub enum LogSource<'a> {
     Rtt(&'a mut Session),
-    Serial,
+    /// Reads RTT from a target that is already running, without resetting it
+    ///
+    /// The whole of RAM is scanned for the RTT control block, so this works without the kernel's ELF
+    Attach(&'a mut Session),
+    /// Reads logs from the first USB serial port, the baud rate is detected if it is `None`
+    ///
+    /// The target is reset through `reset` once the port is open, and again for each baud rate tried
+    Serial {
+        baud: Option<u32>,
+        reset: Option<&'a XfelDevice>,
+    },
+    /// Reads the RTT buffer through a GDB server listening at `host:port`
+    Gdb(String),
 }
 
 impl<'a> LogSource<'a> {
-    fn attach(self, elf: &Elf) -> Result<LogSession<'a>> {
+    fn attach(self, elf: Option<&Elf>) -> Result<LogSession<'a>> {
+        let rtt_addr = || {
+            elf.and_then(|elf| elf.rtt_buffer_address())
+                .ok_or_else(|| anyhow!("rtt buffer not available"))
+        };
         match self {
             LogSource::Rtt(session) => {
+                let elf = elf.ok_or_else(|| anyhow!("kernel elf not available"))?;
                 session.core(0).unwrap().reset_and_halt(TIMEOUT)?;
-                start_program(session, &elf)?;
-                let channel = attach_rtt(&elf, session)?;
-                let core = session.core(0)?;
-                Ok(LogSession::Rtt { core, channel })
+                start_program(session, elf)?;
+                let rtt_addr = rtt_addr()?
+                    .try_into()
+                    .map_err(|_| anyhow!("rtt buffer address doesn't fit in 32 bits"))?;
+                let scan_region = ScanRegion::Exact(rtt_addr);
+                let mem_map = session.target().memory_map.clone();
+                let mut core = session.core(0)?;
+                let channel = attach_rtt(&mut core, &mem_map, &scan_region)?;
+                Ok(LogSession::Rtt {
+                    core,
+                    channel,
+                    mem_map,
+                    scan_region,
+                })
             }
-            LogSource::Serial => {
+            LogSource::Attach(session) => {
+                let scan_region = ScanRegion::Ram;
+                let mem_map = session.target().memory_map.clone();
+                let mut core = session.core(0)?;
+                let channel = attach_rtt(&mut core, &mem_map, &scan_region)?;
+                Ok(LogSession::Rtt {
+                    core,
+                    channel,
+                    mem_map,
+                    scan_region,
+                })
+            }
+            LogSource::Serial { baud, reset } => {
                 let port = serialport::available_ports()?
                     .into_iter()
                     .filter(|p| matches!(p.port_type, SerialPortType::UsbPort(_)))
                     .next()
                     .ok_or_else(|| anyhow!("no serial port found"))?;
                 println!("attaching to port: {:?}", port.port_name);
-                let port = serialport::new(port.port_name, 115200)
-                    .timeout(Duration::from_secs(60))
-                    .open()?;
-                Ok(LogSession::Serial(port))
+                if let Some(baud) = baud {
+                    let port = serialport::new(port.port_name, baud)
+                        .timeout(Duration::from_secs(60))
+                        .open()?;
+                    if let Some(xfel) = reset {
+                        xfel.reset()?;
+                    }
+                    return Ok(LogSession::Serial {
+                        port,
+                        pending: vec![],
+                    });
+                }
+                detect_baud(&port.port_name, reset)
+            }
+            LogSource::Gdb(addr) => {
+                let rtt_addr = rtt_addr()?;
+                println!("attaching to gdb server: {}", addr);
+                Ok(LogSession::Gdb(GdbSession::attach(&addr, rtt_addr)?))
             }
         }
     }
 }
 
+/// Finds the baud rate the target logs at, by resetting it at each rate in [`BAUD_RATES`] until `LOG_START` is seen
+///
+/// Without `reset` this relies on the target being reset by hand while we listen.
+fn detect_baud(port_name: &str, reset: Option<&XfelDevice>) -> Result<LogSession<'static>> {
+    for &baud in BAUD_RATES {
+        println!("trying {} baud", baud);
+        let mut port = serialport::new(port_name, baud)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        if let Some(xfel) = reset {
+            xfel.reset()?;
+        }
+        let start = Instant::now();
+        let mut buf = vec![];
+        let mut read_buf = [0u8; 1024];
+        while start.elapsed() < BAUD_DETECT_WINDOW {
+            match port.read(&mut read_buf) {
+                Ok(len) => buf.extend_from_slice(&read_buf[..len]),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(pos) = buf.windows(9).position(|w| w == b"LOG_START") {
+                println!("detected {} baud", baud);
+                port.set_timeout(Duration::from_secs(60))?;
+                return Ok(LogSession::Serial {
+                    port,
+                    pending: buf.split_off(pos),
+                });
+            }
+        }
+    }
+    Err(anyhow!(
+        "failed to detect baud rate, set `serial_baud` in app.toml or pass --baud"
+    ))
+}
+
 enum LogSession<'a> {
     Rtt {
         core: probe_rs::Core<'a>,
         channel: UpChannel,
+        mem_map: Vec<MemoryRegion>,
+        scan_region: ScanRegion,
     },
-    Serial(Box<dyn SerialPort>),
+    /// `pending` holds bytes read during baud detection, which are returned before reading from the port again
+    Serial {
+        port: Box<dyn SerialPort>,
+        pending: Vec<u8>,
+    },
+    Gdb(GdbSession),
 }
 
 impl<'a> LogSession<'a> {
@@ -264,18 +626,92 @@ impl<'a> LogSession<'a> {
             LogSession::Rtt {
                 ref mut core,
                 channel,
+                ..
             } => Ok(channel.read(core, buf)?),
-            LogSession::Serial(port) => Ok(port.read(buf)?),
+            LogSession::Serial { port, pending } => {
+                if pending.is_empty() {
+                    return Ok(port.read(buf)?);
+                }
+                let len = buf.len().min(pending.len());
+                buf[..len].copy_from_slice(&pending[..len]);
+                pending.drain(..len);
+                Ok(len)
+            }
+            LogSession::Gdb(gdb) => gdb.read(buf),
         }
     }
 
+    /// Reattaches to the log stream after the target has been reset
+    ///
+    /// The kernel re-initializes its RTT control block on boot, so the old up channel is no longer valid
+    fn reattach(&mut self) -> Result<()> {
+        match self {
+            LogSession::Rtt {
+                ref mut core,
+                channel,
+                mem_map,
+                scan_region,
+            } => {
+                // give the kernel a moment to boot and set up the control block again
+                std::thread::sleep(Duration::from_millis(100));
+                *channel = attach_rtt(core, mem_map, scan_region)?;
+                Ok(())
+            }
+            LogSession::Serial { .. } | LogSession::Gdb(_) => Ok(()),
+        }
+    }
+
+    /// Measures the high-watermark of every thread stack, and warns about any that are close to overflowing
+    ///
+    /// This relies on the kernel painting task stacks with [`STACK_PAINT`], so the watermark is the lowest word
+    /// that no longer holds the pattern. Only probe sessions can read target memory, so other sessions are skipped.
+    fn check_stacks(&mut self, tasks: &[codegen::Task]) -> Result<()> {
+        let core = match self {
+            LogSession::Rtt { ref mut core, .. } => core,
+            LogSession::Serial { .. } | LogSession::Gdb(_) => {
+                println!("{}", "stack check is only supported with a probe".yellow());
+                return Ok(());
+            }
+        };
+        for task in tasks {
+            if task.init_stack_size == 0 {
+                continue;
+            }
+            let mut words = vec![0u32; task.init_stack_size / 4];
+            let slots = task.stack_space.len() / task.init_stack_size;
+            for slot in 0..slots {
+                let bottom = task.stack_space.start + slot * task.init_stack_size;
+                core.read_32(bottom as u32, &mut words)?;
+                // stacks grow down, so the first word that was written to is the high-watermark
+                let unused = match words.iter().position(|w| *w != STACK_PAINT) {
+                    Some(unused) => unused,
+                    // an untouched stack isn't in use by any thread
+                    None => continue,
+                };
+                let used = task.init_stack_size - unused * 4;
+                let percent = used * 100 / task.init_stack_size;
+                let msg = format!(
+                    "{} stack {}: {}/{} bytes used ({}%)",
+                    task.name, slot, used, task.init_stack_size, percent
+                );
+                if percent >= STACK_WARN_PERCENT {
+                    println!("{}", msg.yellow().bold());
+                } else {
+                    println!("{}", msg);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn halt(&mut self) -> Result<()> {
         match self {
             LogSession::Rtt { ref mut core, .. } => {
                 core.halt(TIMEOUT)?;
                 Ok(())
             }
-            LogSession::Serial(_) => Ok(()),
+            LogSession::Serial { .. } => Ok(()),
+            LogSession::Gdb(gdb) => gdb.halt(),
         }
     }
 
@@ -285,19 +721,179 @@ impl<'a> LogSession<'a> {
                 let halted = core.core_halted()?;
                 Ok(halted)
             }
-            LogSession::Serial(_) => Ok(false),
+            LogSession::Serial { .. } | LogSession::Gdb(_) => Ok(false),
+        }
+    }
+}
+
+/// A log transport that reads the kernel's RTT up channel using GDB's machine interface
+///
+/// GDB can't read target memory while the core is running, so each read briefly interrupts the target,
+/// copies any pending bytes out of the RTT buffer, advances the channel's read pointer, and resumes.
+struct GdbSession {
+    process: Child,
+    stdout: BufReader<ChildStdout>,
+    port: u16,
+    rtt_addr: u64,
+}
+
+impl GdbSession {
+    // offset of up channel 0 in the RTT control block, after the 16 byte id and the two channel counts
+    const UP_CHANNEL_OFFSET: u64 = 24;
+    // offset of the read pointer inside of an RTT channel descriptor
+    const READ_OFFSET: u64 = 16;
+
+    fn attach(addr: &str, rtt_addr: u64) -> Result<Self> {
+        let port = addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .ok_or_else(|| anyhow!("gdb address must be in the form host:port"))?;
+        let gdb = std::env::var("K5_GDB").unwrap_or_else(|_| "gdb-multiarch".to_string());
+        let mut process = Command::new(gdb)
+            .arg("--interpreter=mi2")
+            .arg("-q")
+            .arg("-nx")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = BufReader::new(
+            process
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("gdb stdout unavailable"))?,
+        );
+        let mut session = GdbSession {
+            process,
+            stdout,
+            port,
+            rtt_addr,
+        };
+        session.command(&format!("-target-select extended-remote {}", addr))?;
+        session.command("-exec-continue")?;
+        Ok(session)
+    }
+
+    /// Sends a command to GDB and returns its result record
+    fn command(&mut self, cmd: &str) -> Result<String> {
+        let stdin = self
+            .process
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("gdb stdin unavailable"))?;
+        writeln!(stdin, "{}", cmd)?;
+        stdin.flush()?;
+        loop {
+            let line = self.read_line()?;
+            if let Some(err) = line.strip_prefix("^error") {
+                return Err(anyhow!(
+                    "gdb on port {} failed {:?}: {}",
+                    self.port,
+                    cmd,
+                    err
+                ));
+            }
+            if line.starts_with('^') {
+                return Ok(line);
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(anyhow!("gdb exited"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    fn interrupt(&mut self) -> Result<()> {
+        self.command("-exec-interrupt")?;
+        while !self.read_line()?.starts_with("*stopped") {}
+        Ok(())
+    }
+
+    fn read_memory(&mut self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let resp = self.command(&format!("-data-read-memory-bytes {:#x} {}", addr, len))?;
+        let contents = resp
+            .split("contents=\"")
+            .nth(1)
+            .and_then(|c| c.split('"').next())
+            .ok_or_else(|| anyhow!("malformed gdb memory response: {}", resp))?;
+        let mut bytes = Vec::with_capacity(len);
+        for i in (0..contents.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&contents[i..i + 2], 16)?);
+        }
+        Ok(bytes)
+    }
+
+    fn write_u32(&mut self, addr: u64, val: u32) -> Result<()> {
+        let hex: String = val
+            .to_le_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.command(&format!("-data-write-memory-bytes {:#x} {}", addr, hex))?;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.interrupt()?;
+        let len = self.read_channel(buf);
+        self.command("-exec-continue")?;
+        let len = len?;
+        if len == 0 {
+            std::thread::sleep(Duration::from_millis(50));
         }
+        Ok(len)
     }
+
+    fn read_channel(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let channel_addr = self.rtt_addr + Self::UP_CHANNEL_OFFSET;
+        let desc = self.read_memory(channel_addr, 24)?;
+        let word = |i: usize| u32::from_le_bytes(desc[i * 4..i * 4 + 4].try_into().unwrap());
+        let (buffer, size, write, read) = (word(1), word(2), word(3), word(4));
+        if write == read || size == 0 {
+            return Ok(0);
+        }
+        let available = if write > read {
+            write - read
+        } else {
+            size - read
+        };
+        let len = (available as usize).min(buf.len());
+        let data = self.read_memory((buffer + read) as u64, len)?;
+        buf[..len].copy_from_slice(&data);
+        self.write_u32(channel_addr + Self::READ_OFFSET, (read + len as u32) % size)?;
+        Ok(len)
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        self.interrupt()?;
+        let _ = self.command("-gdb-exit");
+        Ok(())
+    }
+}
+
+/// How long the rest of a frame has to arrive after its length byte, before the length is assumed to be corrupt
+const FRAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+enum FrameState {
+    /// Waiting for the length byte that starts the next frame
+    #[default]
+    WaitingLength,
+    /// Waiting for the `expected` bytes that follow a length byte, which was seen at `started`
+    ReadingFrame { expected: u8, started: Instant },
 }
 
 #[derive(Default)]
 struct LogReader {
     buf: Vec<u8>,
-    current_frame_length: Option<usize>,
+    state: FrameState,
 }
 impl LogReader {
     fn find_start_marker(&mut self, log: &mut LogSession) -> Result<()> {
-        if matches!(log, LogSession::Rtt { .. }) {
+        if matches!(log, LogSession::Rtt { .. } | LogSession::Gdb(_)) {
             return Ok(());
         }
         let mut search_buf = vec![];
@@ -325,18 +921,92 @@ impl LogReader {
         Ok(())
     }
 
-    fn frame(&mut self) -> Result<Option<(usize, Vec<u8>)>> {
-        if self.current_frame_length.is_none() && self.buf.len() >= 2 {
-            self.current_frame_length = Some(self.buf[0] as usize);
-        }
-        if let Some(length) = self.current_frame_length {
-            if length + 1 < self.buf.len() {
-                let mut frame = self.buf.split_off(length + 1);
-                std::mem::swap(&mut self.buf, &mut frame);
-                self.current_frame_length = None;
-                return Ok(Some((frame[1] as usize, frame[2..].to_vec())));
+    /// Returns the next complete frame's task ID, the task's name if the kernel included it, and the frame's data
+    ///
+    /// If a dropped byte corrupts a length, the frame would never complete. So when the rest of a frame doesn't
+    /// arrive within [`FRAME_TIMEOUT`], its length byte is discarded and the following byte is tried as a length.
+    fn frame(&mut self) -> Result<Option<(usize, Option<String>, Vec<u8>)>> {
+        loop {
+            match self.state {
+                FrameState::WaitingLength => match self.buf.first() {
+                    // every frame contains at least a task ID, so a zero can't be a length
+                    Some(0) => {
+                        self.buf.remove(0);
+                    }
+                    Some(&expected) => {
+                        self.state = FrameState::ReadingFrame {
+                            expected,
+                            started: Instant::now(),
+                        }
+                    }
+                    None => return Ok(None),
+                },
+                FrameState::ReadingFrame { expected, started } => {
+                    let length = expected as usize;
+                    if self.buf.len() > length {
+                        let mut frame = self.buf.split_off(length + 1);
+                        std::mem::swap(&mut self.buf, &mut frame);
+                        self.state = FrameState::WaitingLength;
+                        return Self::parse_frame(&frame).map(Some);
+                    }
+                    if started.elapsed() < FRAME_TIMEOUT {
+                        return Ok(None);
+                    }
+                    self.buf.remove(0);
+                    self.state = FrameState::WaitingLength;
+                }
             }
         }
-        Ok(None)
+    }
+
+    fn parse_frame(frame: &[u8]) -> Result<(usize, Option<String>, Vec<u8>)> {
+        let task_id = frame[1] as usize;
+        if task_id >= RESERVED_TASK_IDS || task_id & TASK_NAME_FLAG == 0 {
+            return Ok((task_id, None, frame[2..].to_vec()));
+        }
+        let name_len = *frame
+            .get(2)
+            .ok_or_else(|| anyhow!("log frame is missing its task name"))?
+            as usize;
+        let name = frame
+            .get(3..3 + name_len)
+            .ok_or_else(|| anyhow!("log frame is shorter than its task name"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        Ok((
+            task_id & !TASK_NAME_FLAG,
+            Some(name),
+            frame[3 + name_len..].to_vec(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_resync() {
+        let mut reader = LogReader::default();
+        // a corrupt length byte, followed by a complete frame from task 1
+        reader.buf.extend_from_slice(&[200, 2, 1, 0xaa]);
+        assert!(reader.frame().unwrap().is_none());
+        std::thread::sleep(FRAME_TIMEOUT);
+        let frame = reader.frame().unwrap();
+        assert_eq!(frame, Some((1, None, vec![0xaa])));
+        assert!(reader.frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_kernel_frames_have_no_name() {
+        for id in [
+            REBOOT_TASK_ID,
+            MEMORY_DUMP_TASK_ID,
+            STATS_TASK_ID,
+            CRASH_LOG_TASK_ID,
+            DIAGNOSTICS_TASK_ID,
+        ] {
+            let frame = LogReader::parse_frame(&[3, id as u8, 1, 2]).unwrap();
+            assert_eq!(frame, (id, None, vec![1, 2]));
+        }
     }
 }
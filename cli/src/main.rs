@@ -1,50 +1,184 @@
 use std::{
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use clap::Parser;
-use color_eyre::Result;
+use color_eyre::{eyre::anyhow, Result};
 use colored::Colorize;
-use logs::LogSource;
+use logs::{LogSource, MemoryFilter, StackCheck};
+use size::SizeReport;
 mod build;
 mod elf;
 mod flash;
+mod gdb;
 mod image;
 mod logs;
+mod size;
+mod watch;
 mod xfel;
 
 fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
     let args = Args::parse();
     match args {
-        Args::Build { path } => {
+        Args::Build { path, watch: true } => {
+            watch::watch(&path, |config| config.build(&path).map(|_| ()))?;
+        }
+        Args::Build { path, watch: false } => {
             let mut config = parse_config(&path)?;
             config.build(&path)?;
         }
-        Args::Flash { path } => {
+        Args::Flash { path, watch: true } => {
+            watch::watch(&path, |config| {
+                config.build(&path)?;
+                flash::flash_all(config)
+            })?;
+        }
+        Args::Flash { path, watch: false } => {
             let mut config = parse_config(&path)?;
             let _ = config.build(&path)?;
-            flash::flash(&config)?;
-            //flash::flash(config.probe, ihex_path)?;
+            flash::flash_all(&config)?;
+        }
+        Args::Logs {
+            path,
+            gdb,
+            baud,
+            check_stack,
+            check_stack_after,
+            check_stack_every,
+            stats,
+        } => {
+            let check_stack = (check_stack || check_stack_every.is_some()).then(|| StackCheck {
+                after: Duration::from_secs(check_stack_after),
+                every: check_stack_every.map(Duration::from_secs),
+            });
+            logs(&path, gdb, baud, check_stack, None, stats)?;
         }
-        Args::Logs { path } => {
+        Args::Attach { path } => attach(&path)?,
+        Args::Gdb { path, task, port } => gdb(&path, task.as_deref(), port)?,
+        Args::MemoryDumps {
+            path,
+            gdb,
+            baud,
+            task,
+            addr,
+            len,
+        } => {
+            let filter = MemoryFilter { task, addr, len };
+            logs(&path, gdb, baud, None, Some(filter), false)?;
+        }
+        Args::Size {
+            path,
+            baseline,
+            threshold,
+            save,
+            json,
+        } => {
             let mut config = parse_config(&path)?;
-            let target = config.build(&path)?;
-            let mut session = flash::flash(&config)?;
-            let kernel_path = target.join("kernel.elf");
-            let log_source = match &mut session {
-                flash::Session::Xfel(xfel) => {
-                    xfel.reset()?;
-                    LogSource::Serial
-                }
-                flash::Session::Probe(session) => LogSource::Rtt(session),
-            };
-            logs::print_logs(&config, kernel_path, log_source)?;
+            config.resolve_paths(&path)?;
+            let report =
+                SizeReport::from_path(&config.target_path(), &config.regions, &config.tasks)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                report.print();
+            }
+            if let Some(save) = save {
+                fs::write(save, serde_json::to_vec_pretty(&report)?)?;
+            }
+            if let Some(baseline) = baseline {
+                let baseline: SizeReport = serde_json::from_slice(&fs::read(baseline)?)?;
+                report.compare(&baseline, threshold)?;
+            }
+            report.check_capacity()?;
         }
     }
     Ok(())
 }
+
+fn logs(
+    path: &Path,
+    gdb: Option<String>,
+    baud: Option<u32>,
+    check_stack: Option<StackCheck>,
+    mem_filter: Option<MemoryFilter>,
+    stats: bool,
+) -> Result<()> {
+    let mut config = parse_config(path)?;
+    if let Some(filter) = &mem_filter {
+        if !config.tasks.iter().any(|t| t.name == filter.task) {
+            return Err(anyhow!("unknown task {:?}", filter.task));
+        }
+    }
+    let target = config.build(path)?;
+    let kernel_path = target.join("kernel.elf");
+    if let Some(gdb) = gdb {
+        return logs::print_logs(
+            &config,
+            kernel_path,
+            LogSource::Gdb(gdb),
+            check_stack,
+            mem_filter,
+            stats,
+        );
+    }
+    let mut session = flash::flash(&config)?;
+    let log_source = match &mut session {
+        flash::Session::Xfel(xfel) => LogSource::Serial {
+            baud: baud.or(config.serial_baud),
+            reset: Some(&*xfel),
+        },
+        flash::Session::Probe(session) => LogSource::Rtt(session),
+    };
+    logs::print_logs(
+        &config,
+        kernel_path,
+        log_source,
+        check_stack,
+        mem_filter,
+        stats,
+    )
+}
+fn attach(path: &Path) -> Result<()> {
+    let mut config = parse_config(path)?;
+    config.resolve_paths(path)?;
+    let kernel_path = config.target_path().join("kernel.elf");
+    let mut session = flash::attach_probe_session(&config)?;
+    logs::print_logs(
+        &config,
+        kernel_path,
+        LogSource::Attach(&mut session),
+        None,
+        None,
+        false,
+    )
+}
+
+/// Flashes the app, and serves GDB on `port` with the registers of `task`, or the running thread if it is `None`
+fn gdb(path: &Path, task: Option<&str>, port: u16) -> Result<()> {
+    let mut config = parse_config(path)?;
+    let task = task
+        .map(|name| {
+            config
+                .tasks
+                .iter()
+                .position(|t| t.name == name)
+                .ok_or_else(|| anyhow!("unknown task {:?}", name))
+        })
+        .transpose()?;
+    let target = config.build(path)?;
+    let kernel_elf = fs::read(target.join("kernel.elf"))?;
+    let mut session = match flash::flash(&config)? {
+        flash::Session::Probe(session) => session,
+        flash::Session::Xfel(_) => {
+            return Err(anyhow!("gdb is only supported with a probe"));
+        }
+    };
+    gdb::serve(&mut session, &kernel_elf, task, port)
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "🏔 - k5's helper tool for flashing, debugging, and building k5 projects", long_about = None)]
 enum Args {
@@ -53,12 +187,18 @@ enum Args {
         /// path to directory containing `app.toml`
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// keep running, and rebuild whenever a source file of the kernel or a task changes
+        #[clap(long)]
+        watch: bool,
     },
     /// Flashes a k5 app from the `app.toml` file, to the specified chip
     Flash {
         /// path to directory containing `app.toml`
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// keep running, and rebuild and flash whenever a source file of the kernel or a task changes
+        #[clap(long)]
+        watch: bool,
     },
 
     /// Flashes and displays logs for a k5 app
@@ -66,15 +206,121 @@ enum Args {
         /// path to directory containing `app.toml`
         #[clap(default_value = ".")]
         path: PathBuf,
+        /// read logs through an already running GDB server at `host:port`, instead of flashing
+        #[clap(long)]
+        gdb: Option<String>,
+        /// baud rate of the serial log port, overrides `serial_baud` in `app.toml`. Detected if neither is set
+        #[clap(long)]
+        baud: Option<u32>,
+        /// warn if any thread's stack usage is close to its stack size
+        #[clap(long)]
+        check_stack: bool,
+        /// number of seconds to run before checking stack usage
+        #[clap(long, default_value = "5")]
+        check_stack_after: u64,
+        /// keep checking stack usage every this many seconds, implies `--check-stack`
+        #[clap(long)]
+        check_stack_every: Option<u64>,
+        /// show the scheduling stats tasks log with `userspace::log_stats`
+        #[clap(long)]
+        stats: bool,
+    },
+
+    /// Displays logs from an already running k5 app over RTT, without flashing or resetting it
+    ///
+    /// Logs are decoded using the ELFs from the last build, if they are missing raw frames are printed instead
+    Attach {
+        /// path to directory containing `app.toml`
+        #[clap(default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Flashes a k5 app, and serves the GDB remote protocol for debugging it through the probe
+    ///
+    /// Connect with `target remote localhost:<port>`. Registers are those of the selected task's thread, memory,
+    /// breakpoints, and stepping aren't limited to it. Only Cortex-M targets are supported.
+    Gdb {
+        /// path to directory containing `app.toml`
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// name of the task whose registers to show, defaults to whichever thread is running
+        #[clap(long)]
+        task: Option<String>,
+        /// TCP port to listen for GDB on
+        #[clap(long, default_value = "3333")]
+        port: u16,
+    },
+
+    /// Flashes a k5 app, and displays the memory dumps in its logs that overlap a range
+    ///
+    /// Nothing is read from the device, the task has to dump the memory itself by calling
+    /// `userspace::dump_memory`. This requires a debug build of the kernel. Logs are displayed as usual.
+    MemoryDumps {
+        /// path to directory containing `app.toml`
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// read logs through an already running GDB server at `host:port`, instead of flashing
+        #[clap(long)]
+        gdb: Option<String>,
+        /// baud rate of the serial log port, overrides `serial_baud` in `app.toml`. Detected if neither is set
+        #[clap(long)]
+        baud: Option<u32>,
+        /// only display dumps made by this task
+        #[clap(long)]
+        task: String,
+        /// start address of the range to display from each dump, in hex or decimal
+        #[clap(long, parse(try_from_str = parse_int))]
+        addr: u64,
+        /// length of the range to display from each dump, in bytes
+        #[clap(long, parse(try_from_str = parse_int))]
+        len: u64,
+    },
+
+    /// Reports the flash and RAM used by each task in the last build, without rebuilding
+    ///
+    /// Regions more than 80% full are shown in yellow, and more than 95% in red, which also fails the command
+    Size {
+        /// path to directory containing `app.toml`
+        #[clap(default_value = ".")]
+        path: PathBuf,
+        /// a report saved with `--save`, fails if any region has grown by more than `threshold` bytes
+        #[clap(long)]
+        baseline: Option<PathBuf>,
+        /// number of bytes a region can grow by before the comparison against `baseline` fails
+        #[clap(long, default_value = "0")]
+        threshold: usize,
+        /// saves the report as JSON, for use as a baseline later
+        #[clap(long)]
+        save: Option<PathBuf>,
+        /// prints the report as JSON instead of a table
+        #[clap(long)]
+        json: bool,
     },
 }
 
+fn parse_int(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Parses `app.toml`, along with `app.local.toml` if it exists
+///
+/// `app.local.toml` is a partial config, see [`build::ConfigOverlay`]. The fields it sets are layered over
+/// `app.toml`, and its tasks replace the tasks with the same name, see [`build::Config::merge`].
 fn parse_config(path: &Path) -> Result<build::Config> {
-    let mut config = config::Config::default();
     let path = fs::canonicalize(path)?;
-    config.merge(config::File::from(path.join("app.toml")).required(true))?;
+    let mut base = config::Config::default();
+    base.merge(config::File::from(path.join("app.toml")).required(true))?;
+    let mut config: build::Config = base.try_into()?;
 
-    let config: build::Config = config.try_into()?;
+    let local_path = path.join("app.local.toml");
+    if local_path.exists() {
+        let mut local = config::Config::default();
+        local.merge(config::File::from(local_path))?;
+        config.merge(local.try_into()?);
+    }
     Ok(config)
 }
 
@@ -0,0 +1,286 @@
+//! Reports the flash and RAM used by each task in an already built image
+//!
+//! Task sizes are taken from the regions allocated to each task in `task_list.json`, so they include the
+//! alignment padding and stack space that the image actually uses. The kernel's size is read from `kernel.elf`.
+//! The breakdown into `.text`, `.data`, and `.bss` is read from the section headers of each `final.elf`.
+use color_eyre::{eyre::anyhow, Result};
+use colored::{ColoredString, Colorize};
+use goblin::{
+    elf::section_header::{SHF_ALLOC, SHF_WRITE, SHT_NOBITS},
+    Object,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use crate::build::{get_elf_size, MemoryRole, MemorySection, Task};
+
+/// Regions fuller than this percentage are printed as a warning
+const WARN_PERCENT: f64 = 80.0;
+/// Regions fuller than this percentage are printed as an error, and fail [`SizeReport::check_capacity`]
+const ERROR_PERCENT: f64 = 95.0;
+
+/// Bytes used in each memory region, keyed by task name and then region name
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct SizeReport {
+    pub tasks: BTreeMap<String, BTreeMap<String, usize>>,
+    /// The size of each task's sections, keyed by task name
+    #[serde(default)]
+    pub sections: BTreeMap<String, SectionSizes>,
+    /// The capacity of each memory region, except the shared region which isn't divided between tasks
+    #[serde(default)]
+    pub capacity: BTreeMap<String, usize>,
+    /// The region the kernel's code is in, which the image is flashed to
+    #[serde(default)]
+    pub flash: Option<String>,
+}
+
+/// The size of each kind of section in an ELF, counted the same way as binutils' `size`
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy)]
+pub struct SectionSizes {
+    /// Read only sections, like `.text` and `.rodata`
+    pub text: usize,
+    /// Writable sections with an initial value, like `.data`
+    pub data: usize,
+    /// Writable sections without an initial value, like `.bss` and `.uninit`
+    pub bss: usize,
+    /// The task's stack space, this is 0 for the kernel
+    pub stack: usize,
+}
+
+impl SizeReport {
+    /// Reads the sizes of the image in `path`, the target directory of a previous `k5 build`
+    pub fn from_path(
+        path: &Path,
+        regions: &HashMap<String, MemorySection>,
+        tasks: &[Task],
+    ) -> Result<SizeReport> {
+        if !path.join("final.srec").exists() {
+            return Err(anyhow!(
+                "no image found in {:?}, run `k5 build` first",
+                path.display()
+            ));
+        }
+        let mut report = SizeReport {
+            capacity: regions
+                .iter()
+                .filter(|(_, r)| r.role != MemoryRole::Shared)
+                .map(|(name, r)| (name.clone(), r.size))
+                .collect(),
+            ..Default::default()
+        };
+        let kernel_path = path.join("kernel.elf");
+        let kernel = get_elf_size(&kernel_path, regions, 0)?;
+        report.tasks.insert(
+            "kernel".to_string(),
+            kernel
+                .into_iter()
+                .map(|(name, range)| (name, range.len()))
+                .collect(),
+        );
+        let (kernel_sections, text_start) = elf_sections(&kernel_path)?;
+        report
+            .sections
+            .insert("kernel".to_string(), kernel_sections);
+        report.flash = text_start.and_then(|addr| {
+            regions
+                .iter()
+                .find(|(_, r)| r.role != MemoryRole::Shared && r.contains(addr))
+                .map(|(name, _)| name.clone())
+        });
+        let task_list: codegen::TaskList =
+            serde_json::from_slice(&fs::read(path.join("task_list.json"))?)?;
+        for task in task_list.tasks {
+            let mut sizes = BTreeMap::new();
+            let ranges = task.regions.iter().chain([&task.stack_space]);
+            for range in ranges {
+                let (name, _) = regions
+                    .iter()
+                    .find(|(_, r)| r.contains(range.start))
+                    .ok_or_else(|| anyhow!("{:?} has a region outside of memory", task.name))?;
+                *sizes.entry(name.clone()).or_default() += range.len();
+            }
+            let config = tasks.iter().find(|t| t.name == task.name).ok_or_else(|| {
+                anyhow!("{:?} is no longer in app.toml, rebuild first", task.name)
+            })?;
+            let (sections, _) = elf_sections(&config.target_dir().join("final.elf"))?;
+            report.sections.insert(
+                task.name.clone(),
+                SectionSizes {
+                    stack: task.stack_space.len(),
+                    ..sections
+                },
+            );
+            report.tasks.insert(task.name, sizes);
+        }
+        Ok(report)
+    }
+
+    /// The bytes used in each region by the whole image
+    fn totals(&self) -> BTreeMap<&str, usize> {
+        let mut totals = BTreeMap::new();
+        for sizes in self.tasks.values() {
+            for (region, size) in sizes {
+                *totals.entry(region.as_str()).or_default() += size;
+            }
+        }
+        totals
+    }
+
+    /// Prints a table of each task's sections, and the percentage of each region it uses
+    pub fn print(&self) {
+        let name_width = self.tasks.keys().map(|n| n.len()).max().unwrap_or_default() + 2;
+        let mut header = format!(
+            "{:<name_width$} {:>8} {:>8} {:>8} {:>8}",
+            "task", ".text", ".data", ".bss", "stack"
+        );
+        for region in self.capacity.keys() {
+            header += &format!(" {:>width$}", region, width = column_width(region));
+        }
+        println!("{}", header.bold());
+        for (task, sizes) in &self.tasks {
+            let sections = self.sections.get(task).copied().unwrap_or_default();
+            print!(
+                "{:<name_width$} {:>8} {:>8} {:>8} {:>8}",
+                task, sections.text, sections.data, sections.bss, sections.stack
+            );
+            for (region, capacity) in &self.capacity {
+                let size = sizes.get(region).copied().unwrap_or_default();
+                let width = column_width(region) - 1;
+                print!(" {}", color_percent(percent(size, *capacity), width));
+            }
+            println!();
+        }
+        let totals = self.totals();
+        print!("{}{:36}", format!("{:<name_width$}", "total").bold(), "");
+        for (region, capacity) in &self.capacity {
+            let size = totals.get(region.as_str()).copied().unwrap_or_default();
+            let width = column_width(region) - 1;
+            print!(" {}", color_percent(percent(size, *capacity), width));
+        }
+        println!();
+        for (region, capacity) in &self.capacity {
+            let size = totals.get(region.as_str()).copied().unwrap_or_default();
+            let label = if self.flash.as_deref() == Some(region) {
+                "image"
+            } else {
+                region
+            };
+            println!(
+                "{}: {} of {} bytes in {} ({})",
+                label,
+                size,
+                capacity,
+                region,
+                color_percent(percent(size, *capacity), 0)
+            );
+        }
+    }
+
+    /// Returns an error listing every region that is more than 95% full
+    pub fn check_capacity(&self) -> Result<()> {
+        let totals = self.totals();
+        let full: Vec<_> = self
+            .capacity
+            .iter()
+            .filter_map(|(region, capacity)| {
+                let size = totals.get(region.as_str()).copied().unwrap_or_default();
+                let percent = percent(size, *capacity);
+                (percent > ERROR_PERCENT)
+                    .then(|| format!("{region}: {size} of {capacity} bytes ({percent:.1}%)"))
+            })
+            .collect();
+        if full.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("regions are nearly full:\n{}", full.join("\n")))
+        }
+    }
+
+    /// Returns an error listing every region that has grown more than `threshold` bytes compared to `baseline`
+    pub fn compare(&self, baseline: &SizeReport, threshold: usize) -> Result<()> {
+        let mut grown = vec![];
+        for (task, sizes) in &self.tasks {
+            for (region, size) in sizes {
+                let base = baseline
+                    .tasks
+                    .get(task)
+                    .and_then(|s| s.get(region))
+                    .copied()
+                    .unwrap_or_default();
+                if size.saturating_sub(base) > threshold {
+                    grown.push(format!("{task} {region}: {base} -> {size} bytes"));
+                }
+            }
+        }
+        if grown.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("sizes grew beyond baseline:\n{}", grown.join("\n")))
+        }
+    }
+}
+
+/// The width of a region's column in [`SizeReport::print`], wide enough for its name and a percentage
+fn column_width(region: &str) -> usize {
+    region.len().max(8)
+}
+
+fn percent(size: usize, capacity: usize) -> f64 {
+    if capacity == 0 {
+        0.0
+    } else {
+        size as f64 * 100.0 / capacity as f64
+    }
+}
+
+/// Formats `percent` right aligned to `width`, in yellow if it is over [`WARN_PERCENT`] or red if over [`ERROR_PERCENT`]
+fn color_percent(percent: f64, width: usize) -> ColoredString {
+    let text = format!("{:>width$.1}%", percent);
+    if percent > ERROR_PERCENT {
+        text.red()
+    } else if percent > WARN_PERCENT {
+        text.yellow()
+    } else {
+        text.normal()
+    }
+}
+
+/// Sums the sizes of the allocated sections in `elf`, and returns them along with the address of `.text`
+fn elf_sections(elf: &Path) -> Result<(SectionSizes, Option<usize>)> {
+    let bytes = fs::read(elf).map_err(|err| {
+        anyhow!(
+            "failed to read {:?}: {}, run `k5 build` first",
+            elf.display(),
+            err
+        )
+    })?;
+    let elf = if let Object::Elf(e) = Object::parse(&bytes)? {
+        e
+    } else {
+        return Err(anyhow!("object must be an elf"));
+    };
+    let mut sizes = SectionSizes::default();
+    let mut text_start = None;
+    for header in &elf.section_headers {
+        let flags = header.sh_flags as u32;
+        if flags & SHF_ALLOC == 0 || header.sh_size == 0 {
+            continue;
+        }
+        if elf.shdr_strtab.get_at(header.sh_name) == Some(".text") {
+            text_start = Some(header.sh_addr as usize);
+        }
+        let size = header.sh_size as usize;
+        if flags & SHF_WRITE == 0 {
+            sizes.text += size;
+        } else if header.sh_type == SHT_NOBITS {
+            sizes.bss += size;
+        } else {
+            sizes.data += size;
+        }
+    }
+    Ok((sizes, text_start))
+}
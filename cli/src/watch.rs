@@ -0,0 +1,106 @@
+//! Rebuilds an app each time its sources change, for `k5 build --watch` and `k5 flash --watch`
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::Result;
+use colored::Colorize;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::build::{Config, TaskSource};
+
+/// Changes that arrive less than this long after the previous one are handled by the same rebuild
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Runs `cycle` on the app in `path`, and again each time a source file of the kernel or a task changes
+///
+/// The config is parsed again for each cycle, so changes to `app.toml` are picked up too. Errors from parsing the
+/// config or from `cycle` are printed instead of returned, so a broken build doesn't end the watch.
+pub fn watch(path: &Path, mut cycle: impl FnMut(&mut Config) -> Result<()>) -> Result<()> {
+    let path = fs::canonicalize(path)?;
+    loop {
+        // the watcher is created before building, so changes saved during the build trigger another one
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&path, RecursiveMode::Recursive)?;
+
+        println!("{}", format!("[{}] building", timestamp()).bold());
+        let start = Instant::now();
+        let res = crate::parse_config(&path).and_then(|mut config| {
+            config.resolve_paths(&path)?;
+            watch_sources(&mut watcher, &path, &config)?;
+            cycle(&mut config)
+        });
+        let elapsed = start.elapsed().as_secs_f32();
+        match res {
+            Ok(()) => println!(
+                "{}",
+                format!("[{}] finished in {:.1}s", timestamp(), elapsed).green()
+            ),
+            Err(err) => println!(
+                "{}\n{:?}",
+                format!("[{}] failed in {:.1}s", timestamp(), elapsed).red(),
+                err
+            ),
+        }
+
+        println!("watching for changes");
+        loop {
+            let event = rx.recv()??;
+            if event.paths.iter().any(|p| is_source(p)) {
+                break;
+            }
+        }
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+    }
+}
+
+/// Watches the crates of the kernel and every task, other than the ones inside the already watched `app_path`
+fn watch_sources(watcher: &mut RecommendedWatcher, app_path: &Path, config: &Config) -> Result<()> {
+    let mut dirs: Vec<&PathBuf> = config
+        .tasks
+        .iter()
+        .map(|task| {
+            let TaskSource::Crate { crate_path } = &task.source;
+            crate_path
+        })
+        .chain([&config.kernel.crate_path])
+        .filter(|dir| !dir.starts_with(app_path))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+    Ok(())
+}
+
+/// Whether a change to `path` should trigger a rebuild, build outputs in `target` directories never do
+fn is_source(path: &Path) -> bool {
+    if path.components().any(|c| c.as_os_str() == "target") {
+        return false;
+    }
+    let is_config = matches!(
+        path.file_name().and_then(|n| n.to_str()),
+        Some("Cargo.toml" | "app.toml" | "app.local.toml")
+    );
+    let is_code = matches!(path.extension().and_then(|e| e.to_str()), Some("rs" | "x"));
+    is_config || is_code
+}
+
+/// The current UTC time of day, as `hh:mm:ss`
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
@@ -1,3 +1,4 @@
+use std::io::Write;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
@@ -5,22 +6,66 @@ use std::time::Duration;
 
 use color_eyre::eyre::anyhow;
 use color_eyre::Result;
+use colored::Colorize;
 use serde::Deserialize;
 use wait_timeout::ChildExt;
 
+const MAX_RETRIES: usize = 5;
+const INITIAL_TIMEOUT_MS: u64 = 200;
+
+/// Controls how many times, and how patiently, we try to connect to a device in FEL mode
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct XfelConfig {
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+    /// The timeout of the first attempt, each retry doubles the previous timeout
+    #[serde(default = "default_initial_timeout_ms")]
+    pub initial_timeout_ms: u64,
+}
+
+impl Default for XfelConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            initial_timeout_ms: INITIAL_TIMEOUT_MS,
+        }
+    }
+}
+
+fn default_max_retries() -> usize {
+    MAX_RETRIES
+}
+
+fn default_initial_timeout_ms() -> u64 {
+    INITIAL_TIMEOUT_MS
+}
+
 pub struct XfelDevice {
     flash: InternalFlash,
 }
 
 impl XfelDevice {
-    pub fn connect(flash: InternalFlash) -> Result<XfelDevice> {
-        let mut child = xfel_cmd().arg("version").spawn()?;
-        if let Some(status) = child.wait_timeout(Duration::from_millis(500))? {
-            if !status.success() {
-                return Err(anyhow!("xfel version failed"));
+    pub fn connect(flash: InternalFlash, config: &XfelConfig) -> Result<XfelDevice> {
+        let mut timeout = Duration::from_millis(config.initial_timeout_ms);
+        let mut attempt = 0;
+        // USB enumeration can be slow on some boards, so we retry `xfel version` with a growing timeout
+        while !xfel_version(timeout)? {
+            if attempt == config.max_retries {
+                println!();
+                println!("{} {}", " Hint ".on_red().white(), "Remember to put the device into FEL mode, this can be done by holding down the FEL button and hitting reset.");
+                return Err(anyhow!(
+                    "xfel version failed after {} retries",
+                    config.max_retries
+                ));
             }
-        } else {
-            return Err(anyhow!("xfel version failed"));
+            print!(".");
+            std::io::stdout().flush()?;
+            std::thread::sleep(timeout);
+            timeout *= 2;
+            attempt += 1;
+        }
+        if attempt > 0 {
+            println!();
         }
         let mut cmd = xfel_cmd();
         match flash {
@@ -60,6 +105,18 @@ impl XfelDevice {
     }
 }
 
+/// Runs `xfel version`, returning false if it fails or doesn't finish before `timeout`
+fn xfel_version(timeout: Duration) -> Result<bool> {
+    let mut child = xfel_cmd().arg("version").spawn()?;
+    if let Some(status) = child.wait_timeout(timeout)? {
+        Ok(status.success())
+    } else {
+        child.kill()?;
+        child.wait()?;
+        Ok(false)
+    }
+}
+
 fn xfel_cmd() -> Command {
     Command::new("xfel")
 }
@@ -0,0 +1,10 @@
+# Assembled with `llvm-mc -triple=riscv64 -filetype=obj rv64-symbols.s -o rv64-symbols.o`
+# The symbols are placed above 4GiB, to check that 64-bit addresses aren't truncated
+    .text
+    .globl _start
+_start:
+    nop
+    .globl main
+    .set main, 0x100000000
+    .globl _SEGGER_RTT
+    .set _SEGGER_RTT, 0x100001000
@@ -0,0 +1,246 @@
+//! Generates typed IPC stubs from interface description files
+//!
+//! Each TOML file in a task's `interfaces/` directory describes the messages a single task accepts.
+//! The file stem is used as the task name, so `interfaces/foo.toml` generates `mod foo_ipc`.
+//!
+//! ```toml
+//! [[fields]]
+//! name = "led"
+//! type = "u8"
+//! direction = "in"
+//!
+//! [[fields]]
+//! name = "state"
+//! type = "u8"
+//! direction = "out"
+//! ```
+//!
+//! `in` fields are placed in the request struct, and `out` fields in the response. Both structs derive
+//! `bytemuck::Pod`, so fields must be ordered so that no padding is required, and the calling crate
+//! must depend on `bytemuck` with the `derive` feature.
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Interface {
+    #[serde(skip)]
+    pub task: String,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub direction: Direction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Interface {
+    pub fn parse(task: impl Into<String>, toml: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut interface: Interface = toml::from_str(toml)?;
+        interface.task = task.into();
+        Ok(interface)
+    }
+
+    pub fn gen_code(&self) -> String {
+        let task = self.task.to_lowercase();
+        let name = camel_case(&self.task);
+        let mut code = format!(
+            "pub mod {task}_ipc {{
+use userspace::abi::{{CapRef, Error}};
+use userspace::CapExt;
+"
+        );
+        if let Some(port) = crate::port_id(&self.task) {
+            code += &format!(
+                "
+/// The port the `{task}` task listens on
+pub const PORT: userspace::abi::PortId = {};
+",
+                crate::port_literal(&port)
+            );
+        }
+        code += &gen_struct(&format!("{name}Request"), self.fields(Direction::In));
+        code += &gen_struct(&format!("{name}Response"), self.fields(Direction::Out));
+        code += &format!(
+            "
+/// Sends a [`{name}Request`] to the `{task}` task and waits for its reply
+pub fn {task}_call(cap: CapRef, req: &{name}Request) -> Result<{name}Response, Error> {{
+    let mut req = *req;
+    let mut resp: {name}Response = bytemuck::Zeroable::zeroed();
+    cap.call(bytemuck::bytes_of_mut(&mut req), bytemuck::bytes_of_mut(&mut resp))?;
+    Ok(resp)
+}}
+}}
+"
+        );
+        code
+    }
+
+    fn fields(&self, direction: Direction) -> impl Iterator<Item = &Field> {
+        self.fields.iter().filter(move |f| f.direction == direction)
+    }
+}
+
+fn gen_struct<'a>(name: &str, fields: impl Iterator<Item = &'a Field>) -> String {
+    let mut code = format!(
+        "
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct {name} {{
+"
+    );
+    for field in fields {
+        code += &format!("    pub {}: {},\n", field.name, field.ty);
+    }
+    code += "}\n";
+    code
+}
+
+fn camel_case(name: &str) -> String {
+    name.split(['_', '-'])
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Reads every interface in `dir` and returns the generated code
+pub fn gen_interfaces_from_dir(dir: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    let mut code = String::new();
+    for path in paths {
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", path.display());
+        let task = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or("invalid interface file name")?;
+        let interface = Interface::parse(task, &fs::read_to_string(&path)?)?;
+        code += &interface.gen_code();
+    }
+    Ok(code)
+}
+
+/// Returns the names of the tasks described by the interfaces in `dir`, sorted by name
+pub fn interface_tasks(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut tasks = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+            continue;
+        }
+        if let Some(task) = path.file_stem().and_then(|s| s.to_str()) {
+            tasks.push(task.to_string());
+        }
+    }
+    tasks.sort();
+    Ok(tasks)
+}
+
+/// Generates IPC stubs for the crate being built, this should be called from a task's `build.rs`
+///
+/// The stubs are read from the optional `interfaces/` directory next to the crate's `Cargo.toml`,
+/// and written to `$OUT_DIR/interfaces.rs`. If the directory doesn't exist an empty file is written.
+pub fn gen_interfaces() -> Result<(), Box<dyn std::error::Error>> {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?);
+    let dir = manifest_dir.join("interfaces");
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let code = if dir.is_dir() {
+        gen_interfaces_from_dir(&dir)?
+    } else {
+        String::new()
+    };
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR environment variable not set"));
+    fs::write(out_dir.join("interfaces.rs"), code.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gen_code() {
+        let interface = Interface::parse(
+            "led_ctl",
+            r#"
+            [[fields]]
+            name = "led"
+            type = "u8"
+            direction = "in"
+
+            [[fields]]
+            name = "state"
+            type = "u8"
+            direction = "out"
+
+            [[fields]]
+            name = "on"
+            type = "u8"
+            direction = "in"
+            "#,
+        )
+        .unwrap();
+        let expected = r#"pub mod led_ctl_ipc {
+use userspace::abi::{CapRef, Error};
+use userspace::CapExt;
+
+/// The port the `led_ctl` task listens on
+pub const PORT: userspace::abi::PortId = *b"led_ctl\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct LedCtlRequest {
+    pub led: u8,
+    pub on: u8,
+}
+
+#[derive(Clone, Copy, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct LedCtlResponse {
+    pub state: u8,
+}
+
+/// Sends a [`LedCtlRequest`] to the `led_ctl` task and waits for its reply
+pub fn led_ctl_call(cap: CapRef, req: &LedCtlRequest) -> Result<LedCtlResponse, Error> {
+    let mut req = *req;
+    let mut resp: LedCtlResponse = bytemuck::Zeroable::zeroed();
+    cap.call(bytemuck::bytes_of_mut(&mut req), bytemuck::bytes_of_mut(&mut resp))?;
+    Ok(resp)
+}
+}
+"#;
+        assert_eq!(interface.gen_code(), expected);
+    }
+
+    #[test]
+    fn test_gen_code_long_task_name() {
+        // names longer than a `PortId` can't listen on a port, so they get no `PORT` constant
+        let interface = Interface::parse("a_very_long_task_name", "").unwrap();
+        let code = interface.gen_code();
+        assert!(!code.contains("pub const PORT"));
+        assert!(code.contains("pub struct AVeryLongTaskNameRequest {\n}\n"));
+        assert!(code.contains("pub fn a_very_long_task_name_call("));
+    }
+}
@@ -1,9 +1,14 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::fmt;
 use std::fs;
 use std::ops::Range;
 use std::path::PathBuf;
 
+mod interface;
+pub use interface::*;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TaskList {
     pub tasks: Vec<Task>,
@@ -16,14 +21,124 @@ pub struct Task {
     pub stack_space: Range<usize>,
     pub init_stack_size: usize,
     pub regions: Vec<Range<usize>>,
+    /// Named entrypoints, as byte offsets from the start of the task's flash region
+    #[serde(default)]
+    pub entrypoints: BTreeMap<String, usize>,
+    /// Capabilities given to the task's first thread at boot
+    #[serde(default)]
+    pub caps: Vec<CapDesc>,
+    /// The address range of the task's `.text` section
+    #[serde(default)]
+    pub text: Option<Range<usize>>,
+    /// Bytes of thread-local storage at the top of each of the task's stacks
+    #[serde(default)]
+    pub tls_size: usize,
+}
+
+/// A static capability, the port is named and converted to a `PortId` during codegen
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CapDesc {
+    Listen(String),
+    Connect(String),
+}
+
+impl CapDesc {
+    fn gen_code(&self) -> String {
+        let (variant, port) = match self {
+            CapDesc::Listen(port) => ("Listen", port),
+            CapDesc::Connect(port) => ("Connect", port),
+        };
+        let port = port_id(port).expect("port name longer than 16 bytes");
+        format!("kernel::abi::Cap::{variant}(kernel::abi::{variant} {{ port: {port:?} }})")
+    }
+}
+
+/// Returns a byte string literal for `port`, e.g `*b"foo\x00..."`
+pub fn port_literal(port: &[u8; 16]) -> String {
+    format!("*b\"{}\"", port.escape_ascii())
 }
 
+/// Converts a port name into a `PortId`, padding it with zeros
+///
+/// Returns `None` if the name is longer than a `PortId`
+pub fn port_id(name: &str) -> Option<[u8; 16]> {
+    let mut port = [0u8; 16];
+    port.get_mut(..name.len())?.copy_from_slice(name.as_bytes());
+    Some(port)
+}
+
+/// A problem with a task list that would produce a broken kernel image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodegenError {
+    DuplicateTaskName(String),
+    MissingIdleTask,
+    NullEntrypoint(String),
+    EmptyStackSpace(String),
+    StackTooLarge {
+        task: String,
+        init_stack_size: usize,
+        stack_space: usize,
+    },
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::DuplicateTaskName(name) => write!(f, "more than one task is named {name:?}"),
+            CodegenError::MissingIdleTask => write!(f, "no task is named \"idle\""),
+            CodegenError::NullEntrypoint(name) => write!(f, "task {name:?} has a zero entrypoint"),
+            CodegenError::EmptyStackSpace(name) => write!(f, "task {name:?} has no stack space"),
+            CodegenError::StackTooLarge {
+                task,
+                init_stack_size,
+                stack_space,
+            } => write!(
+                f,
+                "task {task:?} has an initial stack of {init_stack_size} bytes, but only {stack_space} bytes of stack space"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
 impl TaskList {
+    /// Checks the task list for mistakes that would otherwise show up as linker or boot failures
+    pub fn validate(&self) -> Result<(), Vec<CodegenError>> {
+        let mut errors = vec![];
+        let mut names = BTreeSet::new();
+        for task in &self.tasks {
+            if !names.insert(task.name.as_str()) {
+                errors.push(CodegenError::DuplicateTaskName(task.name.clone()));
+            }
+            if task.entrypoint == 0 {
+                errors.push(CodegenError::NullEntrypoint(task.name.clone()));
+            }
+            if task.stack_space.is_empty() {
+                errors.push(CodegenError::EmptyStackSpace(task.name.clone()));
+            } else if task.init_stack_size > task.stack_space.len() {
+                errors.push(CodegenError::StackTooLarge {
+                    task: task.name.clone(),
+                    init_stack_size: task.init_stack_size,
+                    stack_space: task.stack_space.len(),
+                });
+            }
+        }
+        if !names.contains("idle") {
+            errors.push(CodegenError::MissingIdleTask);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     fn gen_code(&self) -> String {
-        let mut code = format!(
-            "
+        let mut code = "
         pub static TASKS: &[kernel::TaskDesc] = &["
-        );
+            .to_string();
         for task in &self.tasks {
             code += &format!(
                 "kernel::TaskDesc {{
@@ -31,9 +146,22 @@ name: {:?},
 entrypoint: {},
 stack_space: {:?},
 init_stack_size: {},
-regions: &{:?}
+regions: &{:?},
+caps: &[{}],
+pre_start_hook: None,
+tls_size: {},
 }},",
-                task.name, task.entrypoint, task.stack_space, task.init_stack_size, task.regions,
+                task.name,
+                task.entrypoint,
+                task.stack_space,
+                task.init_stack_size,
+                task.regions,
+                task.caps
+                    .iter()
+                    .map(CapDesc::gen_code)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                task.tls_size,
             );
         }
         code += "];\n";
@@ -44,11 +172,34 @@ regions: &{:?}
                 task.name.to_uppercase(),
                 i
             );
+            for (entry, offset) in &task.entrypoints {
+                code += &format!(
+                    "pub const TASK_{}_ENTRY_{}: usize = {:#x};\n",
+                    task.name.to_uppercase(),
+                    entry.to_uppercase().replace('-', "_"),
+                    offset
+                );
+            }
             code += &format!(
                 "pub const {}: kernel::ThreadBuilder = unsafe {{ kernel::ThreadBuilder::new({}) }};\n",
                 task.name.to_uppercase(),
                 i
             );
+            if let Some(text) = &task.text {
+                code += &format!(
+                    "pub const TASK_{}_TEXT: core::ops::Range<usize> = {:#x}..{:#x};\n",
+                    task.name.to_uppercase(),
+                    text.start,
+                    text.end
+                );
+            }
+            if let Some(port) = port_id(&task.name) {
+                code += &format!(
+                    "pub const {}_PORT: kernel::abi::PortId = {};\n",
+                    task.name.to_uppercase(),
+                    port_literal(&port)
+                );
+            }
         }
         code
     }
@@ -60,8 +211,63 @@ pub fn gen_tasklist() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed={}", env);
     let task_list = fs::read(env)?;
     let task_list: TaskList = serde_json::from_slice(&task_list)?;
+    if let Err(errors) = task_list.validate() {
+        let errors = errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(format!("invalid task list:\n{errors}").into());
+    }
     let code = task_list.gen_code();
     let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR environment variable not set"));
     fs::write(out_dir.join("codegen.rs"), code.as_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str) -> Task {
+        Task {
+            name: name.to_string(),
+            entrypoint: 0x1000,
+            stack_space: 0x2000_0000..0x2000_1000,
+            init_stack_size: 0x400,
+            regions: vec![],
+            entrypoints: BTreeMap::new(),
+            caps: vec![],
+            text: None,
+            tls_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let list = TaskList {
+            tasks: vec![task("idle"), task("foo")],
+        };
+        assert_eq!(list.validate(), Ok(()));
+
+        let mut bad = task("foo");
+        bad.entrypoint = 0;
+        bad.init_stack_size = 0x2000;
+        let list = TaskList {
+            tasks: vec![task("foo"), bad],
+        };
+        assert_eq!(
+            list.validate(),
+            Err(vec![
+                CodegenError::DuplicateTaskName("foo".to_string()),
+                CodegenError::NullEntrypoint("foo".to_string()),
+                CodegenError::StackTooLarge {
+                    task: "foo".to_string(),
+                    init_stack_size: 0x2000,
+                    stack_space: 0x1000,
+                },
+                CodegenError::MissingIdleTask,
+            ])
+        );
+    }
+}
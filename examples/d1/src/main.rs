@@ -142,14 +142,14 @@ fn main() -> ! {
             .priority(7)
             .budget(5)
             .cooldown(usize::MAX)
-            .listen(*b"0123456789abcdef"),
+            .listen(task_table::FOO_PORT),
     );
     let bar_thread = kernel.thread(
         task_table::BAR
             .priority(7)
             .budget(100)
             .cooldown(50)
-            .connect(*b"0123456789abcdef"),
+            .connect(task_table::FOO_PORT),
     );
     kernel.endpoint(bar_thread, foo_thread, 0);
     kernel.start()
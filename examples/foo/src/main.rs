@@ -21,7 +21,7 @@ pub fn main() -> ! {
     let mut buf = [0u8; 10];
     let mut toggle = false;
     loop {
-        match userspace::recv::<_, [u8; 32]>(0, &mut buf) {
+        match userspace::recv_copy::<_, [u8; 32]>(0, &mut buf) {
             Ok(resp) => {
                 defmt::println!("resp: {:?} buf: {:?}", resp, buf);
                 if let Some(cap) = resp.cap {
@@ -40,6 +40,8 @@ pub fn main() -> ! {
                                 defmt::error!("syscall err: {:?}", err);
                             }
                         }
+                        userspace::RecvRespBody::Notification(_)
+                        | userspace::RecvRespBody::Cap(_) => {}
                     }
                     if toggle {
                         led.set_low();
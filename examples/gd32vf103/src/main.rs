@@ -0,0 +1,125 @@
+#![feature(alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use riscv::interrupt::Mutex;
+
+#[global_allocator]
+static ALLOCATOR: RISCVHeap = RISCVHeap::empty();
+
+kernel::include_task_table! {}
+
+const RCU_APB2EN: *mut u32 = 0x4002_1018 as *mut u32;
+const GPIOA_CTL1: *mut u32 = 0x4001_0804 as *mut u32;
+const USART0_STAT: *const u32 = 0x4001_3800 as *const u32;
+const USART0_DATA: *mut u32 = 0x4001_3804 as *mut u32;
+const USART0_BAUD: *mut u32 = 0x4001_3808 as *mut u32;
+const USART0_CTL0: *mut u32 = 0x4001_380C as *mut u32;
+
+#[no_mangle]
+fn _log_impl(bytes: &[u8]) {
+    for byte in bytes {
+        // Safety: USART0 is only touched by the kernel, which is single threaded
+        unsafe {
+            // wait for TBE, the transmit buffer empty flag
+            while ptr::read_volatile(USART0_STAT) & (1 << 7) == 0 {}
+            ptr::write_volatile(USART0_DATA, *byte as u32);
+        }
+    }
+}
+
+/// Sets up USART0 on PA9 for 115200 8n1, running from the 8MHz internal oscillator the chip boots with
+fn init_uart() {
+    // Safety: these registers are only written here, before the kernel starts
+    unsafe {
+        // enable the GPIOA and USART0 clocks
+        ptr::write_volatile(
+            RCU_APB2EN,
+            ptr::read_volatile(RCU_APB2EN) | (1 << 2) | (1 << 14),
+        );
+        // PA9 as a 50MHz alternate function push-pull output
+        let ctl1 = ptr::read_volatile(GPIOA_CTL1) & !(0xF << 4);
+        ptr::write_volatile(GPIOA_CTL1, ctl1 | (0b1011 << 4));
+        // 8MHz / (16 * 115200) = 4.34, which is a mantissa of 4 and a fraction of 5/16
+        ptr::write_volatile(USART0_BAUD, (4 << 4) | 5);
+        // enable the USART and its transmitter
+        ptr::write_volatile(USART0_CTL0, (1 << 13) | (1 << 3));
+    }
+}
+
+#[riscv_rt::entry]
+fn main() -> ! {
+    riscv::register::mscratch::write(0x0);
+    init_uart();
+
+    {
+        const HEAP_SIZE: usize = 0x1000;
+        static mut HEAP: &mut [MaybeUninit<u8>; HEAP_SIZE] =
+            &mut [MaybeUninit::uninit(); HEAP_SIZE];
+        // Safety: we only ever access this once durring init, so this operation is safe
+        crate::ALLOCATOR.init(unsafe { HEAP })
+    }
+
+    let mut kernel = kernel::KernelBuilder::new(task_table::TASKS);
+    let _idle = kernel.idle_thread(task_table::IDLE);
+    kernel.start()
+}
+
+pub struct RISCVHeap {
+    heap: Mutex<RefCell<Heap>>,
+}
+
+impl RISCVHeap {
+    pub const fn empty() -> RISCVHeap {
+        RISCVHeap {
+            heap: Mutex::new(RefCell::new(Heap::empty())),
+        }
+    }
+
+    pub fn init(&self, mem: &'static mut [MaybeUninit<u8>]) {
+        riscv::interrupt::free(move |cs| {
+            self.heap.borrow(*cs).borrow_mut().init_from_slice(mem);
+        });
+    }
+}
+
+unsafe impl GlobalAlloc for RISCVHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        riscv::interrupt::free(|cs| {
+            self.heap
+                .borrow(*cs)
+                .borrow_mut()
+                .allocate_first_fit(layout)
+                .ok()
+                .map_or(core::ptr::null_mut(), |allocation| allocation.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        riscv::interrupt::free(|cs| {
+            self.heap
+                .borrow(*cs)
+                .borrow_mut()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        });
+    }
+}
+
+#[alloc_error_handler]
+fn oom(_: core::alloc::Layout) -> ! {
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
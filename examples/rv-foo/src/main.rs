@@ -15,7 +15,7 @@ pub fn main() -> ! {
     let mut buf = [0u8; 10];
     loop {
         defmt::println!("recv");
-        match userspace::recv::<_, [u8; 32]>(0, &mut buf) {
+        match userspace::recv_copy::<_, [u8; 32]>(0, &mut buf) {
             Ok(resp) => {
                 defmt::println!("resp: {:?} buf: {:?}", resp, buf);
                 if let Some(cap) = resp.cap {
@@ -34,6 +34,8 @@ pub fn main() -> ! {
                                 defmt::println!("syscall err: {:?}", err);
                             }
                         }
+                        userspace::RecvRespBody::Notification(_)
+                        | userspace::RecvRespBody::Cap(_) => {}
                     }
                 }
             }
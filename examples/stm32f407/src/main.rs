@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(alloc_error_handler)]
+#![feature(naked_functions)]
+#![feature(asm_sym)]
+
+extern crate alloc;
+
+use alloc_cortex_m::CortexMHeap;
+use core::{mem::MaybeUninit, panic::PanicInfo};
+use cortex_m_rt::{entry, exception};
+use defmt::{error, info};
+
+kernel::include_task_table! {}
+
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+#[entry]
+fn main() -> ! {
+    {
+        const HEAP_SIZE: usize = 0x1000;
+        static mut HEAP: &mut [MaybeUninit<u8>; HEAP_SIZE] =
+            &mut [MaybeUninit::uninit(); HEAP_SIZE];
+        // Safety: we only ever access this once durring init, so this operation is safe
+        crate::ALLOCATOR.init(unsafe { HEAP })
+    }
+
+    let mut kernel = kernel::KernelBuilder::new(task_table::TASKS);
+    let _idle = kernel.idle_thread(task_table::IDLE);
+
+    info!("booting");
+    kernel.start()
+}
+
+#[alloc_error_handler]
+fn oom(_: core::alloc::Layout) -> ! {
+    error!("kernel out of memory");
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("kern panic: {}", defmt::Display2Format(info));
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+/// Hands MemManage faults, like a stack guard hit, to the kernel, which restarts the faulting task
+///
+/// This has to be a tail call, so the kernel sees the registers as the fault left them.
+#[no_mangle]
+#[naked]
+unsafe extern "C" fn MemoryManagement() {
+    core::arch::asm!("b {}", sym kernel::mem_manage, options(noreturn))
+}
+
+#[exception]
+unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    // MMARVALID is set when the MemFault address register holds the address that faulted
+    let mmfsr = core::ptr::read_volatile(0xE000ED28 as *const u8);
+    let mmfar = core::ptr::read_volatile(0xE000ED34 as *const u32);
+    if mmfsr & (1 << 7) != 0 {
+        if let Some(task) = kernel::stack_guard_task(mmfar as usize) {
+            error!("task {} overflowed its stack at {:x}", task, mmfar);
+        }
+    }
+    defmt::println!("{:?}", defmt::Debug2Format(ef));
+    defmt::println!("MemFault reg {:b}", mmfsr);
+    defmt::println!("MemFault addr: {:x}", mmfar);
+    defmt::println!(
+        "UsageFault reg {:b}",
+        core::ptr::read_volatile(0xE000ED2A as *const u16)
+    );
+
+    loop {}
+}
@@ -1,6 +1,8 @@
 #![no_std]
 #![no_main]
 #![feature(alloc_error_handler)]
+#![feature(naked_functions)]
+#![feature(asm_sym)]
 
 extern crate alloc;
 
@@ -34,7 +36,7 @@ fn main() -> ! {
             .priority(7)
             .budget(100)
             .cooldown(50)
-            .connect(*b"0123456789abcdef"),
+            .connect(task_table::FOO_PORT),
     );
     let foo_thread = kernel.thread(
         task_table::FOO
@@ -52,7 +54,7 @@ fn main() -> ! {
             // .loan_mem(RegionBuilder::device(stm32l562::GPIOG::PTR).write().read())
             //.loan_mem(RegionBuilder::device(stm32l562::PWR::PTR).write().read())
             //.loan_mem(RegionBuilder::device(stm32l562::FLASH::PTR).write().read())
-            .listen(*b"0123456789abcdef"),
+            .listen(task_table::FOO_PORT),
     );
 
     kernel.endpoint(bar_thread, foo_thread, 0);
@@ -76,8 +78,25 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
+/// Hands MemManage faults, like a stack guard hit, to the kernel, which restarts the faulting task
+///
+/// This has to be a tail call, so the kernel sees the registers as the fault left them.
+#[no_mangle]
+#[naked]
+unsafe extern "C" fn MemoryManagement() {
+    core::arch::asm!("b {}", sym kernel::mem_manage, options(noreturn))
+}
+
 #[exception]
 unsafe fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    // MMARVALID is set when the MemFault address register holds the address that faulted
+    let mmfsr = core::ptr::read_volatile(0xE000ED28 as *const u8);
+    let mmfar = core::ptr::read_volatile(0xE000ED34 as *const u32);
+    if mmfsr & (1 << 7) != 0 {
+        if let Some(task) = kernel::stack_guard_task(mmfar as usize) {
+            error!("task {} overflowed its stack at {:x}", task, mmfar);
+        }
+    }
     defmt::println!("{:?}", defmt::Debug2Format(ef));
     defmt::println!(
         "MemFault reg {:b}",
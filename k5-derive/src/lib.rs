@@ -0,0 +1,355 @@
+//! Derive and attribute macros for typed IPC between k5 tasks
+//!
+//! The generated code refers to `::k5_ipc` and `::userspace`, so crates using it need both as dependencies.
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Data, DeriveInput, Error, Fields, FnArg,
+    Ident, ItemStruct, ItemTrait, Path, ReceiverKind, ReturnType, TraitItem, Type,
+};
+
+/// Derives `k5_ipc::Message` for a struct or enum whose fields are all messages
+///
+/// Structs encode their fields in order. Enums encode a `u8` variant index and then the variant's fields, so they
+/// can have at most 256 variants.
+#[proc_macro_derive(Message)]
+pub fn derive_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_message_inner(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn derive_message_inner(mut input: DeriveInput) -> Result<TokenStream2, Error> {
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::k5_ipc::Message));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let (max_len, encode, decode) = match &input.data {
+        Data::Struct(data) => {
+            let (bindings, pattern) = field_bindings(&data.fields);
+            let max_len = fields_max_len(&data.fields);
+            let encode = quote! {
+                let Self #pattern = self;
+                #(::k5_ipc::Message::encode(#bindings, w)?;)*
+                Ok(())
+            };
+            let decode = quote! {
+                #(let #bindings = ::k5_ipc::Message::decode(r)?;)*
+                Ok(Self #pattern)
+            };
+            (max_len, encode, decode)
+        }
+        Data::Enum(data) => {
+            if data.variants.len() > 256 {
+                return Err(Error::new_spanned(
+                    &data.variants,
+                    "messages can have at most 256 variants",
+                ));
+            }
+            let mut max_lens = vec![];
+            let mut encode_arms = vec![];
+            let mut decode_arms = vec![];
+            for (i, variant) in data.variants.iter().enumerate() {
+                let i = i as u8;
+                let ident = &variant.ident;
+                let (bindings, pattern) = field_bindings(&variant.fields);
+                max_lens.push(fields_max_len(&variant.fields));
+                encode_arms.push(quote! {
+                    Self::#ident #pattern => {
+                        ::k5_ipc::Message::encode(&#i, w)?;
+                        #(::k5_ipc::Message::encode(#bindings, w)?;)*
+                    }
+                });
+                decode_arms.push(quote! {
+                    #i => {
+                        #(let #bindings = ::k5_ipc::Message::decode(r)?;)*
+                        Ok(Self::#ident #pattern)
+                    }
+                });
+            }
+            let max_len = quote! { 1 + ::k5_ipc::max_len(&[#(#max_lens),*]) };
+            let encode = quote! {
+                match self {
+                    #(#encode_arms)*
+                }
+                Ok(())
+            };
+            let decode = quote! {
+                match <u8 as ::k5_ipc::Message>::decode(r)? {
+                    #(#decode_arms)*
+                    _ => Err(::k5_ipc::Error::Invalid),
+                }
+            };
+            (max_len, encode, decode)
+        }
+        Data::Union(data) => {
+            return Err(Error::new_spanned(
+                data.union_token,
+                "unions can't be messages",
+            ))
+        }
+    };
+    Ok(quote! {
+        impl #impl_generics ::k5_ipc::Message for #name #ty_generics #where_clause {
+            const MAX_LEN: usize = #max_len;
+
+            #[allow(unused_variables)]
+            fn encode(&self, w: &mut ::k5_ipc::Writer<'_>) -> Result<(), ::k5_ipc::Error> {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode(r: &mut ::k5_ipc::Reader<'_>) -> Result<Self, ::k5_ipc::Error> {
+                #decode
+            }
+        }
+    })
+}
+
+/// Returns a binding for each field, and the pattern that destructures the fields into them
+fn field_bindings(fields: &Fields) -> (Vec<Ident>, TokenStream2) {
+    let bindings: Vec<_> = (0..fields.len())
+        .map(|i| format_ident!("field_{}", i))
+        .collect();
+    let pattern = match fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            quote! { { #(#names: #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { ( #(#bindings),* ) },
+        Fields::Unit => quote! {},
+    };
+    (bindings, pattern)
+}
+
+fn fields_max_len(fields: &Fields) -> TokenStream2 {
+    let tys = fields.iter().map(|field| &field.ty);
+    quote! { 0 #(+ <#tys as ::k5_ipc::Message>::MAX_LEN)* }
+}
+
+/// Turns a trait into the interface of a task serving typed IPC requests
+///
+/// Each method must take `&mut self`, and its arguments and return type must implement `k5_ipc::Message`. The
+/// macro adds a `serve_loop` method to the trait, which receives requests forever, decodes them, calls the matching
+/// method, and replies with what it returns.
+///
+/// It also generates a `{Trait}Client` trait with a method of the same name for each request, which sends it to
+/// the endpoint and decodes the reply. Implement it with [`macro@k5_client`].
+///
+/// A request is encoded as the method's index, in the order the methods are declared, followed by its arguments as
+/// a tuple. A reply starts with 0 and then the return value, or is just 1 if the server couldn't decode the request.
+#[proc_macro_attribute]
+pub fn k5_task(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(Span::call_site(), "k5_task doesn't take any arguments")
+            .into_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemTrait);
+    k5_task_inner(item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Method {
+    ident: Ident,
+    attrs: Vec<syn::Attribute>,
+    args: Vec<Type>,
+    reply: Type,
+}
+
+impl Method {
+    fn request(&self) -> Type {
+        match self.args.as_slice() {
+            [arg] => arg.clone(),
+            args => parse_quote! { (#(#args,)*) },
+        }
+    }
+}
+
+fn k5_task_inner(mut item: ItemTrait) -> Result<TokenStream2, Error> {
+    if !item.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &item.generics,
+            "k5_task traits can't be generic",
+        ));
+    }
+    let mut methods = vec![];
+    for trait_item in &item.items {
+        let method = match trait_item {
+            TraitItem::Fn(method) => method,
+            _ => continue,
+        };
+        let sig = &method.sig;
+        if !sig.generics.params.is_empty() {
+            return Err(Error::new_spanned(
+                &sig.generics,
+                "k5_task methods can't be generic",
+            ));
+        }
+        match sig.inputs.first() {
+            Some(FnArg::Receiver(receiver))
+                if matches!(receiver.kind, ReceiverKind::Reference(_, _, Some(_))) => {}
+            _ => {
+                return Err(Error::new(
+                    sig.span(),
+                    "k5_task methods must take `&mut self`",
+                ))
+            }
+        }
+        let args = sig
+            .inputs
+            .iter()
+            .skip(1)
+            .map(|arg| match arg {
+                FnArg::Typed(arg) => (*arg.ty).clone(),
+                FnArg::Receiver(_) => unreachable!("only the first argument can be self"),
+            })
+            .collect();
+        let reply = match &sig.output {
+            ReturnType::Default => parse_quote! { () },
+            ReturnType::Type(_, ty) => (**ty).clone(),
+        };
+        methods.push(Method {
+            ident: sig.ident.clone(),
+            attrs: method
+                .attrs
+                .iter()
+                .filter(|attr| attr.path().is_ident("doc"))
+                .cloned()
+                .collect(),
+            args,
+            reply,
+        });
+    }
+    if methods.len() > 256 {
+        return Err(Error::new_spanned(
+            &item.ident,
+            "k5_task traits can have at most 256 methods",
+        ));
+    }
+
+    let requests: Vec<_> = methods.iter().map(Method::request).collect();
+    let replies: Vec<_> = methods.iter().map(|method| &method.reply).collect();
+    let indices: Vec<_> = (0..methods.len()).map(|i| i as u8).collect();
+    let arg_names: Vec<Vec<_>> = methods
+        .iter()
+        .map(|method| {
+            (0..method.args.len())
+                .map(|i| format_ident!("arg_{}", i))
+                .collect()
+        })
+        .collect();
+    let request_patterns: Vec<_> = arg_names
+        .iter()
+        .map(|names| match names.as_slice() {
+            [name] => quote! { #name },
+            names => quote! { (#(#names,)*) },
+        })
+        .collect();
+    let idents: Vec<_> = methods.iter().map(|method| &method.ident).collect();
+
+    item.items.push(parse_quote! {
+        /// Receives requests sent to the endpoints in `mask` forever, replying to each with its method's result
+        fn serve_loop(&mut self, mask: u32) -> ! {
+            const IN_LEN: usize = 1 + ::k5_ipc::max_len(&[#(<#requests as ::k5_ipc::Message>::MAX_LEN),*]);
+            const OUT_LEN: usize = 1 + ::k5_ipc::max_len(&[#(<#replies as ::k5_ipc::Message>::MAX_LEN),*]);
+            let mut in_buf = [0u8; IN_LEN];
+            let mut out_buf = [0u8; OUT_LEN];
+            ::userspace::serve(mask, &mut in_buf, &mut out_buf, |method, req, out| match method {
+                #(
+                    #indices => {
+                        let #request_patterns: #requests = ::k5_ipc::decode(req)?;
+                        ::k5_ipc::encode(&self.#idents(#(#arg_names),*), out)
+                    }
+                )*
+                _ => Err(::k5_ipc::Error::Invalid),
+            })
+        }
+    });
+
+    let client = format_ident!("{}Client", item.ident);
+    let vis = &item.vis;
+    let client_methods = methods.iter().enumerate().map(|(i, method)| {
+        let i = i as u8;
+        let ident = &method.ident;
+        let attrs = &method.attrs;
+        let args = &method.args;
+        let names = &arg_names[i as usize];
+        let request = &requests[i as usize];
+        let reply = &method.reply;
+        let request_value = match names.as_slice() {
+            [name] => quote! { #name },
+            names => quote! { (#(#names,)*) },
+        };
+        quote! {
+            #(#attrs)*
+            fn #ident(&self, #(#names: #args),*) -> Result<#reply, ::userspace::abi::Error> {
+                let mut req_buf = [0u8; 1 + <#request as ::k5_ipc::Message>::MAX_LEN];
+                let mut out_buf = [0u8; 1 + <#reply as ::k5_ipc::Message>::MAX_LEN];
+                let request: #request = #request_value;
+                ::userspace::CapExt::call_msg(&self.endpoint(), #i, &request, &mut req_buf, &mut out_buf)
+            }
+        }
+    });
+    let doc = format!("A client for [`{}`], generated by `k5_task`", item.ident);
+    Ok(quote! {
+        #item
+
+        #[doc = #doc]
+        #vis trait #client {
+            /// The endpoint the server is listening on
+            fn endpoint(&self) -> ::userspace::abi::CapRef;
+
+            #(#client_methods)*
+        }
+    })
+}
+
+/// Implements the client generated by [`macro@k5_task`] for a tuple struct wrapping the server's endpoint
+///
+/// It takes the path to the server's trait, so `#[k5_client(FooServer)] struct FooClient(CapRef);` implements
+/// `FooServerClient` for `FooClient`.
+#[proc_macro_attribute]
+pub fn k5_client(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let server = parse_macro_input!(attr as Path);
+    let item = parse_macro_input!(item as ItemStruct);
+    k5_client_inner(server, item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn k5_client_inner(mut server: Path, item: ItemStruct) -> Result<TokenStream2, Error> {
+    match &item.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {}
+        _ => {
+            return Err(Error::new_spanned(
+                &item,
+                "k5_client must be on a struct with a single unnamed `CapRef` field",
+            ))
+        }
+    }
+    match server.segments.last_mut() {
+        Some(last) => last.ident = format_ident!("{}Client", last.ident),
+        None => {
+            return Err(Error::new(
+                Span::call_site(),
+                "expected the path to a k5_task trait",
+            ))
+        }
+    }
+    let name = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics #server for #name #ty_generics #where_clause {
+            fn endpoint(&self) -> ::userspace::abi::CapRef {
+                self.0
+            }
+        }
+    })
+}
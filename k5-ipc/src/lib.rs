@@ -0,0 +1,337 @@
+//! The encoding used for typed IPC messages between k5 tasks
+//!
+//! Messages are fixed size types, so [`Message::MAX_LEN`] can size buffers at compile time. Integers are encoded
+//! little-endian at their full width, with `usize` and `isize` widened to 64 bits, `bool` as a byte, enums as a
+//! `u8` variant index followed by the variant's fields, and everything else as its fields in order.
+//!
+//! Usually [`Message`] is derived, and the messages are sent by the servers and clients generated by
+//! `userspace::k5_task` and `userspace::k5_client`.
+#![no_std]
+
+#[cfg(feature = "derive")]
+pub use k5_derive::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is too small for the encoded message
+    BufferOverflow,
+    /// The message ended before every field was decoded
+    Truncated,
+    /// The message has an invalid value, like an unknown enum variant, or has bytes left over once decoded
+    Invalid,
+}
+
+/// A type that can be sent in an IPC message
+pub trait Message: Sized {
+    /// The most bytes an encoded value can take
+    const MAX_LEN: usize;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error>;
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error>;
+}
+
+/// Appends encoded values to a buffer
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::BufferOverflow)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// The number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Reads encoded values from the front of a buffer
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn read(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.buf.len() {
+            return Err(Error::Truncated);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read(N)?);
+        Ok(array)
+    }
+
+    /// The bytes that haven't been read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+/// Encodes `msg` to the start of `buf`, and returns the encoded length
+pub fn encode<T: Message>(msg: &T, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut w = Writer::new(buf);
+    msg.encode(&mut w)?;
+    Ok(w.len())
+}
+
+/// Decodes a message that takes up all of `buf`
+pub fn decode<T: Message>(buf: &[u8]) -> Result<T, Error> {
+    let mut r = Reader::new(buf);
+    let msg = T::decode(&mut r)?;
+    if !r.remaining().is_empty() {
+        return Err(Error::Invalid);
+    }
+    Ok(msg)
+}
+
+/// Returns the largest of `lens`, for computing [`Message::MAX_LEN`] in a const
+pub const fn max_len(lens: &[usize]) -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < lens.len() {
+        if lens[i] > max {
+            max = lens[i];
+        }
+        i += 1;
+    }
+    max
+}
+
+macro_rules! impl_int {
+    ($($ty:ty),*) => {
+        $(
+            impl Message for $ty {
+                const MAX_LEN: usize = core::mem::size_of::<$ty>();
+
+                fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+                    w.write(&self.to_le_bytes())
+                }
+
+                fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+                    Ok(<$ty>::from_le_bytes(r.read_array()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Message for usize {
+    const MAX_LEN: usize = u64::MAX_LEN;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        (*self as u64).encode(w)
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        usize::try_from(u64::decode(r)?).map_err(|_| Error::Invalid)
+    }
+}
+
+impl Message for isize {
+    const MAX_LEN: usize = i64::MAX_LEN;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        (*self as i64).encode(w)
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        isize::try_from(i64::decode(r)?).map_err(|_| Error::Invalid)
+    }
+}
+
+impl Message for bool {
+    const MAX_LEN: usize = 1;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        (*self as u8).encode(w)
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
+
+impl Message for char {
+    const MAX_LEN: usize = u32::MAX_LEN;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        (*self as u32).encode(w)
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        char::from_u32(u32::decode(r)?).ok_or(Error::Invalid)
+    }
+}
+
+impl Message for () {
+    const MAX_LEN: usize = 0;
+
+    fn encode(&self, _w: &mut Writer<'_>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn decode(_r: &mut Reader<'_>) -> Result<Self, Error> {
+        Ok(())
+    }
+}
+
+impl<T: Message> Message for Option<T> {
+    const MAX_LEN: usize = 1 + T::MAX_LEN;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        match self {
+            None => false.encode(w),
+            Some(value) => {
+                true.encode(w)?;
+                value.encode(w)
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        if bool::decode(r)? {
+            Ok(Some(T::decode(r)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: Message, E: Message> Message for Result<T, E> {
+    const MAX_LEN: usize = 1 + max_len(&[T::MAX_LEN, E::MAX_LEN]);
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        match self {
+            Ok(value) => {
+                0u8.encode(w)?;
+                value.encode(w)
+            }
+            Err(err) => {
+                1u8.encode(w)?;
+                err.encode(w)
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        match u8::decode(r)? {
+            0 => Ok(Ok(T::decode(r)?)),
+            1 => Ok(Err(E::decode(r)?)),
+            _ => Err(Error::Invalid),
+        }
+    }
+}
+
+impl<T: Message, const N: usize> Message for [T; N] {
+    const MAX_LEN: usize = N * T::MAX_LEN;
+
+    fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+        self.iter().try_for_each(|value| value.encode(w))
+    }
+
+    fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+        let mut array: [Option<T>; N] = [(); N].map(|_| None);
+        for value in array.iter_mut() {
+            *value = Some(T::decode(r)?);
+        }
+        Ok(array.map(|value| value.unwrap()))
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident $i:tt),+) => {
+        impl<$($name: Message),+> Message for ($($name,)+) {
+            const MAX_LEN: usize = 0 $(+ $name::MAX_LEN)+;
+
+            fn encode(&self, w: &mut Writer<'_>) -> Result<(), Error> {
+                $(self.$i.encode(w)?;)+
+                Ok(())
+            }
+
+            fn decode(r: &mut Reader<'_>) -> Result<Self, Error> {
+                Ok(($($name::decode(r)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0, B 1);
+impl_tuple!(A 0, B 1, C 2);
+impl_tuple!(A 0, B 1, C 2, D 3);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Error, Message};
+
+    fn round_trip<T: Message + PartialEq + core::fmt::Debug>(value: T) {
+        let mut buf = [0u8; 64];
+        let len = encode(&value, &mut buf).unwrap();
+        assert!(len <= T::MAX_LEN);
+        assert_eq!(decode::<T>(&buf[..len]).unwrap(), value);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(0x1234u16);
+        round_trip(-5i64);
+        round_trip(usize::MAX);
+        round_trip(true);
+        round_trip('k');
+        round_trip(Some(7u32));
+        round_trip(None::<u32>);
+        round_trip(Ok::<u8, u16>(3));
+        round_trip(Err::<u8, u16>(300));
+        round_trip([1u8, 2, 3]);
+        round_trip((1u8, false, 2.5f32));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut buf = [0u8; 8];
+        let len = encode(&(0x0102u16, Some(true)), &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x02, 0x01, 1, 1]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut buf = [0u8; 3];
+        assert_eq!(encode(&1u32, &mut buf), Err(Error::BufferOverflow));
+        assert_eq!(decode::<u32>(&[1, 2]), Err(Error::Truncated));
+        assert_eq!(decode::<u8>(&[1, 2]), Err(Error::Invalid));
+        assert_eq!(decode::<bool>(&[2]), Err(Error::Invalid));
+    }
+}
@@ -0,0 +1,60 @@
+use k5_ipc::{decode, encode, Error, Message};
+
+#[derive(Message, Debug, PartialEq)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[derive(Message, Debug, PartialEq)]
+struct Wrapper<T>(T, bool);
+
+#[derive(Message, Debug, PartialEq)]
+struct Unit;
+
+#[derive(Message, Debug, PartialEq)]
+enum Request {
+    Ping,
+    Move(Point),
+    Blink { led: u8, times: Option<u16> },
+}
+
+fn round_trip<T: Message + PartialEq + core::fmt::Debug>(value: T) {
+    let mut buf = [0u8; 64];
+    let len = encode(&value, &mut buf).unwrap();
+    assert!(len <= T::MAX_LEN);
+    assert_eq!(decode::<T>(&buf[..len]).unwrap(), value);
+}
+
+#[test]
+fn test_derive_struct() {
+    assert_eq!(Point::MAX_LEN, 8);
+    assert_eq!(Wrapper::<u16>::MAX_LEN, 3);
+    assert_eq!(Unit::MAX_LEN, 0);
+    round_trip(Point { x: -1, y: 2 });
+    round_trip(Wrapper(0xABCDu16, true));
+    round_trip(Unit);
+}
+
+#[test]
+fn test_derive_enum() {
+    assert_eq!(Request::MAX_LEN, 1 + 8);
+    round_trip(Request::Ping);
+    round_trip(Request::Move(Point { x: 3, y: 4 }));
+    round_trip(Request::Blink {
+        led: 2,
+        times: Some(5),
+    });
+
+    let mut buf = [0u8; 16];
+    let len = encode(
+        &Request::Blink {
+            led: 1,
+            times: None,
+        },
+        &mut buf,
+    )
+    .unwrap();
+    assert_eq!(&buf[..len], &[2, 1, 0]);
+    assert_eq!(decode::<Request>(&[3]), Err(Error::Invalid));
+}
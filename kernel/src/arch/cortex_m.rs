@@ -4,28 +4,65 @@ use core::{
     mem, ptr,
     sync::atomic::{AtomicPtr, Ordering},
 };
-use cortex_m::peripheral::scb::SystemHandler;
+use cortex_m::interrupt::InterruptNumber;
+use cortex_m::peripheral::scb::{Exception, SystemHandler, VectActive};
+use cortex_m::peripheral::{NVIC, SCB};
 use mem::MaybeUninit;
 
 use abi::{SyscallArgs, SyscallIndex, SyscallReturn, SyscallReturnType, ThreadRef};
 use rtt_target::{rtt_init, UpChannel};
 
+#[cfg(feature = "cortex_m7m")]
+use super::cortex_m7m::apply_region_table;
 use super::syscall_inner;
+#[cfg(not(feature = "cortex_m7m"))]
+use crate::regions::{CachePolicy, Region, MAX_REGIONS};
 use crate::syscalls::CallReturn;
 use crate::KernelError;
 use crate::{
-    regions::{Region, RegionAttr, RegionTable},
+    regions::{RegionAttr, RegionTable, STACK_GUARD_LEN},
+    security::SecurityConfig,
     task_ptr::{TaskPtr, TaskPtrMut},
-    Kernel, Task, TaskDesc, Tcb,
+    Kernel, Task, TaskDesc, Tcb, TASK_CAPACITY,
 };
 
 const INITIAL_PSR: u32 = 1 << 24;
-const EXC_RETURN: u32 = 0xFFFFFFED; //FIXME(sphw): this is only correct on v8m in secure mode
+// returns to thread mode on the PSP with an extended (FPU) frame, on v8m this also selects the secure state
+// and v7m treats those bits as reserved ones, so the same value works for both
+const EXC_RETURN: u32 = 0xFFFFFFED;
 
 static mut KERNEL_INIT: AtomicBool = AtomicBool::new(false);
 static mut KERNEL: MaybeUninit<Kernel> = MaybeUninit::uninit();
 #[no_mangle]
-static mut CURRENT_TCB: AtomicPtr<Tcb> = AtomicPtr::new(ptr::null_mut());
+static CURRENT_TCB: AtomicPtr<Tcb> = AtomicPtr::new(ptr::null_mut());
+/// The TCB each task last ran on, indexed by task
+static TASK_TCBS: [AtomicPtr<Tcb>; TASK_CAPACITY] = {
+    const NULL_TCB: AtomicPtr<Tcb> = AtomicPtr::new(ptr::null_mut());
+    [NULL_TCB; TASK_CAPACITY]
+};
+
+/// Tells a debugger where to find each thread's registers, read by `k5 gdb`
+///
+/// The [`SavedThreadState`] is the first field of a [`Tcb`], so a pointer to a TCB also points to its saved
+/// registers, which are up to date whenever the core is in the kernel, or running a different thread.
+#[repr(C)]
+pub struct DebugInfo {
+    /// Bumped whenever the layout of this struct or [`SavedThreadState`] changes
+    version: u32,
+    task_capacity: u32,
+    current_tcb: &'static AtomicPtr<Tcb>,
+    task_tcbs: &'static [AtomicPtr<Tcb>; TASK_CAPACITY],
+}
+
+#[no_mangle]
+#[used]
+#[allow(non_upper_case_globals)]
+static __k5_debug_info: DebugInfo = DebugInfo {
+    version: 1,
+    task_capacity: TASK_CAPACITY as u32,
+    current_tcb: &CURRENT_TCB,
+    task_tcbs: &TASK_TCBS,
+};
 
 pub(crate) fn init_kernel<'k, 't>(tasks: &'t [TaskDesc]) -> &'k mut Kernel {
     // Safety: this is all unsafe due to the use of static mut, but its a kernel so watcha gonna do
@@ -34,6 +71,7 @@ pub(crate) fn init_kernel<'k, 't>(tasks: &'t [TaskDesc]) -> &'k mut Kernel {
             panic!("kernel already inited");
         }
         init_log();
+        crate::crash_log::report();
         let kern = KERNEL.write(Kernel::from_tasks(tasks).unwrap());
         KERNEL_INIT.store(true, Ordering::SeqCst);
         kern
@@ -46,7 +84,11 @@ pub(crate) unsafe fn kernel() -> *mut Kernel {
 }
 
 pub(crate) unsafe fn set_current_tcb(task: &Tcb) {
-    CURRENT_TCB.store(task as *const Tcb as *mut Tcb, Ordering::SeqCst);
+    let tcb = task as *const Tcb as *mut Tcb;
+    CURRENT_TCB.store(tcb, Ordering::SeqCst);
+    if let Some(task_tcb) = TASK_TCBS.get(task.task.0) {
+        task_tcb.store(tcb, Ordering::Relaxed);
+    }
 }
 
 pub(crate) fn start_root_task(task: &Task, tcb: &Tcb) -> ! {
@@ -83,6 +125,9 @@ pub(crate) fn start_root_task(task: &Task, tcb: &Tcb) -> ! {
         }
     }
 
+    // without this a stack guard hit escalates to a HardFault, which can't be recovered from
+    p.SCB.enable(Exception::MemoryManagement);
+
     p.SYST.set_reload(20_000);
     p.SYST.clear_current();
     p.SYST.enable_counter();
@@ -123,8 +168,15 @@ pub(crate) fn start_root_task(task: &Task, tcb: &Tcb) -> ! {
     }
 }
 
-pub(crate) fn init_tcb_stack(task: &Task, tcb: &mut Tcb) {
+pub(crate) fn init_tcb_stack(task: &mut Task, tcb: &mut Tcb) {
     let stack_addr = tcb.stack_pointer - mem::size_of::<ExceptionFrame>();
+    // the MPU needs regions aligned to their size, so the guard starts at the first aligned address in the stack
+    let guard_start = (tcb.stack_pointer + task.tls_size - task.stack_size + STACK_GUARD_LEN - 1)
+        & !(STACK_GUARD_LEN - 1);
+    let guard = guard_start..guard_start + STACK_GUARD_LEN;
+    if guard.end > stack_addr || !task.region_table.push_guard(guard) {
+        defmt::warn!("no room in the MPU for a stack guard in task {}", task.name);
+    }
     let stack_ptr: TaskPtrMut<ExceptionFrame> =
     // Safety: We are essentially inventing a lifetime here, but its fine because we are the
     // kernel and can guarantee that no one else will touch this memory until we say so
@@ -138,29 +190,31 @@ pub(crate) fn init_tcb_stack(task: &Task, tcb: &mut Tcb) {
     stack_exc_frame.xpsr = INITIAL_PSR;
     stack_exc_frame.lr = 0xFFFF_FFFF;
     tcb.saved_state.psp = stack_addr as u32;
+    tcb.saved_state.r9 = tcb.tls_base as u32;
     tcb.saved_state.exc_return = EXC_RETURN;
 }
 
-pub(crate) fn clear_mem(task: &Task) {
-    let stack = &task.available_stack_ptr[0].start;
-    for region in &task.region_table.regions {
-        if !region.range.contains(stack) {
-            continue;
-        }
-        // Safety: We are creating a lifetime that lasts for the body of this function;
-        // this is safe, because we are in Kernel mode, and are simply wiping the memory
-        let ptr = unsafe {
-            TaskPtrMut::<'_, [u32]>::from_raw_parts(region.range.start, region.range.len() / 32)
-        };
-        let mem = task
-            .validate_mut_ptr(ptr)
-            .expect("pointer not in task memory");
-        for word in mem {
-            *word = 0xdeadf00d;
+/// Returns the name of the running task if `addr` is in one of its stack guards
+///
+/// Meant to be called from a HardFault handler with the faulting address, to tell a stack overflow apart from
+/// other access violations. MemManage faults are handled by [`mem_manage`], which restarts the task.
+pub fn stack_guard_task(addr: usize) -> Option<&'static str> {
+    // Safety: the kernel is only read after it has been initialized, and the fault handler calling this
+    // has preempted any code that could be writing to it
+    unsafe {
+        if !KERNEL_INIT.load(Ordering::SeqCst) {
+            return None;
         }
+        let tcb = CURRENT_TCB.load(Ordering::SeqCst).as_ref()?;
+        let task = (*kernel()).task(tcb.task).ok()?;
+        task.region_table.is_guard(addr).then_some(task.name)
     }
 }
 
+pub(crate) fn clear_mem(task: &Task, security: &SecurityConfig) {
+    crate::security::clear_task_mem(task, security, cortex_m::peripheral::SYST::get_current());
+}
+
 #[repr(C)]
 #[derive(Default)]
 pub struct SavedThreadState {
@@ -312,6 +366,177 @@ fn systick_inner() {
     }
 }
 
+/// Restarts the task that caused a MemManage fault, like a task panic, and switches to the next thread
+///
+/// The kernel binary routes its `MemoryManagement` vector here with a tail call, so every register is still as
+/// the fault left it, see the `stm32l5` example. MemManage faults are enabled by
+/// `start_root_task`, so a stack guard hit lands here rather than in the HardFault handler. Faults in the kernel
+/// itself can't be recovered from, and panic instead.
+#[naked]
+pub unsafe extern "C" fn mem_manage() {
+    asm!(
+        " tst lr, #4 @ bit 2 of EXC_RETURN is set when the fault came from a thread on the PSP
+         beq 1f
+         movw r0, #:lower16:CURRENT_TCB
+         movt r0, #:upper16:CURRENT_TCB
+         ldr r1, [r0] @ load the value of CURRENT_TCB into r1
+         movs r2, r1
+         mrs r12, PSP @ store PSP in r12
+         stm r2!, {{r4-r12, lr}} @ store r4-r11 & psp in r12
+         vstm r2, {{s16-s31}} @ store float registers
+         bl {inner}
+         movw r0, #:lower16:CURRENT_TCB
+         movt r0, #:upper16:CURRENT_TCB
+         ldr r0, [r0]
+         @ restore volatile registers, plus load PSP into r12
+         ldm r0!, {{r4-r12, lr}}
+         vldm r0, {{s16-s31}}
+         msr PSP, r12
+         bx lr
+
+         1:
+         b {kernel_fault}
+         ",
+        inner = sym mem_manage_inner,
+        kernel_fault = sym mem_manage_kernel,
+        options(noreturn)
+    )
+}
+
+/// Returns the MemManage fault status, and the faulting address if the core recorded one, clearing the status
+fn take_mem_fault() -> (u8, Option<usize>) {
+    // MMARVALID is set when MMFAR holds the address that faulted
+    const MMARVALID: u8 = 1 << 7;
+    // Safety: fault handlers can't be preempted by the kernel, so nothing else is using these registers
+    let scb = unsafe { &*SCB::PTR };
+    let mmfsr = scb.cfsr.read() as u8;
+    let addr = (mmfsr & MMARVALID != 0).then(|| scb.mmfar.read() as usize);
+    // Safety: the MMFSR bits are write-one-to-clear, and writing zero to the rest of CFSR has no effect
+    unsafe { scb.cfsr.write(u32::from(mmfsr)) };
+    (mmfsr, addr)
+}
+
+fn mem_manage_inner() {
+    let (mmfsr, addr) = take_mem_fault();
+    // Safety: This function is only ever called by `mem_manage` when the fault came from a thread, so
+    // it hasn't preempted the kernel
+    let kernel = unsafe { &mut *kernel() };
+    let task_ref = match kernel.scheduler.current_thread() {
+        Ok(tcb) => tcb.task,
+        Err(err) => defmt::panic!("MemManage fault with no current thread: {}", err),
+    };
+    let task = kernel.task(task_ref).unwrap();
+    let msg: &[u8] = match addr {
+        Some(addr) if task.region_table.is_guard(addr) => {
+            defmt::error!("task {} overflowed its stack at {:x}", task.name, addr);
+            b"stack overflow"
+        }
+        Some(addr) => {
+            defmt::error!(
+                "task {} faulted accessing {:x} (MMFSR {:b})",
+                task.name,
+                addr,
+                mmfsr
+            );
+            b"memory fault"
+        }
+        None => {
+            defmt::error!("task {} faulted (MMFSR {:b})", task.name, mmfsr);
+            b"memory fault"
+        }
+    };
+    crate::crash_log::record_panic(task_ref.0 as u8, task.name, kernel.scheduler.ticks, msg);
+    match kernel.restart_task(task_ref) {
+        Ok(next_thread) => switch_thread(kernel, next_thread),
+        Err(err) => defmt::panic!("failed to restart task {}: {}", task_ref.0, err),
+    }
+}
+
+fn mem_manage_kernel() -> ! {
+    let (mmfsr, addr) = take_mem_fault();
+    defmt::panic!(
+        "MemManage fault in the kernel at {} (MMFSR {:b})",
+        addr,
+        mmfsr
+    )
+}
+
+/// Handles every device interrupt, delivering it to the thread that claimed it
+///
+/// Like `SysTick`, device interrupts run at the lowest priority so they never preempt the kernel
+#[allow(non_snake_case)]
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn DefaultHandler() {
+    asm!(
+        " movw r0, #:lower16:CURRENT_TCB
+         movt r0, #:upper16:CURRENT_TCB
+         ldr r1, [r0] @ load the value of CURRENT_TCB into r1
+         movs r2, r1
+         mrs r12, PSP @ store PSP in r12
+         stm r2!, {{r4-r12, lr}} @ store r4-r11 & psp in r12
+         vstm r2, {{s16-s31}} @ store float registers
+         bl {inner}
+         movw r0, #:lower16:CURRENT_TCB
+         movt r0, #:upper16:CURRENT_TCB
+         ldr r0, [r0]
+         @ restore volatile registers, plus load PSP into r12
+         ldm r0!, {{r4-r12, lr}}
+         vldm r0, {{s16-s31}}
+         msr PSP, r12
+         bx lr
+         ",
+        inner = sym irq_inner,
+        options(noreturn)
+    )
+}
+
+fn irq_inner() {
+    let irq = if let VectActive::Interrupt { irqn } = SCB::vect_active() {
+        irqn
+    } else {
+        return;
+    };
+    // Safety: This function is only ever called by `DefaultHandler`, which
+    // can't preempt the kernel, so it is safe for us to access the kernel
+    let kernel = unsafe { &mut *kernel() };
+    match kernel.interrupt(irq.into()) {
+        Ok(Some(tcb_ref)) => switch_thread(kernel, tcb_ref),
+        Ok(None) => {}
+        // `interrupt` masks the IRQ before delivering it, so a failed delivery leaves it masked rather than
+        // firing again straight away
+        Err(err) => defmt::error!("failed to deliver irq {}: {}", irq, err),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Irq(u16);
+
+// Safety: `Irq` is only created from IRQ numbers claimed by tasks, which the NVIC ignores if they are out of range
+unsafe impl InterruptNumber for Irq {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
+
+pub(crate) fn mask_irq(irq: u16) {
+    NVIC::mask(Irq(irq));
+}
+
+pub(crate) fn unmask_irq(irq: u16) {
+    // Safety: unmasking can break critical sections based on masking, the kernel doesn't use those,
+    // and the interrupt is always handled by the kernel first, which only passes it on to the owning task
+    unsafe { NVIC::unmask(Irq(irq)) }
+}
+
+/// Clears the interrupt's pending bit and unmasks it, once its task has handled it
+///
+/// A level-triggered source that is still asserted becomes pending again straight away.
+pub(crate) fn ack_irq(irq: u16) {
+    NVIC::unpend(Irq(irq));
+    unmask_irq(irq);
+}
+
 #[inline]
 pub(crate) unsafe fn get_current_tcb() -> &'static mut Tcb {
     &mut *CURRENT_TCB.load(Ordering::SeqCst)
@@ -333,7 +558,7 @@ pub(crate) fn translate_task_ptr<'a, T: ptr::Pointee + ?Sized>(
     // Safety: We only use return this reference when validated, so this is safe
     let r = unsafe { task_ptr.ptr() };
     let (ptr, _) = (r as *const T).to_raw_parts();
-    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table.regions).then_some(r)
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
 }
 
 pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
@@ -343,17 +568,19 @@ pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
     // Safety: We only use return this reference when validated, so this is safe
     let r = unsafe { task_ptr.ptr() };
     let (ptr, _) = (r as *mut T).to_raw_parts();
-    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table.regions).then_some(r)
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
 }
 
-fn validate_addr(addr: usize, len: usize, regions: &[Region]) -> bool {
+fn validate_addr(addr: usize, len: usize, regions: &RegionTable) -> bool {
     let end = addr + len - 1;
     regions.iter().any(|r| {
         r.range.contains(&addr) && r.range.contains(&end) && r.attr.contains(RegionAttr::Read)
     })
 }
 
-fn apply_region_table(table: &RegionTable) {
+/// Programs the MPU with `table`, disabling the regions it doesn't use
+#[cfg(not(feature = "cortex_m7m"))]
+fn apply_region_table<const N: usize>(table: &RegionTable<N>) {
     const DISABLE: u32 = 0b000;
     const PRIVDEFENA: u32 = 0b100;
     // Safety: We only call this function from syscall and systick handlers, which don't preempt the kernel
@@ -368,10 +595,11 @@ fn apply_region_table(table: &RegionTable) {
         mpu.ctrl.write(DISABLE | PRIVDEFENA);
     }
 
-    for (i, region) in table.regions.iter().enumerate() {
+    for (i, region) in table.iter().enumerate() {
         apply_region(i, region, mpu);
     }
-    for i in table.regions.len()..8 {
+    // a task's table always has MAX_REGIONS entries, so a smaller table still clears everything the last one set
+    for i in table.len()..N.max(MAX_REGIONS) {
         clear_region(i, mpu);
     }
 
@@ -394,6 +622,7 @@ fn apply_region_table(table: &RegionTable) {
     }
 }
 
+#[cfg(not(feature = "cortex_m7m"))]
 fn apply_region(i: usize, region: &Region, mpu: &cortex_m::peripheral::mpu::RegisterBlock) {
     let ap = if region.attr.contains(RegionAttr::Write) {
         0b01
@@ -417,8 +646,14 @@ fn apply_region(i: usize, region: &Region, mpu: &cortex_m::peripheral::mpu::Regi
     } else {
         let rw = u32::from(region.attr.contains(RegionAttr::Read)) << 1
             | u32::from(region.attr.contains(RegionAttr::Write));
-        // write-back transient, not shared
-        (0b0100_0100 | rw | rw << 4, 0b00)
+        match CachePolicy::from_attr(region.attr) {
+            // write-back transient, not shared
+            CachePolicy::WriteBack => (0b0100_0100 | rw | rw << 4, 0b00),
+            // write-through non-transient, not shared
+            CachePolicy::WriteThrough => (0b1000_1000 | rw | rw << 4, 0b00),
+            // normal memory, inner and outer non-cacheable
+            CachePolicy::Uncached => (0b0100_0100, 0b00),
+        }
     };
 
     // start of memory region
@@ -434,15 +669,18 @@ fn apply_region(i: usize, region: &Region, mpu: &cortex_m::peripheral::mpu::Regi
     let rnr = i as u32;
     // Safety: this just writes the region register, no memory safety impact
     unsafe { mpu.rnr.write(rnr) };
+    // baseline parts only have 4 regions, so they never touch mair1
     if rnr < 4 {
         let mut mair0 = mpu.mair[0].read();
+        mair0 &= !(0xFF << (rnr * 8));
         mair0 |= (mair as u32) << (rnr * 8);
         // Safety: writes mair0, no memory safety impact
         unsafe { mpu.mair[0].write(mair0) };
     } else {
         let mut mair1 = mpu.mair[1].read();
+        mair1 &= !(0xFF << ((rnr - 4) * 8));
         mair1 |= (mair as u32) << ((rnr - 4) * 8);
-        // Safety: writes mair0, no memory safety impact
+        // Safety: writes mair1, no memory safety impact
         unsafe { mpu.mair[1].write(mair1) };
     }
     // Safety: write the start and end of the region
@@ -452,6 +690,7 @@ fn apply_region(i: usize, region: &Region, mpu: &cortex_m::peripheral::mpu::Regi
     }
 }
 
+#[cfg(not(feature = "cortex_m7m"))]
 fn clear_region(i: usize, mpu: &cortex_m::peripheral::mpu::RegisterBlock) {
     // Safety: writes the region num, no impact on memory safety
     unsafe { mpu.rnr.write(i as u32) };
@@ -478,6 +717,11 @@ unsafe fn init_log() {
     CHANNEL = Some(channels.up.0);
 }
 
+/// Resets the system by requesting a reset through the SCB's AIRCR register
+pub(crate) fn reboot() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
 pub fn log(bytes: &[u8]) {
     // Safety: the kernel is non-reentrant so we can't get multiple mutable copies of `CHANNEL`
     if let Some(ch) = unsafe { &mut CHANNEL } {
@@ -0,0 +1,150 @@
+//! MPU support for ARMv7-M parts (Cortex-M3/M4/M7)
+//!
+//! Everything else, from the context switch to the exception frame, is shared with [`super::cortex_m`].
+//! That includes saving the FPU registers, so for now only parts with an FPU, like the Cortex-M4F, are supported.
+//! ARMv7-M's MPU has no `RLAR`, instead each region is a power of two in size and aligned to it, described by a
+//! base address in `RBAR` and a size, attributes, and subregion disable bits in `RASR`.
+//!
+//! Regions of at least 256 bytes are split into 8 subregions, which can be disabled individually, so a region
+//! is programmed as the smallest aligned block containing it, with the subregions it doesn't touch disabled.
+//! Ranges that don't start and end on a subregion boundary can't be described, so [`RegionTable::push`] rejects
+//! them, see [`is_representable`]. The CLI lays tasks out so their own regions always can be.
+use crate::regions::{CachePolicy, Region, RegionAttr, RegionTable, MAX_REGIONS};
+
+/// The smallest region the ARMv7-M MPU supports
+const MIN_REGION_LEN: u64 = 32;
+/// Regions smaller than this can't be split into subregions
+const MIN_SUBREGION_REGION_LEN: u64 = 256;
+
+/// Programs the MPU with `table`, disabling the regions it doesn't use
+pub(crate) fn apply_region_table<const N: usize>(table: &RegionTable<N>) {
+    const DISABLE: u32 = 0b000;
+    const PRIVDEFENA: u32 = 0b100;
+    // Safety: We only call this function from syscall and systick handlers, which don't preempt the kernel
+    // So we know we are the only ones using the MPU
+    let mpu = unsafe { &*cortex_m::peripheral::MPU::PTR };
+    // Safety: this is all "safe", its just marked as unsafe because cortex_m's registers
+    // are always unsafe
+    unsafe {
+        // data memory barrier to force memory sync before this inst, required by the cortex-m manual
+        cortex_m::asm::dmb();
+        // disable MPU while we configure
+        mpu.ctrl.write(DISABLE | PRIVDEFENA);
+    }
+
+    // overlapping regions take the attributes of the highest numbered one, and rounding a region out to
+    // subregions can make it overlap a guard, so guards are always programmed last
+    let is_guard = |r: &&Region| r.attr.contains(RegionAttr::Guard);
+    let regions = table
+        .iter()
+        .filter(|r| !is_guard(r))
+        .chain(table.iter().filter(is_guard));
+    for (i, region) in regions.enumerate() {
+        apply_region(i, region, mpu);
+    }
+    // a task's table always has MAX_REGIONS entries, so a smaller table still clears everything the last one set
+    for i in table.len()..N.max(MAX_REGIONS) {
+        clear_region(i, mpu);
+    }
+
+    // Safety: this is all "safe", its just marked as unsafe because cortex_m's registers
+    // are always unsafe
+    unsafe {
+        const ENABLE: u32 = 0b001;
+        // re-enable mpu
+        mpu.ctrl.write(ENABLE | PRIVDEFENA);
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+fn apply_region(i: usize, region: &Region, mpu: &cortex_m::peripheral::mpu::RegisterBlock) {
+    // unprivileged access mirrors the ARMv8-M encoding, the kernel keeps read-write access to every region
+    let ap: u32 = if region.attr.contains(RegionAttr::Write) {
+        0b011
+    } else if region.attr.contains(RegionAttr::Read) {
+        0b110
+    } else {
+        0b001
+    };
+    // ARMv7-M has no MAIR, the memory type is encoded directly in the TEX, S, C, and B bits
+    let (tex, s, c, b): (u32, u32, u32, u32) = if region.attr.contains(RegionAttr::Device) {
+        // shareable device memory
+        (0b000, 1, 0, 1)
+    } else if region.attr.contains(RegionAttr::Dma) {
+        // DMA memory is shared and never cached
+        (0b001, 1, 0, 0)
+    } else {
+        match CachePolicy::from_attr(region.attr) {
+            // write-back, read and write allocate
+            CachePolicy::WriteBack => (0b001, 0, 1, 1),
+            // write-through, no write allocate
+            CachePolicy::WriteThrough => (0b000, 0, 1, 0),
+            CachePolicy::Uncached => (0b001, 0, 0, 0),
+        }
+    };
+    let (base, size_log2, srd) = region_layout(region);
+
+    let rasr = (!region.attr.contains(RegionAttr::Exec) as u32) << 28 // XN
+        | ap << 24
+        | tex << 19
+        | s << 18
+        | c << 17
+        | b << 16
+        | (srd as u32) << 8
+        | (size_log2 - 1) << 1 // the region is 2^(SIZE + 1) bytes
+        | 1; // enable
+
+    // Safety: this just writes the region register, no memory safety impact
+    unsafe { mpu.rnr.write(i as u32) };
+    // Safety: write the base and attributes of the region
+    unsafe {
+        mpu.rbar.write(base);
+        mpu.rasr.write(rasr);
+    }
+}
+
+/// Finds the smallest aligned power of two block containing `region`, returning its base address, its size
+/// as a power of two, and which of its subregions to disable
+fn region_layout(region: &Region) -> (u32, u32, u8) {
+    let start = region.range.start as u64;
+    let end = region.range.end as u64;
+    // the block has to be big enough that the first and last byte share everything above its size
+    let diff = start ^ (end - 1);
+    let len = (1u64 << (u64::BITS - diff.leading_zeros())).max(MIN_REGION_LEN);
+    let base = start & !(len - 1);
+    let mut srd = 0;
+    if len >= MIN_SUBREGION_REGION_LEN {
+        let sub_len = len / 8;
+        for i in 0..8 {
+            let sub_start = base + i * sub_len;
+            if sub_start + sub_len <= start || sub_start >= end {
+                srd |= 1 << i;
+            }
+        }
+    }
+    (base as u32, len.trailing_zeros(), srd)
+}
+
+/// Returns true if the block [`region_layout`] picks for `region`, less its disabled subregions, is exactly
+/// `region`
+pub(crate) fn is_representable(region: &Region) -> bool {
+    if region.range.is_empty() {
+        return false;
+    }
+    let (_, size_log2, _) = region_layout(region);
+    let len = 1u64 << size_log2;
+    let granule = if len >= MIN_SUBREGION_REGION_LEN {
+        len / 8
+    } else {
+        len
+    };
+    region.range.start as u64 % granule == 0 && region.range.end as u64 % granule == 0
+}
+
+fn clear_region(i: usize, mpu: &cortex_m::peripheral::mpu::RegisterBlock) {
+    // Safety: writes the region num, no impact on memory safety
+    unsafe { mpu.rnr.write(i as u32) };
+    // Safety: clears the particular region, no impact on memory safety
+    unsafe { mpu.rasr.write(0) };
+}
@@ -7,7 +7,7 @@ pub(crate) fn start_root_task(_task: &Task, _tcb: &Tcb) -> ! {
         std::thread::sleep(std::time::Duration::from_secs(100));
     }
 }
-pub(crate) fn init_tcb_stack(_task: &Task, _tcb: &mut Tcb) {}
+pub(crate) fn init_tcb_stack(_task: &mut Task, _tcb: &mut Tcb) {}
 
 pub(crate) fn init_kernel<'k, 't>(tasks: &'t [crate::TaskDesc]) -> &'k mut crate::Kernel {
     let _ = crate::Kernel::from_tasks(tasks).unwrap();
@@ -15,6 +15,16 @@ pub(crate) fn init_kernel<'k, 't>(tasks: &'t [crate::TaskDesc]) -> &'k mut crate
 }
 
 pub fn log(_bytes: &[u8]) {}
+
+pub(crate) fn mask_irq(_irq: u16) {}
+
+pub(crate) fn unmask_irq(_irq: u16) {}
+
+pub(crate) fn ack_irq(_irq: u16) {}
+
+pub(crate) fn reboot() -> ! {
+    unimplemented!()
+}
 #[derive(Default)]
 pub struct SavedThreadState {}
 
@@ -60,7 +70,7 @@ pub(crate) fn translate_mut_task_ptr<'a, T: std::ptr::Pointee + ?Sized>(
     }
 }
 
-pub(crate) fn clear_mem(_task: &Task) {}
+pub(crate) fn clear_mem(_task: &Task, _security: &crate::SecurityConfig) {}
 
 unsafe fn get_current_tcb() -> &mut Tcb {
     todo!()
@@ -1,7 +1,13 @@
 #[cfg(feature = "cortex_m")]
 pub mod cortex_m;
-#[cfg(feature = "std")]
+#[cfg(feature = "cortex_m7m")]
+pub mod cortex_m7m;
+#[cfg(all(feature = "std", not(feature = "qemu")))]
 pub mod dummy;
+#[cfg(feature = "qemu")]
+pub mod qemu;
+#[cfg(feature = "rv32")]
+pub mod rv32;
 #[cfg(feature = "rv64")]
 pub mod rv64;
 
@@ -10,12 +16,17 @@ use crate::{syscalls::CallReturn, KernelError};
 #[cfg(feature = "cortex_m")]
 pub use self::cortex_m::*;
 
+#[cfg(feature = "rv32")]
+pub use self::rv32::*;
+
 #[cfg(feature = "rv64")]
 pub use self::rv64::*;
 
-use abi::{SyscallIndex, SyscallReturn, SyscallReturnType};
-#[cfg(feature = "std")]
+use abi::{SyscallIndex, SyscallReturn};
+#[cfg(all(feature = "std", not(feature = "qemu")))]
 pub use dummy::*;
+#[cfg(feature = "qemu")]
+pub use self::qemu::*;
 
 pub(crate) fn syscall_inner(index: SyscallIndex) {
     // Safety: We are safe to access global state due to our interrupt model
@@ -28,14 +39,9 @@ pub(crate) fn syscall_inner(index: SyscallIndex) {
     let ret = match kernel.syscall(index, args) {
         Ok(ret) => ret,
         Err(KernelError::ABI(err)) => CallReturn::Return {
-            ret: SyscallReturn::new()
-                .with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Error)
-                .with(SyscallReturn::SYSCALL_LEN, u8::from(err) as u64),
+            ret: SyscallReturn::error(err),
         },
-        err => {
-            let _ = err.unwrap();
-            return;
-        }
+        Err(err) => defmt::panic!("syscall failed: {}", err),
     };
     match ret {
         CallReturn::Replace { next_thread } => switch_thread(kernel, next_thread),
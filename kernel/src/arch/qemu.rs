@@ -0,0 +1,342 @@
+//! A simulated target that runs the real scheduler on the host, for integration tests
+//!
+//! Each kernel thread runs on its own `std::thread`, but like a single core only one of them runs at a time: the
+//! others park on a [`Condvar`] until the scheduler switches to them. Tasks make syscalls with [`syscall`], and a
+//! timer thread ticks the scheduler every [`TICK`]. A host thread can't be interrupted, so when the timer preempts a
+//! thread it keeps running until its next syscall, where it waits to be switched back to.
+//!
+//! Task memory is simulated too. [`alloc_region`] hands out ranges of made-up addresses backed by host buffers, and
+//! task pointers are validated against both the task's regions and those buffers before they are translated, so a
+//! task that passes a bad pointer gets an error just like on hardware. Tasks read and write their own memory with
+//! [`read_mem`] and [`write_mem`], and [`entrypoint`] gives each task function an address in that memory. Addresses
+//! the kernel hands back to tasks, like the address of a loaned page, are host addresses rather than simulated ones.
+//!
+//! Nothing here is specific to QEMU, it runs anywhere with `std`, including under `qemu-user`. The kernel is a global,
+//! so only one can be started per process. The integration tests run with
+//! `cargo test --no-default-features --features qemu qemu`.
+use abi::{SyscallArgs, SyscallIndex, SyscallReturn, ThreadRef};
+use core::mem::{self, MaybeUninit};
+use core::ops::Range;
+use core::ptr;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use crate::regions::RegionTable;
+use crate::security::SecurityConfig;
+use crate::task::Task;
+use crate::task_ptr::{TaskPtr, TaskPtrMut};
+use crate::tcb::Tcb;
+use crate::{Kernel, RegionAttr};
+
+/// How often the timer thread ticks the scheduler
+pub const TICK: Duration = Duration::from_millis(10);
+/// The address the first simulated region starts at
+const REGION_BASE: usize = 0x1000_0000;
+/// Simulated regions are aligned to this, and separated by at least this much unbacked space
+const REGION_ALIGN: usize = 0x1000;
+
+static KERNEL_INIT: AtomicBool = AtomicBool::new(false);
+static mut KERNEL: MaybeUninit<Kernel> = MaybeUninit::uninit();
+static CURRENT_TCB: AtomicPtr<Tcb> = AtomicPtr::new(ptr::null_mut());
+
+/// Held while running kernel code, which is what makes the kernel single threaded
+static CPU: Mutex<()> = Mutex::new(());
+/// Notified whenever the kernel may have switched threads
+static SWITCHED: Condvar = Condvar::new();
+/// The ID of the host thread the scheduler last switched to, see [`SavedThreadState`]
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+static NEXT_REGION: AtomicUsize = AtomicUsize::new(REGION_BASE);
+
+thread_local! {
+    static THREAD_ID: Cell<usize> = Cell::new(0);
+    /// Stands in for the thread pointer register, which holds the base of the thread's TLS
+    static TP: Cell<usize> = Cell::new(0);
+}
+
+/// The host buffers backing task memory, keyed by the simulated address they start at
+///
+/// Buffers are never freed or resized, so pointers into them stay valid for the life of the process.
+fn memory() -> &'static Mutex<HashMap<usize, Vec<u8>>> {
+    static MEMORY: OnceLock<Mutex<HashMap<usize, Vec<u8>>>> = OnceLock::new();
+    MEMORY.get_or_init(Default::default)
+}
+
+/// The task functions registered with [`entrypoint`]
+///
+/// They are keyed by the host address of their backing memory, since that's what the kernel stores in a TCB once
+/// it has translated the entrypoint.
+fn entrypoints() -> &'static Mutex<HashMap<usize, fn() -> !>> {
+    static ENTRYPOINTS: OnceLock<Mutex<HashMap<usize, fn() -> !>>> = OnceLock::new();
+    ENTRYPOINTS.get_or_init(Default::default)
+}
+
+/// Allocates `len` bytes of zeroed task memory, and returns its simulated address range
+///
+/// Add the range to a [`crate::TaskDesc`]'s regions to give a task access to it.
+pub fn alloc_region(len: usize) -> Range<usize> {
+    let stride = (len + REGION_ALIGN).next_multiple_of(REGION_ALIGN);
+    let start = NEXT_REGION.fetch_add(stride, Ordering::Relaxed);
+    memory().lock().unwrap().insert(start, vec![0; len]);
+    start..start + len
+}
+
+/// Gives `f` a simulated address, so it can be used as a task or thread entrypoint
+///
+/// The address is the start of the returned range, a small region of its own which has to be added to the task's
+/// regions like any other.
+pub fn entrypoint(f: fn() -> !) -> Range<usize> {
+    let range = alloc_region(mem::size_of::<usize>());
+    let host = backing_ptr(range.start, range.len()).unwrap();
+    entrypoints().lock().unwrap().insert(host.addr(), f);
+    range
+}
+
+/// Copies task memory starting at `addr` into `buf`
+///
+/// # Panics
+/// If any of the range isn't backed by a region from [`alloc_region`]
+pub fn read_mem(addr: usize, buf: &mut [u8]) {
+    let host = backing_ptr(addr, buf.len()).expect("read from unbacked task memory");
+    // Safety: `backing_ptr` checked the range is within a single region buffer
+    unsafe { ptr::copy_nonoverlapping(host, buf.as_mut_ptr(), buf.len()) }
+}
+
+/// Copies `data` into task memory starting at `addr`
+///
+/// # Panics
+/// If any of the range isn't backed by a region from [`alloc_region`]
+pub fn write_mem(addr: usize, data: &[u8]) {
+    let host = backing_ptr(addr, data.len()).expect("write to unbacked task memory");
+    // Safety: `backing_ptr` checked the range is within a single region buffer
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), host, data.len()) }
+}
+
+/// Finds the host address of `len` bytes of task memory at `addr`, if they are all in one region buffer
+fn backing_ptr(addr: usize, len: usize) -> Option<*mut u8> {
+    let end = addr.checked_add(len)?;
+    let mut memory = memory().lock().unwrap();
+    let (base, buf) = memory
+        .iter_mut()
+        .find(|(base, buf)| **base <= addr && end <= **base + buf.len())?;
+    // Safety: the offset is within the buffer, which was just checked
+    Some(unsafe { buf.as_mut_ptr().add(addr - *base) })
+}
+
+/// Makes a syscall from the calling task thread, blocking until the kernel switches back to it
+///
+/// # Panics
+/// If called from a thread that wasn't spawned by the kernel
+pub fn syscall(index: SyscallIndex, args: &mut SyscallArgs) -> SyscallReturn {
+    let id = THREAD_ID.with(Cell::get);
+    assert_ne!(id, 0, "syscalls can only be made from k5 threads");
+    let cpu = wait_for_turn(CPU.lock().unwrap(), id);
+    // Safety: we hold `CPU`, and the current TCB is this thread's, so nothing else is using it
+    let tcb = unsafe { get_current_tcb() };
+    tcb.saved_state.args = SyscallArgs { ..*args };
+    super::syscall_inner(index);
+    SWITCHED.notify_all();
+    let _cpu = wait_for_turn(cpu, id);
+    // Safety: as above, we have been switched back to
+    let tcb = unsafe { get_current_tcb() };
+    SyscallReturn::from_bits(tcb.saved_state.ret)
+}
+
+/// Returns the base of the calling thread's thread-local storage, like reading `tp` on RISC-V
+///
+/// The address is simulated, read and write the storage with [`read_mem`] and [`write_mem`].
+pub fn thread_local_ptr() -> usize {
+    TP.with(Cell::get)
+}
+
+/// Waits until the scheduler has switched to the thread with `id`
+fn wait_for_turn(cpu: MutexGuard<'static, ()>, id: usize) -> MutexGuard<'static, ()> {
+    SWITCHED
+        .wait_while(cpu, |_| RUNNING.load(Ordering::SeqCst) != id)
+        .unwrap()
+}
+
+pub(crate) fn start_root_task(_task: &Task, tcb: &Tcb) -> ! {
+    {
+        let _cpu = CPU.lock().unwrap();
+        // Safety: the TCB is stored in `KERNEL`, so its location is stable
+        unsafe { set_current_tcb(tcb) };
+        SWITCHED.notify_all();
+    }
+    std::thread::Builder::new()
+        .name("k5 timer".into())
+        .spawn(|| loop {
+            std::thread::sleep(TICK);
+            let _cpu = CPU.lock().unwrap();
+            systick_inner();
+            SWITCHED.notify_all();
+        })
+        .expect("failed to spawn timer thread");
+    loop {
+        std::thread::park();
+    }
+}
+
+fn systick_inner() {
+    // Safety: we hold `CPU`, so nothing else is using the kernel
+    let kernel = unsafe { &mut *kernel() };
+    if let Some(tcb_ref) = kernel.scheduler.tick().unwrap() {
+        let tcb = kernel.scheduler.get_tcb(tcb_ref).unwrap();
+        // Safety: The TCB comes from the kernel which is stored statically so this is safe
+        unsafe { set_current_tcb(tcb) }
+    }
+}
+
+/// Spawns the host thread the TCB runs on, which waits until the scheduler first switches to it
+pub(crate) fn init_tcb_stack(_task: &mut Task, tcb: &mut Tcb) {
+    let entry = *entrypoints()
+        .lock()
+        .unwrap()
+        .get(&tcb.entrypoint)
+        .expect("entrypoint wasn't registered with `qemu::entrypoint`");
+    let id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    tcb.saved_state.id = id;
+    tcb.saved_state.tp = tcb.tls_base;
+    std::thread::Builder::new()
+        .name(format!("k5 thread {}", id))
+        .spawn(move || {
+            THREAD_ID.with(|t| t.set(id));
+            let cpu = wait_for_turn(CPU.lock().unwrap(), id);
+            // Safety: we hold `CPU`, and the scheduler has switched to this thread
+            let tp = unsafe { get_current_tcb() }.saved_state.tp;
+            TP.with(|t| t.set(tp));
+            drop(cpu);
+            entry()
+        })
+        .expect("failed to spawn thread");
+}
+
+pub(crate) fn init_kernel<'k, 't>(tasks: &'t [crate::TaskDesc]) -> &'k mut crate::Kernel {
+    if KERNEL_INIT.swap(true, Ordering::SeqCst) {
+        panic!("kernel already inited");
+    }
+    // Safety: `KERNEL_INIT` guarantees this only happens once, and no task threads exist yet
+    unsafe { KERNEL.write(Kernel::from_tasks(tasks).unwrap()) }
+}
+
+#[inline]
+pub(crate) unsafe fn kernel() -> *mut Kernel {
+    KERNEL.as_mut_ptr()
+}
+
+pub fn log(_bytes: &[u8]) {}
+
+// interrupts aren't simulated
+pub(crate) fn mask_irq(_irq: u16) {}
+
+pub(crate) fn unmask_irq(_irq: u16) {}
+
+pub(crate) fn ack_irq(_irq: u16) {}
+
+pub(crate) fn reboot() -> ! {
+    panic!("the simulated target can't reboot")
+}
+
+#[derive(Default)]
+pub struct SavedThreadState {
+    /// Identifies the host thread running the TCB, `0` until it is spawned
+    id: usize,
+    /// The thread's TLS base, loaded into [`thread_local_ptr`] when the thread first runs
+    tp: usize,
+    args: SyscallArgs,
+    ret: u64,
+}
+
+impl SavedThreadState {
+    pub(super) fn syscall_args(&self) -> &SyscallArgs {
+        &self.args
+    }
+
+    pub fn set_syscall_return(&mut self, ret: abi::SyscallReturn) {
+        self.ret = ret.into();
+    }
+}
+
+pub(crate) fn translate_task_ptr<'a, T: ptr::Pointee + ?Sized>(
+    task_ptr: TaskPtr<'a, T>,
+    task: &Task,
+) -> Option<&'a T> {
+    // Safety: the pointer holds a simulated address, it is only dereferenced once it has been translated
+    let r = unsafe { task_ptr.ptr() };
+    let (ptr, metadata) = (r as *const T).to_raw_parts();
+    let len = mem::size_of_val(r);
+    let host = translate(
+        ptr.addr(),
+        len,
+        mem::align_of_val(r),
+        &task.region_table,
+        false,
+    )?;
+    // Safety: `translate` checked that the whole value is in a region buffer, and is aligned
+    Some(unsafe { &*ptr::from_raw_parts(host as *const (), metadata) })
+}
+
+pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
+    task_ptr: TaskPtrMut<'a, T>,
+    task: &Task,
+) -> Option<&'a mut T> {
+    // Safety: the pointer holds a simulated address, it is only dereferenced once it has been translated
+    let r = unsafe { task_ptr.ptr() };
+    let (ptr, metadata) = (r as *mut T).to_raw_parts();
+    let len = mem::size_of_val(r);
+    let host = translate(
+        ptr.addr(),
+        len,
+        mem::align_of_val(r),
+        &task.region_table,
+        true,
+    )?;
+    // Safety: `translate` checked that the whole value is in a region buffer, and is aligned
+    Some(unsafe { &mut *ptr::from_raw_parts_mut(host as *mut (), metadata) })
+}
+
+/// Checks `len` bytes at `addr` are in one of the task's regions and backed by host memory, and returns their host address
+fn translate(
+    addr: usize,
+    len: usize,
+    align: usize,
+    regions: &RegionTable,
+    write: bool,
+) -> Option<*mut u8> {
+    let end = addr.checked_add(len)?;
+    let allowed = regions.iter().any(|r| {
+        r.range.start <= addr
+            && end <= r.range.end
+            && r.attr.contains(RegionAttr::Read)
+            && (!write || r.attr.contains(RegionAttr::Write))
+    });
+    if !allowed {
+        return None;
+    }
+    let host = backing_ptr(addr, len)?;
+    (host.addr() % align == 0).then_some(host)
+}
+
+pub(crate) fn clear_mem(task: &Task, security: &SecurityConfig) {
+    let seed = NEXT_THREAD_ID.load(Ordering::Relaxed) as u32;
+    crate::security::clear_task_mem(task, security, seed);
+}
+
+pub(crate) unsafe fn set_current_tcb(tcb: &Tcb) {
+    CURRENT_TCB.store(tcb as *const Tcb as *mut Tcb, Ordering::SeqCst);
+    RUNNING.store(tcb.saved_state.id, Ordering::SeqCst);
+}
+
+pub(super) unsafe fn get_current_tcb() -> &'static mut Tcb {
+    &mut *CURRENT_TCB.load(Ordering::SeqCst)
+}
+
+#[inline]
+pub(crate) fn switch_thread(kernel: &mut Kernel, tcb_ref: ThreadRef) {
+    let tcb = kernel.scheduler.get_tcb(tcb_ref).unwrap();
+    // Safety: The TCB comes from the kernel which is stored statically so this is safe
+    unsafe { set_current_tcb(tcb) }
+}
@@ -0,0 +1,405 @@
+use abi::{SyscallArgs, SyscallIndex, ThreadRef};
+use core::arch::asm;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::mcause::{Exception, Interrupt, Trap};
+use riscv::register::mstatus::MPP;
+
+use crate::regions::{RegionTable, MAX_REGIONS};
+use crate::security::SecurityConfig;
+pub(crate) use crate::task::Task;
+use crate::task_ptr::{TaskPtr, TaskPtrMut};
+use crate::tcb::Tcb;
+use crate::{Kernel, RegionAttr};
+
+/// The `mstatus.MPP` bits, which hold the privilege mode `mret` returns to. All zeros is user mode
+const MSTATUS_MPP_MASK: usize = 0b11 << 11;
+
+/// Each region is matched by a pair of PMP entries, the first holds its start and the second its end
+const PMP_ENTRIES: usize = MAX_REGIONS * 2;
+/// `pmpcfg` address matching mode, where an entry covers everything from the previous entry's address up to its own
+const PMP_TOR: u8 = 0b01 << 3;
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_X: u8 = 1 << 2;
+
+static mut KERNEL_INIT: AtomicBool = AtomicBool::new(false);
+static mut KERNEL: MaybeUninit<Kernel> = MaybeUninit::uninit();
+
+pub(crate) fn start_root_task(task: &Task, tcb: &Tcb) -> ! {
+    apply_region_table(&task.region_table);
+    unsafe {
+        set_current_tcb(tcb);
+    }
+
+    riscv::register::mepc::write(tcb.entrypoint);
+    unsafe { riscv::register::mstatus::set_mpp(MPP::User) };
+    unsafe {
+        asm!(
+            "
+            lw tp, ({tls})
+            csrrw a0, mscratch, a0
+            sw sp,  32*4(a0)
+            csrrw a0, mscratch, a0
+            lw sp, ({sp})
+            mret
+            ",
+            // the root thread doesn't start through the trap epilogue, so its `tp` has to be loaded here
+            tls = in(reg) &tcb.tls_base,
+            sp = in(reg) &tcb.stack_pointer,
+            options(noreturn)
+        )
+    };
+}
+
+pub(crate) fn init_tcb_stack(_task: &mut Task, tcb: &mut Tcb) {
+    tcb.saved_state.sp = tcb.stack_pointer as u32;
+    tcb.saved_state.tp = tcb.tls_base as u32;
+    tcb.saved_state.pc = tcb.entrypoint as u32;
+}
+
+pub(crate) fn init_kernel<'k, 't>(tasks: &'t [crate::TaskDesc]) -> &'k mut crate::Kernel {
+    log(b"LOG_START");
+    unsafe {
+        if KERNEL_INIT.load(Ordering::SeqCst) {
+            panic!("kernel already inited");
+        }
+        let kern = KERNEL.write(Kernel::from_tasks(tasks).unwrap());
+        KERNEL_INIT.store(true, Ordering::SeqCst);
+        kern
+    }
+}
+
+#[inline]
+pub(crate) unsafe fn kernel() -> *mut Kernel {
+    KERNEL.as_mut_ptr()
+}
+
+// TODO: external interrupts are routed through the platform's interrupt controller, which k5 doesn't manage yet
+pub(crate) fn mask_irq(_irq: u16) {}
+
+pub(crate) fn unmask_irq(_irq: u16) {}
+
+pub(crate) fn ack_irq(_irq: u16) {}
+
+/// Restarts the system by jumping back to the reset entrypoint with interrupts disabled
+///
+/// There is no standard way to reset a RISC-V core, so this is a soft reset, peripherals are left in their current state
+pub(crate) fn reboot() -> ! {
+    extern "C" {
+        fn _start() -> !;
+    }
+    // Safety: `_start` is the reset entrypoint provided by riscv-rt, which re-initializes the stack, `.bss`, and `.data`
+    // before entering the kernel again. We disable interrupts first so no trap can fire while that happens
+    unsafe {
+        asm!(
+            "
+            csrw mie, zero
+            csrci mstatus, 0x8
+            fence.i
+            jr {start}
+            ",
+            start = in(reg) _start as usize,
+            options(noreturn)
+        )
+    }
+}
+
+pub fn log(bytes: &[u8]) {
+    extern "Rust" {
+        fn _log_impl(bytes: &[u8]);
+    }
+    unsafe { _log_impl(bytes) };
+}
+
+#[derive(Default)]
+#[repr(C)]
+pub struct SavedThreadState {
+    ra: u32,
+    sp: u32,
+    gp: u32,
+    tp: u32,
+    t0: u32,
+    t1: u32,
+    t2: u32,
+    s0: u32,
+    s1: u32,
+    a0: u32,
+    a1: u32,
+    a2: u32,
+    a3: u32,
+    a4: u32,
+    a5: u32,
+    a6: u32,
+    a7: u32,
+    s2: u32,
+    s3: u32,
+    s4: u32,
+    s5: u32,
+    s6: u32,
+    s7: u32,
+    s8: u32,
+    s9: u32,
+    s10: u32,
+    s11: u32,
+    t3: u32,
+    t4: u32,
+    t5: u32,
+    t6: u32,
+    pc: u32,
+    // Extra register to contain the last machine-mode stack pointer
+    mpc: u32,
+}
+
+impl SavedThreadState {
+    pub(super) fn syscall_args(&self) -> &SyscallArgs {
+        // Safety: repr(c) guarentees the order of fields, we are taking the first
+        // 6 fields as SyscallArgs
+        unsafe { mem::transmute(&self.a2) }
+    }
+
+    pub fn syscall_args_mut(&mut self) -> &mut SyscallArgs {
+        // Safety: repr(c) guarentees the order of fields, we are taking the first
+        // 6 fields as SyscallArgs
+        unsafe { mem::transmute(&mut self.a2) }
+    }
+
+    pub fn set_syscall_return(&mut self, ret: abi::SyscallReturn) {
+        // 64 bit return values are split across a0 and a1, low half first
+        let (high, low) = ret.split();
+        self.a0 = low;
+        self.a1 = high;
+    }
+}
+
+pub(crate) fn translate_task_ptr<'a, T: ptr::Pointee + ?Sized>(
+    task_ptr: TaskPtr<'a, T>,
+    task: &Task,
+) -> Option<&'a T> {
+    // Safety: We only use return this reference when validated, so this is safe
+    let r = unsafe { task_ptr.ptr() };
+    let (ptr, _) = (r as *const T).to_raw_parts();
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
+}
+
+pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
+    task_ptr: TaskPtrMut<'a, T>,
+    task: &Task,
+) -> Option<&'a mut T> {
+    // Safety: We only use return this reference when validated, so this is safe
+    let r = unsafe { task_ptr.ptr() };
+    let (ptr, _) = (r as *mut T).to_raw_parts();
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
+}
+
+fn validate_addr(addr: usize, len: usize, regions: &RegionTable) -> bool {
+    let end = addr + len - 1;
+    regions.iter().any(|r| {
+        r.range.contains(&addr) && r.range.contains(&end) && r.attr.contains(RegionAttr::Read)
+    })
+}
+
+/// Writes `$value` to the PMP CSR named `$csr` followed by `$i`, which has to be one of the listed indices
+///
+/// CSR numbers are encoded in the instruction, so each index needs its own `csrw`
+macro_rules! write_pmp_csr {
+    ($csr:literal, $i:expr, $value:expr, [$($n:literal),*]) => {
+        match $i {
+            $($n => asm!(concat!("csrw ", $csr, $n, ", {}"), in(reg) $value),)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Programs the PMP with the task's regions, so user mode can only access memory it has been given
+///
+/// Every region is matched top-of-range, using two entries. Machine mode ignores entries that aren't locked,
+/// so the kernel keeps access to all memory.
+fn apply_region_table(table: &RegionTable) {
+    let mut addrs = [0usize; PMP_ENTRIES];
+    let mut cfgs = [0u8; PMP_ENTRIES];
+    for (i, region) in table.iter().enumerate() {
+        let attr = region.attr;
+        let mut cfg = PMP_TOR;
+        // guards are left with no permissions, so user mode can't touch them
+        if !attr.contains(RegionAttr::Guard) {
+            for (flag, bit) in [
+                (RegionAttr::Read, PMP_R),
+                (RegionAttr::Write, PMP_W),
+                (RegionAttr::Exec, PMP_X),
+            ] {
+                if attr.contains(flag) {
+                    cfg |= bit;
+                }
+            }
+        }
+        // pmpaddr holds bits 33:2 of the address
+        addrs[2 * i] = region.range.start >> 2;
+        addrs[2 * i + 1] = region.range.end >> 2;
+        cfgs[2 * i + 1] = cfg;
+    }
+    // Safety: only unlocked entries are written, which don't apply to machine mode, so this can't fault the kernel
+    unsafe {
+        // turn every entry off first, so a half written table never grants more than either the old or new one
+        for i in 0..PMP_ENTRIES / 4 {
+            write_pmp_csr!("pmpcfg", i, 0usize, [0, 1, 2, 3]);
+        }
+        for (i, addr) in addrs.into_iter().enumerate() {
+            write_pmp_csr!(
+                "pmpaddr",
+                i,
+                addr,
+                [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+            );
+        }
+        for (i, cfg) in cfgs.chunks(4).enumerate() {
+            let cfg = u32::from_le_bytes([cfg[0], cfg[1], cfg[2], cfg[3]]) as usize;
+            write_pmp_csr!("pmpcfg", i, cfg, [0, 1, 2, 3]);
+        }
+    }
+}
+
+pub(crate) fn clear_mem(task: &Task, security: &SecurityConfig) {
+    let seed = riscv::register::mcycle::read() as u32;
+    crate::security::clear_task_mem(task, security, seed);
+}
+
+pub(crate) unsafe fn set_current_tcb(task: &Tcb) {
+    riscv::register::mscratch::write((task as *const Tcb).addr())
+}
+
+pub(super) unsafe fn get_current_tcb() -> &'static mut Tcb {
+    &mut *(riscv::register::mscratch::read() as *mut Tcb)
+}
+
+unsafe fn trap_handler(index: SyscallIndex) {
+    let cause = riscv::register::mcause::read();
+    match cause.cause() {
+        Trap::Interrupt(Interrupt::MachineExternal) => {}
+        Trap::Exception(Exception::UserEnvCall) => {
+            let tcb = get_current_tcb();
+            tcb.saved_state.pc += 4;
+            super::syscall_inner(index);
+        }
+        _ => {}
+    }
+}
+
+#[inline]
+pub(crate) fn switch_thread(kernel: &mut Kernel, tcb_ref: ThreadRef) {
+    let current_tcb = unsafe { get_current_tcb() };
+    let tcb = kernel.scheduler.get_tcb_mut(tcb_ref).unwrap();
+    tcb.saved_state.mpc = current_tcb.saved_state.mpc;
+    // Safety: The TCB comes from the kernel which is stored statically so this is safe
+    unsafe { set_current_tcb(tcb) }
+    let task_ref = tcb.task;
+    let task = kernel.task(task_ref).unwrap();
+    apply_region_table(&task.region_table);
+}
+
+#[no_mangle]
+#[export_name = "_start_trap"]
+#[naked]
+unsafe extern "C" fn _start_trap() -> ! {
+    asm!(
+        "
+         .align 4
+         # we store the current task pointer in mscratch
+         # so we swap it into a0, and then save all the pointers to saved state
+         csrrw a0, mscratch, a0
+         sw ra,   0*4(a0)
+         sw sp,   1*4(a0)
+         sw gp,   2*4(a0)
+         sw tp,   3*4(a0)
+         sw t0,   4*4(a0)
+         sw t1,   5*4(a0)
+         sw t2,   6*4(a0)
+         sw s0,   7*4(a0)
+         sw s1,   8*4(a0)
+         # sw a0,  9*4(a0) # skipping a0 because we are using it to store current TCB
+         sw a1,  10*4(a0)
+         sw a2,  11*4(a0)
+         sw a3,  12*4(a0)
+         sw a4,  13*4(a0)
+         sw a5,  14*4(a0)
+         sw a6,  15*4(a0)
+         sw a7,  16*4(a0)
+         sw s2,  17*4(a0)
+         sw s3,  18*4(a0)
+         sw s4,  19*4(a0)
+         sw s5,  20*4(a0)
+         sw s6,  21*4(a0)
+         sw s7,  22*4(a0)
+         sw s8,  23*4(a0)
+         sw s9,  24*4(a0)
+         sw s10, 25*4(a0)
+         sw s11, 26*4(a0)
+         sw t3,  27*4(a0)
+         sw t4,  28*4(a0)
+         sw t5,  29*4(a0)
+         sw t6,  30*4(a0)
+
+         csrr a1, mepc # store task pc
+         sw a1,  31*4(a0)
+
+         csrr a1, mscratch
+         sw a1, 9*4(a0) # store a0 now that
+
+         lw sp, 32*4(a0) # load old machine stack pointer
+         csrrw a0, mscratch, a0
+
+
+         jal {trap_handler}
+
+         # the handler may have switched threads, so mscratch holds the TCB we are returning to
+         csrr t6, mscratch
+         lw t5,  31*4(t6)     # restore mepc
+         csrw mepc, t5
+         # clearing MPP means mret always drops to user mode, whatever mode the trap came from
+         li t5, {mpp_mask}
+         csrc mstatus, t5
+
+         lw ra,   0*4(t6)
+         lw gp,   2*4(t6)
+         lw tp,   3*4(t6)
+         lw t0,   4*4(t6)
+         lw t1,   5*4(t6)
+         lw t2,   6*4(t6)
+         lw s0,   7*4(t6)
+         lw s1,   8*4(t6)
+         lw a0,   9*4(t6)
+         lw a1,  10*4(t6)
+         lw a2,  11*4(t6)
+         lw a3,  12*4(t6)
+         lw a4,  13*4(t6)
+         lw a5,  14*4(t6)
+         lw a6,  15*4(t6)
+         lw a7,  16*4(t6)
+         lw s2,  17*4(t6)
+         lw s3,  18*4(t6)
+         lw s4,  19*4(t6)
+         lw s5,  20*4(t6)
+         lw s6,  21*4(t6)
+         lw s7,  22*4(t6)
+         lw s8,  23*4(t6)
+         lw s9,  24*4(t6)
+         lw s10, 25*4(t6)
+         lw s11, 26*4(t6)
+         lw t3,  27*4(t6)
+         lw t4,  28*4(t6)
+         lw t5,  29*4(t6)
+         sw sp,  32*4(t6)
+         lw sp,   1*4(t6)
+
+         # t6 holds the TCB pointer, so it is restored last
+         lw t6,  30*4(t6)
+
+         mret
+
+     ",
+     trap_handler = sym trap_handler,
+     mpp_mask = const MSTATUS_MPP_MASK,
+     options(noreturn)
+    )
+}
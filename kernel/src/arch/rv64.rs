@@ -6,12 +6,16 @@ use core::sync::atomic::{AtomicBool, Ordering};
 use riscv::register::mcause::{Exception, Interrupt, Trap};
 use riscv::register::mstatus::MPP;
 
-use crate::regions::Region;
+use crate::regions::RegionTable;
+use crate::security::SecurityConfig;
 pub(crate) use crate::task::Task;
 use crate::task_ptr::{TaskPtr, TaskPtrMut};
 use crate::tcb::Tcb;
 use crate::{Kernel, RegionAttr};
 
+/// The `mstatus.MPP` bits, which hold the privilege mode `mret` returns to. All zeros is user mode
+const MSTATUS_MPP_MASK: usize = 0b11 << 11;
+
 static mut KERNEL_INIT: AtomicBool = AtomicBool::new(false);
 static mut KERNEL: MaybeUninit<Kernel> = MaybeUninit::uninit();
 
@@ -25,20 +29,24 @@ pub(crate) fn start_root_task(_task: &Task, tcb: &Tcb) -> ! {
     unsafe {
         asm!(
             "
+            ld tp, ({tls})
             csrrw a0, mscratch, a0
             sd sp,  32*8(a0)
             csrrw a0, mscratch, a0
             ld sp, ({sp})
             mret
             ",
+            // the root thread doesn't start through the trap epilogue, so its `tp` has to be loaded here
+            tls = in(reg) &tcb.tls_base,
             sp = in(reg) &tcb.stack_pointer,
             options(noreturn)
         )
     };
 }
 
-pub(crate) fn init_tcb_stack(_task: &Task, tcb: &mut Tcb) {
+pub(crate) fn init_tcb_stack(_task: &mut Task, tcb: &mut Tcb) {
     tcb.saved_state.sp = tcb.stack_pointer as u64;
+    tcb.saved_state.tp = tcb.tls_base as u64;
     tcb.saved_state.pc = tcb.entrypoint as u64;
 }
 
@@ -59,6 +67,36 @@ pub(crate) unsafe fn kernel() -> *mut Kernel {
     KERNEL.as_mut_ptr()
 }
 
+// TODO: external interrupts are routed through the platform's PLIC, which k5 doesn't manage yet
+pub(crate) fn mask_irq(_irq: u16) {}
+
+pub(crate) fn unmask_irq(_irq: u16) {}
+
+pub(crate) fn ack_irq(_irq: u16) {}
+
+/// Restarts the system by jumping back to the reset entrypoint with interrupts disabled
+///
+/// There is no standard way to reset a RISC-V core, so this is a soft reset, peripherals are left in their current state
+pub(crate) fn reboot() -> ! {
+    extern "C" {
+        fn _start() -> !;
+    }
+    // Safety: `_start` is the reset entrypoint provided by riscv-rt, which re-initializes the stack, `.bss`, and `.data`
+    // before entering the kernel again. We disable interrupts first so no trap can fire while that happens
+    unsafe {
+        asm!(
+            "
+            csrw mie, zero
+            csrci mstatus, 0x8
+            fence.i
+            jr {start}
+            ",
+            start = in(reg) _start as usize,
+            options(noreturn)
+        )
+    }
+}
+
 pub fn log(bytes: &[u8]) {
     extern "Rust" {
         fn _log_impl(bytes: &[u8]);
@@ -130,7 +168,7 @@ pub(crate) fn translate_task_ptr<'a, T: ptr::Pointee + ?Sized>(
     // Safety: We only use return this reference when validated, so this is safe
     let r = unsafe { task_ptr.ptr() };
     let (ptr, _) = (r as *const T).to_raw_parts();
-    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table.regions).then_some(r)
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
 }
 
 pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
@@ -140,17 +178,20 @@ pub(crate) fn translate_mut_task_ptr<'a, T: ptr::Pointee + ?Sized>(
     // Safety: We only use return this reference when validated, so this is safe
     let r = unsafe { task_ptr.ptr() };
     let (ptr, _) = (r as *mut T).to_raw_parts();
-    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table.regions).then_some(r)
+    validate_addr(ptr.addr(), mem::size_of_val(r), &task.region_table).then_some(r)
 }
 
-fn validate_addr(addr: usize, len: usize, regions: &[Region]) -> bool {
+fn validate_addr(addr: usize, len: usize, regions: &RegionTable) -> bool {
     let end = addr + len - 1;
     regions.iter().any(|r| {
         r.range.contains(&addr) && r.range.contains(&end) && r.attr.contains(RegionAttr::Read)
     })
 }
 
-pub(crate) fn clear_mem(_task: &Task) {}
+pub(crate) fn clear_mem(task: &Task, security: &SecurityConfig) {
+    let seed = riscv::register::mcycle::read() as u32;
+    crate::security::clear_task_mem(task, security, seed);
+}
 
 pub(crate) unsafe fn set_current_tcb(task: &Tcb) {
     riscv::register::mscratch::write((task as *const Tcb).addr())
@@ -239,11 +280,14 @@ unsafe extern "C" fn _start_trap() -> ! {
 
          jal {trap_handler}
 
-         csrrw t6, mscratch, t6
+         # the handler may have switched threads, so mscratch holds the TCB we are returning to
+         csrr t6, mscratch
          ld t5,  31*8(t6)     # restore mepc
          csrw mepc, t5
+         # clearing MPP means mret always drops to user mode, whatever mode the trap came from
+         li t5, {mpp_mask}
+         csrc mstatus, t5
 
-         ld t5,  31*8(t6)
          ld ra,   0*8(t6)
          ld gp,   2*8(t6)
          ld tp,   3*8(t6)
@@ -276,15 +320,14 @@ unsafe extern "C" fn _start_trap() -> ! {
          sd sp,  32*8(t6)
          ld sp,   1*8(t6)
 
-         csrrw t6, mscratch, t6 #
-         csrrw t5, mscratch, t5
-         ld t6,  30*8(t5)
-         csrrw t5, mscratch, t5
+         # t6 holds the TCB pointer, so it is restored last
+         ld t6,  30*8(t6)
 
          mret
 
      ",
      trap_handler = sym trap_handler,
+     mpp_mask = const MSTATUS_MPP_MASK,
      options(noreturn)
     )
 }
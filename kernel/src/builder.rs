@@ -1,12 +1,16 @@
 use core::{mem, ops::Range};
 
-use abi::{Cap, Endpoint, Listen, PortId, ThreadRef};
+use abi::{Cap, Endpoint, Listen, PortId, SemaphoreId, ThreadRef};
 use alloc::boxed::Box;
 use cordyceps::List;
 use enumflags2::BitFlags;
 
+#[cfg(feature = "exec_validation")]
+use crate::ElfSection;
 use crate::{
-    regions::{Region, RegionAttr},
+    regions::{CachePolicy, Region, RegionAttr},
+    security::SecurityConfig,
+    task::{Task, TaskState},
     CapEntry, Kernel, KernelError, TaskDesc, TaskRef,
 };
 
@@ -19,6 +23,8 @@ use crate::{
 pub struct KernelBuilder<'a> {
     cycles_per_tick: usize,
     idle_task_set: bool,
+    /// The thread given an endpoint by the last call to [`KernelBuilder::endpoint`], for [`KernelBuilder::queue_depth`]
+    last_endpoint: Option<ThreadRef>,
     kernel: &'a mut Kernel,
 }
 
@@ -32,6 +38,7 @@ impl KernelBuilder<'_> {
             cycles_per_tick: 400_000,
             kernel: crate::arch::init_kernel(tasks),
             idle_task_set: false,
+            last_endpoint: None,
         }
     }
 
@@ -44,6 +51,56 @@ impl KernelBuilder<'_> {
         self
     }
 
+    /// Registers a callback that is run once every thread besides the idle thread has exited
+    ///
+    /// This can be used to log a final message, blink an LED, or exit the process when testing
+    pub fn on_shutdown(&mut self, f: fn()) -> &mut Self {
+        self.kernel.scheduler.on_shutdown = Some(f);
+        self
+    }
+
+    /// Requeues exhausted threads early when the system is under-loaded
+    ///
+    /// Every tick the idle thread runs counts twice towards the cooldown of each exhausted thread.
+    /// Threads that come off cooldown together are requeued in priority order, so higher priority threads run first.
+    pub fn idle_boost(&mut self, enable: bool) -> &mut Self {
+        self.kernel.scheduler.idle_boost = enable;
+        self
+    }
+
+    /// Allows admin tasks to map peripherals in `range` at runtime with `userspace::map_device`
+    ///
+    /// `range` should only cover device memory, never RAM or flash. This can be called multiple times to
+    /// register several windows.
+    pub fn device_space(&mut self, range: Range<usize>) -> &mut Self {
+        self.kernel
+            .devices
+            .add_window(range)
+            .expect("too many device windows");
+        self
+    }
+
+    /// Sets the kernel's hardening options, such as the pattern used to clear task RAM
+    pub fn security(&mut self, config: SecurityConfig) -> &mut Self {
+        self.kernel.security = config;
+        self
+    }
+
+    /// Calls `defrag` at most once every `ticks` ticks, while the idle thread is running
+    ///
+    /// The kernel doesn't own its allocator, so `defrag` should coalesce the free list of the global allocator
+    /// used by the kernel binary. This bounds the time later allocations spend scanning a fragmented free list.
+    #[cfg(feature = "heap_defrag")]
+    pub fn heap_defrag_interval(&mut self, ticks: usize, defrag: fn()) -> &mut Self {
+        assert!(ticks > 0, "heap defrag interval must be at least one tick");
+        self.kernel.scheduler.heap_defrag = Some(crate::scheduler::HeapDefrag {
+            interval: ticks as u64,
+            last_run: 0,
+            defrag,
+        });
+        self
+    }
+
     /// Spawns a new thread, and retunrs the thread buf
     pub fn thread(&mut self, thread: ThreadBuilder) -> ThreadRef {
         let task_ref = TaskRef(thread.index);
@@ -51,22 +108,50 @@ impl KernelBuilder<'_> {
             .kernel
             .task_mut(task_ref)
             .expect("invalid thread index");
-        let entrypoint = task.entrypoint;
+        let mut caps = thread.caps;
+        push_static_caps(task, &mut caps);
+        let entrypoint = match thread.entry_offset {
+            Some(offset) => task.entrypoint_at(offset).expect("invalid entry offset"),
+            None => task.entrypoint,
+        };
         for loan in thread.loans.into_iter() {
-            task.region_table
-                .push(loan.build())
-                .expect("loan add failed");
+            let loan = loan.build();
+            if let Some(existing) = task.region_table.overlapping(&loan) {
+                panic!(
+                    "loan {:#x}..{:#x} overlaps task region {:#x}..{:#x}",
+                    loan.range.start, loan.range.end, existing.range.start, existing.range.end
+                );
+            }
+            task.region_table.push(loan).expect("loan add failed");
         }
-        self.kernel
+        let tcb_ref = self
+            .kernel
             .spawn_thread(
                 task_ref,
                 thread.priority,
                 thread.budget,
                 thread.cooldown,
                 entrypoint,
-                thread.caps,
+                caps,
             )
-            .unwrap()
+            .unwrap();
+        for irq in thread.irqs {
+            self.kernel
+                .claim_irq(tcb_ref, irq)
+                .expect("failed to claim interrupt");
+        }
+        for irq in thread.irq_caps {
+            self.kernel
+                .grant_irq(tcb_ref, irq)
+                .expect("failed to grant interrupt");
+        }
+        for (id, count) in thread.semaphores {
+            self.kernel
+                .registry
+                .create_semaphore(id, count)
+                .expect("too many semaphores");
+        }
+        tcb_ref
     }
 
     /// Spawns the idle thread, this must be run at least once per builder
@@ -74,9 +159,11 @@ impl KernelBuilder<'_> {
         let task_ref = TaskRef(thread.index);
         let task = self.kernel.task(task_ref).expect("invalid thread index");
         let entrypoint = task.entrypoint;
+        let mut caps = thread.caps;
+        push_static_caps(task, &mut caps);
         let t = self
             .kernel
-            .spawn_thread(task_ref, 0, usize::MAX, 0, entrypoint, thread.caps)
+            .spawn_thread(task_ref, 0, usize::MAX, 0, entrypoint, caps)
             .unwrap();
 
         self.idle_task_set = true;
@@ -91,21 +178,150 @@ impl KernelBuilder<'_> {
     /// `addr` is the address for the endpoint, this is used to allow a single task to accept multiple message types
     pub fn endpoint(&mut self, task: ThreadRef, dest: ThreadRef, addr: usize) -> &mut Self {
         let _dest = self.kernel.scheduler.get_tcb(dest).unwrap();
-        let task = self.kernel.scheduler.get_tcb_mut(task).unwrap();
-        task.add_cap(Cap::Endpoint(Endpoint {
+        let tcb = self.kernel.scheduler.get_tcb_mut(task).unwrap();
+        tcb.add_cap(Cap::Endpoint(Endpoint {
             tcb_ref: dest,
             addr,
             disposable: false,
+            max_queue_depth: 0,
         }));
+        self.last_endpoint = Some(task);
+        self
+    }
+
+    /// Limits the endpoint added by the last call to [`KernelBuilder::endpoint`] to `depth` queued messages
+    ///
+    /// Once `depth` messages sent through the endpoint are waiting to be received, sending another fails with
+    /// [`abi::Error::QueueFull`], so a slow receiver can't exhaust the heap. A depth of 0 means no limit.
+    pub fn queue_depth(&mut self, depth: u8) -> &mut Self {
+        let task = self
+            .last_endpoint
+            .expect("queue_depth must follow a call to endpoint");
+        let task = self.kernel.scheduler.get_tcb_mut(task).unwrap();
+        let entry = task
+            .capabilities
+            .back_mut()
+            .expect("queue_depth must follow a call to endpoint");
+        // set in place, so the endpoint keeps the CapRef it was given
+        // Safety: only the cap is changed, the entry isn't moved out of its pinned allocation
+        match unsafe { &mut entry.get_unchecked_mut().cap } {
+            Cap::Endpoint(endpoint) => endpoint.max_queue_depth = depth,
+            _ => panic!("queue_depth must follow a call to endpoint"),
+        }
+        self
+    }
+
+    /// Gives `task` a [`Cap::Notify`] it can use to set notification bits on `dest`
+    pub fn notify(&mut self, task: ThreadRef, dest: ThreadRef) -> &mut Self {
+        let _dest = self.kernel.scheduler.get_tcb(dest).unwrap();
+        let task = self.kernel.scheduler.get_tcb_mut(task).unwrap();
+        task.add_cap(Cap::Notify(dest));
+        self
+    }
+
+    /// Gives `task` a [`Cap::SharedFlash`] for `range`, which its task can map read-only with `map_shared`
+    ///
+    /// `range` should be in flash, and aligned to the MPU's region granularity. Any number of tasks can be given the
+    /// same range.
+    pub fn shared_flash(&mut self, task: ThreadRef, range: Range<usize>) -> &mut Self {
+        assert!(!range.is_empty(), "shared flash range is empty");
+        let task = self.kernel.scheduler.get_tcb_mut(task).unwrap();
+        task.add_cap(Cap::SharedFlash { range });
+        self
+    }
+
+    /// Gives `task` a [`Cap::Heap`] for `range`, which it can allocate from with `userspace::TaskHeap`
+    ///
+    /// `range` must be inside one of the task's writable regions, and aligned to [`crate::HEAP_BLOCK_LEN`]. Threads
+    /// given the same range share the heap. The first heap given to a thread also becomes its task's heap, which
+    /// every thread in the task can allocate from without a cap, including threads started with `spawn_thread`.
+    pub fn heap(&mut self, task: ThreadRef, range: Range<usize>) -> &mut Self {
+        let task_ref = self.kernel.scheduler.get_tcb(task).unwrap().task;
+        assert!(
+            self.kernel
+                .task(task_ref)
+                .unwrap()
+                .region_table
+                .iter()
+                .any(|r| r.attr.contains(RegionAttr::Write)
+                    && r.range.start <= range.start
+                    && range.end <= r.range.end),
+            "heap {:#x}..{:#x} isn't in the task's RAM",
+            range.start,
+            range.end
+        );
+        // Safety: the range is in the task's RAM, which the kernel can write
+        let mut heap = unsafe { crate::heap::Heap::new(range.clone()) }
+            .expect("heap is unaligned or too small for a block");
+        heap.reset();
+        let task_heap = &mut self.kernel.task_mut(task_ref).unwrap().heap;
+        if task_heap.is_none() {
+            *task_heap = Some(range.clone());
+        }
+        let tcb = self.kernel.scheduler.get_tcb_mut(task).unwrap();
+        tcb.add_cap(Cap::Heap {
+            base: range.start,
+            size: range.len(),
+        });
+        self
+    }
+
+    /// Connects each of `clients` to `server`, with a reply endpoint from `server` back to each client
+    ///
+    /// The `i`th client's endpoints use `base_addr + i` as their address in both directions, so the server can
+    /// tell which client sent a message from its address.
+    pub fn endpoint_group(
+        &mut self,
+        clients: &[ThreadRef],
+        server: ThreadRef,
+        base_addr: usize,
+    ) -> &mut Self {
+        assert!(
+            base_addr.checked_add(clients.len()).is_some(),
+            "endpoint group addresses overflow"
+        );
+        for (i, &client) in clients.iter().enumerate() {
+            self.endpoint(client, server, base_addr + i);
+            self.endpoint(server, client, base_addr + i);
+        }
+        self
+    }
+
+    /// Warns about any of the task's executable regions that don't contain one of its `.text` sections
+    ///
+    /// The task's `.text` range is generated by codegen as `TASK_<NAME>_TEXT`
+    #[cfg(feature = "exec_validation")]
+    pub fn validate_exec_regions(&mut self, index: usize, sections: &[ElfSection]) -> &mut Self {
+        let task = self
+            .kernel
+            .task(TaskRef(index))
+            .expect("invalid task index");
+        task.region_table.validate_exec_regions(sections);
         self
     }
 
-    /// Starts the kernel
+    /// Logs how full the kernel's tables are, see [`Kernel::diagnostics`], and then starts the kernel
     pub fn start(self) -> ! {
+        let diagnostics = self.kernel.diagnostics();
+        defmt::info!("kernel tables: {}", diagnostics);
+        crate::defmt_log::log_diagnostics(&diagnostics);
         self.kernel.start()
     }
 }
 
+/// Adds the task's static caps to `caps`, if no thread has been spawned in the task yet
+fn push_static_caps(task: &Task, caps: &mut List<CapEntry>) {
+    if task.state != TaskState::Pending {
+        return;
+    }
+    for cap in task.static_caps {
+        caps.push_back(Box::pin(CapEntry {
+            cap: cap.clone(),
+            _links: Default::default(),
+        }));
+    }
+}
+
 /// A builder for a thread, that can be passed into [`KernelBuilder`]
 ///
 /// This struct will almost always be generated using the consts from the generated `task_table`
@@ -116,6 +332,10 @@ pub struct ThreadBuilder {
     cooldown: usize,
     caps: List<CapEntry>,
     loans: heapless::Vec<RegionBuilder, 16>,
+    entry_offset: Option<usize>,
+    irqs: heapless::Vec<u8, 8>,
+    irq_caps: heapless::Vec<u16, 8>,
+    semaphores: heapless::Vec<(SemaphoreId, usize), 4>,
 }
 
 impl ThreadBuilder {
@@ -132,6 +352,10 @@ impl ThreadBuilder {
             cooldown: 0,
             caps: List::new(),
             loans: heapless::Vec::new(),
+            entry_offset: None,
+            irqs: heapless::Vec::new(),
+            irq_caps: heapless::Vec::new(),
+            semaphores: heapless::Vec::new(),
         }
     }
 
@@ -160,6 +384,15 @@ impl ThreadBuilder {
         self
     }
 
+    /// Starts the thread at a byte offset from the start of the task's flash region, rather than the task's entrypoint
+    ///
+    /// This lets a single task export multiple entry functions. The offsets are usually taken from the
+    /// `TASK_<NAME>_ENTRY_<ENTRY>` consts generated by codegen. The offset is validated when the thread is spawned.
+    pub fn entry_offset(mut self, offset: usize) -> Self {
+        self.entry_offset = Some(offset);
+        self
+    }
+
     /// Adds a listen cap to the thread
     pub fn listen(mut self, port: PortId) -> Self {
         self.caps.push_back(Box::pin(CapEntry {
@@ -169,6 +402,17 @@ impl ThreadBuilder {
         self
     }
 
+    /// Adds a listen cap to the thread that registers it as one of several shards for the port
+    ///
+    /// See [`abi::Cap::ListenShard`]
+    pub fn listen_shard(mut self, port: PortId) -> Self {
+        self.caps.push_back(Box::pin(CapEntry {
+            cap: Cap::ListenShard(Listen { port }),
+            _links: Default::default(),
+        }));
+        self
+    }
+
     /// Adds a connect cap to the thread
     pub fn connect(mut self, port: PortId) -> Self {
         self.caps.push_back(Box::pin(CapEntry {
@@ -178,6 +422,62 @@ impl ThreadBuilder {
         self
     }
 
+    /// Adds an admin cap to the thread, allowing it to make privileged syscalls like reboot
+    pub fn admin(mut self) -> Self {
+        self.caps.push_back(Box::pin(CapEntry {
+            cap: Cap::Admin,
+            _links: Default::default(),
+        }));
+        self
+    }
+
+    /// Adds a direct interrupt cap to the thread, routing `irq` to it
+    ///
+    /// The thread must also hold an admin cap, and each interrupt can only be claimed by a single thread
+    pub fn interrupt(mut self, irq: u8) -> Self {
+        self.irqs
+            .push(irq)
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))
+            .unwrap();
+        self.caps.push_back(Box::pin(CapEntry {
+            cap: Cap::DirectInterrupt(irq),
+            _links: Default::default(),
+        }));
+        self
+    }
+
+    /// Adds an IRQ cap to the thread, letting it wait for and acknowledge `irq_number` itself
+    ///
+    /// Unlike [`ThreadBuilder::interrupt`], this doesn't need an admin cap. Each interrupt can only be granted to a
+    /// single thread. See [`abi::Cap::Irq`]
+    pub fn irq(mut self, irq_number: u16) -> Self {
+        self.irq_caps
+            .push(irq_number)
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))
+            .unwrap();
+        self.caps.push_back(Box::pin(CapEntry {
+            cap: Cap::Irq { irq_number },
+            _links: Default::default(),
+        }));
+        self
+    }
+
+    /// Adds a semaphore cap to the thread, creating the semaphore with `initial_count` units
+    ///
+    /// Threads share a semaphore by each being given the same `id`, in which case the count of the
+    /// first thread spawned is used. See [`abi::Cap::Semaphore`]
+    pub fn semaphore(mut self, id: SemaphoreId, initial_count: usize) -> Self {
+        self.semaphores
+            .push((id, initial_count))
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))
+            .unwrap();
+        self.caps.push_back(Box::pin(CapEntry {
+            cap: Cap::Semaphore(id),
+            _links: Default::default(),
+        }));
+        self
+    }
+
     pub fn loan_mem(mut self, region: RegionBuilder) -> Self {
         self.loans
             .push(region)
@@ -221,7 +521,23 @@ impl RegionBuilder {
         self
     }
 
-    fn build(self) -> Region {
+    /// Sets the cache policy of the region, normal memory defaults to [`CachePolicy::WriteBack`]
+    ///
+    /// This has no effect on device and DMA regions, which are never cached.
+    pub fn cached(mut self, policy: CachePolicy) -> Self {
+        self.0
+            .attr
+            .remove(RegionAttr::WriteThrough | RegionAttr::Uncached);
+        self.0.attr |= policy.attr();
+        self
+    }
+
+    /// Marks the region as non-cacheable, shorthand for `cached(CachePolicy::Uncached)`
+    pub fn uncached(self) -> Self {
+        self.cached(CachePolicy::Uncached)
+    }
+
+    pub(crate) fn build(self) -> Region {
         self.0
     }
 }
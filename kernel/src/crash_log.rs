@@ -0,0 +1,244 @@
+//! A post-mortem log of task panics, kept in RAM that isn't cleared on reset
+//!
+//! Each panic is appended to a small ring buffer in the `.uninit` section, dropping the oldest records when it is
+//! full. The runtime doesn't zero `.uninit`, so the buffer survives a reset as long as the device keeps power. On the
+//! next boot the kernel logs the crashes it finds, see [`report`], and then clears it.
+//!
+//! Each record is laid out as its little-endian `u16` length, the task's ID, the little-endian `u64` tick it
+//! paniked at, the length-prefixed task name, and the panic message.
+use core::mem::MaybeUninit;
+
+use defmt::warn;
+
+use crate::defmt_log;
+
+/// Marks the buffer as holding records, rather than whatever RAM held at power on
+const MAGIC: u32 = 0x6b35_6372;
+/// The size of the whole crash log, including its header
+const CRASH_LOG_LEN: usize = 512;
+const DATA_LEN: usize = CRASH_LOG_LEN - 12;
+const RECORD_HEADER_LEN: usize = 12;
+/// Names longer than this are truncated, so they don't crowd out the message
+const MAX_NAME_LEN: usize = 32;
+/// Messages longer than this are truncated, so the log always has room for a few records
+const MAX_MSG_LEN: usize = 200;
+
+#[cfg_attr(feature = "cortex_m", link_section = ".uninit.k5_crash_log")]
+static mut CRASH_LOG: MaybeUninit<CrashLog> = MaybeUninit::uninit();
+
+#[repr(C)]
+pub(crate) struct CrashLog {
+    magic: u32,
+    /// The offset of the oldest record in `data`
+    start: u32,
+    /// The number of bytes of `data` used by records
+    len: u32,
+    data: [u8; DATA_LEN],
+}
+
+impl CrashLog {
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && (self.start as usize) < DATA_LEN && self.len as usize <= DATA_LEN
+    }
+
+    fn clear(&mut self) {
+        self.magic = MAGIC;
+        self.start = 0;
+        self.len = 0;
+    }
+
+    /// Appends a record, dropping the oldest records until there is room for it
+    fn push(&mut self, task_id: u8, name: &str, ticks: u64, msg: &[u8]) {
+        if !self.is_valid() {
+            self.clear();
+        }
+        let name = &name.as_bytes()[..name.len().min(MAX_NAME_LEN)];
+        let msg = &msg[..msg.len().min(MAX_MSG_LEN)];
+        let len = RECORD_HEADER_LEN + name.len() + msg.len();
+        while DATA_LEN - (self.len as usize) < len {
+            let oldest = self.record_len(self.start as usize);
+            if oldest == 0 || oldest > self.len as usize {
+                self.clear();
+                break;
+            }
+            self.start = ((self.start as usize + oldest) % DATA_LEN) as u32;
+            self.len -= oldest as u32;
+        }
+        let mut offset = (self.start + self.len) as usize % DATA_LEN;
+        for part in [
+            &(len as u16).to_le_bytes()[..],
+            &[task_id],
+            &ticks.to_le_bytes(),
+            &[name.len() as u8],
+            name,
+            msg,
+        ] {
+            for byte in part {
+                self.data[offset] = *byte;
+                offset = (offset + 1) % DATA_LEN;
+            }
+        }
+        self.len += len as u32;
+    }
+
+    fn record_len(&self, offset: usize) -> usize {
+        u16::from_le_bytes([self.data[offset], self.data[(offset + 1) % DATA_LEN]]) as usize
+    }
+
+    /// Iterates over the records from oldest to newest, stopping at the first one that is malformed
+    fn records(&self) -> impl Iterator<Item = CrashRecord> + '_ {
+        let mut offset = self.start as usize;
+        let mut remaining = if self.is_valid() {
+            self.len as usize
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            if remaining < RECORD_HEADER_LEN {
+                return None;
+            }
+            let len = self.record_len(offset);
+            if len < RECORD_HEADER_LEN || len > remaining {
+                remaining = 0;
+                return None;
+            }
+            let mut buf = heapless::Vec::new();
+            for i in 0..len {
+                buf.push(self.data[(offset + i) % DATA_LEN]).ok()?;
+            }
+            offset = (offset + len) % DATA_LEN;
+            remaining -= len;
+            let record = CrashRecord { buf };
+            if RECORD_HEADER_LEN + record.name_len() > len {
+                remaining = 0;
+                return None;
+            }
+            Some(record)
+        })
+    }
+}
+
+/// A copy of one record from the [`CrashLog`]
+pub(crate) struct CrashRecord {
+    buf: heapless::Vec<u8, DATA_LEN>,
+}
+
+impl CrashRecord {
+    pub(crate) fn task_id(&self) -> u8 {
+        self.buf[2]
+    }
+
+    pub(crate) fn ticks(&self) -> u64 {
+        let mut ticks = [0u8; 8];
+        ticks.copy_from_slice(&self.buf[3..11]);
+        u64::from_le_bytes(ticks)
+    }
+
+    fn name_len(&self) -> usize {
+        self.buf[11] as usize
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        let name = &self.buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + self.name_len()];
+        core::str::from_utf8(name).unwrap_or("?")
+    }
+
+    pub(crate) fn msg(&self) -> &str {
+        let msg = &self.buf[RECORD_HEADER_LEN + self.name_len()..];
+        core::str::from_utf8(msg).unwrap_or("<invalid msg>")
+    }
+}
+
+fn crash_log() -> &'static mut CrashLog {
+    // Safety: the kernel is single threaded, so there is only ever one reference to the log at a time.
+    // Every field of `CrashLog` is an integer, so any bit pattern left in RAM is a valid value, the magic just
+    // tells us whether it holds records.
+    unsafe { CRASH_LOG.assume_init_mut() }
+}
+
+/// Records a task panic in the crash log
+pub(crate) fn record_panic(task_id: u8, name: &str, ticks: u64, msg: &[u8]) {
+    crash_log().push(task_id, name, ticks, msg);
+}
+
+/// The task ID the kernel logs the number of crashes left over from before the last reset with
+///
+/// It is followed by a warning for each crash, so the host can flag them.
+pub(crate) const CRASH_LOG_ID: u8 = 0xFC;
+
+/// Logs the crashes recorded before the last reset, if there are any, and then clears the crash log
+///
+/// This must be called after the log is initialized
+pub(crate) fn report() {
+    let log = crash_log();
+    let count = log.records().count();
+    if count > 0 {
+        defmt_log::log(CRASH_LOG_ID, &(count as u32).to_le_bytes());
+        for record in log.records() {
+            warn!(
+                "task {} ({}) paniked at tick {} before the last reset: {}",
+                record.task_id(),
+                record.name(),
+                record.ticks(),
+                record.msg()
+            );
+        }
+    }
+    log.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CrashLog, DATA_LEN, MAGIC, MAX_MSG_LEN};
+
+    fn empty_log() -> CrashLog {
+        CrashLog {
+            magic: MAGIC,
+            start: 0,
+            len: 0,
+            data: [0; DATA_LEN],
+        }
+    }
+
+    #[test]
+    fn test_push_records() {
+        let mut log = empty_log();
+        log.push(1, "ping", 42, b"oh no");
+        log.push(2, "pong", 43, b"");
+        let records: Vec<_> = log.records().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].task_id(), 1);
+        assert_eq!(records[0].name(), "ping");
+        assert_eq!(records[0].ticks(), 42);
+        assert_eq!(records[0].msg(), "oh no");
+        assert_eq!(records[1].name(), "pong");
+        assert_eq!(records[1].msg(), "");
+
+        log.clear();
+        assert_eq!(log.records().count(), 0);
+    }
+
+    #[test]
+    fn test_push_wraps() {
+        let mut log = empty_log();
+        let msg = [b'a'; MAX_MSG_LEN];
+        for i in 0..10 {
+            log.push(i, "task", i as u64, &msg);
+        }
+        let records: Vec<_> = log.records().collect();
+        assert_eq!(records.len(), 2, "only the newest records should fit");
+        assert_eq!(records[0].task_id(), 8);
+        assert_eq!(records[1].task_id(), 9);
+        assert_eq!(records[1].msg().len(), MAX_MSG_LEN);
+    }
+
+    #[test]
+    fn test_invalid_log() {
+        let mut log = empty_log();
+        log.magic = 0x1234;
+        log.len = 100;
+        assert_eq!(log.records().count(), 0);
+        log.push(3, "task", 0, b"msg");
+        assert_eq!(log.records().count(), 1);
+    }
+}
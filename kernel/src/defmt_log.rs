@@ -26,12 +26,104 @@ unsafe impl defmt::Logger for KernelLogger {
     }
 }
 
+/// The task ID used to tell the host that the kernel is about to reset, so it can reattach to the log stream
+pub(crate) const REBOOT_ID: u8 = 0xFF;
+
+/// The task ID used for memory dumps requested with the `ReadMemory` syscall
+///
+/// Each frame contains the ID of the dumped task, followed by the little-endian `u64` address of the data and the data itself
+#[cfg(debug_assertions)]
+pub(crate) const MEMORY_DUMP_ID: u8 = 0xFE;
+
+/// Writes `data` to the log as one or more memory dump frames
+#[cfg(debug_assertions)]
+pub(crate) fn log_memory(task_id: u8, addr: usize, data: &[u8]) {
+    const HEADER_LEN: usize = 9;
+    let mut buf = [0u8; 254];
+    for (i, chunk) in data.chunks(buf.len() - HEADER_LEN).enumerate() {
+        let chunk_addr = (addr + i * (buf.len() - HEADER_LEN)) as u64;
+        buf[0] = task_id;
+        buf[1..HEADER_LEN].copy_from_slice(&chunk_addr.to_le_bytes());
+        buf[HEADER_LEN..HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+        log(MEMORY_DUMP_ID, &buf[..HEADER_LEN + chunk.len()]);
+    }
+}
+
+/// The task ID used for stats logged with the `Stats` syscall
+///
+/// Each frame contains the ID of the task, followed by the little-endian fields of its [`abi::TaskStats`] in order
+pub(crate) const STATS_ID: u8 = 0xFD;
+
+/// Writes a thread's stats to the log as a stats frame
+pub(crate) fn log_stats(task_id: u8, stats: &abi::TaskStats) {
+    let mut buf = [0u8; 25];
+    buf[0] = task_id;
+    buf[1..9].copy_from_slice(&stats.cpu_ticks.to_le_bytes());
+    buf[9..13].copy_from_slice(&stats.ipc_count.to_le_bytes());
+    buf[13..21].copy_from_slice(&stats.ipc_bytes.to_le_bytes());
+    buf[21..25].copy_from_slice(&stats.context_switches.to_le_bytes());
+    log(STATS_ID, &buf);
+}
+
+/// The task ID used for the kernel's table usage, logged once when the kernel starts
+///
+/// The frame contains the fields of [`crate::KernelDiagnostics`] in order, each as a little-endian `u32`
+pub(crate) const DIAGNOSTICS_ID: u8 = 0xFB;
+
+/// Writes the kernel's table usage to the log as a diagnostics frame
+pub(crate) fn log_diagnostics(diagnostics: &crate::KernelDiagnostics) {
+    let fields = [
+        diagnostics.tcb_used,
+        diagnostics.tcb_capacity,
+        diagnostics.task_count,
+        diagnostics.task_capacity,
+        diagnostics.registry_entries,
+        diagnostics.registry_capacity,
+    ];
+    let mut buf = [0u8; 24];
+    for (chunk, field) in buf.chunks_mut(4).zip(fields) {
+        chunk.copy_from_slice(&(field as u32).to_le_bytes());
+    }
+    log(DIAGNOSTICS_ID, &buf);
+}
+
+/// Set in a frame's ID when the ID is followed by the length-prefixed name of the task that sent it
+///
+/// Task IDs are always below this, so it can't be confused with [`REBOOT_ID`], `MEMORY_DUMP_ID`, [`STATS_ID`],
+/// [`crate::crash_log::CRASH_LOG_ID`] or [`DIAGNOSTICS_ID`], which the host checks for first.
+pub(crate) const TASK_NAME_FLAG: u8 = 0x80;
+
 pub(crate) fn log(id: u8, log_buf: &[u8]) {
-    let mut buf = [0u8; 1200];
-    buf[0] = log_buf.len() as u8 + 1;
+    write_frame(id, None, log_buf);
+}
+
+/// Writes frames that include the task's name, so the host doesn't have to match task IDs to its config
+///
+/// The name is truncated to [`abi::MAX_LOG_NAME_LEN`] bytes. `log_buf` is split over as many frames as it takes
+/// for each frame's length to fit in its length byte.
+pub(crate) fn log_task(id: u8, name: &str, log_buf: &[u8]) {
+    let name = &name.as_bytes()[..name.len().min(abi::MAX_LOG_NAME_LEN)];
+    // leaves room for the length byte, the ID, the name's length and the name, so the length byte can't wrap
+    let max_payload = u8::MAX as usize - 2 - 1 - name.len();
+    for chunk in log_buf.chunks(max_payload) {
+        write_frame(id | TASK_NAME_FLAG, Some(name), chunk);
+    }
+}
+
+/// Writes a single frame, which must be no more than 256 bytes including the length byte
+fn write_frame(id: u8, name: Option<&[u8]>, log_buf: &[u8]) {
+    let mut buf = [0u8; 256];
     buf[1] = id;
-    // NOTE: this assumes that the internal task index is the same as codegen task index, which is true for embedded,
-    // but for systems with dynamic tasks is not true.
-    buf[2..log_buf.len() + 2].clone_from_slice(log_buf);
-    crate::arch::log(&buf[..log_buf.len() + 2]);
+    let mut len = 2;
+    if let Some(name) = name {
+        buf[len] = name.len() as u8;
+        buf[len + 1..len + 1 + name.len()].copy_from_slice(name);
+        len += name.len() + 1;
+    }
+    // NOTE: without a name this assumes that the internal task index is the same as codegen task index, which is
+    // true for embedded, but for systems with dynamic tasks is not true.
+    buf[len..len + log_buf.len()].clone_from_slice(log_buf);
+    len += log_buf.len();
+    buf[0] = (len - 1) as u8;
+    crate::arch::log(&buf[..len]);
 }
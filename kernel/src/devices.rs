@@ -0,0 +1,84 @@
+//! Tracks the peripherals tasks have mapped at runtime with [`abi::SyscallFn::MapDevice`]
+//!
+//! Tasks can only map addresses inside a device window registered with [`crate::KernelBuilder::device_space`],
+//! which keeps RAM and flash out of reach. Each peripheral can only be mapped by a single task.
+use core::ops::Range;
+
+use crate::TaskRef;
+
+/// The maximum number of device windows the kernel can be configured with
+const MAX_DEVICE_WINDOWS: usize = 4;
+/// The maximum number of peripherals that can be mapped at runtime across all tasks
+const MAX_DEVICE_MAPPINGS: usize = 16;
+
+#[derive(Default)]
+pub(crate) struct DeviceMap {
+    windows: heapless::Vec<Range<usize>, MAX_DEVICE_WINDOWS>,
+    mappings: heapless::Vec<(TaskRef, Range<usize>), MAX_DEVICE_MAPPINGS>,
+}
+
+impl DeviceMap {
+    pub(crate) fn add_window(&mut self, window: Range<usize>) -> Result<(), abi::Error> {
+        self.windows
+            .push(window)
+            .map_err(|_| abi::Error::BufferOverflow)
+    }
+
+    /// Records that `task` has mapped `range`
+    ///
+    /// Returns [`abi::Error::BadAccess`] if `range` isn't inside a device window, and [`abi::Error::InvalidLoan`]
+    /// if any part of it is already mapped.
+    pub(crate) fn claim(&mut self, task: TaskRef, range: Range<usize>) -> Result<(), abi::Error> {
+        if range.is_empty()
+            || !self
+                .windows
+                .iter()
+                .any(|w| w.start <= range.start && range.end <= w.end)
+        {
+            return Err(abi::Error::BadAccess);
+        }
+        if self
+            .mappings
+            .iter()
+            .any(|(_, r)| r.start < range.end && range.start < r.end)
+        {
+            return Err(abi::Error::InvalidLoan);
+        }
+        self.mappings
+            .push((task, range))
+            .map_err(|_| abi::Error::BufferOverflow)
+    }
+
+    /// Releases a mapping made by [`DeviceMap::claim`]
+    pub(crate) fn release(&mut self, task: TaskRef, range: Range<usize>) {
+        self.mappings.retain(|(t, r)| !(*t == task && *r == range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceMap;
+    use crate::TaskRef;
+
+    #[test]
+    fn test_claim() {
+        let mut map = DeviceMap::default();
+        map.add_window(0x4000_0000..0x5000_0000).unwrap();
+        assert!(matches!(
+            map.claim(TaskRef(0), 0x2000_0000..0x2000_0100),
+            Err(abi::Error::BadAccess)
+        ));
+        assert!(matches!(
+            map.claim(TaskRef(0), 0x4fff_ff00..0x5000_0100),
+            Err(abi::Error::BadAccess)
+        ));
+        map.claim(TaskRef(0), 0x4000_1000..0x4000_1400).unwrap();
+        assert!(matches!(
+            map.claim(TaskRef(1), 0x4000_1200..0x4000_1800),
+            Err(abi::Error::InvalidLoan)
+        ));
+        map.claim(TaskRef(1), 0x4000_1400..0x4000_1800).unwrap();
+        map.release(TaskRef(0), 0x4000_1000..0x4000_1400);
+        map.claim(TaskRef(1), 0x4000_1000..0x4000_1400).unwrap();
+    }
+}
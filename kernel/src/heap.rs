@@ -0,0 +1,168 @@
+//! Per-task heaps, granted to a thread with [`abi::Cap::Heap`]
+//!
+//! A heap is split into [`BLOCK_LEN`] byte blocks. The blocks in use are tracked by a bitmap stored in the first
+//! blocks of the heap itself, so the kernel doesn't need any memory of its own per heap. An all zero bitmap is an
+//! empty heap. Clearing the task's RAM when it restarts overwrites the bitmap, so the task's heaps are reset
+//! afterwards, see `Task::reset_heaps`.
+//!
+//! Allocations are first fit, and are freed with the same size they were allocated with, like
+//! [`core::alloc::GlobalAlloc`].
+use core::ops::Range;
+
+/// The granularity of allocations, every allocation is rounded up to a multiple of this
+pub const BLOCK_LEN: usize = 32;
+
+pub(crate) struct Heap {
+    base: usize,
+    blocks: usize,
+}
+
+impl Heap {
+    /// Returns the heap in `range`, or `None` if it is too small to hold a single block after the bitmap
+    ///
+    /// # Safety
+    /// `range` must be memory the kernel can write, and that only the heap's task uses
+    pub(crate) unsafe fn new(range: Range<usize>) -> Option<Self> {
+        if range.start % BLOCK_LEN != 0 {
+            return None;
+        }
+        let blocks = range.len() / BLOCK_LEN;
+        let heap = Heap {
+            base: range.start,
+            blocks,
+        };
+        if heap.bitmap_blocks() < blocks {
+            Some(heap)
+        } else {
+            None
+        }
+    }
+
+    /// The blocks at the start of the heap taken up by the bitmap, which has a bit for every block
+    fn bitmap_blocks(&self) -> usize {
+        ((self.blocks + 7) / 8 + BLOCK_LEN - 1) / BLOCK_LEN
+    }
+
+    fn bitmap(&mut self) -> &mut [u8] {
+        // Safety: the bitmap is inside the heap, which `new` requires to be writable
+        unsafe { core::slice::from_raw_parts_mut(self.base as *mut u8, (self.blocks + 7) / 8) }
+    }
+
+    fn is_used(&mut self, block: usize) -> bool {
+        self.bitmap()[block / 8] & (1 << (block % 8)) != 0
+    }
+
+    fn set_used(&mut self, blocks: Range<usize>, used: bool) {
+        for block in blocks {
+            let bit = 1 << (block % 8);
+            let byte = &mut self.bitmap()[block / 8];
+            if used {
+                *byte |= bit;
+            } else {
+                *byte &= !bit;
+            }
+        }
+    }
+
+    /// Marks every block as free
+    pub(crate) fn reset(&mut self) {
+        self.bitmap().fill(0);
+    }
+
+    /// Allocates `size` bytes aligned to `align`, returning their address, or `None` if no free range is large enough
+    pub(crate) fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let len = block_count(size);
+        let align = align.max(1);
+        let mut start = self.bitmap_blocks();
+        while start + len <= self.blocks {
+            if (self.base + start * BLOCK_LEN) % align != 0 {
+                start += 1;
+                continue;
+            }
+            match (start..start + len).find(|&b| self.is_used(b)) {
+                Some(used) => start = used + 1,
+                None => {
+                    self.set_used(start..start + len, true);
+                    return Some(self.base + start * BLOCK_LEN);
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees the `size` bytes at `addr`, which must have been returned by [`Heap::alloc`] with the same size
+    pub(crate) fn free(&mut self, addr: usize, size: usize) -> Result<(), abi::Error> {
+        let offset = addr.checked_sub(self.base).ok_or(abi::Error::BadAccess)?;
+        if offset % BLOCK_LEN != 0 {
+            return Err(abi::Error::BadAccess);
+        }
+        let start = offset / BLOCK_LEN;
+        let end = start + block_count(size);
+        if start < self.bitmap_blocks()
+            || end > self.blocks
+            || !(start..end).all(|b| self.is_used(b))
+        {
+            return Err(abi::Error::BadAccess);
+        }
+        self.set_used(start..end, false);
+        Ok(())
+    }
+}
+
+/// The number of blocks an allocation of `size` bytes takes up, without overflowing for sizes near `usize::MAX`
+fn block_count(size: usize) -> usize {
+    (size / BLOCK_LEN + usize::from(size % BLOCK_LEN != 0)).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Heap, BLOCK_LEN};
+    use alloc::boxed::Box;
+
+    #[repr(align(64))]
+    struct Mem([u8; 1024]);
+
+    fn heap(mem: &mut Mem) -> Heap {
+        let base = mem.0.as_mut_ptr() as usize;
+        let mut heap = unsafe { Heap::new(base..base + mem.0.len()) }.unwrap();
+        heap.reset();
+        heap
+    }
+
+    #[test]
+    fn test_alloc_free() {
+        let mut mem = Box::new(Mem([0xFF; 1024]));
+        let base = mem.0.as_ptr() as usize;
+        let mut heap = heap(&mut mem);
+        let a = heap.alloc(40, 8).unwrap();
+        assert_eq!(a, base + BLOCK_LEN, "the first block holds the bitmap");
+        let b = heap.alloc(1, 1).unwrap();
+        assert_eq!(b, a + 2 * BLOCK_LEN);
+        heap.free(a, 40).unwrap();
+        assert_eq!(heap.alloc(32, 1), Some(a), "freed blocks should be reused");
+        assert!(matches!(heap.free(b + 1, 1), Err(abi::Error::BadAccess)));
+        heap.free(b, 1).unwrap();
+        assert!(
+            matches!(heap.free(b, 1), Err(abi::Error::BadAccess)),
+            "double free"
+        );
+    }
+
+    #[test]
+    fn test_alloc_align_and_exhaustion() {
+        let mut mem = Box::new(Mem([0; 1024]));
+        let mut heap = heap(&mut mem);
+        let a = heap.alloc(8, 64).unwrap();
+        assert_eq!(a % 64, 0);
+        let mut count = 1;
+        while heap.alloc(BLOCK_LEN, 1).is_some() {
+            count += 1;
+        }
+        assert_eq!(
+            count,
+            1024 / BLOCK_LEN - 1,
+            "the gap left by the aligned allocation should be filled"
+        );
+        assert_eq!(heap.alloc(1, 1), None);
+    }
+}
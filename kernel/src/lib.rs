@@ -14,10 +14,17 @@ extern crate alloc;
 
 mod arch;
 mod builder;
+mod crash_log;
 mod defmt_log;
+mod devices;
+mod heap;
+#[cfg(feature = "static_ipc_pool")]
+mod msg_pool;
 mod regions;
 mod registry;
+mod req_queue;
 mod scheduler;
+mod security;
 mod space;
 mod syscalls;
 mod task;
@@ -25,19 +32,39 @@ mod task_ptr;
 mod tcb;
 
 use defmt::Format;
+use devices::DeviceMap;
 use registry::Registry;
 use syscalls::{
-    CallReturn, CallSysCall, CapsCall, ConnectCall, ListenCall, LogCall, PanikCall, RecvCall,
-    SendCall, SysCall,
+    AckIrqCall, AllocCall, CallReturn, CallSysCall, CapsCall, ConnectCall, GetTaskInfoCall,
+    ListenCall, LogCall, MapDeviceCall, MapSharedCall, NotifyCall, PanikCall, PollCapsCall,
+    RebootCall, RecvCall, RevokeCall, SemPostCall, SemWaitCall, SendCall, SendCapCall, SendVecCall,
+    SleepCall, SpawnCall, StackUsageCall, StatsCall, SysCall, TimestampCall, UnmapCall,
+    WaitIrqCall, WaitNotifyCall, YieldCall,
 };
+#[cfg(debug_assertions)]
+use syscalls::{ReadMemoryCall, WriteMemoryCall};
 use tcb::*;
 
+pub use abi;
+#[cfg(feature = "qemu")]
+pub use arch::qemu;
+#[cfg(feature = "cortex_m")]
+pub use arch::{mem_manage, stack_guard_task};
 pub use builder::*;
-pub use regions::RegionAttr;
-#[cfg(test)]
+pub use heap::BLOCK_LEN as HEAP_BLOCK_LEN;
+#[cfg(feature = "exec_validation")]
+pub use regions::ElfSection;
+pub use regions::{CachePolicy, RegionAttr};
+pub use security::{ClearPattern, SecurityConfig};
+pub use task::Task;
+#[cfg(all(test, feature = "qemu"))]
+mod qemu_tests;
+// the unit tests rely on the dummy backend, which doesn't check task pointers against simulated memory like the
+// qemu backend does, so they only build without it
+#[cfg(all(test, not(feature = "qemu")))]
 mod tests;
 
-use abi::{Cap, CapRef, Endpoint, SyscallArgs, SyscallIndex, ThreadRef};
+use abi::{Cap, CapRef, Endpoint, SyscallArgs, SyscallIndex, SyscallReturn, ThreadRef};
 use alloc::{boxed::Box, collections::BinaryHeap};
 use cordyceps::{
     list::{self, Links},
@@ -52,38 +79,50 @@ use task::*;
 use task_ptr::TaskPtr;
 
 pub(crate) const TCB_CAPACITY: usize = 16;
+pub(crate) const TASK_CAPACITY: usize = 5;
 
 pub struct Kernel {
     pub(crate) scheduler: Scheduler,
     pub(crate) registry: Registry,
     epoch: usize,
-    tasks: Vec<Task, 5>,
+    tasks: Vec<Task, TASK_CAPACITY>,
+    irqs: heapless::FnvIndexMap<u8, ThreadRef, 16>,
+    /// Interrupts granted with [`Cap::Irq`], keyed by IRQ number
+    irq_caps: heapless::FnvIndexMap<u16, IrqRoute, 16>,
+    devices: DeviceMap,
+    security: SecurityConfig,
 }
 
 impl Kernel {
     pub fn from_tasks(tasks: &[TaskDesc]) -> Result<Self, KernelError> {
-        let tasks: heapless::Vec<_, 5> = tasks
+        let tasks: heapless::Vec<_, TASK_CAPACITY> = tasks
             .iter()
             .map(|desc| {
-                Task::new(
+                let mut task = Task::new(
                     desc.region_table(),
                     desc.init_stack_size,
                     desc.stack_space.clone(),
                     // Safety: entrypoints are static in k5 currently, so this is safe
                     unsafe { TaskPtr::from_raw_parts(desc.entrypoint, ()) },
                     false,
-                )
+                );
+                task.name = desc.name;
+                task.static_caps = desc.caps;
+                task.pre_start_hook = desc.pre_start_hook;
+                task.set_tls_size(desc.tls_size);
+                task
             })
             .collect();
         let kernel = Kernel::new(tasks)?;
         Ok(kernel)
     }
 
-    pub(crate) fn new(tasks: Vec<Task, 5>) -> Result<Self, KernelError> {
+    pub(crate) fn new(tasks: Vec<Task, TASK_CAPACITY>) -> Result<Self, KernelError> {
         let current_thread = ThreadTime {
             tcb_ref: ThreadRef(0),
             time: 20,
             loaned_tcb: None,
+            priority: 0,
         };
         Ok(Kernel {
             scheduler: Scheduler {
@@ -91,13 +130,35 @@ impl Kernel {
                 current_thread,
                 exhausted_threads: List::new(),
                 wait_queue: BinaryHeap::default(),
+                on_shutdown: None,
+                live_thread_count: 0,
+                idle_boost: false,
+                #[cfg(feature = "heap_defrag")]
+                heap_defrag: None,
+                ticks: 0,
             },
             registry: Registry::default(),
             epoch: 0,
             tasks,
+            irqs: heapless::FnvIndexMap::new(),
+            irq_caps: heapless::FnvIndexMap::new(),
+            devices: DeviceMap::default(),
+            security: SecurityConfig::default(),
         })
     }
 
+    /// Returns how much of the kernel's fixed size tables are in use
+    pub fn diagnostics(&self) -> KernelDiagnostics {
+        KernelDiagnostics {
+            tcb_used: self.scheduler.tcbs.len(),
+            tcb_capacity: TCB_CAPACITY,
+            task_count: self.tasks.len(),
+            task_capacity: TASK_CAPACITY,
+            registry_entries: self.registry.len(),
+            registry_capacity: Registry::CAPACITY,
+        }
+    }
+
     pub(crate) fn spawn_thread(
         &mut self,
         task_ref: TaskRef,
@@ -108,18 +169,26 @@ impl Kernel {
         caps: List<CapEntry>,
     ) -> Result<ThreadRef, KernelError> {
         let epoch = self.epoch;
+        let security = self.security;
+        let is_init = !self.scheduler.tcbs.iter().any(|t| t.task == task_ref);
         let task = self.task_mut(task_ref)?;
         let entrypoint = task
             .validate_ptr(entrypoint)
             .ok_or(KernelError::InvalidEntrypoint)?;
         let entrypoint_addr = (entrypoint as *const fn() -> !).addr();
         if task.state != TaskState::Started {
-            arch::clear_mem(task);
+            arch::clear_mem(task, &security);
+            task.reset_heaps(caps.iter().map(|entry| &entry.cap));
+            if let Some(hook) = task.pre_start_hook {
+                hook(task);
+            }
+            task.state = TaskState::Started;
         }
         let stack = task.alloc_stack().ok_or(KernelError::StackExhausted)?;
+        let tls_base = task.init_tls(stack)?;
         let mut tcb = Tcb::new(
             task_ref,
-            stack,
+            tls_base,
             priority,
             budget,
             cooldown,
@@ -127,27 +196,217 @@ impl Kernel {
             epoch,
             caps,
         );
+        tcb.tls_base = tls_base;
+        tcb.is_init = is_init;
         arch::init_tcb_stack(task, &mut tcb);
         self.scheduler.spawn(tcb)
     }
 
+    /// Kills every thread in `task_ref` and respawns its init thread, returning the thread to switch to
+    ///
+    /// The task's memory is cleared before its init thread runs again. This is how both [`syscalls::PanikCall`] and
+    /// the arch's fault handlers recover from a task that can't continue.
+    pub(crate) fn restart_task(&mut self, task_ref: TaskRef) -> Result<ThreadRef, KernelError> {
+        let task = self.task_mut(task_ref)?;
+        task.state = TaskState::Pending;
+        task.reset_stack_ptr();
+        task.region_table.remove_guards();
+
+        let threads: Vec<ThreadRef, TCB_CAPACITY> = (0..TCB_CAPACITY)
+            .map(ThreadRef)
+            .filter(|t| matches!(self.scheduler.tcbs.get(**t), Some(tcb) if tcb.task == task_ref))
+            .collect();
+        let mut init = None;
+        for tcb_ref in threads {
+            let tcb = self.scheduler.exit_thread(tcb_ref)?;
+            if tcb.is_init {
+                init = Some((
+                    tcb.priority,
+                    tcb.budget,
+                    tcb.cooldown,
+                    tcb.capabilities,
+                    tcb.entrypoint,
+                ));
+            }
+        }
+        let (priority, budget, cooldown, caps, entrypoint) =
+            init.ok_or(KernelError::InitTCBNotFound)?;
+        // Safety: the entrypoint was validated when the init thread was first spawned, and is validated again
+        // by `spawn_thread`
+        let entrypoint = unsafe { TaskPtr::from_raw_parts(entrypoint, ()) };
+        self.spawn_thread(task_ref, priority, budget, cooldown, entrypoint, caps)?;
+        let next_thread = self
+            .scheduler
+            .next_thread(0)?
+            .unwrap_or_else(DomainEntry::idle);
+        self.scheduler.switch_thread(next_thread)
+    }
+
+    /// Routes `irq` to the specified thread, the thread must hold a [`Cap::Admin`]
+    pub(crate) fn claim_irq(&mut self, tcb_ref: ThreadRef, irq: u8) -> Result<(), KernelError> {
+        if !self.scheduler.get_tcb(tcb_ref)?.is_admin() {
+            return Err(KernelError::ABI(abi::Error::InvalidCap));
+        }
+        if self.irqs.contains_key(&irq) || self.irq_caps.contains_key(&u16::from(irq)) {
+            return Err(KernelError::InterruptClaimed);
+        }
+        self.irqs
+            .insert(irq, tcb_ref)
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
+        Ok(())
+    }
+
+    /// Routes `irq` to the specified thread, which should hold a [`Cap::Irq`] for it
+    ///
+    /// The interrupt stays masked until the thread first waits for it.
+    pub(crate) fn grant_irq(&mut self, tcb_ref: ThreadRef, irq: u16) -> Result<(), KernelError> {
+        let claimed = u8::try_from(irq).is_ok_and(|irq| self.irqs.contains_key(&irq));
+        if claimed || self.irq_caps.contains_key(&irq) {
+            return Err(KernelError::InterruptClaimed);
+        }
+        self.irq_caps
+            .insert(
+                irq,
+                IrqRoute {
+                    tcb_ref,
+                    pending: false,
+                    enabled: false,
+                },
+            )
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
+        Ok(())
+    }
+
+    /// Delivers `irq` to the thread that claimed it, and returns the next thread to run if it should preempt the current one
+    ///
+    /// The interrupt is masked until the thread is ready for the next one, see [`Kernel::unmask_irqs`]
+    pub(crate) fn interrupt(&mut self, irq: u16) -> Result<Option<ThreadRef>, KernelError> {
+        if let Some(route) = self.irq_caps.get_mut(&irq) {
+            arch::mask_irq(irq);
+            let tcb = self.scheduler.get_tcb(route.tcb_ref)?;
+            if matches!(tcb.state, ThreadState::WaitingIrq { irq: waiting } if waiting == irq) {
+                let priority = tcb.priority;
+                self.scheduler
+                    .unblock(route.tcb_ref, priority, SyscallReturn::copy(0))?;
+            } else {
+                route.pending = true;
+            }
+        } else {
+            let direct = u8::try_from(irq)
+                .ok()
+                .and_then(|irq| Some((irq, *self.irqs.get(&irq)?)));
+            let (irq, tcb_ref) = if let Some(direct) = direct {
+                direct
+            } else {
+                return Ok(None);
+            };
+            arch::mask_irq(irq.into());
+            self.send_inner(
+                Endpoint {
+                    tcb_ref,
+                    addr: abi::INTERRUPT_ADDR,
+                    disposable: false,
+                    max_queue_depth: 0,
+                },
+                IPCMsgBody::copy(&[irq]),
+                None,
+                None,
+            )?;
+        }
+        let current_priority = self.scheduler.current_thread.priority as usize;
+        if let Some(next_thread) = self.scheduler.next_thread(current_priority)? {
+            return self.scheduler.switch_thread(next_thread).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Unmasks every interrupt claimed by the thread
+    pub(crate) fn unmask_irqs(&self, tcb_ref: ThreadRef) {
+        for (irq, owner) in self.irqs.iter() {
+            if *owner == tcb_ref {
+                arch::unmask_irq((*irq).into());
+            }
+        }
+    }
+
     pub(crate) fn task(&self, task_ref: TaskRef) -> Result<&Task, KernelError> {
         self.tasks
             .get(task_ref.0)
-            .ok_or(KernelError::InvalidTaskRef)
+            .ok_or(KernelError::InvalidTaskRef(task_ref))
+    }
+
+    /// Returns the name of the task, or an empty string for an invalid task
+    pub fn task_name(&self, task_ref: TaskRef) -> &'static str {
+        self.task(task_ref).map(|t| t.name).unwrap_or_default()
     }
 
     pub(crate) fn task_mut(&mut self, task_ref: TaskRef) -> Result<&mut Task, KernelError> {
         self.tasks
             .get_mut(task_ref.0)
-            .ok_or(KernelError::InvalidTaskRef)
+            .ok_or(KernelError::InvalidTaskRef(task_ref))
+    }
+
+    /// Removes every thread's [`Cap::Endpoint`] to the same thread and address as `endpoint`
+    ///
+    /// This walks every capability of every thread, so it costs O(threads * caps per thread). With at most
+    /// [`TCB_CAPACITY`] threads that is fine for an occasional revocation, but it shouldn't be on a hot path.
+    pub(crate) fn revoke_endpoint(&mut self, endpoint: Endpoint) {
+        for tcb in self.scheduler.tcbs.iter_mut() {
+            tcb.remove_caps(|cap| match cap {
+                Cap::Endpoint(e) => e.tcb_ref == endpoint.tcb_ref && e.addr == endpoint.addr,
+                _ => false,
+            });
+        }
+    }
+
+    /// Checks that the endpoint behind `dest` has room for another message, failing with [`abi::Error::QueueFull`]
+    /// if it doesn't
+    ///
+    /// Syscalls check this before copying the message out of the caller, so a full queue doesn't cost an allocation
+    /// or unmap a page that is being sent.
+    pub(crate) fn check_queue_depth(&self, dest: CapRef) -> Result<(), KernelError> {
+        match self.scheduler.current_thread()?.cap(dest) {
+            Ok(Cap::Endpoint(endpoint)) => self.endpoint_has_room(endpoint),
+            _ => Err(KernelError::ABI(abi::Error::InvalidCap)),
+        }
+    }
+
+    fn endpoint_has_room(&self, endpoint: &Endpoint) -> Result<(), KernelError> {
+        if endpoint.max_queue_depth == 0 {
+            return Ok(());
+        }
+        let dest_tcb = self.scheduler.get_tcb(endpoint.tcb_ref)?;
+        if dest_tcb.req_queue.depth(endpoint.addr) >= endpoint.max_queue_depth as usize {
+            return Err(KernelError::ABI(abi::Error::QueueFull));
+        }
+        Ok(())
     }
 
     /// Sends a message from the current thread to the specified endpoint
     /// This function takes a [`CapRef`] and expects it to be an [`Endpoint`]
     pub(crate) fn send(&mut self, dest: CapRef, msg: IPCMsgBody) -> Result<(), KernelError> {
         let endpoint = self.scheduler.current_thread_mut()?.endpoint(dest)?;
-        self.send_inner(endpoint, msg, None)
+        self.send_inner(endpoint, msg, None, None)
+    }
+
+    /// Sends a copy of the current thread's capability `cap_ref` to the endpoint `dest`
+    ///
+    /// The copy is added to the receiving thread's capabilities when it receives the message. Reply endpoints and
+    /// interrupts are tied to the thread holding them, and heaps to the RAM of the task holding them, so they can't be
+    /// delegated.
+    pub(crate) fn send_cap(&mut self, dest: CapRef, cap_ref: CapRef) -> Result<(), KernelError> {
+        let cap = match self.scheduler.current_thread()?.cap(cap_ref) {
+            Ok(Cap::Endpoint(endpoint)) if endpoint.disposable => {
+                return Err(KernelError::ABI(abi::Error::NotSupported))
+            }
+            Ok(Cap::DirectInterrupt(_) | Cap::Irq { .. } | Cap::Heap { .. }) => {
+                return Err(KernelError::ABI(abi::Error::NotSupported))
+            }
+            Ok(cap) => cap.clone(),
+            Err(_) => return Err(KernelError::ABI(abi::Error::InvalidCap)),
+        };
+        let endpoint = self.scheduler.current_thread_mut()?.endpoint(dest)?;
+        self.send_inner(endpoint, IPCMsgBody::copy(&[]), None, Some(cap))
     }
 
     fn send_inner(
@@ -155,20 +414,35 @@ impl Kernel {
         endpoint: Endpoint,
         body: IPCMsgBody,
         reply_endpoint: Option<Endpoint>,
+        cap: Option<Cap>,
     ) -> Result<(), KernelError> {
+        self.endpoint_has_room(&endpoint)?;
+        self.scheduler
+            .current_thread_mut()?
+            .record_ipc(body.bytes().len());
+        if endpoint.disposable {
+            // replying ends the call from `endpoint.tcb_ref`, so the current thread is only boosted by the
+            // callers still blocked on it, see [`Kernel::call`]
+            let src_ref = self.scheduler.current_thread.tcb_ref;
+            let boost = self.caller_priority(src_ref, endpoint.tcb_ref)?;
+            let src = self.scheduler.current_thread_mut()?;
+            src.boosted_priority = boost.filter(|&p| p > src.priority);
+            let priority = src.effective_priority();
+            self.scheduler.current_thread.priority = priority as u8;
+        }
         let dest_tcb = self.scheduler.get_tcb_mut(endpoint.tcb_ref)?;
         let is_call = reply_endpoint.is_some();
-        dest_tcb.req_queue.push_back(Box::pin(IPCMsg {
+        dest_tcb.req_queue.push_back(IPCMsg::alloc(IPCMsg {
             _links: Links::default(),
             reply_endpoint,
             body,
+            cap,
             addr: endpoint.addr,
         }));
 
-        if let ThreadState::Waiting { ref recv_req } = dest_tcb.state {
-            let addr = recv_req.mask;
-            if addr & endpoint.addr == endpoint.addr {
-                let recv_req = if let ThreadState::Waiting { recv_req } =
+        if let ThreadState::Waiting { ref recv_req, .. } = dest_tcb.state {
+            if recv_req.matches(endpoint.addr) {
+                let recv_req = if let ThreadState::Waiting { recv_req, .. } =
                     core::mem::replace(&mut dest_tcb.state, ThreadState::Ready)
                 {
                     recv_req
@@ -178,7 +452,7 @@ impl Kernel {
                 let task = self
                     .tasks
                     .get_mut(dest_tcb.task.0)
-                    .ok_or(KernelError::InvalidTaskRef)?;
+                    .ok_or(KernelError::InvalidTaskRef(dest_tcb.task))?;
                 if let RecvRes::NotFound(_) = dest_tcb.recv(task, recv_req)? {
                     panic!("recv not found")
                 }
@@ -194,8 +468,34 @@ impl Kernel {
         Ok(())
     }
 
+    /// Returns the highest priority of the threads blocked calling `tcb_ref`, other than `except`
+    ///
+    /// These are the callers whose message is still queued, and those `tcb_ref` holds a reply endpoint for.
+    fn caller_priority(
+        &self,
+        tcb_ref: ThreadRef,
+        except: ThreadRef,
+    ) -> Result<Option<usize>, KernelError> {
+        let tcb = self.scheduler.get_tcb(tcb_ref)?;
+        let queued = tcb.req_queue.iter().filter_map(|msg| msg.reply_endpoint);
+        let received = tcb.capabilities.iter().filter_map(|entry| match entry.cap {
+            Cap::Endpoint(endpoint) if endpoint.disposable => Some(endpoint),
+            _ => None,
+        });
+        Ok(queued
+            .chain(received)
+            .map(|endpoint| endpoint.tcb_ref)
+            .filter(|&caller| caller != except)
+            .filter_map(|caller| self.scheduler.get_tcb(caller).ok())
+            .map(|caller| caller.effective_priority())
+            .max())
+    }
+
     /// Sends a message to an endpoint, and pauses the current thread's execution till a response is
     /// received
+    ///
+    /// Until it replies, the receiving thread runs at the caller's priority if that is higher than its own,
+    /// so a medium priority thread can't starve the caller by preempting it.
     pub(crate) fn call(
         &mut self,
         dest: CapRef,
@@ -208,10 +508,18 @@ impl Kernel {
             tcb_ref: src_ref,
             addr: endpoint.addr | 0x80000000,
             disposable: true,
+            max_queue_depth: 0,
         };
         recv_req.mask = reply_endpoint.addr;
-        self.send_inner(endpoint, msg, Some(reply_endpoint))?;
-        self.scheduler.wait(recv_req, true) // last bit is flipped for reply TODO(sphw): replace with proper bitmask
+        // checked before boosting, since a full queue means the destination won't be replying
+        self.endpoint_has_room(&endpoint)?;
+        let src_priority = self.scheduler.current_thread()?.effective_priority();
+        let dest_tcb = self.scheduler.get_tcb_mut(endpoint.tcb_ref)?;
+        if src_priority > dest_tcb.effective_priority() {
+            dest_tcb.boosted_priority = Some(src_priority);
+        }
+        self.send_inner(endpoint, msg, Some(reply_endpoint), None)?;
+        self.scheduler.wait(recv_req, None, true) // last bit is flipped for reply TODO(sphw): replace with proper bitmask
     }
 
     pub(crate) fn start(&mut self) -> ! {
@@ -259,6 +567,79 @@ impl Kernel {
             abi::SyscallFn::Listen => {
                 ListenCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
             }
+            abi::SyscallFn::Reboot => {
+                RebootCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            #[cfg(debug_assertions)]
+            abi::SyscallFn::ReadMemory => ReadMemoryCall::from_args(args)
+                .exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self),
+            #[cfg(debug_assertions)]
+            abi::SyscallFn::WriteMemory => WriteMemoryCall::from_args(args)
+                .exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self),
+            #[cfg(not(debug_assertions))]
+            abi::SyscallFn::ReadMemory | abi::SyscallFn::WriteMemory => {
+                Err(KernelError::ABI(abi::Error::InvalidCap))
+            }
+            abi::SyscallFn::Timestamp => {
+                TimestampCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::MapDevice => {
+                MapDeviceCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Unmap => {
+                UnmapCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::SendVec => {
+                SendVecCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::GetTaskInfo => GetTaskInfoCall::from_args(args)
+                .exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self),
+            abi::SyscallFn::SemWait => {
+                SemWaitCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::SemPost => {
+                SemPostCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::PollCaps => {
+                PollCapsCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Yield => {
+                YieldCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Sleep => {
+                SleepCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Spawn => {
+                SpawnCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Revoke => {
+                RevokeCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::MapShared => {
+                MapSharedCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Notify => {
+                NotifyCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::WaitNotify => WaitNotifyCall::from_args(args)
+                .exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self),
+            abi::SyscallFn::Stats => {
+                StatsCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::StackUsage => StackUsageCall::from_args(args)
+                .exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self),
+            abi::SyscallFn::WaitIrq => {
+                WaitIrqCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::AckIrq => {
+                AckIrqCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::SendCap => {
+                SendCapCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
+            abi::SyscallFn::Alloc => {
+                AllocCall::from_args(args).exec(index.get(SyscallIndex::SYSCALL_ARG_TYPE), self)
+            }
         }
     }
 }
@@ -266,26 +647,90 @@ impl Kernel {
 #[derive(Debug)]
 pub enum KernelError {
     InvalidPriority,
-    InvalidThreadRef,
-    InvalidTaskRef,
+    InvalidThreadRef(ThreadRef),
+    InvalidTaskRef(TaskRef),
     InvalidEntrypoint,
     TooManyThreads,
-    InvalidCapRef,
+    InvalidCapRef(CapRef),
     StackExhausted,
     InvalidTaskPtr,
     InitTCBNotFound,
+    InterruptClaimed,
     ABI(abi::Error),
 }
 
+impl Format for KernelError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            KernelError::InvalidPriority => {
+                defmt::write!(f, "InvalidPriority: priority must be below 8")
+            }
+            KernelError::InvalidThreadRef(tcb_ref) => {
+                defmt::write!(
+                    f,
+                    "InvalidThreadRef({}): no thread with that ref",
+                    tcb_ref.0
+                )
+            }
+            KernelError::InvalidTaskRef(task_ref) => {
+                defmt::write!(f, "InvalidTaskRef({}): no task with that index", task_ref.0)
+            }
+            KernelError::InvalidEntrypoint => {
+                defmt::write!(
+                    f,
+                    "InvalidEntrypoint: entrypoint is outside of the task's regions"
+                )
+            }
+            KernelError::TooManyThreads => defmt::write!(f, "TooManyThreads: thread table is full"),
+            KernelError::InvalidCapRef(cap_ref) => {
+                defmt::write!(
+                    f,
+                    "InvalidCapRef({=usize:#x}): thread doesn't hold that cap",
+                    cap_ref.0
+                )
+            }
+            KernelError::StackExhausted => {
+                defmt::write!(f, "StackExhausted: no stack space left in task")
+            }
+            KernelError::InvalidTaskPtr => {
+                defmt::write!(
+                    f,
+                    "InvalidTaskPtr: pointer is outside of the task's regions"
+                )
+            }
+            KernelError::InitTCBNotFound => defmt::write!(f, "InitTCBNotFound: no init thread"),
+            KernelError::InterruptClaimed => {
+                defmt::write!(f, "InterruptClaimed: interrupt is routed to another thread")
+            }
+            KernelError::ABI(err) => defmt::write!(f, "ABI({})", err),
+        }
+    }
+}
+
 impl From<abi::Error> for KernelError {
     fn from(v: abi::Error) -> Self {
         Self::ABI(v)
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct TaskRef(pub usize);
 
+/// How close the kernel's fixed size tables are to full, returned by [`Kernel::diagnostics`]
+///
+/// Spawning a thread past `tcb_capacity` fails with [`KernelError::TooManyThreads`], and listening on a port or
+/// creating a semaphore past `registry_capacity` fails with [`abi::Error::BufferOverflow`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Format)]
+pub struct KernelDiagnostics {
+    pub tcb_used: usize,
+    pub tcb_capacity: usize,
+    pub task_count: usize,
+    pub task_capacity: usize,
+    /// The ports listened on, plus the semaphores created
+    pub registry_entries: usize,
+    pub registry_capacity: usize,
+}
+
 #[derive(PartialEq, Eq, Debug, Format)]
 pub(crate) struct DomainEntry {
     tcb_ref: ThreadRef,
@@ -328,29 +773,112 @@ impl DomainEntry {
 enum ThreadState {
     Waiting {
         recv_req: RecvReq<'static>,
+        /// The tick the wait times out at, if it was started with a timeout
+        deadline: Option<u64>,
+    },
+    /// Blocked on a semaphore until another thread posts to it
+    Blocked,
+    /// Suspended by [`abi::SyscallFn::Sleep`] until the scheduler reaches `wake_tick`
+    Sleeping {
+        wake_tick: u64,
+    },
+    /// Waiting with [`abi::SyscallFn::WaitNotify`] for any of the notification bits in `mask`
+    WaitingNotify {
+        mask: u32,
+    },
+    /// Waiting with [`abi::SyscallFn::WaitIrq`] for `irq` to fire
+    WaitingIrq {
+        irq: u16,
     },
     Ready,
     #[allow(dead_code)]
     Running,
 }
 
+/// Where an interrupt granted with [`Cap::Irq`] is delivered, see [`Kernel::grant_irq`]
+struct IrqRoute {
+    tcb_ref: ThreadRef,
+    /// Set when the interrupt fires while the thread isn't waiting for it
+    pending: bool,
+    /// Whether the interrupt has been unmasked, which first happens when the thread waits for it
+    enabled: bool,
+}
+
 struct CapEntry {
     _links: list::Links<CapEntry>,
     cap: Cap,
 }
 
+impl CapEntry {
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *const CapEntry {
+        self
+    }
+
+    /// Returns the [`CapRef`] userspace uses to refer to this entry
+    ///
+    /// The entry's address is used as an opaque token, userspace never dereferences it. Entries are pinned in a
+    /// thread's capability list, so the address is stable until the entry is removed.
+    #[inline]
+    pub(crate) fn cap_ref(&self) -> CapRef {
+        CapRef(self.as_ptr().addr())
+    }
+}
+
 pub(crate) struct IPCMsg {
     _links: list::Links<IPCMsg>,
     addr: usize,
     reply_endpoint: Option<Endpoint>,
     body: IPCMsgBody,
+    /// A capability being delegated to the receiver, see [`Kernel::send_cap`]
+    cap: Option<Cap>,
 }
 
+impl IPCMsg {
+    #[cfg(not(feature = "static_ipc_pool"))]
+    fn alloc(self) -> Pin<Box<IPCMsg>> {
+        Box::pin(self)
+    }
+
+    #[cfg(feature = "static_ipc_pool")]
+    fn alloc(self) -> msg_pool::MsgHandle {
+        msg_pool::MsgHandle::new(self)
+    }
+}
+
+/// Messages up to this length are stored inline in [`IPCMsgBody::Short`], rather than on the heap
+const SHORT_MSG_LEN: usize = 8;
+
 enum IPCMsgBody {
+    Short { buf: [u8; SHORT_MSG_LEN], len: u8 },
     Buf(Box<[u8]>),
     Page(&'static [u8]),
 }
 
+impl IPCMsgBody {
+    /// Copies `bytes` into a message, only allocating if it is longer than [`SHORT_MSG_LEN`]
+    fn copy(bytes: &[u8]) -> Self {
+        if bytes.len() <= SHORT_MSG_LEN {
+            let mut buf = [0; SHORT_MSG_LEN];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            IPCMsgBody::Short {
+                buf,
+                len: bytes.len() as u8,
+            }
+        } else {
+            IPCMsgBody::Buf(Box::from(bytes))
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            IPCMsgBody::Short { buf, len } => &buf[..*len as usize],
+            IPCMsgBody::Buf(buf) => buf,
+            IPCMsgBody::Page(slice) => slice,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! linked_impl {
     ($t: ty) => {
@@ -376,7 +904,8 @@ macro_rules! linked_impl {
     };
 }
 
-linked_impl! {IPCMsg }
+#[cfg(not(feature = "static_ipc_pool"))]
+linked_impl! { IPCMsg }
 linked_impl! { DomainEntry }
 linked_impl! { CapEntry }
 
@@ -386,10 +915,27 @@ pub struct TaskDesc {
     pub stack_space: Range<usize>,
     pub init_stack_size: usize,
     pub regions: &'static [Range<usize>],
+    /// Capabilities given to the first thread spawned in this task, in addition to those set on its [`ThreadBuilder`]
+    pub caps: &'static [Cap],
+    /// Called before the first thread in this task is spawned, and again if the task is restarted after a panic
+    ///
+    /// This runs in the kernel, so it can configure peripherals the task itself can't access, or loan it extra memory
+    /// with [`Task::loan_mem`]
+    pub pre_start_hook: Option<fn(&mut Task)>,
+    /// Bytes of thread-local storage reserved at the top of each thread's stack, out of `init_stack_size`
+    ///
+    /// The storage is zeroed when the thread is spawned, and its address is passed in `tp` on RISC-V and `r9` on
+    /// cortex-m. Tasks using it on cortex-m must be built with `r9` reserved. It is rounded up to a multiple of 8
+    /// bytes on cortex-m and 16 on RISC-V, so the stack below it stays aligned.
+    pub tls_size: usize,
 }
 
 impl TaskDesc {
     fn region_table(&self) -> RegionTable {
+        assert!(
+            self.regions.len() <= regions::MAX_REGIONS,
+            "task has more regions than the MPU supports"
+        );
         RegionTable {
             regions: self
                 .regions
@@ -0,0 +1,228 @@
+//! A statically allocated pool of [`IPCMsg`] nodes
+//!
+//! When the `static_ipc_pool` feature is enabled, IPC messages are allocated from [`MSG_POOL`]
+//! rather than the heap. If the pool is exhausted we fall back to the heap, so a pool that is too
+//! small will only cost allocations, not messages. The pool size is set at build time using the
+//! `K5_IPC_POOL_SIZE` environment variable, and defaults to 16.
+use crate::IPCMsg;
+use alloc::boxed::Box;
+use core::{mem::MaybeUninit, ops::Deref, ptr::NonNull};
+
+pub(crate) const POOL_SIZE: usize = parse_pool_size(option_env!("K5_IPC_POOL_SIZE"));
+
+static mut MSG_POOL: StaticMsgPool<POOL_SIZE> = StaticMsgPool::new();
+
+const fn parse_pool_size(size: Option<&str>) -> usize {
+    let size = match size {
+        Some(size) => size.as_bytes(),
+        None => return 16,
+    };
+    let mut i = 0;
+    let mut n = 0;
+    while i < size.len() {
+        assert!(
+            size[i].is_ascii_digit(),
+            "K5_IPC_POOL_SIZE must be an integer"
+        );
+        n = n * 10 + (size[i] - b'0') as usize;
+        i += 1;
+    }
+    n
+}
+
+pub(crate) struct StaticMsgPool<const N: usize> {
+    slots: [MaybeUninit<IPCMsg>; N],
+    free_list: [usize; N],
+    free: usize,
+}
+
+impl<const N: usize> StaticMsgPool<N> {
+    const fn new() -> Self {
+        let mut free_list = [0; N];
+        let mut i = 0;
+        while i < N {
+            free_list[i] = i;
+            i += 1;
+        }
+        Self {
+            slots: MaybeUninit::uninit_array(),
+            free_list,
+            free: N,
+        }
+    }
+
+    fn alloc(&mut self, msg: IPCMsg) -> Result<NonNull<IPCMsg>, IPCMsg> {
+        if self.free == 0 {
+            return Err(msg);
+        }
+        self.free -= 1;
+        let slot = &mut self.slots[self.free_list[self.free]];
+        Ok(NonNull::from(slot.write(msg)))
+    }
+
+    fn index_of(&self, ptr: NonNull<IPCMsg>) -> Option<usize> {
+        let start = self.slots.as_ptr().addr();
+        let addr = ptr.as_ptr().addr();
+        if addr < start || addr >= start + core::mem::size_of_val(&self.slots) {
+            return None;
+        }
+        Some((addr - start) / core::mem::size_of::<IPCMsg>())
+    }
+
+    /// Drops the message and returns its slot to the pool, returns false if the message was not allocated by the pool
+    ///
+    /// # Safety
+    /// `ptr` must point to a live message owned by the caller, which is not used again after this call
+    unsafe fn free(&mut self, ptr: NonNull<IPCMsg>) -> bool {
+        let i = if let Some(i) = self.index_of(ptr) {
+            i
+        } else {
+            return false;
+        };
+        self.slots[i].assume_init_drop();
+        self.free_list[self.free] = i;
+        self.free += 1;
+        true
+    }
+
+    /// Allocates `msg` in the pool, or on the heap if the pool is full
+    fn alloc_or_box(&mut self, msg: IPCMsg) -> NonNull<IPCMsg> {
+        self.alloc(msg)
+            .unwrap_or_else(|msg| NonNull::from(Box::leak(Box::new(msg))))
+    }
+
+    /// Drops a message allocated with [`StaticMsgPool::alloc_or_box`], freeing it through a `Box` if it
+    /// isn't in the pool
+    ///
+    /// # Safety
+    /// `ptr` must point to a live message owned by the caller, which is not used again after this call
+    unsafe fn free_or_box(&mut self, ptr: NonNull<IPCMsg>) {
+        if !self.free(ptr) {
+            drop(Box::from_raw(ptr.as_ptr()));
+        }
+    }
+}
+
+/// An owning handle to an [`IPCMsg`], allocated in [`MSG_POOL`] or on the heap if the pool is full
+pub(crate) struct MsgHandle(NonNull<IPCMsg>);
+
+impl MsgHandle {
+    pub(crate) fn new(msg: IPCMsg) -> Self {
+        // Safety: The kernel is single threaded, so we are the only ones accessing `MSG_POOL`
+        MsgHandle(unsafe { MSG_POOL.alloc_or_box(msg) })
+    }
+}
+
+impl Deref for MsgHandle {
+    type Target = IPCMsg;
+
+    fn deref(&self) -> &Self::Target {
+        // Safety: the handle owns the message, so the pointer is valid for the handle's lifetime
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl Drop for MsgHandle {
+    fn drop(&mut self) {
+        // Safety: The kernel is single threaded, so we are the only ones accessing `MSG_POOL`.
+        // The handle owns the message, so it hasn't been freed yet
+        unsafe { MSG_POOL.free_or_box(self.0) }
+    }
+}
+
+// Safety: messages are never moved while owned by a handle, and we uphold the other
+// guarantees outlined in [`cordyceps::Linked`] in the same way as `linked_impl!`
+unsafe impl cordyceps::Linked<cordyceps::list::Links<IPCMsg>> for IPCMsg {
+    type Handle = MsgHandle;
+
+    fn into_ptr(r: Self::Handle) -> NonNull<Self> {
+        let ptr = r.0;
+        core::mem::forget(r);
+        ptr
+    }
+
+    unsafe fn from_ptr(ptr: NonNull<Self>) -> Self::Handle {
+        MsgHandle(ptr)
+    }
+
+    unsafe fn links(target: NonNull<Self>) -> NonNull<cordyceps::list::Links<Self>> {
+        target.cast()
+    }
+}
+
+#[cfg(all(test, feature = "static_ipc_pool"))]
+mod tests {
+    use super::StaticMsgPool;
+    use crate::{IPCMsg, IPCMsgBody};
+    use alloc::boxed::Box;
+
+    fn msg(id: u8) -> IPCMsg {
+        IPCMsg {
+            _links: Default::default(),
+            addr: 0,
+            reply_endpoint: None,
+            body: IPCMsgBody::Buf(Box::new([id])),
+            cap: None,
+        }
+    }
+
+    fn id(msg: &IPCMsg) -> u8 {
+        match &msg.body {
+            IPCMsgBody::Buf(buf) => buf[0],
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_exhaust_falls_back_to_box() {
+        let mut pool = StaticMsgPool::<2>::new();
+        let a = pool.alloc_or_box(msg(0));
+        let b = pool.alloc_or_box(msg(1));
+        assert!(pool.index_of(a).is_some() && pool.index_of(b).is_some());
+        assert_eq!(pool.free, 0);
+        let c = pool.alloc_or_box(msg(2));
+        assert_eq!(
+            pool.index_of(c),
+            None,
+            "a full pool should fall back to the heap"
+        );
+        assert_eq!(unsafe { id(c.as_ref()) }, 2);
+        unsafe {
+            pool.free_or_box(a);
+            pool.free_or_box(b);
+            pool.free_or_box(c);
+        }
+        assert_eq!(pool.free, 2);
+    }
+
+    #[test]
+    fn test_free_reuses_slots() {
+        let mut pool = StaticMsgPool::<2>::new();
+        let a = pool.alloc_or_box(msg(0));
+        let b = pool.alloc_or_box(msg(1));
+        assert_ne!(pool.index_of(a), pool.index_of(b));
+        assert!(unsafe { pool.free(a) });
+        assert_eq!(pool.free, 1);
+        let c = pool.alloc_or_box(msg(2));
+        assert_eq!(c, a, "the freed slot should be reused");
+        assert_eq!(unsafe { id(c.as_ref()) }, 2);
+        assert_eq!(unsafe { id(b.as_ref()) }, 1);
+        unsafe {
+            pool.free_or_box(b);
+            pool.free_or_box(c);
+        }
+        assert_eq!(pool.free, 2);
+    }
+
+    #[test]
+    fn test_fallback_drop() {
+        let mut pool = StaticMsgPool::<1>::new();
+        let a = pool.alloc_or_box(msg(0));
+        let b = pool.alloc_or_box(msg(1));
+        // the fallback isn't the pool's to free, so it must be dropped through its `Box`
+        unsafe { pool.free_or_box(b) };
+        assert_eq!(pool.free, 0, "freeing the fallback shouldn't return a slot");
+        unsafe { pool.free_or_box(a) };
+        assert_eq!(pool.free, 1);
+    }
+}
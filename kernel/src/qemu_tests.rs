@@ -0,0 +1,157 @@
+//! Integration tests that run the kernel on the [`arch::qemu`] backend, see its docs for how to run them
+use super::*;
+
+#[test]
+fn test_qemu_call() {
+    use abi::{SyscallDataType, SyscallFn, SyscallReturnType};
+    use arch::qemu;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Mutex, OnceLock};
+    use std::time::Duration;
+
+    static SERVER_MEM: OnceLock<Range<usize>> = OnceLock::new();
+    static CLIENT_MEM: OnceLock<Range<usize>> = OnceLock::new();
+    static IDLE_MEM: OnceLock<Range<usize>> = OnceLock::new();
+    static CLIENT_CAP: AtomicUsize = AtomicUsize::new(0);
+    static REPLY: OnceLock<Mutex<mpsc::Sender<Vec<u8>>>> = OnceLock::new();
+    static SERVER_TP: AtomicUsize = AtomicUsize::new(0);
+
+    fn syscall(f: SyscallFn, args: &mut SyscallArgs) -> Result<SyscallReturn, abi::Error> {
+        let index = SyscallIndex::new()
+            .with(SyscallIndex::SYSCALL_FN, f)
+            .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy);
+        let ret = qemu::syscall(index, args);
+        match ret.get(SyscallReturn::SYSCALL_TYPE) {
+            SyscallReturnType::Error => {
+                Err(abi::Error::from(ret.get(SyscallReturn::SYSCALL_LEN) as u8))
+            }
+            _ => Ok(ret),
+        }
+    }
+
+    fn server() -> ! {
+        // the server is the highest priority thread, so it is the root thread the kernel starts first
+        SERVER_TP.store(qemu::thread_local_ptr(), Ordering::SeqCst);
+        let mem = SERVER_MEM.get().unwrap().start;
+        let (buf, resp) = (mem, mem + 0x100);
+        loop {
+            let mut args = SyscallArgs {
+                arg1: buf,
+                arg2: 4,
+                arg4: resp,
+                ..Default::default()
+            };
+            syscall(SyscallFn::Recv, &mut args).expect("recv failed");
+            let mut resp_bytes = [0u8; core::mem::size_of::<abi::RecvResp>()];
+            qemu::read_mem(resp, &mut resp_bytes);
+            // Safety: the kernel wrote a valid `RecvResp` to `resp`
+            let resp: abi::RecvResp =
+                unsafe { core::ptr::read_unaligned(resp_bytes.as_ptr() as *const _) };
+            let mut msg = [0u8; 4];
+            qemu::read_mem(buf, &mut msg);
+            assert_eq!(&msg, b"ping");
+            qemu::write_mem(buf, b"pong");
+            let mut args = SyscallArgs {
+                arg1: buf,
+                arg2: 4,
+                arg3: *resp.cap.expect("call should have a reply cap"),
+                ..Default::default()
+            };
+            syscall(SyscallFn::Send, &mut args).expect("reply failed");
+        }
+    }
+
+    fn client() -> ! {
+        let mem = CLIENT_MEM.get().unwrap().start;
+        let (req, out, resp) = (mem, mem + 0x10, mem + 0x100);
+        qemu::write_mem(req, b"ping");
+        let mut args = SyscallArgs {
+            arg1: req,
+            arg2: 4,
+            arg3: CLIENT_CAP.load(Ordering::SeqCst),
+            arg4: resp,
+            arg5: out,
+            arg6: 4,
+        };
+        let mut reply = vec![0u8; 4];
+        if syscall(SyscallFn::Call, &mut args).is_ok() {
+            qemu::read_mem(out, &mut reply);
+        } else {
+            reply.clear();
+        }
+        REPLY.get().unwrap().lock().unwrap().send(reply).unwrap();
+        loop {
+            std::thread::park();
+        }
+    }
+
+    fn idle() -> ! {
+        loop {
+            std::thread::park();
+        }
+    }
+
+    fn desc(
+        name: &'static str,
+        entry: fn() -> !,
+        mem: &OnceLock<Range<usize>>,
+        tls_size: usize,
+    ) -> TaskDesc {
+        let code = qemu::entrypoint(entry);
+        let mem = mem.get_or_init(|| qemu::alloc_region(0x1000)).clone();
+        TaskDesc {
+            name,
+            entrypoint: code.start,
+            stack_space: mem.start + 0x800..mem.end,
+            init_stack_size: 0x200,
+            regions: Box::leak(Box::new([code, mem])),
+            caps: &[],
+            pre_start_hook: None,
+            tls_size,
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    REPLY.set(Mutex::new(tx)).unwrap();
+    std::thread::spawn(|| {
+        let tasks = Box::leak(Box::new([
+            desc("server", server, &SERVER_MEM, 0x20),
+            desc("client", client, &CLIENT_MEM, 0),
+            desc("idle", idle, &IDLE_MEM, 0),
+        ]));
+        let mut kernel = KernelBuilder::new(tasks);
+        // Safety: each index is a task in `tasks`
+        let (server, client, idle) = unsafe {
+            (
+                ThreadBuilder::new(0).priority(2),
+                ThreadBuilder::new(1).priority(1),
+                ThreadBuilder::new(2),
+            )
+        };
+        let server = kernel.thread(server);
+        let client = kernel.thread(client);
+        kernel.endpoint(client, server, 0);
+        kernel.idle_thread(idle);
+        // Safety: the kernel hasn't started yet, so nothing else is using it
+        let kern = unsafe { &*arch::kernel() };
+        let cap = kern
+            .scheduler
+            .get_tcb(client)
+            .unwrap()
+            .capabilities
+            .back()
+            .unwrap();
+        CLIENT_CAP.store(*cap.cap_ref(), Ordering::SeqCst);
+        kernel.start()
+    });
+    let reply = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("client never got a reply");
+    assert_eq!(reply, b"pong");
+    // the TLS sits at the top of the server's first stack, which starts 0x200 bytes into its stack space
+    let server_mem = SERVER_MEM.get().unwrap();
+    assert_eq!(
+        SERVER_TP.load(Ordering::SeqCst),
+        server_mem.start + 0x800 + 0x200 - 0x20
+    );
+}
@@ -3,68 +3,253 @@ use enumflags2::{bitflags, BitFlags};
 
 use crate::KernelError;
 
-#[derive(Clone, Default)]
-pub struct RegionTable {
-    pub regions: heapless::Vec<Region, 8>,
+/// A section of a task's ELF, the ranges are generated by codegen as `TASK_<NAME>_TEXT`
+#[cfg(feature = "exec_validation")]
+pub struct ElfSection {
+    pub name: &'static str,
+    pub range: Range<usize>,
+}
+
+/// The number of MPU regions a task can use
+#[cfg(not(any(feature = "cortex_m23", feature = "mpu_regions_16")))]
+pub const MAX_REGIONS: usize = 8;
+/// The number of MPU regions a task can use, ARMv8-M baseline parts only have 4
+#[cfg(feature = "cortex_m23")]
+pub const MAX_REGIONS: usize = 4;
+/// The number of MPU regions a task can use, on ARMv8-M parts whose MPU has 16
+#[cfg(all(feature = "mpu_regions_16", not(feature = "cortex_m23")))]
+pub const MAX_REGIONS: usize = 16;
+
+/// The most regions the memory protection hardware can hold, no [`RegionTable`] can be larger
+#[cfg(feature = "cortex_m23")]
+pub const MPU_REGIONS: usize = 4;
+/// The most regions the memory protection hardware can hold, no [`RegionTable`] can be larger
+#[cfg(feature = "cortex_m7m")]
+pub const MPU_REGIONS: usize = 8;
+/// The most regions the memory protection hardware can hold, no [`RegionTable`] can be larger
+#[cfg(all(
+    feature = "cortex_m",
+    not(any(feature = "cortex_m23", feature = "cortex_m7m"))
+))]
+pub const MPU_REGIONS: usize = 16;
+/// The most regions the memory protection hardware can hold, no [`RegionTable`] can be larger
+///
+/// RISC-V uses two PMP entries per region, and most parts have 16
+#[cfg(not(feature = "cortex_m"))]
+pub const MPU_REGIONS: usize = 8;
+
+const _: () = assert!(
+    MAX_REGIONS <= MPU_REGIONS,
+    "mpu_regions_16 is only supported on ARMv8-M mainline"
+);
+
+/// True when MPU regions are aligned power of two blocks, which can't describe every range
+const POW2_REGIONS: bool = cfg!(feature = "cortex_m7m");
+
+/// Returns true if the MPU can protect exactly `region`, without rounding it out
+#[cfg(feature = "cortex_m7m")]
+fn representable(region: &Region) -> bool {
+    crate::arch::cortex_m7m::is_representable(region)
+}
+
+/// Returns true if the MPU can protect exactly `region`, without rounding it out
+#[cfg(not(feature = "cortex_m7m"))]
+fn representable(_region: &Region) -> bool {
+    true
+}
+
+/// The size of the no-access guard at the bottom of each thread's stack, the smallest region the MPU supports
+pub const STACK_GUARD_LEN: usize = 32;
+
+/// The regions a task can access, which are programmed into the MPU whenever one of its threads runs
+///
+/// Tasks use the default capacity of [`MAX_REGIONS`], tables with another capacity can be made with a different
+/// `N`, as long as it isn't more than [`MPU_REGIONS`].
+#[derive(Clone)]
+pub struct RegionTable<const N: usize = MAX_REGIONS> {
+    pub(crate) regions: heapless::Vec<Region, N>,
+}
+
+impl<const N: usize> Default for RegionTable<N> {
+    fn default() -> Self {
+        let () = Self::CAPACITY_CHECK;
+        Self {
+            regions: heapless::Vec::new(),
+        }
+    }
 }
 
 #[allow(dead_code)]
-impl RegionTable {
+impl<const N: usize> RegionTable<N> {
+    /// Fails to compile if the table can't fit in the MPU, evaluated by the table's methods
+    const CAPACITY_CHECK: () = assert!(N <= MPU_REGIONS, "region table is larger than the MPU");
+
+    /// The number of regions in the table
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Inserts a region into the table, overwriting the attributes of any overlapping regions
+    ///
+    /// Existing regions that are entirely covered by the new region are dropped, and those that
+    /// partially overlap are trimmed or split. If the new region borders a region with identical
+    /// attributes the two are merged into a single entry.
+    ///
+    /// On ARMv7-M the MPU can only describe aligned power of two blocks, less some of their eighths, so a push that
+    /// would leave a region it can't describe fails with [`abi::Error::NotSupported`] and leaves the table untouched.
     pub fn push(&mut self, region: Region) -> Result<(), KernelError> {
+        let () = Self::CAPACITY_CHECK;
+        if !POW2_REGIONS {
+            return self.insert(region);
+        }
+        let mut table = self.clone();
+        table.insert(region)?;
+        if !table.regions.iter().all(representable) {
+            return Err(KernelError::ABI(abi::Error::NotSupported));
+        }
+        *self = table;
+        Ok(())
+    }
+
+    fn insert(&mut self, region: Region) -> Result<(), KernelError> {
+        if self.regions.iter().any(|r| {
+            r.range.start <= region.range.start
+                && r.range.end >= region.range.end
+                && r.attr.contains(region.attr)
+        }) {
+            return Ok(());
+        }
         let mut i = 0;
-        let mut inserted_at = None;
         while i < self.regions.len() {
-            if self.regions[i] == region {
+            let existing = &mut self.regions[i];
+            if existing.range.end <= region.range.start || existing.range.start >= region.range.end
+            {
                 i += 1;
                 continue;
             }
-            if inserted_at.map(|x| i > x + 2).unwrap_or_default() {
-                return Ok(());
-            }
-            let mut old_end = None;
-
-            if self.regions[i].range.contains(&region.range.start) {
-                if self.regions[i].range.contains(&region.range.end)
-                    && self.regions[i].attr.contains(region.attr)
-                {
-                    return Ok(());
-                }
-                inserted_at = Some(i);
-                old_end = Some(self.regions[i].range.end);
-                self.regions[i].range.end = region.range.start; // TODO: Handle case where region.start == new_region.start
-            }
-            if let Some(end) = old_end {
-                if (self.regions[i].range.start..end).contains(&region.range.end) {
-                    self.regions
-                        .insert(
-                            i + 1,
-                            Region {
-                                range: region.range.end..end,
-                                attr: self.regions[i].attr,
-                            },
-                        )
-                        .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
-                }
-            } else if self.regions[i].range.contains(&region.range.end) {
-                self.regions[i].range.start = region.range.end; // TODO: Handle case where region.start == new_region.start
-            }
-            if inserted_at.is_none()
-                && region.range.start > self.regions[i].range.end
-                && ((i == self.regions.len() - 1)
-                    || region.range.end < self.regions[i + 1].range.start)
+            if existing.range.start >= region.range.start && existing.range.end <= region.range.end
             {
-                inserted_at = Some(i);
+                // the existing region is a subset of the new one, so it is replaced entirely
+                self.regions.remove(i);
+                continue;
             }
-            if inserted_at == Some(i) {
+            if existing.range.start < region.range.start && existing.range.end > region.range.end {
+                // the new region is in the middle of the existing one, so split it in two
+                let tail = Region {
+                    range: region.range.end..existing.range.end,
+                    attr: existing.attr,
+                };
+                existing.range.end = region.range.start;
                 self.regions
-                    .insert(i + 1, region.clone())
+                    .insert(i + 1, tail)
                     .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
+                i += 2;
+                continue;
+            }
+            if existing.range.start < region.range.start {
+                existing.range.end = region.range.start;
+            } else {
+                existing.range.start = region.range.end;
             }
             i += 1;
         }
+        let i = self
+            .regions
+            .iter()
+            .position(|r| r.range.start >= region.range.start)
+            .unwrap_or(self.regions.len());
+        self.regions
+            .insert(i, region)
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
+        if i + 1 < self.regions.len()
+            && self.regions[i].range.end == self.regions[i + 1].range.start
+            && self.regions[i].attr == self.regions[i + 1].attr
+        {
+            self.regions[i].range.end = self.regions[i + 1].range.end;
+            self.regions.remove(i + 1);
+        }
+        if i > 0
+            && self.regions[i - 1].range.end == self.regions[i].range.start
+            && self.regions[i - 1].attr == self.regions[i].attr
+        {
+            self.regions[i - 1].range.end = self.regions[i].range.end;
+            self.regions.remove(i);
+        }
         Ok(())
     }
 
+    /// Inserts a [`RegionAttr::Guard`] region covering `range`
+    ///
+    /// A guard usually splits the region it sits in, so unless there is room for two more entries this returns
+    /// false and leaves the table untouched. A missing guard only costs overflow detection, so callers can carry on.
+    pub fn push_guard(&mut self, range: Range<usize>) -> bool {
+        if self.regions.len() + 2 > N {
+            return false;
+        }
+        self.push(Region {
+            range,
+            attr: RegionAttr::Guard.into(),
+        })
+        .is_ok()
+    }
+
+    /// Removes every guard added with [`RegionTable::push_guard`], merging the regions they split back together
+    pub fn remove_guards(&mut self) {
+        while let Some(guard) = self
+            .regions
+            .iter()
+            .find(|r| r.attr.contains(RegionAttr::Guard))
+            .cloned()
+        {
+            self.pop(guard);
+        }
+    }
+
+    /// Returns the guards added with [`RegionTable::push_guard`] that overlap `range`, in address order
+    pub fn guards_in(&self, range: Range<usize>) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.regions
+            .iter()
+            .filter(move |r| {
+                r.attr.contains(RegionAttr::Guard)
+                    && r.range.start < range.end
+                    && r.range.end > range.start
+            })
+            .map(|r| r.range.clone())
+    }
+
+    /// Returns true if `addr` is inside a guard added with [`RegionTable::push_guard`]
+    pub fn is_guard(&self, addr: usize) -> bool {
+        self.regions
+            .iter()
+            .any(|r| r.attr.contains(RegionAttr::Guard) && r.range.contains(&addr))
+    }
+
+    /// Iterates over the regions in the table, ordered by start address
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    /// Iterates mutably over the regions in the table, ordered by start address
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Region> {
+        self.regions.iter_mut()
+    }
+
+    /// Returns true if `region` shares any addresses with a region already in the table
+    pub fn overlaps(&self, region: &Region) -> bool {
+        self.overlapping(region).is_some()
+    }
+
+    /// Returns the first region in the table that shares any addresses with `region`
+    pub fn overlapping(&self, region: &Region) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|r| r.range.start < region.range.end && region.range.start < r.range.end)
+    }
+
     pub fn pop(&mut self, region: Region) {
         let mut i = 0;
         while i < self.regions.len() {
@@ -84,6 +269,34 @@ impl RegionTable {
             i += 1;
         }
     }
+
+    /// Warns about executable regions that don't overlap a `.text` section in `sections`
+    ///
+    /// Executable RAM lets a task run code it has written, so it should only be granted deliberately.
+    /// Returns false if any region was flagged.
+    #[cfg(feature = "exec_validation")]
+    pub fn validate_exec_regions(&self, sections: &[ElfSection]) -> bool {
+        let mut valid = true;
+        for region in self
+            .regions
+            .iter()
+            .filter(|r| r.attr.contains(RegionAttr::Exec))
+        {
+            let contains_text = sections
+                .iter()
+                .filter(|s| s.name == ".text")
+                .any(|s| s.range.start < region.range.end && region.range.start < s.range.end);
+            if !contains_text {
+                defmt::warn!(
+                    "region {=usize:#x}..{=usize:#x} is executable, but contains no code",
+                    region.range.start,
+                    region.range.end
+                );
+                valid = false;
+            }
+        }
+        valid
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -101,6 +314,40 @@ pub enum RegionAttr {
     Exec,
     Device,
     Dma,
+    // `WriteThrough` and `Uncached` together encode the region's [`CachePolicy`]
+    WriteThrough,
+    Uncached,
+    /// Denies all access, used below each thread's stack so an overflow faults instead of corrupting memory
+    Guard,
+}
+
+/// The cache policy applied to normal (non-device) memory regions
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    #[default]
+    WriteBack,
+    WriteThrough,
+    Uncached,
+}
+
+impl CachePolicy {
+    pub(crate) fn from_attr(attr: BitFlags<RegionAttr>) -> Self {
+        if attr.contains(RegionAttr::Uncached) {
+            CachePolicy::Uncached
+        } else if attr.contains(RegionAttr::WriteThrough) {
+            CachePolicy::WriteThrough
+        } else {
+            CachePolicy::WriteBack
+        }
+    }
+
+    pub(crate) fn attr(self) -> BitFlags<RegionAttr> {
+        match self {
+            CachePolicy::WriteBack => BitFlags::empty(),
+            CachePolicy::WriteThrough => RegionAttr::WriteThrough.into(),
+            CachePolicy::Uncached => RegionAttr::Uncached.into(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -109,7 +356,7 @@ mod tests {
 
     #[test]
     fn test_insert_region() {
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[Region {
                 range: 0..200,
                 attr: Default::default(),
@@ -139,7 +386,7 @@ mod tests {
                 }
             ]
         );
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[Region {
                 range: 0..30,
                 attr: Default::default(),
@@ -165,7 +412,7 @@ mod tests {
                 },
             ]
         );
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[
                 Region {
                     range: 0..50,
@@ -202,7 +449,7 @@ mod tests {
             ]
         );
 
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[
                 Region {
                     range: 0..50,
@@ -240,9 +487,66 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_insert_superset_region() {
+        let mut table: RegionTable = RegionTable {
+            regions: heapless::Vec::from_slice(&[Region {
+                range: 50..100,
+                attr: RegionAttr::Read.into(),
+            }])
+            .unwrap(),
+        };
+        table
+            .push(Region {
+                range: 0..200,
+                attr: RegionAttr::Read | RegionAttr::Write | RegionAttr::Exec,
+            })
+            .unwrap();
+        assert_eq!(
+            table.regions.as_slice(),
+            &[Region {
+                range: 0..200,
+                attr: RegionAttr::Read | RegionAttr::Write | RegionAttr::Exec,
+            }]
+        );
+
+        let mut table: RegionTable = RegionTable {
+            regions: heapless::Vec::from_slice(&[
+                Region {
+                    range: 0..50,
+                    attr: RegionAttr::Write.into(),
+                },
+                Region {
+                    range: 90..100,
+                    attr: Default::default(),
+                },
+            ])
+            .unwrap(),
+        };
+        table
+            .push(Region {
+                range: 50..80,
+                attr: RegionAttr::Write.into(),
+            })
+            .unwrap();
+        assert_eq!(
+            table.regions.as_slice(),
+            &[
+                Region {
+                    range: 0..80,
+                    attr: RegionAttr::Write.into(),
+                },
+                Region {
+                    range: 90..100,
+                    attr: Default::default(),
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_pop_region() {
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[
                 Region {
                     range: 0..20,
@@ -271,7 +575,7 @@ mod tests {
             },]
         );
 
-        let mut table = RegionTable {
+        let mut table: RegionTable = RegionTable {
             regions: heapless::Vec::from_slice(&[
                 Region {
                     range: 0..10,
@@ -306,4 +610,89 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_overlaps() {
+        let mut table: RegionTable = RegionTable {
+            regions: heapless::Vec::from_slice(&[Region {
+                range: 0x100..0x200,
+                attr: RegionAttr::Read.into(),
+            }])
+            .unwrap(),
+        };
+        let loan = Region {
+            range: 0x200..0x300,
+            attr: RegionAttr::Write.into(),
+        };
+        assert!(!table.overlaps(&loan));
+        assert!(table.overlaps(&Region {
+            range: 0x1f0..0x210,
+            attr: RegionAttr::Write.into(),
+        }));
+        table.push(loan.clone()).unwrap();
+        // a loan overlapping itself, or one that was already pushed, is a conflict
+        assert_eq!(table.overlapping(&loan), Some(&loan));
+        assert!(table.overlaps(&Region {
+            range: 0x2ff..0x400,
+            attr: RegionAttr::Write.into(),
+        }));
+        assert!(!table.overlaps(&Region {
+            range: 0x300..0x400,
+            attr: RegionAttr::Write.into(),
+        }));
+    }
+
+    #[test]
+    fn test_stack_guards() {
+        let ram = RegionAttr::Read | RegionAttr::Write;
+        let mut table: RegionTable = RegionTable {
+            regions: heapless::Vec::from_slice(&[Region {
+                range: 0..0x400,
+                attr: ram,
+            }])
+            .unwrap(),
+        };
+        assert!(table.push_guard(0x100..0x120));
+        assert!(table.is_guard(0x110));
+        assert!(!table.is_guard(0x120));
+        assert_eq!(table.regions.len(), 3);
+        while table.regions.len() + 2 <= MAX_REGIONS {
+            let start = table.regions.last().unwrap().range.start + 0x20;
+            assert!(table.push_guard(start..start + 0x10));
+        }
+        let before = table.regions.clone();
+        assert!(!table.push_guard(0x20..0x40), "the table should be full");
+        assert_eq!(table.regions, before);
+        table.remove_guards();
+        assert_eq!(
+            table.regions.as_slice(),
+            &[Region {
+                range: 0..0x400,
+                attr: ram,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_small_region_table() {
+        let ram = RegionAttr::Read | RegionAttr::Write;
+        let mut table = RegionTable::<3>::default();
+        for start in [0x0, 0x1000, 0x2000] {
+            table
+                .push(Region {
+                    range: start..start + 0x100,
+                    attr: ram,
+                })
+                .unwrap();
+        }
+        assert_eq!(table.len(), 3);
+        assert!(table
+            .push(Region {
+                range: 0x3000..0x3100,
+                attr: ram,
+            })
+            .is_err());
+        assert!(!table.push_guard(0x20..0x40), "the table should be full");
+        assert_eq!(table.len(), 3);
+    }
 }
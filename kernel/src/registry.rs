@@ -1,18 +1,91 @@
-use abi::{Connect, Endpoint, Listen, PortId};
+use abi::{Connect, Endpoint, Listen, PortId, SemaphoreId, ThreadRef};
+
+/// The maximum number of threads that can listen on a single port
+const MAX_SHARDS: usize = 4;
+/// The maximum number of threads that can be blocked on a single semaphore
+const MAX_SEM_WAITERS: usize = 8;
+/// The maximum number of threads that can be waiting for ports to open at once
+const MAX_PENDING_CONNECTS: usize = 8;
+/// The maximum number of ports that can be listened on at once
+const MAX_PORTS: usize = 8;
+/// The maximum number of semaphores that can exist at once
+const MAX_SEMAPHORES: usize = 8;
+
+/// The endpoints listening on a port, connections are handed out round-robin
+#[derive(Default)]
+struct Shards {
+    endpoints: heapless::Vec<Endpoint, MAX_SHARDS>,
+    next: usize,
+}
+
+/// A counting semaphore, along with the threads blocked on it and their priorities
+struct Semaphore {
+    count: usize,
+    waiters: heapless::Vec<(ThreadRef, usize), MAX_SEM_WAITERS>,
+}
+
+/// A thread blocked in [`abi::SyscallFn::PollCaps`] until one of `ports` is listened on
+struct PendingConnect {
+    ports: heapless::Vec<PortId, { abi::MAX_POLL_PORTS }>,
+    tcb_ref: ThreadRef,
+    priority: usize,
+}
 
 #[derive(Default)]
 pub(crate) struct Registry {
-    index: heapless::FnvIndexMap<PortId, Endpoint, 8>,
+    index: heapless::FnvIndexMap<PortId, Shards, MAX_PORTS>,
+    semaphores: heapless::FnvIndexMap<SemaphoreId, Semaphore, MAX_SEMAPHORES>,
+    pending_connects: heapless::Vec<PendingConnect, MAX_PENDING_CONNECTS>,
 }
 
 impl Registry {
+    /// The number of ports and semaphores the registry can hold
+    pub(crate) const CAPACITY: usize = MAX_PORTS + MAX_SEMAPHORES;
+
+    /// The number of ports listened on, plus the number of semaphores
+    pub(crate) fn len(&self) -> usize {
+        self.index.len() + self.semaphores.len()
+    }
+
+    /// Makes `endpoint` the only listener on the port
     pub(crate) fn listen(&mut self, listen: Listen, endpoint: Endpoint) -> Result<(), abi::Error> {
+        let mut shards = Shards::default();
+        shards
+            .endpoints
+            .push(endpoint)
+            .map_err(|_| abi::Error::BufferOverflow)?;
         self.index
-            .insert(listen.port, endpoint)
+            .insert(listen.port, shards)
             .map_err(|_| abi::Error::BufferOverflow)?;
         Ok(())
     }
 
+    /// Adds `endpoint` to the port's listeners, alongside any existing shards
+    pub(crate) fn listen_shard(
+        &mut self,
+        listen: Listen,
+        endpoint: Endpoint,
+    ) -> Result<(), abi::Error> {
+        if !self.index.contains_key(&listen.port) {
+            return self.listen(listen, endpoint);
+        }
+        let shards = self
+            .index
+            .get_mut(&listen.port)
+            .ok_or(abi::Error::PortNotOpen)?;
+        if shards
+            .endpoints
+            .iter()
+            .any(|e| e.tcb_ref == endpoint.tcb_ref && e.addr == endpoint.addr)
+        {
+            return Ok(());
+        }
+        shards
+            .endpoints
+            .push(endpoint)
+            .map_err(|_| abi::Error::BufferOverflow)
+    }
+
     #[allow(dead_code)]
     pub(crate) fn close(&mut self, port: PortId) -> Result<(), abi::Error> {
         self.index.remove(&port).ok_or(abi::Error::BufferOverflow)?;
@@ -20,9 +93,209 @@ impl Registry {
     }
 
     pub(crate) fn connect(&mut self, connect: Connect) -> Result<Endpoint, abi::Error> {
-        self.index
-            .get(&connect.port)
-            .ok_or(abi::Error::PortNotOpen)
-            .cloned()
+        let shards = self
+            .index
+            .get_mut(&connect.port)
+            .ok_or(abi::Error::PortNotOpen)?;
+        let endpoint = *shards
+            .endpoints
+            .get(shards.next % shards.endpoints.len())
+            .ok_or(abi::Error::PortNotOpen)?;
+        shards.next = shards.next.wrapping_add(1);
+        Ok(endpoint)
+    }
+
+    /// Returns the index of the first port in `ports` that is being listened on
+    pub(crate) fn first_open(&self, ports: &[PortId]) -> Option<usize> {
+        ports.iter().position(|port| self.index.contains_key(port))
+    }
+
+    /// Records that `tcb_ref` is blocked until one of `ports` is listened on
+    pub(crate) fn wait_for_ports(
+        &mut self,
+        ports: &[PortId],
+        tcb_ref: ThreadRef,
+        priority: usize,
+    ) -> Result<(), abi::Error> {
+        let ports = heapless::Vec::from_slice(ports).map_err(|_| abi::Error::BufferOverflow)?;
+        self.pending_connects
+            .push(PendingConnect {
+                ports,
+                tcb_ref,
+                priority,
+            })
+            .map_err(|_| abi::Error::BufferOverflow)
+    }
+
+    /// Removes every thread waiting for `port` to open
+    ///
+    /// Each thread is returned with the index of `port` in the list it waited on, and its priority
+    pub(crate) fn take_pending(
+        &mut self,
+        port: &PortId,
+    ) -> heapless::Vec<(ThreadRef, usize, usize), MAX_PENDING_CONNECTS> {
+        let mut woken = heapless::Vec::new();
+        self.pending_connects.retain(|pending| {
+            match pending.ports.iter().position(|p| p == port) {
+                Some(i) => {
+                    // woken can't overflow, as it has the same capacity as pending_connects
+                    let _ = woken.push((pending.tcb_ref, i, pending.priority));
+                    false
+                }
+                None => true,
+            }
+        });
+        woken
+    }
+
+    /// Creates a semaphore with `count` units, if one with the same id doesn't already exist
+    pub(crate) fn create_semaphore(
+        &mut self,
+        id: SemaphoreId,
+        count: usize,
+    ) -> Result<(), abi::Error> {
+        if self.semaphores.contains_key(&id) {
+            return Ok(());
+        }
+        let semaphore = Semaphore {
+            count,
+            waiters: heapless::Vec::new(),
+        };
+        self.semaphores
+            .insert(id, semaphore)
+            .map_err(|_| abi::Error::BufferOverflow)?;
+        Ok(())
+    }
+
+    /// Takes a unit from the semaphore
+    ///
+    /// Returns `false` if the count was zero, in which case `tcb_ref` has been added to the waiters and must block
+    pub(crate) fn sem_wait(
+        &mut self,
+        id: SemaphoreId,
+        tcb_ref: ThreadRef,
+        priority: usize,
+    ) -> Result<bool, abi::Error> {
+        let semaphore = self.semaphores.get_mut(&id).ok_or(abi::Error::InvalidCap)?;
+        if semaphore.count > 0 {
+            semaphore.count -= 1;
+            return Ok(true);
+        }
+        semaphore
+            .waiters
+            .push((tcb_ref, priority))
+            .map_err(|_| abi::Error::QueueFull)?;
+        Ok(false)
+    }
+
+    /// Returns a unit to the semaphore
+    ///
+    /// If any threads are waiting, the unit is handed straight to the highest priority one, which is returned
+    /// along with its priority so it can be requeued. Waiters with the same priority are woken in FIFO order.
+    pub(crate) fn sem_post(
+        &mut self,
+        id: SemaphoreId,
+    ) -> Result<Option<(ThreadRef, usize)>, abi::Error> {
+        let semaphore = self.semaphores.get_mut(&id).ok_or(abi::Error::InvalidCap)?;
+        let next = semaphore
+            .waiters
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, (_, priority))| *priority)
+            .map(|(i, _)| i);
+        if let Some(i) = next {
+            semaphore.waiters[i..].rotate_left(1);
+            return Ok(semaphore.waiters.pop());
+        }
+        semaphore.count = semaphore
+            .count
+            .checked_add(1)
+            .ok_or(abi::Error::BufferOverflow)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registry;
+    use abi::{Connect, Endpoint, Listen, ThreadRef};
+
+    fn endpoint(tcb: usize) -> Endpoint {
+        Endpoint {
+            tcb_ref: ThreadRef(tcb),
+            addr: 0,
+            disposable: false,
+            max_queue_depth: 0,
+        }
+    }
+
+    #[test]
+    fn test_connect_round_robin() {
+        let mut registry = Registry::default();
+        let port = [1; 16];
+        registry.listen(Listen { port }, endpoint(1)).unwrap();
+        registry.listen_shard(Listen { port }, endpoint(2)).unwrap();
+        registry.listen_shard(Listen { port }, endpoint(2)).unwrap();
+        let order: Vec<_> = (0..4)
+            .map(|_| registry.connect(Connect { port }).unwrap().tcb_ref.0)
+            .collect();
+        assert_eq!(order, [1, 2, 1, 2]);
+        registry.listen(Listen { port }, endpoint(3)).unwrap();
+        assert_eq!(registry.connect(Connect { port }).unwrap().tcb_ref.0, 3);
+    }
+
+    #[test]
+    fn test_pending_connects() {
+        let mut registry = Registry::default();
+        let (a, b) = ([1; 16], [2; 16]);
+        assert_eq!(registry.first_open(&[a, b]), None);
+        registry.wait_for_ports(&[a, b], ThreadRef(1), 1).unwrap();
+        registry.wait_for_ports(&[a], ThreadRef(2), 1).unwrap();
+        registry.listen(Listen { port: b }, endpoint(3)).unwrap();
+        assert_eq!(registry.first_open(&[a, b]), Some(1));
+        let woken = registry.take_pending(&b);
+        assert!(matches!(woken[..], [(ThreadRef(1), 1, _)]));
+        let woken = registry.take_pending(&a);
+        assert!(matches!(woken[..], [(ThreadRef(2), 0, _)]));
+        assert!(registry.take_pending(&a).is_empty());
+    }
+
+    #[test]
+    fn test_semaphore() {
+        let mut registry = Registry::default();
+        registry.create_semaphore(1, 1).unwrap();
+        // creating an existing semaphore leaves its count alone
+        registry.create_semaphore(1, 5).unwrap();
+        assert!(registry.sem_wait(1, ThreadRef(1), 2).unwrap());
+        assert!(!registry.sem_wait(1, ThreadRef(2), 2).unwrap());
+        assert!(!registry.sem_wait(1, ThreadRef(3), 4).unwrap());
+        assert!(!registry.sem_wait(1, ThreadRef(4), 2).unwrap());
+        let woken: Vec<_> = (0..3)
+            .map(|_| {
+                let (tcb_ref, _) = registry.sem_post(1).unwrap().unwrap();
+                tcb_ref.0
+            })
+            .collect();
+        assert_eq!(woken, [3, 2, 4]);
+        assert!(registry.sem_post(1).unwrap().is_none());
+        assert!(registry.sem_wait(1, ThreadRef(1), 2).unwrap());
+        assert!(matches!(
+            registry.sem_wait(2, ThreadRef(1), 2),
+            Err(abi::Error::InvalidCap)
+        ));
+    }
+
+    #[test]
+    fn test_semaphore_waiters_full() {
+        let mut registry = Registry::default();
+        registry.create_semaphore(1, 0).unwrap();
+        for i in 0..super::MAX_SEM_WAITERS {
+            assert!(!registry.sem_wait(1, ThreadRef(i), 1).unwrap());
+        }
+        assert!(matches!(
+            registry.sem_wait(1, ThreadRef(9), 1),
+            Err(abi::Error::QueueFull)
+        ));
     }
 }
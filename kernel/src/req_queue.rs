@@ -0,0 +1,146 @@
+//! A thread's queue of pending IPC messages
+//!
+//! Messages are queued per endpoint address, and each address is drained in FIFO order. When a thread
+//! receives, the address queues are visited round-robin, starting after the address that was last received from.
+//! This means a sender that floods an address can't starve other addresses, and the order messages are received
+//! in for a given address doesn't depend on how other senders were scheduled.
+//!
+//! Compared to a single global FIFO, messages sent to *different* addresses may now be received out of send
+//! order. Messages sent to the same address are still received in the order they were sent.
+use alloc::vec::Vec;
+use cordyceps::{list::Links, Linked, List};
+
+use crate::IPCMsg;
+
+type MsgHandle = <IPCMsg as Linked<Links<IPCMsg>>>::Handle;
+
+struct AddrQueue {
+    addr: usize,
+    msgs: List<IPCMsg>,
+}
+
+#[derive(Default)]
+pub(crate) struct PerSenderQueue {
+    queues: Vec<AddrQueue>,
+    next: usize,
+}
+
+impl PerSenderQueue {
+    pub(crate) fn push_back(&mut self, msg: MsgHandle) {
+        let addr = msg.addr;
+        if let Some(queue) = self.queues.iter_mut().find(|q| q.addr == addr) {
+            queue.msgs.push_back(msg);
+            return;
+        }
+        let mut msgs = List::new();
+        msgs.push_back(msg);
+        self.queues.push(AddrQueue { addr, msgs });
+    }
+
+    /// The number of messages queued for `addr`
+    pub(crate) fn depth(&self, addr: usize) -> usize {
+        self.queues
+            .iter()
+            .find(|q| q.addr == addr)
+            .map_or(0, |q| q.msgs.len())
+    }
+
+    /// Removes the next message whose address `matches`, visiting addresses in round-robin order
+    pub(crate) fn pop(&mut self, matches: impl Fn(usize) -> bool) -> Option<MsgHandle> {
+        let len = self.queues.len();
+        let i = (0..len)
+            .map(|i| (self.next + i) % len)
+            .find(|&i| matches(self.queues[i].addr))?;
+        let msg = self.queues[i].msgs.pop_front();
+        if self.queues[i].msgs.is_empty() {
+            // the queue after `i` shifts down into its place, so it is next in line
+            self.queues.remove(i);
+            self.next = i;
+        } else {
+            self.next = i + 1;
+        }
+        msg
+    }
+
+    /// Iterates over every queued message, in no particular order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &IPCMsg> {
+        self.queues.iter().flat_map(|q| q.msgs.iter())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PerSenderQueue;
+    use crate::{IPCMsg, IPCMsgBody};
+    use alloc::boxed::Box;
+
+    fn msg(addr: usize, id: u8) -> IPCMsg {
+        IPCMsg {
+            _links: Default::default(),
+            addr,
+            reply_endpoint: None,
+            body: IPCMsgBody::Buf(Box::new([id])),
+            cap: None,
+        }
+    }
+
+    fn pop_id(queue: &mut PerSenderQueue, mask: usize) -> Option<u8> {
+        let msg = queue.pop(|addr| addr & mask == mask)?;
+        match &msg.body {
+            IPCMsgBody::Buf(buf) => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut queue = PerSenderQueue::default();
+        for id in 0..3 {
+            queue.push_back(msg(1, id).alloc());
+        }
+        queue.push_back(msg(2, 10).alloc());
+        queue.push_back(msg(4, 20).alloc());
+        let order: Vec<_> = core::iter::from_fn(|| pop_id(&mut queue, 0)).collect();
+        assert_eq!(order, [0, 10, 20, 1, 2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_mask() {
+        let mut queue = PerSenderQueue::default();
+        queue.push_back(msg(1, 0).alloc());
+        queue.push_back(msg(3, 1).alloc());
+        queue.push_back(msg(3, 2).alloc());
+        assert_eq!(pop_id(&mut queue, 2), Some(1));
+        assert_eq!(pop_id(&mut queue, 2), Some(2));
+        assert_eq!(pop_id(&mut queue, 2), None);
+        assert_eq!(pop_id(&mut queue, 1), Some(0));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_mask_any() {
+        let mut queue = PerSenderQueue::default();
+        queue.push_back(msg(1, 0).alloc());
+        queue.push_back(msg(2, 1).alloc());
+        queue.push_back(msg(4, 2).alloc());
+        let any = |queue: &mut PerSenderQueue| {
+            let msg = queue.pop(|addr| addr & 0b101 != 0)?;
+            Some(msg.addr)
+        };
+        assert_eq!(any(&mut queue), Some(1));
+        assert_eq!(any(&mut queue), Some(4));
+        assert_eq!(
+            any(&mut queue),
+            None,
+            "the message sent to 2 wasn't selected"
+        );
+        assert_eq!(pop_id(&mut queue, 2), Some(1));
+        assert!(queue.is_empty());
+    }
+}
@@ -4,7 +4,7 @@ use crate::linked_impl;
 use crate::space::Space;
 use crate::tcb::{RecvReq, Tcb};
 use crate::{DomainEntry, ThreadState, TCB_CAPACITY};
-use abi::ThreadRef;
+use abi::{SyscallReturn, ThreadRef};
 use alloc::boxed::Box;
 use alloc::collections::BinaryHeap;
 use cordyceps::{list::Links, List};
@@ -16,12 +16,34 @@ pub(crate) struct Scheduler {
     pub(crate) wait_queue: BinaryHeap<DomainEntry>,
     pub(crate) exhausted_threads: List<ExhaustedThread>,
     pub(crate) current_thread: ThreadTime,
+    /// Number of ticks since the scheduler started
+    pub(crate) ticks: u64,
+    /// Called once when the idle thread is the only thread left, see [`crate::KernelBuilder::on_shutdown`]
+    pub(crate) on_shutdown: Option<fn()>,
+    /// The number of threads spawned and not yet exited, excluding the idle thread
+    pub(crate) live_thread_count: usize,
+    /// Shortens the cooldown of exhausted threads while the idle thread runs, see [`crate::KernelBuilder::idle_boost`]
+    pub(crate) idle_boost: bool,
+    /// Periodically compacts the heap while the idle thread runs, see [`crate::KernelBuilder::heap_defrag_interval`]
+    #[cfg(feature = "heap_defrag")]
+    pub(crate) heap_defrag: Option<HeapDefrag>,
+}
+
+/// A callback that defragments the kernel heap, run from the idle thread's time slot
+#[cfg(feature = "heap_defrag")]
+pub(crate) struct HeapDefrag {
+    pub(crate) interval: u64,
+    pub(crate) last_run: u64,
+    pub(crate) defrag: fn(),
 }
 
 impl Scheduler {
     pub fn spawn(&mut self, tcb: Tcb) -> Result<ThreadRef, KernelError> {
         let priority = tcb.priority as u8;
         let tcb_ref = ThreadRef(self.tcbs.push(tcb).ok_or(KernelError::TooManyThreads)?);
+        if tcb_ref != ThreadRef::idle() {
+            self.live_thread_count += 1;
+        }
         self.wait_queue.push(DomainEntry {
             tcb_ref,
             loaned_tcb: None,
@@ -33,53 +55,198 @@ impl Scheduler {
     pub(crate) fn wait(
         &mut self,
         recv_req: RecvReq<'static>,
+        deadline: Option<u64>,
         loan: bool,
     ) -> Result<ThreadRef, KernelError> {
         let src = self.current_thread_mut()?;
-        src.state = ThreadState::Waiting { recv_req };
+        src.state = ThreadState::Waiting { recv_req, deadline };
 
-        let mut next_thread = self.next_thread(0).unwrap_or_else(DomainEntry::idle);
+        let mut next_thread = self.next_thread(0)?.unwrap_or_else(DomainEntry::idle);
         if loan {
             next_thread.loaned_tcb = Some(self.current_thread.tcb_ref)
         }
         self.switch_thread(next_thread)
     }
 
-    pub fn next_thread(&mut self, current_priority: usize) -> Option<DomainEntry> {
+    /// Blocks the current thread and switches to the next one
+    ///
+    /// The thread won't run again until it is requeued with [`Scheduler::add_thread`].
+    pub(crate) fn block(&mut self) -> Result<ThreadRef, KernelError> {
+        self.suspend(ThreadState::Blocked)
+    }
+
+    /// Suspends the current thread for `ticks` ticks and switches to the next one
+    pub(crate) fn sleep(&mut self, ticks: usize) -> Result<ThreadRef, KernelError> {
+        let wake_tick = self.ticks.saturating_add(ticks as u64);
+        self.suspend(ThreadState::Sleeping { wake_tick })
+    }
+
+    /// Suspends the current thread until a notification bit in `mask` is set, and switches to the next one
+    pub(crate) fn wait_notify(&mut self, mask: u32) -> Result<ThreadRef, KernelError> {
+        self.suspend(ThreadState::WaitingNotify { mask })
+    }
+
+    /// Suspends the current thread until `irq` fires, and switches to the next one
+    pub(crate) fn wait_irq(&mut self, irq: u16) -> Result<ThreadRef, KernelError> {
+        self.suspend(ThreadState::WaitingIrq { irq })
+    }
+
+    fn suspend(&mut self, state: ThreadState) -> Result<ThreadRef, KernelError> {
+        self.current_thread_mut()?.state = state;
+        let next_thread = self.next_thread(0)?.unwrap_or_else(DomainEntry::idle);
+        self.switch_thread(next_thread)
+    }
+
+    /// Requeues a thread blocked with [`Scheduler::block`], setting `ret` as the result of the syscall it blocked in
+    ///
+    /// The thread is queued at `priority`, or its effective priority if a priority boost puts that higher
+    pub(crate) fn unblock(
+        &mut self,
+        tcb_ref: ThreadRef,
+        priority: usize,
+        ret: SyscallReturn,
+    ) -> Result<(), KernelError> {
+        let tcb = self.get_tcb_mut(tcb_ref)?;
+        tcb.state = ThreadState::Ready;
+        tcb.saved_state.set_syscall_return(ret);
+        let priority = priority.max(tcb.effective_priority());
+        self.add_thread(priority, tcb_ref)
+    }
+
+    /// Switches to the next ready thread of the same or higher priority, requeueing the current thread
+    ///
+    /// The current thread keeps the rest of its budget, rather than being treated as exhausted. Returns `None` if
+    /// no other thread is ready, in which case the current thread keeps running. Threads at priority 0 only share
+    /// it with the idle thread, so they never yield.
+    pub(crate) fn yield_thread(&mut self) -> Result<Option<ThreadRef>, KernelError> {
+        let priority = self.current_thread.priority;
+        let next_thread = match priority.checked_sub(1) {
+            Some(min_priority) => self.next_thread(min_priority as usize)?,
+            None => None,
+        };
+        let next_thread = match next_thread {
+            Some(next_thread) => next_thread,
+            None => return Ok(None),
+        };
+        let current = DomainEntry::new(
+            self.current_thread.tcb_ref,
+            self.current_thread.loaned_tcb,
+            priority,
+        );
+        let next_thread = self.switch_thread(next_thread)?;
+        self.wait_queue.push(current);
+        Ok(Some(next_thread))
+    }
+
+    /// Returns the number of threads that are alive, excluding the idle thread
+    pub fn live_threads(&self) -> usize {
+        self.live_thread_count
+    }
+
+    /// Removes a thread from the scheduler, along with any pending entries in the wait queue and exhausted list
+    ///
+    /// This doesn't switch away from the thread, so if `tcb_ref` is the current thread the caller must switch threads.
+    pub(crate) fn exit_thread(&mut self, tcb_ref: ThreadRef) -> Result<Tcb, KernelError> {
+        let tcb = self
+            .tcbs
+            .remove(*tcb_ref)
+            .ok_or(KernelError::InvalidThreadRef(tcb_ref))?;
+        if tcb_ref != ThreadRef::idle() {
+            self.live_thread_count -= 1;
+        }
+        self.wait_queue.retain(|e| e.tcb_ref != tcb_ref);
+        let mut cursor = self.exhausted_threads.cursor_front_mut();
+        while let Some(t) = cursor.current() {
+            if t.tcb_ref == Some(tcb_ref) {
+                cursor.remove_current();
+            } else {
+                cursor.move_next();
+            }
+        }
+        Ok(tcb)
+    }
+
+    pub fn next_thread(
+        &mut self,
+        current_priority: usize,
+    ) -> Result<Option<DomainEntry>, KernelError> {
+        let next_thread = self.next_thread_inner(current_priority)?;
+        if next_thread.is_none() && self.live_threads() == 0 {
+            if let Some(on_shutdown) = self.on_shutdown.take() {
+                on_shutdown();
+            }
+        }
+        Ok(next_thread)
+    }
+
+    fn next_thread_inner(
+        &mut self,
+        current_priority: usize,
+    ) -> Result<Option<DomainEntry>, KernelError> {
         loop {
-            if self
-                .wait_queue
-                .peek()
-                .map(|i| i.priority > current_priority as u8)
-                .unwrap_or_default()
-            {
-                let thread = self.wait_queue.pop().unwrap();
-                if thread.tcb_ref == self.current_thread.tcb_ref {
-                    continue;
-                    // when next thread is called we typically want the next possible thread,
-                    // available, not ourselves. Plus we are already executing.
-                }
-                let tcb = self.tcbs.get(*thread.tcb_ref).unwrap();
-                if let ThreadState::Waiting { .. } = tcb.state {
-                    // bad things can happen if we switch to waiting
-                    continue;
-                }
-                return Some(thread);
+            let thread = match self.wait_queue.peek() {
+                Some(thread) if thread.priority > current_priority as u8 => self.wait_queue.pop(),
+                _ => None,
+            };
+            let thread = if let Some(thread) = thread {
+                thread
             } else {
-                return None;
+                return Ok(None);
+            };
+            if thread.tcb_ref == self.current_thread.tcb_ref {
+                continue;
+                // when next thread is called we typically want the next possible thread,
+                // available, not ourselves. Plus we are already executing.
             }
+            let tcb = self.get_tcb(thread.tcb_ref)?;
+            if matches!(
+                tcb.state,
+                ThreadState::Waiting { .. }
+                    | ThreadState::Blocked
+                    | ThreadState::Sleeping { .. }
+                    | ThreadState::WaitingNotify { .. }
+                    | ThreadState::WaitingIrq { .. }
+            ) {
+                // bad things can happen if we switch to waiting
+                continue;
+            }
+            return Ok(Some(thread));
         }
     }
 
+    /// Queues a thread to run at `priority`, or its boosted priority if that is higher, replacing any entry it
+    /// already has
     pub fn add_thread(&mut self, priority: usize, tcb_ref: ThreadRef) -> Result<(), KernelError> {
+        let priority = match self.tcbs.get(*tcb_ref).and_then(|t| t.boosted_priority) {
+            Some(boosted) => priority.max(boosted),
+            None => priority,
+        };
+        // an entry left from before a boost changed would run the thread at the wrong priority
+        self.wait_queue.retain(|e| e.tcb_ref != tcb_ref);
         self.wait_queue
             .push(DomainEntry::new(tcb_ref, None, priority as u8));
         Ok(())
     }
 
     pub fn tick(&mut self) -> Result<Option<ThreadRef>, KernelError> {
+        self.ticks = self.ticks.wrapping_add(1);
+        let current = self.current_thread_mut()?;
+        current.stats.cpu_ticks = current.stats.cpu_ticks.wrapping_add(1);
+        self.expire_deadlines()?;
+        #[cfg(feature = "heap_defrag")]
+        if let Some(heap_defrag) = &mut self.heap_defrag {
+            // only defragment when nothing else wants to run, so it never delays a real thread
+            if self.current_thread.tcb_ref == ThreadRef::idle()
+                && self.ticks.wrapping_sub(heap_defrag.last_run) >= heap_defrag.interval
+            {
+                heap_defrag.last_run = self.ticks;
+                (heap_defrag.defrag)();
+            }
+        }
         // requeue exhausted threads
         {
+            // only the idle thread running means there is spare CPU time
+            let boost = self.idle_boost && self.current_thread.tcb_ref == ThreadRef::idle();
             let mut cursor = self.exhausted_threads.cursor_front_mut();
             cursor.move_prev(); // THIS IS PROBABLY WRONG
             let mut remove_flag = false;
@@ -92,16 +259,16 @@ impl Scheduler {
             } {
                 if let Some(tcb_ref) = t.tcb_ref {
                     let loaned_tcb = t.loaned_tcb;
-                    let time = t.decrement();
+                    let time = t.decrement(boost);
                     defmt::trace!("decrement exhausted thread: {:?} {:?}", tcb_ref, time);
                     if time == 0 {
                         remove_flag = true;
                         let tcb = self
                             .tcbs
                             .get(*tcb_ref)
-                            .ok_or(KernelError::InvalidThreadRef)?;
+                            .ok_or(KernelError::InvalidThreadRef(tcb_ref))?;
                         let domain_entry =
-                            DomainEntry::new(tcb_ref, loaned_tcb, tcb.priority as u8);
+                            DomainEntry::new(tcb_ref, loaned_tcb, tcb.effective_priority() as u8);
                         self.wait_queue.push(domain_entry);
                     }
                 }
@@ -110,30 +277,54 @@ impl Scheduler {
         self.current_thread.time -= 1;
         // check if current thread's budget has been surpassed
         if self.current_thread.time == 0 {
-            let current_tcb = self
-                .tcbs
-                .get(*self.current_thread.time_thread())
-                .ok_or(KernelError::InvalidThreadRef)?;
+            let current_tcb = self.tcbs.get(*self.current_thread.time_thread()).ok_or(
+                KernelError::InvalidThreadRef(self.current_thread.time_thread()),
+            )?;
             let exhausted_thread = ExhaustedThread {
                 tcb_ref: Some(self.current_thread.tcb_ref),
                 time: current_tcb.cooldown,
+                elapsed_boost: 0,
                 loaned_tcb: self.current_thread.loaned_tcb,
                 _links: Default::default(),
             };
             self.exhausted_threads
                 .push_front(Box::pin(exhausted_thread));
             defmt::trace!("exhausting: {:?}", self.current_thread);
-            let next_thread = self.next_thread(0).unwrap_or_else(DomainEntry::idle);
+            let next_thread = self.next_thread(0)?.unwrap_or_else(DomainEntry::idle);
             return self.switch_thread(next_thread).map(Some);
         }
-        let current_tcb = self.current_thread()?;
-        let current_priority = current_tcb.priority;
-        if let Some(next_thread) = self.next_thread(current_priority) {
+        if let Some(next_thread) = self.next_thread(self.current_thread.priority as usize)? {
             return self.switch_thread(next_thread).map(Some);
         }
         Ok(None)
     }
 
+    /// Wakes every thread whose deadline has passed
+    ///
+    /// A recv given a deadline by [`Scheduler::wait`] fails with [`abi::Error::Timeout`], while a
+    /// [`Scheduler::sleep`] returns successfully.
+    fn expire_deadlines(&mut self) -> Result<(), KernelError> {
+        for i in 0..TCB_CAPACITY {
+            let tcb = match self.tcbs.get_mut(i) {
+                Some(tcb) => tcb,
+                None => continue,
+            };
+            let ret = match tcb.state {
+                ThreadState::Waiting {
+                    deadline: Some(deadline),
+                    ..
+                } if deadline <= self.ticks => SyscallReturn::error(abi::Error::Timeout),
+                ThreadState::Sleeping { wake_tick } if wake_tick <= self.ticks => {
+                    SyscallReturn::copy(0)
+                }
+                _ => continue,
+            };
+            let priority = tcb.effective_priority();
+            self.unblock(ThreadRef(i), priority, ret)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn switch_thread(
         &mut self,
         next_thread: DomainEntry,
@@ -141,19 +332,22 @@ impl Scheduler {
         let loaned_tcb = if let Some(loaned) = self.current_thread.loaned_tcb {
             self.tcbs
                 .get_mut(*loaned)
-                .ok_or(KernelError::InvalidThreadRef)?
+                .ok_or(KernelError::InvalidThreadRef(loaned))?
         } else {
             self.tcbs
                 .get_mut(*self.current_thread.tcb_ref)
-                .ok_or(KernelError::InvalidThreadRef)?
+                .ok_or(KernelError::InvalidThreadRef(self.current_thread.tcb_ref))?
         };
         loaned_tcb.rem_time = self.current_thread.time;
+        let next_tcb = self.get_tcb_mut(next_thread.tcb_ref)?;
+        next_tcb.stats.context_switches = next_tcb.stats.context_switches.wrapping_add(1);
         // NOTE: we might want to just monomorphize this out, rather than
         // using an if statement
+        let time_tcb_ref = next_thread.loaned_tcb.unwrap_or(next_thread.tcb_ref);
         let time_tcb = self
             .tcbs
-            .get(*next_thread.loaned_tcb.unwrap_or(next_thread.tcb_ref))
-            .ok_or(KernelError::InvalidThreadRef)?;
+            .get(*time_tcb_ref)
+            .ok_or(KernelError::InvalidThreadRef(time_tcb_ref))?;
         defmt::trace!(
             "switching: {:?} -> {:?}",
             self.current_thread.tcb_ref,
@@ -167,6 +361,7 @@ impl Scheduler {
                 time_tcb.budget
             },
             loaned_tcb: next_thread.loaned_tcb,
+            priority: self.get_tcb(next_thread.tcb_ref)?.effective_priority() as u8,
         };
         Ok(next_thread.tcb_ref)
     }
@@ -183,14 +378,16 @@ impl Scheduler {
 
     #[inline]
     pub fn get_tcb(&self, tcb_ref: ThreadRef) -> Result<&Tcb, KernelError> {
-        self.tcbs.get(*tcb_ref).ok_or(KernelError::InvalidThreadRef)
+        self.tcbs
+            .get(*tcb_ref)
+            .ok_or(KernelError::InvalidThreadRef(tcb_ref))
     }
 
     #[inline]
     pub fn get_tcb_mut(&mut self, tcb_ref: ThreadRef) -> Result<&mut Tcb, KernelError> {
         self.tcbs
             .get_mut(*tcb_ref)
-            .ok_or(KernelError::InvalidThreadRef)
+            .ok_or(KernelError::InvalidThreadRef(tcb_ref))
     }
 }
 
@@ -198,17 +395,26 @@ impl Scheduler {
 pub(crate) struct ExhaustedThread {
     _links: Links<ExhaustedThread>,
     time: usize,
+    /// Ticks spent exhausted while the system was otherwise idle
+    elapsed_boost: usize,
     tcb_ref: Option<ThreadRef>,
     pub(crate) loaned_tcb: Option<ThreadRef>,
 }
 
 impl ExhaustedThread {
-    fn decrement(self: Pin<&mut ExhaustedThread>) -> usize {
+    /// Decrements the thread's cooldown, and returns the ticks left until it should be requeued
+    ///
+    /// When `boost` is set the tick also counts towards `elapsed_boost`, so a thread that spends its whole cooldown
+    /// alongside the idle thread is requeued after roughly half of it.
+    fn decrement(self: Pin<&mut ExhaustedThread>, boost: bool) -> usize {
         // Safety: We never move the underlying memory, so this is safe
         unsafe {
             let s = self.get_unchecked_mut();
             s.time = s.time.saturating_sub(1);
-            s.time
+            if boost {
+                s.elapsed_boost += 1;
+            }
+            s.time.saturating_sub(s.elapsed_boost)
         }
     }
 }
@@ -220,6 +426,8 @@ pub(crate) struct ThreadTime {
     pub(crate) tcb_ref: ThreadRef,
     pub(crate) time: usize,
     pub(crate) loaned_tcb: Option<ThreadRef>,
+    /// The running thread's priority, cached so preemption checks don't need to look up its TCB
+    pub(crate) priority: u8,
 }
 
 impl ThreadTime {
@@ -0,0 +1,175 @@
+//! Hardening options for the kernel, configured with [`crate::KernelBuilder::security`]
+use core::mem;
+
+use crate::{task_ptr::TaskPtrMut, Task};
+
+/// The value every word of task RAM is filled with when using [`ClearPattern::Poison`]
+const POISON: u32 = 0xdeadf00d;
+/// The value task stacks are painted with, whatever the [`ClearPattern`], so their high-water mark can be found
+///
+/// Reusing [`POISON`] keeps stacks looking like the rest of RAM under [`ClearPattern::Poison`]. Stacks never hold
+/// anything from a previous run, so painting them doesn't undo [`ClearPattern::Random`].
+const STACK_PAINT: u32 = POISON;
+
+/// What task RAM is filled with before a task is started or restarted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClearPattern {
+    /// Fills every word with `0xdeadf00d`, which is easy to spot in a debugger
+    #[default]
+    Poison,
+    /// Fills every word with a different random value, so a restarted task can't
+    /// learn anything from a predictable fill pattern
+    Random,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SecurityConfig {
+    pub clear_pattern: ClearPattern,
+    /// Reads a word from the hardware TRNG, if the chip has one
+    ///
+    /// When this is `None`, [`ClearPattern::Random`] uses a XorShift PRNG seeded from the SysTick counter
+    /// on Cortex-M, or `mcycle` on RISC-V.
+    pub rng: Option<fn() -> u32>,
+}
+
+impl SecurityConfig {
+    /// Fills `words` according to the configured [`ClearPattern`]
+    ///
+    /// `seed` is only used when no hardware RNG has been configured
+    pub(crate) fn fill(&self, words: &mut [u32], seed: u32) {
+        match (self.clear_pattern, self.rng) {
+            (ClearPattern::Poison, _) => words.fill(POISON),
+            (ClearPattern::Random, Some(rng)) => words.iter_mut().for_each(|w| *w = rng()),
+            (ClearPattern::Random, None) => {
+                let mut rng = XorShift32::new(seed);
+                words.iter_mut().for_each(|w| *w = rng.next());
+            }
+        }
+    }
+}
+
+/// Marsaglia's 32-bit XorShift PRNG
+///
+/// This is not cryptographically secure, it only exists so that cleared memory isn't predictable
+/// on chips without a TRNG.
+struct XorShift32(u32);
+
+impl XorShift32 {
+    fn new(seed: u32) -> Self {
+        // XorShift gets stuck at zero, so a zero seed is replaced
+        Self(if seed == 0 { POISON } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+/// Clears the RAM region containing `task`'s stack, using `config` to pick the fill pattern
+pub(crate) fn clear_task_mem(task: &Task, config: &SecurityConfig, seed: u32) {
+    let stack = &task.initial_stack_ptr.start;
+    for region in task.region_table.iter() {
+        if !region.range.contains(stack) {
+            continue;
+        }
+        // Safety: We are creating a lifetime that lasts for the body of this function;
+        // this is safe, because we are in Kernel mode, and are simply wiping the memory
+        let ptr = unsafe {
+            TaskPtrMut::<'_, [u32]>::from_addr_count(
+                region.range.start,
+                region.range.len() / mem::size_of::<u32>(),
+            )
+        };
+        let mem = task
+            .validate_mut_ptr(ptr)
+            .expect("pointer not in task memory");
+        config.fill(mem, seed);
+    }
+    paint_stacks(task);
+}
+
+/// Paints the task's stack space with [`STACK_PAINT`], see [`stack_usage`]
+///
+/// Stack guards split the stacks' region, and a pointer has to be inside a single region, so the space between
+/// each guard is painted separately. The guards themselves are skipped.
+fn paint_stacks(task: &Task) {
+    let stacks = task.initial_stack_ptr.clone();
+    let mut start = stacks.start;
+    let end = stacks.end;
+    for guard in task
+        .region_table
+        .guards_in(stacks)
+        .chain(core::iter::once(end..end))
+    {
+        let len = guard.start.saturating_sub(start);
+        // Safety: the pointer is validated against the task's regions before it is written to
+        let ptr =
+            unsafe { TaskPtrMut::<'_, [u32]>::from_addr_count(start, len / mem::size_of::<u32>()) };
+        match task.validate_mut_ptr(ptr) {
+            Some(stack) => stack.fill(STACK_PAINT),
+            None => defmt::warn!(
+                "stack {:#x}..{:#x} isn't in task memory",
+                start,
+                start + len
+            ),
+        }
+        start = start.max(guard.end);
+    }
+}
+
+/// Returns the number of bytes of `stack` that have been written to, given the stack grows down from its end
+///
+/// This is a high-water mark, the first word from the bottom that isn't [`STACK_PAINT`] is taken as the deepest
+/// the stack has ever been.
+pub(crate) fn stack_usage(stack: &[u32]) -> usize {
+    let unused = stack
+        .iter()
+        .position(|w| *w != STACK_PAINT)
+        .unwrap_or(stack.len());
+    (stack.len() - unused) * mem::size_of::<u32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stack_usage, ClearPattern, SecurityConfig, POISON, STACK_PAINT};
+
+    #[test]
+    fn test_fill() {
+        let mut words = [0u32; 16];
+        SecurityConfig::default().fill(&mut words, 1234);
+        assert!(words.iter().all(|w| *w == POISON));
+
+        let config = SecurityConfig {
+            clear_pattern: ClearPattern::Random,
+            rng: None,
+        };
+        config.fill(&mut words, 0);
+        assert!(words.iter().all(|w| *w != POISON && *w != 0));
+        assert!(words.windows(2).all(|w| w[0] != w[1]));
+
+        let config = SecurityConfig {
+            clear_pattern: ClearPattern::Random,
+            rng: Some(|| 7),
+        };
+        config.fill(&mut words, 1234);
+        assert!(words.iter().all(|w| *w == 7));
+    }
+
+    #[test]
+    fn test_stack_usage() {
+        let mut stack = [STACK_PAINT; 16];
+        assert_eq!(stack_usage(&stack), 0);
+        stack[12..].fill(0);
+        assert_eq!(stack_usage(&stack), 16);
+        // painted words above a deeper write still count as used
+        stack[4] = 1;
+        assert_eq!(stack_usage(&stack), 48);
+        stack[0] = 1;
+        assert_eq!(stack_usage(&stack), 64);
+    }
+}
@@ -46,6 +46,31 @@ impl<T, const N: usize> Space<T, N> {
         Some(item)
     }
 
+    /// Removes every item from the space, and returns them in index order
+    ///
+    /// The space is reset immediately, so it is empty even if the iterator isn't consumed
+    #[allow(dead_code)]
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        core::mem::take(self).into_iter()
+    }
+
+    /// Removes every item that matches `f`, and returns them in index order
+    ///
+    /// Unlike [`Space::drain`] the remaining items keep their indices. Items are only removed as the iterator is consumed.
+    #[allow(dead_code)]
+    pub fn drain_filter<'a>(
+        &'a mut self,
+        mut f: impl FnMut(&T) -> bool + 'a,
+    ) -> impl Iterator<Item = T> + 'a {
+        (0..N).filter_map(move |i| {
+            if f(self.get(i)?) {
+                self.remove(i)
+            } else {
+                None
+            }
+        })
+    }
+
     #[allow(dead_code)]
     pub fn iter(&self) -> impl Iterator<Item = &T> {
         self.items.iter().filter_map(|i| i.as_ref())
@@ -97,6 +122,38 @@ mod tests {
             assert_eq!(space.push(i), Some(i));
         }
     }
+
+    #[test]
+    fn test_drain_push() {
+        let mut space = Space::<usize, 10>::default();
+        for i in 0..10 {
+            space.push(i);
+        }
+        space.remove(3).expect("item not found");
+        space.remove(7).expect("item not found");
+        let drained: Vec<_> = space.drain().collect();
+        assert_eq!(drained, [0, 1, 2, 4, 5, 6, 8, 9]);
+        assert_eq!(space.len(), 0);
+        assert_eq!(space.get(0), None);
+        for i in 0..10 {
+            assert_eq!(space.push(i), Some(i));
+        }
+        assert_eq!(space.push(10), None);
+    }
+
+    #[test]
+    fn test_drain_filter() {
+        let mut space = Space::<usize, 10>::default();
+        for i in 0..10 {
+            space.push(i);
+        }
+        let evens: Vec<_> = space.drain_filter(|i| i % 2 == 0).collect();
+        assert_eq!(evens, [0, 2, 4, 6, 8]);
+        assert_eq!(space.len(), 5);
+        for i in (1..10).step_by(2) {
+            assert_eq!(space.get(i), Some(&i));
+        }
+    }
 }
 
 // pub struct Iter<'a, T, const N: usize> {
@@ -1,17 +1,18 @@
 use core::mem::{self, MaybeUninit};
 
 use abi::{
-    Cap, CapListEntry, CapRef, RecvResp, SyscallArgs, SyscallDataType, SyscallReturn,
-    SyscallReturnType, ThreadRef,
+    Cap, CapListEntry, CapRef, PortId, RecvResp, SemaphoreId, SyscallArgs, SyscallDataType,
+    SyscallReturn, TaskInfo, TaskStats, ThreadRef,
 };
-use defmt::{error, Format};
+use cordyceps::List;
+use defmt::{error, info, Format};
 
 use crate::{
+    heap::Heap,
     regions::Region,
-    task::TaskState,
     task_ptr::{TaskPtr, TaskPtrMut},
     tcb::{RecvReq, RecvReqInner, RecvRes, Tcb},
-    CapEntry, DomainEntry, IPCMsgBody, Kernel, KernelError, RegionAttr,
+    IPCMsgBody, Kernel, KernelError, RegionAttr, TaskRef, ThreadState, TCB_CAPACITY,
 };
 
 #[repr(C)]
@@ -45,22 +46,92 @@ unsafe impl SysCall for SendCall {
         arg_type: SyscallDataType,
         kern: &mut Kernel,
     ) -> Result<CallReturn, KernelError> {
+        kern.check_queue_depth(self.cap_ref)?;
         let msg = get_msg(kern, arg_type, self.buf_addr, self.buf_len)?;
-        kern.send(self.cap_ref, msg)?;
+        send_msg(kern, self.cap_ref, msg)
+    }
+}
+
+/// Sends `msg` and yields to the next thread if one is waiting at the current thread's priority
+fn send_msg(
+    kern: &mut Kernel,
+    cap_ref: CapRef,
+    msg: IPCMsgBody,
+) -> Result<CallReturn, KernelError> {
+    kern.send(cap_ref, msg)?;
+    yield_to_waiting(kern)
+}
+
+/// Yields to the next thread if one is waiting at the current thread's priority, returning success either way
+fn yield_to_waiting(kern: &mut Kernel) -> Result<CallReturn, KernelError> {
+    let priority = kern.scheduler.current_thread.priority as usize;
+    let next_thread = kern.scheduler.next_thread(priority)?;
+    Ok(match next_thread {
+        Some(next_thread) => CallReturn::Switch {
+            next_thread: next_thread.tcb_ref,
+            ret: SyscallReturn::copy(0),
+        },
+        None => CallReturn::Return {
+            ret: SyscallReturn::copy(0),
+        },
+    })
+}
+
+/// Sends up to [`abi::MAX_SEND_VEC`] buffers as a single message, copying them in order
+///
+/// This lets a header and payload that live in different places be sent without another sender's message being
+/// queued between them.
+#[repr(C)]
+pub(crate) struct SendVecCall {
+    list_addr: usize,
+    count: usize,
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SendVecCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        if self.count > abi::MAX_SEND_VEC {
+            return Err(KernelError::ABI(abi::Error::BufferOverflow));
+        }
+        kern.check_queue_depth(self.cap_ref)?;
         let tcb = kern.scheduler.current_thread()?;
-        let priority = tcb.priority;
-        let next_thread = kern.scheduler.next_thread(priority);
-        Ok(match next_thread {
-            Some(next_thread) => CallReturn::Switch {
-                next_thread: next_thread.tcb_ref,
-                ret: abi::SyscallReturn::new()
-                    .with(abi::SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy),
-            },
-            None => CallReturn::Return {
-                ret: abi::SyscallReturn::new()
-                    .with(abi::SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy),
-            },
-        })
+        let task = kern.task(tcb.task)?;
+        // Safety: TaskPtr ensures that the list belongs to the current task
+        let list =
+            unsafe { TaskPtr::<'_, [abi::IoSlice]>::from_addr_count(self.list_addr, self.count) };
+        let list = task
+            .validate_ptr(list)
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?;
+        let mut buf = alloc::vec::Vec::new();
+        for slice in list {
+            buf.extend_from_slice(get_buf::<1024>(kern, tcb, slice.addr, slice.len)?);
+        }
+        send_msg(kern, self.cap_ref, IPCMsgBody::Buf(buf.into_boxed_slice()))
+    }
+}
+
+/// Sends a copy of one of the calling thread's capabilities through an endpoint, see [`Kernel::send_cap`]
+#[repr(C)]
+pub(crate) struct SendCapCall {
+    cap_ref: CapRef,
+    delegate: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SendCapCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        kern.check_queue_depth(self.cap_ref)?;
+        kern.send_cap(self.cap_ref, self.delegate)?;
+        yield_to_waiting(kern)
     }
 }
 
@@ -82,11 +153,12 @@ unsafe impl SysCall for CallSysCall {
         arg_type: SyscallDataType,
         kern: &mut Kernel,
     ) -> Result<CallReturn, KernelError> {
+        kern.check_queue_depth(self.cap_ref)?;
         let msg = get_msg(kern, arg_type, self.in_addr, self.in_len)?;
         let out_buf =
         // Safety: the caller is giving over memory to us, to overwrite
         // TaskPtrMut ensures that the memory belongs to the correct task
-            unsafe { TaskPtrMut::<'_, [u8]>::from_raw_parts(self.out_addr, self.out_len) };
+            unsafe { TaskPtrMut::from_addr_len_bytes(self.out_addr, self.out_len) };
         let recv_resp =
         // Safety: the caller is giving over memory to us, to overwrite
         // TaskPtrMut ensures that the memory belongs to the correct task
@@ -96,6 +168,7 @@ unsafe impl SysCall for CallSysCall {
             msg,
             RecvReq {
                 mask: 0, // NOTE: this is replaced by the endpoints addr in `call`
+                any: false,
                 resp: recv_resp,
                 inner: RecvReqInner::Buf { out: out_buf },
             },
@@ -113,6 +186,10 @@ pub(crate) struct RecvCall {
     out_len: usize,
     mask: usize,
     resp_addr: usize,
+    /// Either 0 to wait forever, [`abi::RECV_NO_WAIT`], or the number of ticks to wait for
+    timeout: usize,
+    /// Either 0, or [`abi::RECV_ANY`] to match messages sent to any of the addresses in `mask`
+    flags: usize,
 }
 
 // Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
@@ -128,15 +205,20 @@ unsafe impl SysCall for RecvCall {
             let out_buf =
         // Safety: the caller is giving over memory to us, to overwrite
         // TaskPtrMut ensures that the memory belongs to the correct task
-            unsafe { TaskPtrMut::<'_, [u8]>::from_raw_parts(self.out_addr, self.out_len as usize) };
+            unsafe { TaskPtrMut::from_addr_len_bytes(self.out_addr, self.out_len as usize) };
             RecvReqInner::Buf { out: out_buf }
         };
         let recv_resp =
         // Safety: the caller is giving over memory to us, to overwrite
         // TaskPtrMut ensures that the memory belongs to the correct task
             unsafe { TaskPtrMut::<'_, MaybeUninit<RecvResp>>::from_raw_parts(self.resp_addr, ()) };
+        if self.mask & abi::INTERRUPT_ADDR != 0 {
+            // receiving interrupts means the thread is done handling the last one
+            kern.unmask_irqs(kern.scheduler.current_thread.tcb_ref);
+        }
         let recv_req = RecvReq {
             mask: self.mask,
+            any: self.flags & abi::RECV_ANY != 0,
             resp: recv_resp,
             inner: recv_req_inner,
         };
@@ -144,16 +226,20 @@ unsafe impl SysCall for RecvCall {
         let task = kern
             .tasks
             .get_mut(tcb.task.0)
-            .ok_or(KernelError::InvalidTaskRef)?;
+            .ok_or(KernelError::InvalidTaskRef(tcb.task))?;
         if let RecvRes::NotFound(req) = tcb.recv(task, recv_req)? {
+            let deadline = match self.timeout {
+                0 => None,
+                abi::RECV_NO_WAIT => return Err(KernelError::ABI(abi::Error::WouldBlock)),
+                timeout => Some(kern.scheduler.ticks.saturating_add(timeout as u64)),
+            };
             Ok(CallReturn::Replace {
-                next_thread: kern.scheduler.wait(req, false)?,
+                next_thread: kern.scheduler.wait(req, deadline, false)?,
             })
         } else {
             defmt::println!("got msg in recv");
             Ok(CallReturn::Return {
-                ret: abi::SyscallReturn::new()
-                    .with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy),
+                ret: SyscallReturn::copy(0),
             })
         }
     }
@@ -175,7 +261,7 @@ unsafe impl SysCall for LogCall {
         let tcb = kern.scheduler.current_thread()?;
 
         let log_buf = get_buf::<1024>(kern, tcb, self.in_addr, self.in_len)?;
-        crate::defmt_log::log(tcb.task.0 as u8 + 1, log_buf);
+        crate::defmt_log::log_task(tcb.task.0 as u8 + 1, kern.task_name(tcb.task), log_buf);
         Ok(CallReturn::Return {
             ret: abi::SyscallReturn::new(),
         })
@@ -200,7 +286,7 @@ unsafe impl SysCall for CapsCall {
         // Safety: the caller is giving over memory to us, to overwrite
         // TaskPtrMut ensures that the memory belongs to the correct task
         let slice = unsafe {
-            TaskPtrMut::<'_, [CapListEntry]>::from_raw_parts(self.out_addr, self.out_len)
+            TaskPtrMut::<'_, [CapListEntry]>::from_addr_count(self.out_addr, self.out_len)
         };
         let slice = task
             .validate_mut_ptr(slice)
@@ -209,13 +295,11 @@ unsafe impl SysCall for CapsCall {
         let len = slice.len().min(tcb.capabilities.len());
         for (i, entry) in tcb.capabilities.iter().take(len).enumerate() {
             slice[i] = abi::CapListEntry {
-                cap_ref: CapRef((entry as *const CapEntry).addr()),
+                cap_ref: entry.cap_ref(),
                 desc: entry.cap.clone(),
             };
         }
-        let ret = abi::SyscallReturn::new()
-            .with(abi::SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy)
-            .with(abi::SyscallReturn::SYSCALL_LEN, len as u64);
+        let ret = SyscallReturn::copy(len as u64);
         Ok(CallReturn::Return { ret })
     }
 }
@@ -224,6 +308,8 @@ unsafe impl SysCall for CapsCall {
 pub(crate) struct PanikCall {
     addr: usize,
     len: usize,
+    /// the length of the panic message before userspace truncated it to fit in the buffer
+    full_len: usize,
 }
 
 // Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
@@ -237,61 +323,504 @@ unsafe impl SysCall for PanikCall {
         let task_ref = tcb.task;
         let buf = get_buf::<512>(kern, tcb, self.addr, self.len)?;
         if let Ok(s) = core::str::from_utf8(buf) {
-            error!("task {:?} paniked: {}", task_ref.0, s);
+            let s = s.trim_end_matches('\0');
+            if self.full_len > self.len {
+                error!(
+                    "task {:?} paniked: {} (truncated from {} bytes)",
+                    task_ref.0, s, self.full_len
+                );
+            } else {
+                error!("task {:?} paniked: {}", task_ref.0, s);
+            }
         } else {
             error!("task {:?} paniked with invalid msg", task_ref.0);
         }
-        kern.scheduler.wait_queue.retain(|e| {
-            if let Some(tcb) = kern.scheduler.tcbs.get(*e.tcb_ref) {
-                tcb.task != task_ref
-            } else {
-                false
-            }
-        });
-        let task = kern
-            .tasks
-            .get_mut(task_ref.0)
-            .ok_or(KernelError::InvalidTaskRef)?;
-        task.state = TaskState::Pending;
-        task.reset_stack_ptr();
+        let msg = buf.split(|b| *b == 0).next().unwrap_or_default();
+        if let Some(task) = kern.tasks.get(task_ref.0) {
+            let ticks = kern.scheduler.ticks;
+            crate::crash_log::record_panic(task_ref.0 as u8, task.name, ticks, msg);
+        }
+        let next_thread = kern.restart_task(task_ref)?;
+        Ok(CallReturn::Replace { next_thread })
+    }
+}
+
+#[repr(C)]
+pub(crate) struct RebootCall {}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for RebootCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        if !tcb.is_admin() {
+            return Err(KernelError::ABI(abi::Error::InvalidCap));
+        }
+        info!("task {:?} requested reboot", tcb.task.0);
+        crate::defmt_log::log(crate::defmt_log::REBOOT_ID, &[]);
+        crate::arch::reboot()
+    }
+}
+
+#[repr(C)]
+pub(crate) struct TimestampCall {}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for TimestampCall {
+    #[inline]
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        // the tick count is split across `SYSCALL_PTR` and `SYSCALL_LEN`, so only the low 54 bits are returned
+        let ticks = kern.scheduler.ticks;
+        let ret = SyscallReturn::short()
+            .with(SyscallReturn::SYSCALL_PTR, ticks & 0xFFFF_FFFF)
+            .with(SyscallReturn::SYSCALL_LEN, (ticks >> 32) & 0x3F_FFFF);
+        Ok(CallReturn::Return { ret })
+    }
+}
+
+/// Maps a peripheral into the calling task, which must hold an admin cap
+///
+/// The range must sit inside a window registered with [`crate::KernelBuilder::device_space`], and can't already be
+/// mapped by any task.
+#[repr(C)]
+pub(crate) struct MapDeviceCall {
+    addr: usize,
+    len: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for MapDeviceCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        if !tcb.is_admin() {
+            return Err(KernelError::ABI(abi::Error::InvalidCap));
+        }
+        let task_ref = tcb.task;
+        let region = Region {
+            range: self.addr..self.addr.checked_add(self.len).ok_or(abi::Error::BadAccess)?,
+            attr: RegionAttr::Device | RegionAttr::Read | RegionAttr::Write,
+        };
+        if kern.task(task_ref)?.region_table.overlaps(&region) {
+            return Err(KernelError::ABI(abi::Error::InvalidLoan));
+        }
+        kern.devices.claim(task_ref, region.range.clone())?;
+        let range = region.range.clone();
+        if let Err(err) = kern.task_mut(task_ref)?.region_table.push(region) {
+            kern.devices.release(task_ref, range);
+            return Err(err);
+        }
+        info!(
+            "task {:?} mapped device {=usize:#x}..{=usize:#x}",
+            task_ref.0, range.start, range.end
+        );
+        // switching back to the current thread reapplies its region table, so the new region is usable immediately
+        Ok(CallReturn::Switch {
+            next_thread: kern.scheduler.current_thread.tcb_ref,
+            ret: SyscallReturn::copy(0),
+        })
+    }
+}
+
+/// Maps the flash range of a [`Cap::SharedFlash`] into the calling task, and returns its start address
+///
+/// The range comes from the cap, which only [`crate::KernelBuilder::shared_flash`] can create, so it is trusted. It
+/// is always mapped read and execute only, so a task can never write to it. Mapping the same range again is a no-op.
+#[repr(C)]
+pub(crate) struct MapSharedCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for MapSharedCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let range = match tcb.cap(self.cap_ref) {
+            Ok(Cap::SharedFlash { range }) => range.clone(),
+            _ => return Err(KernelError::ABI(abi::Error::InvalidCap)),
+        };
+        let task_ref = tcb.task;
+        let region = Region {
+            range: range.clone(),
+            attr: RegionAttr::Read | RegionAttr::Exec,
+        };
+        let ret = SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, range.start as u64);
+        let region_table = &mut kern.task_mut(task_ref)?.region_table;
+        if region_table.iter().any(|r| *r == region) {
+            return Ok(CallReturn::Return { ret });
+        }
+        if region_table.overlaps(&region) {
+            return Err(KernelError::ABI(abi::Error::InvalidLoan));
+        }
+        region_table.push(region)?;
+        info!(
+            "task {:?} mapped shared flash {=usize:#x}..{=usize:#x}",
+            task_ref.0, range.start, range.end
+        );
+        // switching back to the current thread reapplies its region table, so the new region is usable immediately
+        Ok(CallReturn::Switch {
+            next_thread: kern.scheduler.current_thread.tcb_ref,
+            ret,
+        })
+    }
+}
+
+/// Removes a region from the calling task, such as a page it received or a device it mapped
+///
+/// The range must exactly match a region in the task's region table. Loaned pages are discarded, and devices can be
+/// mapped again by any task.
+#[repr(C)]
+pub(crate) struct UnmapCall {
+    addr: usize,
+    len: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for UnmapCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let task_ref = kern.scheduler.current_thread()?.task;
+        let range = self.addr..self.addr.checked_add(self.len).ok_or(abi::Error::BadAccess)?;
+        let task = kern.task_mut(task_ref)?;
+        let region = task
+            .region_table
+            .iter()
+            .find(|r| r.range == range)
+            .cloned()
+            .ok_or(abi::Error::BadAccess)?;
+        task.region_table.pop(region.clone());
+        if region.attr.contains(RegionAttr::Device) {
+            kern.devices.release(task_ref, range);
+        }
+        // switching back to the current thread reapplies its region table, so the region is unmapped immediately
+        Ok(CallReturn::Switch {
+            next_thread: kern.scheduler.current_thread.tcb_ref,
+            ret: SyscallReturn::copy(0),
+        })
+    }
+}
+
+/// Writes the memory layout and thread count of the task at `task_index` to the caller
+///
+/// Flash is the region containing the task's entrypoint, and RAM is the region containing its stacks.
+#[repr(C)]
+pub(crate) struct GetTaskInfoCall {
+    task_index: usize,
+    out_addr: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for GetTaskInfoCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let task_ref = TaskRef(self.task_index);
         let task = kern
             .tasks
             .get(task_ref.0)
-            .ok_or(KernelError::InvalidTaskRef)?;
-        let mut priority = None;
-        let mut budget = None;
-        let mut cooldown = None;
-        let mut caps = None;
-
-        for i in 0..16 {
-            if let Some(tcb) = kern.scheduler.tcbs.get(i) {
-                if tcb.task == task_ref {
-                    let tcb = kern.scheduler.tcbs.remove(i).unwrap();
-                    if tcb.entrypoint == task.entrypoint.addr() {
-                        priority = Some(tcb.priority);
-                        budget = Some(tcb.budget);
-                        cooldown = Some(tcb.cooldown);
-                        caps = Some(tcb.capabilities);
-                    }
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?;
+        let region_containing = |addr: usize| {
+            task.region_table
+                .iter()
+                .find(|r| r.range.contains(&addr))
+                .map(|r| (r.range.start, r.range.len()))
+                .unwrap_or_default()
+        };
+        let (flash_start, flash_len) = region_containing(task.entrypoint.addr());
+        let (ram_start, ram_len) = region_containing(task.initial_stack_ptr.start);
+        let mut name = [0u8; 32];
+        let len = task.name.len().min(name.len());
+        name[..len].copy_from_slice(&task.name.as_bytes()[..len]);
+        let thread_count = kern
+            .scheduler
+            .tcbs
+            .iter()
+            .filter(|tcb| tcb.task == task_ref)
+            .count();
+        let info = TaskInfo {
+            name,
+            thread_count: thread_count.min(u8::MAX as usize) as u8,
+            flash_start,
+            flash_len,
+            ram_start,
+            ram_len,
+        };
+
+        let tcb = kern.scheduler.current_thread()?;
+        let caller = kern.task(tcb.task)?;
+        // Safety: the caller is giving over memory to us, to overwrite
+        // TaskPtrMut ensures that the memory belongs to the correct task
+        let out =
+            unsafe { TaskPtrMut::<'_, MaybeUninit<TaskInfo>>::from_raw_parts(self.out_addr, ()) };
+        caller
+            .validate_mut_ptr(out)
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?
+            .write(info);
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::copy(mem::size_of::<TaskInfo>() as u64),
+        })
+    }
+}
+
+/// Returns the calling thread's scheduling statistics
+///
+/// With [`SyscallDataType::Copy`] the stats are written to `out_addr`, and with [`SyscallDataType::Short`]
+/// they are written to the log, so the host can display them alongside the task's logs.
+#[repr(C)]
+pub(crate) struct StatsCall {
+    out_addr: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for StatsCall {
+    fn exec(
+        &self,
+        arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let stats = tcb.stats;
+        match arg_type {
+            SyscallDataType::Copy => {
+                let caller = kern.task(tcb.task)?;
+                // Safety: the caller is giving over memory to us, to overwrite
+                // TaskPtrMut ensures that the memory belongs to the correct task
+                let out = unsafe {
+                    TaskPtrMut::<'_, MaybeUninit<TaskStats>>::from_raw_parts(self.out_addr, ())
+                };
+                caller
+                    .validate_mut_ptr(out)
+                    .ok_or(KernelError::ABI(abi::Error::BadAccess))?
+                    .write(stats);
+                Ok(CallReturn::Return {
+                    ret: SyscallReturn::copy(mem::size_of::<TaskStats>() as u64),
+                })
+            }
+            SyscallDataType::Short => {
+                crate::defmt_log::log_stats(tcb.task.0 as u8 + 1, &stats);
+                Ok(CallReturn::Return {
+                    ret: SyscallReturn::new(),
+                })
+            }
+            _ => Err(KernelError::ABI(abi::Error::ReturnTypeMismatch)),
+        }
+    }
+}
+
+/// Returns the most stack the calling thread has used, in bytes
+///
+/// Stacks are painted before a task starts, so this is the distance from the top of the stack to the deepest word
+/// that no longer holds the paint. Thread-local storage at the top of the stack isn't counted, and neither is
+/// anything below the stack's guard, which a thread can't reach without faulting.
+#[repr(C)]
+pub(crate) struct StackUsageCall {}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for StackUsageCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let task = kern.task(tcb.task)?;
+        let top = tcb.stack_pointer;
+        let bottom = top + task.tls_size - task.stack_size;
+        // the guard splits the stack's region in two, and a pointer has to be inside a single region
+        let bottom = task
+            .region_table
+            .guards_in(bottom..top)
+            .map(|guard| guard.end)
+            .fold(bottom, usize::max);
+        // Safety: the stack is validated against the task's regions before it is read
+        let stack = unsafe {
+            TaskPtr::<'_, [u32]>::from_addr_count(bottom, (top - bottom) / mem::size_of::<u32>())
+        };
+        let stack = task
+            .validate_ptr(stack)
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?;
+        let used = crate::security::stack_usage(stack);
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, used as u64),
+        })
+    }
+}
+
+/// Returns the IRQ number of a [`Cap::Irq`] held by the current thread
+fn irq_cap(kern: &Kernel, cap_ref: CapRef) -> Result<u16, KernelError> {
+    match kern.scheduler.current_thread()?.cap(cap_ref)? {
+        Cap::Irq { irq_number } => Ok(*irq_number),
+        _ => Err(KernelError::ABI(abi::Error::InvalidCap)),
+    }
+}
+
+/// Waits for the interrupt of a [`Cap::Irq`] to fire
+///
+/// Returns straight away if it fired since the last wait. The first wait unmasks the interrupt, after that it is
+/// masked each time it fires, until the thread acknowledges it with [`AckIrqCall`].
+#[repr(C)]
+pub(crate) struct WaitIrqCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for WaitIrqCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let irq = irq_cap(kern, self.cap_ref)?;
+        let tcb_ref = kern.scheduler.current_thread.tcb_ref;
+        let route = kern
+            .irq_caps
+            .get_mut(&irq)
+            .filter(|route| route.tcb_ref == tcb_ref)
+            .ok_or(KernelError::ABI(abi::Error::InvalidCap))?;
+        if mem::take(&mut route.pending) {
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::copy(0),
+            });
+        }
+        if !mem::replace(&mut route.enabled, true) {
+            crate::arch::unmask_irq(irq);
+        }
+        Ok(CallReturn::Replace {
+            next_thread: kern.scheduler.wait_irq(irq)?,
+        })
+    }
+}
+
+/// Acknowledges the interrupt of a [`Cap::Irq`], clearing its pending state and unmasking it
+///
+/// The thread should clear the interrupt at its source first, otherwise a level-triggered interrupt fires again
+/// straight away.
+#[repr(C)]
+pub(crate) struct AckIrqCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for AckIrqCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let irq = irq_cap(kern, self.cap_ref)?;
+        let tcb_ref = kern.scheduler.current_thread.tcb_ref;
+        let route = kern
+            .irq_caps
+            .get_mut(&irq)
+            .filter(|route| route.tcb_ref == tcb_ref)
+            .ok_or(KernelError::ABI(abi::Error::InvalidCap))?;
+        route.enabled = true;
+        crate::arch::ack_irq(irq);
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::new(),
+        })
+    }
+}
+
+/// Reads memory from the calling task, only available in debug builds
+///
+/// With [`SyscallDataType::Copy`] the bytes are copied into the out buffer, and with [`SyscallDataType::Short`]
+/// they are written to the log as a memory dump, so the host can display them.
+#[cfg(debug_assertions)]
+#[repr(C)]
+pub(crate) struct ReadMemoryCall {
+    addr: usize,
+    len: usize,
+    out_addr: usize,
+    out_len: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+#[cfg(debug_assertions)]
+unsafe impl SysCall for ReadMemoryCall {
+    fn exec(
+        &self,
+        arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        if !tcb.is_admin() {
+            return Err(KernelError::ABI(abi::Error::InvalidCap));
+        }
+        let src = get_buf::<0>(kern, tcb, self.addr, self.len)?;
+        match arg_type {
+            SyscallDataType::Copy => {
+                let out = get_buf_mut(kern, tcb, self.out_addr, self.out_len)?;
+                if out.len() != src.len() {
+                    return Err(KernelError::ABI(abi::Error::ReturnTypeMismatch));
                 }
+                // Safety: both buffers were validated to be in the task's regions, and have the same length.
+                // They may overlap, so we use `ptr::copy` rather than `copy_from_slice`
+                unsafe { core::ptr::copy(src.as_ptr(), out.as_mut_ptr(), src.len()) };
+            }
+            SyscallDataType::Short => {
+                crate::defmt_log::log_memory(tcb.task.0 as u8 + 1, self.addr, src);
             }
+            _ => return Err(KernelError::ABI(abi::Error::ReturnTypeMismatch)),
         }
-        let (priority, budget, cooldown, caps) =
-            if let Some(priority) = priority
-                && let Some(budget) = budget
-                && let Some(cooldown) = cooldown
-                && let Some(caps) =  caps {
-                    (priority, budget, cooldown, caps)
-                }else {
-                    return Err(KernelError:: InitTCBNotFound);
-                };
-        kern.spawn_thread(task_ref, priority, budget, cooldown, task.entrypoint, caps)?;
-        let next_thread = kern
-            .scheduler
-            .next_thread(0)
-            .unwrap_or_else(DomainEntry::idle);
-        let next_thread = kern.scheduler.switch_thread(next_thread)?;
-        Ok(CallReturn::Replace { next_thread })
+        Ok(CallReturn::Return {
+            ret: abi::SyscallReturn::new(),
+        })
+    }
+}
+
+/// Writes to the calling task's memory, only available in debug builds
+#[cfg(debug_assertions)]
+#[repr(C)]
+pub(crate) struct WriteMemoryCall {
+    addr: usize,
+    len: usize,
+    in_addr: usize,
+    in_len: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+#[cfg(debug_assertions)]
+unsafe impl SysCall for WriteMemoryCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        if !tcb.is_admin() {
+            return Err(KernelError::ABI(abi::Error::InvalidCap));
+        }
+        let src = get_buf::<0>(kern, tcb, self.in_addr, self.in_len)?;
+        let dest = get_buf_mut(kern, tcb, self.addr, self.len)?;
+        if dest.len() != src.len() {
+            return Err(KernelError::ABI(abi::Error::ReturnTypeMismatch));
+        }
+        // Safety: both buffers were validated to be in the task's regions, and have the same length.
+        // They may overlap, so we use `ptr::copy` rather than `copy_from_slice`
+        unsafe { core::ptr::copy(src.as_ptr(), dest.as_mut_ptr(), src.len()) };
+        Ok(CallReturn::Return {
+            ret: abi::SyscallReturn::new(),
+        })
     }
 }
 
@@ -308,24 +837,31 @@ unsafe impl SysCall for ListenCall {
         kern: &mut Kernel,
     ) -> Result<CallReturn, KernelError> {
         let tcb = kern.scheduler.current_thread()?;
-        let listen = match tcb.cap(self.cap_ref)? {
-            abi::Cap::Listen(listen) => listen,
+        let endpoint = abi::Endpoint {
+            tcb_ref: kern.scheduler.current_thread.tcb_ref,
+            addr: 0,
+            disposable: false,
+            max_queue_depth: 0,
+        };
+        let port = match tcb.cap(self.cap_ref)? {
+            abi::Cap::Listen(listen) => {
+                kern.registry.listen(*listen, endpoint).map(|_| listen.port)
+            }
+            abi::Cap::ListenShard(listen) => kern
+                .registry
+                .listen_shard(*listen, endpoint)
+                .map(|_| listen.port),
             _ => {
                 return Err(KernelError::ABI(abi::Error::InvalidCap));
             }
-        };
-        kern.registry
-            .listen(
-                *listen,
-                abi::Endpoint {
-                    tcb_ref: kern.scheduler.current_thread.tcb_ref,
-                    addr: 0,
-                    disposable: false,
-                },
-            )
-            .map_err(KernelError::ABI)?;
+        }
+        .map_err(KernelError::ABI)?;
+        for (tcb_ref, index, priority) in kern.registry.take_pending(&port) {
+            kern.scheduler
+                .unblock(tcb_ref, priority, SyscallReturn::copy(index as u64))?;
+        }
         Ok(CallReturn::Return {
-            ret: SyscallReturn::new().with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy),
+            ret: SyscallReturn::copy(0),
         })
     }
 }
@@ -352,11 +888,9 @@ unsafe impl SysCall for ConnectCall {
         let endpoint = kern.registry.connect(*connect).map_err(KernelError::ABI)?;
         tcb.add_cap(Cap::Endpoint(endpoint));
         let entry = tcb.capabilities.back().unwrap();
-        let cap_ref = (entry.get_ref() as *const CapEntry).addr();
+        let cap_ref = *entry.cap_ref();
         Ok(CallReturn::Return {
-            ret: SyscallReturn::new()
-                .with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy)
-                .with(SyscallReturn::SYSCALL_PTR, cap_ref as u64),
+            ret: SyscallReturn::copy(0).with(SyscallReturn::SYSCALL_PTR, cap_ref as u64),
         })
     }
 }
@@ -372,16 +906,16 @@ fn get_msg(
         SyscallDataType::Short => todo!(),
         SyscallDataType::Copy => {
             let slice = get_buf::<1024>(kern, tcb, addr, len)?;
-            Ok(IPCMsgBody::Buf(alloc::boxed::Box::from(slice)))
+            Ok(IPCMsgBody::copy(slice))
         }
         SyscallDataType::Page => {
             let task = kern
                 .tasks
                 .get_mut(tcb.task.0)
-                .ok_or(KernelError::InvalidTaskRef)?;
+                .ok_or(KernelError::InvalidTaskRef(tcb.task))?;
             // Safety: the caller is giving over memory to us, to overwrite
             // TaskPtrMut ensures that the memory belongs to the correct task
-            let slice = unsafe { TaskPtrMut::<'_, [u8]>::from_raw_parts(addr, len) };
+            let slice = unsafe { TaskPtrMut::from_addr_len_bytes(addr, len) };
             let slice = if let Some(slice) = task.validate_mut_ptr(slice) {
                 slice
             } else {
@@ -405,10 +939,10 @@ fn get_buf<'t, const N: usize>(
     let task = kern
         .tasks
         .get(tcb.task.0)
-        .ok_or(KernelError::InvalidTaskRef)?;
+        .ok_or(KernelError::InvalidTaskRef(tcb.task))?;
     // Safety: the caller is giving over memory to us, to overwrite
     // TaskPtrMut ensures that the memory belongs to the correct task
-    let slice = unsafe { TaskPtr::<'_, [u8]>::from_raw_parts(addr, len) };
+    let slice = unsafe { TaskPtr::from_addr_len_bytes(addr, len) };
     let slice = if let Some(buf) = task.validate_ptr(slice) {
         buf
     } else {
@@ -417,6 +951,23 @@ fn get_buf<'t, const N: usize>(
     Ok(slice)
 }
 
+#[cfg(debug_assertions)]
+fn get_buf_mut<'t>(
+    kern: &Kernel,
+    tcb: &Tcb,
+    addr: usize,
+    len: usize,
+) -> Result<&'t mut [u8], KernelError> {
+    let task = kern
+        .tasks
+        .get(tcb.task.0)
+        .ok_or(KernelError::InvalidTaskRef(tcb.task))?;
+    // Safety: TaskPtrMut ensures that the memory belongs to the correct task
+    let slice = unsafe { TaskPtrMut::from_addr_len_bytes(addr, len) };
+    task.validate_mut_ptr(slice)
+        .ok_or(KernelError::ABI(abi::Error::BadAccess))
+}
+
 #[derive(Format)]
 pub enum CallReturn {
     Replace {
@@ -430,3 +981,377 @@ pub enum CallReturn {
         ret: abi::SyscallReturn,
     },
 }
+
+/// Returns the id of the semaphore `cap_ref` points to
+fn semaphore_id(tcb: &Tcb, cap_ref: CapRef) -> Result<SemaphoreId, KernelError> {
+    match tcb.cap(cap_ref)? {
+        Cap::Semaphore(id) => Ok(*id),
+        _ => Err(KernelError::ABI(abi::Error::InvalidCap)),
+    }
+}
+
+/// Takes a unit from a semaphore, blocking until another thread posts to it if the count is zero
+#[repr(C)]
+pub(crate) struct SemWaitCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SemWaitCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let id = semaphore_id(tcb, self.cap_ref)?;
+        let priority = tcb.priority;
+        let tcb_ref = kern.scheduler.current_thread.tcb_ref;
+        if kern
+            .registry
+            .sem_wait(id, tcb_ref, priority)
+            .map_err(KernelError::ABI)?
+        {
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::copy(0),
+            });
+        }
+        Ok(CallReturn::Replace {
+            next_thread: kern.scheduler.block()?,
+        })
+    }
+}
+
+/// Returns a unit to a semaphore, waking the highest priority thread blocked on it
+#[repr(C)]
+pub(crate) struct SemPostCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SemPostCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let id = semaphore_id(kern.scheduler.current_thread()?, self.cap_ref)?;
+        let waiter = kern.registry.sem_post(id).map_err(KernelError::ABI)?;
+        if let Some((tcb_ref, priority)) = waiter {
+            kern.scheduler.unblock(tcb_ref, priority, SyscallReturn::copy(0))?;
+        }
+        yield_to_waiting(kern)
+    }
+}
+
+/// Blocks until one of a list of ports is listened on, returning its index in the list
+///
+/// This lets a thread wait for a service to start without spinning on [`abi::SyscallFn::Connect`].
+#[repr(C)]
+pub(crate) struct PollCapsCall {
+    ports_addr: usize,
+    count: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for PollCapsCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        if self.count == 0 || self.count > abi::MAX_POLL_PORTS {
+            return Err(KernelError::ABI(abi::Error::BufferOverflow));
+        }
+        let tcb = kern.scheduler.current_thread()?;
+        let task = kern.task(tcb.task)?;
+        // Safety: TaskPtr ensures that the list belongs to the current task
+        let ports =
+            unsafe { TaskPtr::<'_, [PortId]>::from_addr_count(self.ports_addr, self.count) };
+        let ports = task
+            .validate_ptr(ports)
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?;
+        // the ports are copied out of task memory, so the registry can be updated below
+        let ports = heapless::Vec::<PortId, { abi::MAX_POLL_PORTS }>::from_slice(ports)
+            .map_err(|_| KernelError::ABI(abi::Error::BufferOverflow))?;
+        if let Some(index) = kern.registry.first_open(&ports) {
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::copy(index as u64),
+            });
+        }
+        let priority = tcb.priority;
+        let tcb_ref = kern.scheduler.current_thread.tcb_ref;
+        kern.registry
+            .wait_for_ports(&ports, tcb_ref, priority)
+            .map_err(KernelError::ABI)?;
+        Ok(CallReturn::Replace {
+            next_thread: kern.scheduler.block()?,
+        })
+    }
+}
+
+/// Gives up the rest of the current thread's turn to the next ready thread of the same or higher priority
+#[repr(C)]
+pub(crate) struct YieldCall {}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for YieldCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let ret = SyscallReturn::copy(0);
+        Ok(match kern.scheduler.yield_thread()? {
+            Some(next_thread) => CallReturn::Switch { next_thread, ret },
+            None => CallReturn::Return { ret },
+        })
+    }
+}
+
+/// Suspends the current thread for a number of ticks, without it having to wait on an endpoint
+#[repr(C)]
+pub(crate) struct SleepCall {
+    ticks: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SleepCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        if self.ticks == 0 {
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::copy(0),
+            });
+        }
+        // the idle thread is what runs when everything else is suspended, so it can't be suspended itself
+        if kern.scheduler.current_thread.tcb_ref == ThreadRef::idle() {
+            return Err(KernelError::ABI(abi::Error::NotSupported));
+        }
+        Ok(CallReturn::Replace {
+            next_thread: kern.scheduler.sleep(self.ticks)?,
+        })
+    }
+}
+
+/// Spawns a new thread in the calling thread's task, starting at `entrypoint`
+///
+/// The thread gets a stack from the task's stack space and no capabilities. It can't be given a higher priority
+/// than the thread spawning it, and its budget and cooldown must both be at least one tick.
+#[repr(C)]
+pub(crate) struct SpawnCall {
+    entrypoint: usize,
+    priority: usize,
+    budget: usize,
+    cooldown: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for SpawnCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        if self.priority > tcb.priority {
+            return Err(KernelError::ABI(abi::Error::PermissionDenied));
+        }
+        if self.budget == 0 || self.cooldown == 0 {
+            return Err(KernelError::ABI(abi::Error::NotSupported));
+        }
+        // checked up front, since `spawn_thread` has already taken a stack by the time the thread table is full
+        if kern.scheduler.tcbs.len() >= TCB_CAPACITY {
+            return Err(KernelError::ABI(abi::Error::BufferOverflow));
+        }
+        let task_ref = tcb.task;
+        let task = kern.task(task_ref)?;
+        if !task
+            .region_table
+            .iter()
+            .any(|r| r.range.contains(&self.entrypoint) && r.attr.contains(RegionAttr::Exec))
+        {
+            return Err(KernelError::ABI(abi::Error::BadAccess));
+        }
+        // Safety: the entrypoint is validated against the task's regions by `spawn_thread`
+        let entrypoint = unsafe { TaskPtr::from_raw_parts(self.entrypoint, ()) };
+        let tcb_ref = kern
+            .spawn_thread(
+                task_ref,
+                self.priority,
+                self.budget,
+                self.cooldown,
+                entrypoint,
+                List::new(),
+            )
+            .map_err(|err| match err {
+                KernelError::StackExhausted => KernelError::ABI(abi::Error::BufferOverflow),
+                KernelError::InvalidEntrypoint => KernelError::ABI(abi::Error::BadAccess),
+                err => err,
+            })?;
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, *tcb_ref as u64),
+        })
+    }
+}
+
+/// Removes every thread's endpoint to one of the caller's ports, see [`Kernel::revoke_endpoint`]
+///
+/// The port is the address of an endpoint the caller holds. A server that answers each client on the same address
+/// the client sends to, like [`crate::KernelBuilder::endpoint_group`] sets up, kicks a client by revoking the
+/// endpoint it answers that client with. A thread holding a [`Cap::Admin`] instead revokes the port the endpoint
+/// points to, whichever thread it belongs to.
+#[repr(C)]
+pub(crate) struct RevokeCall {
+    cap_ref: CapRef,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for RevokeCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let endpoint = match tcb.cap(self.cap_ref) {
+            Ok(Cap::Endpoint(endpoint)) => *endpoint,
+            _ => return Err(KernelError::ABI(abi::Error::InvalidCap)),
+        };
+        // only the thread behind a port, or an admin, gets to decide who can reach it
+        let endpoint = if tcb.is_admin() {
+            endpoint
+        } else {
+            abi::Endpoint {
+                tcb_ref: kern.scheduler.current_thread.tcb_ref,
+                ..endpoint
+            }
+        };
+        kern.revoke_endpoint(endpoint);
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::copy(0),
+        })
+    }
+}
+
+/// Sets notification bits on the thread a [`Cap::Notify`] points to, waking it if it is waiting for any of them
+#[repr(C)]
+pub(crate) struct NotifyCall {
+    cap_ref: CapRef,
+    bits: u32,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for NotifyCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let dest = match kern.scheduler.current_thread()?.cap(self.cap_ref)? {
+            Cap::Notify(dest) => *dest,
+            _ => return Err(KernelError::ABI(abi::Error::InvalidCap)),
+        };
+        let dest_tcb = kern.scheduler.get_tcb_mut(dest)?;
+        dest_tcb.notify_bits |= self.bits;
+        if let ThreadState::WaitingNotify { mask } = dest_tcb.state {
+            let woken = dest_tcb.notify_bits & mask;
+            if woken != 0 {
+                dest_tcb.notify_bits &= !woken;
+                let priority = dest_tcb.priority;
+                let ret = SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, woken as u64);
+                kern.scheduler.unblock(dest, priority, ret)?;
+            }
+        }
+        yield_to_waiting(kern)
+    }
+}
+
+/// Waits until any of the notification bits in `mask` are set, returning and clearing the ones that are
+#[repr(C)]
+pub(crate) struct WaitNotifyCall {
+    mask: u32,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for WaitNotifyCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        if self.mask == 0 {
+            return Err(KernelError::ABI(abi::Error::WouldBlock));
+        }
+        let tcb = kern.scheduler.current_thread_mut()?;
+        let pending = tcb.notify_bits & self.mask;
+        if pending != 0 {
+            tcb.notify_bits &= !pending;
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, pending as u64),
+            });
+        }
+        Ok(CallReturn::Replace {
+            next_thread: kern.scheduler.wait_notify(self.mask)?,
+        })
+    }
+}
+
+/// Allocates `size` bytes aligned to `align` from the heap behind a [`Cap::Heap`], or frees them if `ptr` isn't 0
+///
+/// A null `cap_ref` uses the calling task's heap, see [`crate::KernelBuilder::heap`]
+///
+/// Frees must pass the same size the allocation was made with. A heap without room fails with
+/// [`abi::Error::BufferOverflow`], and freeing something that wasn't allocated fails with [`abi::Error::BadAccess`].
+#[repr(C)]
+pub(crate) struct AllocCall {
+    cap_ref: CapRef,
+    size: usize,
+    align: usize,
+    ptr: usize,
+}
+
+// Safety: The only requirement for safety in this trait is that the implementer has the same alignment and less than or equal length as [`SyscallArgs`]
+unsafe impl SysCall for AllocCall {
+    fn exec(
+        &self,
+        _arg_type: SyscallDataType,
+        kern: &mut Kernel,
+    ) -> Result<CallReturn, KernelError> {
+        let tcb = kern.scheduler.current_thread()?;
+        let task = kern.task(tcb.task)?;
+        let range = if self.cap_ref.is_null() {
+            task.heap.clone().ok_or(abi::Error::InvalidCap)?
+        } else {
+            match tcb.cap(self.cap_ref) {
+                Ok(Cap::Heap { base, size }) => *base..*base + *size,
+                _ => return Err(KernelError::ABI(abi::Error::InvalidCap)),
+            }
+        };
+        // the bitmap lives in the heap, so the heap must be memory the calling task can write, whoever made the cap
+        // Safety: the pointer is validated against the task's regions before it is used
+        let ptr = unsafe { TaskPtrMut::from_addr_len_bytes(range.start, range.len()) };
+        task.validate_mut_ptr(ptr)
+            .ok_or(KernelError::ABI(abi::Error::BadAccess))?;
+        // Safety: the range was just checked to be in the task's RAM
+        let mut heap = unsafe { Heap::new(range) }.ok_or(abi::Error::BufferOverflow)?;
+        if self.ptr != 0 {
+            heap.free(self.ptr, self.size)?;
+            return Ok(CallReturn::Return {
+                ret: SyscallReturn::copy(0),
+            });
+        }
+        if !self.align.is_power_of_two() {
+            return Err(KernelError::ABI(abi::Error::NotSupported));
+        }
+        let addr = heap
+            .alloc(self.size, self.align)
+            .ok_or(abi::Error::BufferOverflow)?;
+        Ok(CallReturn::Return {
+            ret: SyscallReturn::short().with(SyscallReturn::SYSCALL_PTR, addr as u64),
+        })
+    }
+}
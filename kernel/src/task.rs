@@ -1,19 +1,39 @@
-use crate::arch;
+use crate::heap::Heap;
 use crate::regions::RegionTable;
 use crate::task_ptr::{TaskPtr, TaskPtrMut};
+use crate::{arch, KernelError, RegionBuilder};
+use abi::Cap;
 use core::ops::Range;
 use heapless::Vec;
 
+/// The alignment AAPCS requires of the stack pointer at a public interface
+#[cfg(feature = "cortex_m")]
+const STACK_ALIGN: usize = 8;
+/// The alignment the RISC-V calling convention requires of the stack pointer
+#[cfg(not(feature = "cortex_m"))]
+const STACK_ALIGN: usize = 16;
+
 #[repr(C)]
 #[derive(Clone)]
-pub(crate) struct Task {
+pub struct Task {
+    /// The task's name from its [`crate::TaskDesc`], sent with its log frames
+    pub(crate) name: &'static str,
     pub(crate) region_table: RegionTable,
     pub(crate) stack_size: usize,
     pub(crate) initial_stack_ptr: Range<usize>,
+    /// Free stack space, sorted by address with adjacent ranges merged and empty ranges removed
     pub(crate) available_stack_ptr: Vec<Range<usize>, 8>,
+    /// Bytes reserved for thread-local storage at the top of each thread's stack, see [`crate::TaskDesc::tls_size`]
+    pub(crate) tls_size: usize,
     pub(crate) entrypoint: TaskPtr<'static, fn() -> !>,
     pub(crate) secure: bool,
     pub(crate) state: TaskState,
+    /// Caps given to the first thread spawned in the task, see [`crate::TaskDesc::caps`]
+    pub(crate) static_caps: &'static [Cap],
+    /// Run before the task's first thread starts, see [`crate::TaskDesc::pre_start_hook`]
+    pub(crate) pre_start_hook: Option<fn(&mut Task)>,
+    /// The heap shared by all of the task's threads, allocated from with a null cap, see [`crate::KernelBuilder::heap`]
+    pub(crate) heap: Option<Range<usize>>,
 }
 
 #[repr(u8)]
@@ -32,6 +52,7 @@ impl Task {
         secure: bool,
     ) -> Self {
         Self {
+            name: "",
             region_table,
             stack_size,
             available_stack_ptr: Vec::from_slice(&[initial_stack_ptr.clone()]).unwrap(),
@@ -39,13 +60,37 @@ impl Task {
             secure,
             entrypoint,
             state: TaskState::Pending,
+            tls_size: 0,
+            static_caps: &[],
+            pre_start_hook: None,
+            heap: None,
         }
     }
 
+    /// Adds a region to the task's region table, so every thread in the task can access it
+    pub fn loan_mem(&mut self, region: RegionBuilder) -> Result<(), KernelError> {
+        self.region_table.push(region.build())
+    }
+
     pub(crate) fn reset_stack_ptr(&mut self) {
         self.available_stack_ptr = Vec::from_slice(&[self.initial_stack_ptr.clone()]).unwrap()
     }
 
+    /// Returns an entrypoint `offset` bytes from the start of the task's flash region
+    ///
+    /// The flash region is the region that contains the task's default entrypoint. The returned pointer is not
+    /// validated, that happens when the thread is spawned.
+    pub(crate) fn entrypoint_at(&self, offset: usize) -> Option<TaskPtr<'static, fn() -> !>> {
+        let entrypoint = self.entrypoint.addr();
+        let flash = self
+            .region_table
+            .iter()
+            .find(|r| r.range.contains(&entrypoint))?;
+        let addr = flash.range.start.checked_add(offset)?;
+        // Safety: the pointer is checked against the task's regions by `validate_ptr` before it is used
+        Some(unsafe { TaskPtr::from_raw_parts(addr, ()) })
+    }
+
     pub(crate) fn validate_ptr<'a, T: core::ptr::Pointee + ?Sized>(
         &self,
         ptr: TaskPtr<'a, T>,
@@ -60,31 +105,86 @@ impl Task {
         arch::translate_mut_task_ptr(ptr, self)
     }
 
-    pub(crate) fn alloc_stack(&mut self) -> Option<usize> {
-        for range in &mut self.available_stack_ptr {
-            if range.len() >= self.stack_size {
-                range.start += self.stack_size;
-                return Some(range.start);
-                //TODO: cleanup empty ranges might need to use LL
+    /// Reserves `size` bytes of thread-local storage at the top of each stack, rounded up to [`STACK_ALIGN`]
+    ///
+    /// The stack starts right below the storage, so the rounding keeps its initial stack pointer aligned.
+    pub(crate) fn set_tls_size(&mut self, size: usize) {
+        self.tls_size = (size + STACK_ALIGN - 1) & !(STACK_ALIGN - 1);
+    }
+
+    /// Zeroes the thread-local storage at the top of the stack ending at `stack_top`, and returns its base address
+    ///
+    /// The thread's stack starts below its TLS, at the returned address
+    pub(crate) fn init_tls(&self, stack_top: usize) -> Result<usize, KernelError> {
+        let tls_base = stack_top - self.tls_size;
+        if self.tls_size > 0 {
+            // Safety: the pointer is validated against the task's regions before it is written to
+            let tls = unsafe { TaskPtrMut::from_addr_len_bytes(tls_base, self.tls_size) };
+            self.validate_mut_ptr(tls)
+                .ok_or(KernelError::InvalidTaskPtr)?
+                .fill(0);
+        }
+        Ok(tls_base)
+    }
+
+    /// Marks every block of the task's heap, and of each [`Cap::Heap`] in `caps`, as free
+    ///
+    /// Heaps keep their bitmap in the task's RAM, so this has to be called after the RAM is cleared. Heaps outside
+    /// the task's writable regions are skipped.
+    pub(crate) fn reset_heaps<'a>(&self, caps: impl Iterator<Item = &'a Cap>) {
+        let cap_heaps = caps.filter_map(|cap| match cap {
+            Cap::Heap { base, size } => Some(*base..*base + *size),
+            _ => None,
+        });
+        for range in self.heap.clone().into_iter().chain(cap_heaps) {
+            // Safety: the pointer is validated against the task's regions before it is written to
+            let ptr = unsafe { TaskPtrMut::from_addr_len_bytes(range.start, range.len()) };
+            if self.validate_mut_ptr(ptr).is_none() {
+                continue;
+            }
+            // Safety: the heap was just checked to be in the task's RAM
+            if let Some(mut heap) = unsafe { Heap::new(range) } {
+                heap.reset();
             }
         }
-        None
     }
 
+    /// Allocates a stack from the lowest addressed free range that fits it, and returns the top of the stack
+    pub(crate) fn alloc_stack(&mut self) -> Option<usize> {
+        let i = self
+            .available_stack_ptr
+            .iter()
+            .position(|r| r.len() >= self.stack_size)?;
+        let range = &mut self.available_stack_ptr[i];
+        range.start += self.stack_size;
+        let stack = range.start;
+        if range.is_empty() {
+            self.available_stack_ptr.remove(i);
+        }
+        Some(stack)
+    }
+
+    /// Returns the stack starting at `stack_start` to the free list, merging it with any adjacent free ranges
     #[allow(dead_code)]
     pub(crate) fn make_stack_available(&mut self, stack_start: usize) {
-        for range in &mut self.available_stack_ptr {
-            if range.start == stack_start + self.stack_size {
-                range.start = stack_start;
-                return;
+        let freed = stack_start..stack_start + self.stack_size;
+        let ranges = &mut self.available_stack_ptr;
+        let i = ranges
+            .iter()
+            .position(|r| r.start > freed.start)
+            .unwrap_or(ranges.len());
+        let merge_prev = i > 0 && ranges[i - 1].end == freed.start;
+        let merge_next = i < ranges.len() && ranges[i].start == freed.end;
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                let next = ranges.remove(i);
+                ranges[i - 1].end = next.end;
             }
-            if range.end == stack_start {
-                range.end = stack_start + self.stack_size;
-                return;
+            (true, false) => ranges[i - 1].end = freed.end,
+            (false, true) => ranges[i].start = freed.start,
+            (false, false) => {
+                let _ = ranges.insert(i, freed);
             }
         }
-        let _ = self
-            .available_stack_ptr
-            .push(stack_start..stack_start + self.stack_size);
     }
 }
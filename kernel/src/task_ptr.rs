@@ -7,6 +7,10 @@ pub struct TaskPtr<'a, T: ?Sized> {
 }
 
 impl<'a, T: Pointee + ?Sized> TaskPtr<'a, T> {
+    /// Creates a pointer from an address and pointer metadata
+    ///
+    /// For slices the metadata is an element count, prefer [`TaskPtr::from_addr_len_bytes`] or
+    /// [`TaskPtr::from_addr_count`] which make the unit explicit.
     pub unsafe fn from_raw_parts(addr: usize, metadata: T::Metadata) -> Self {
         TaskPtr {
             ptr: &*core::ptr::from_raw_parts(addr as *const (), metadata),
@@ -24,6 +28,20 @@ impl<'a, T: Pointee + ?Sized> TaskPtr<'a, T> {
     }
 }
 
+impl<'a> TaskPtr<'a, [u8]> {
+    /// Creates a pointer to `byte_len` bytes starting at `addr`
+    pub unsafe fn from_addr_len_bytes(addr: usize, byte_len: usize) -> Self {
+        Self::from_raw_parts(addr, byte_len)
+    }
+}
+
+impl<'a, T> TaskPtr<'a, [T]> {
+    /// Creates a pointer to `count` elements of `T` starting at `addr`
+    pub unsafe fn from_addr_count(addr: usize, count: usize) -> Self {
+        Self::from_raw_parts(addr, count)
+    }
+}
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct TaskPtrMut<'a, T: ?Sized> {
@@ -31,6 +49,10 @@ pub struct TaskPtrMut<'a, T: ?Sized> {
 }
 
 impl<'a, T: Pointee + ?Sized> TaskPtrMut<'a, T> {
+    /// Creates a pointer from an address and pointer metadata
+    ///
+    /// For slices the metadata is an element count, prefer [`TaskPtrMut::from_addr_len_bytes`] or
+    /// [`TaskPtrMut::from_addr_count`] which make the unit explicit.
     pub unsafe fn from_raw_parts(addr: usize, metadata: T::Metadata) -> Self {
         TaskPtrMut {
             ptr: &mut *core::ptr::from_raw_parts_mut(addr as *mut (), metadata),
@@ -42,6 +64,20 @@ impl<'a, T: Pointee + ?Sized> TaskPtrMut<'a, T> {
     }
 }
 
+impl<'a> TaskPtrMut<'a, [u8]> {
+    /// Creates a pointer to `byte_len` bytes starting at `addr`
+    pub unsafe fn from_addr_len_bytes(addr: usize, byte_len: usize) -> Self {
+        Self::from_raw_parts(addr, byte_len)
+    }
+}
+
+impl<'a, T> TaskPtrMut<'a, [T]> {
+    /// Creates a pointer to `count` elements of `T` starting at `addr`
+    pub unsafe fn from_addr_count(addr: usize, count: usize) -> Self {
+        Self::from_raw_parts(addr, count)
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use core::mem::size_of;
@@ -1,30 +1,55 @@
 use core::mem::MaybeUninit;
 
-use abi::{Cap, CapRef, Endpoint, RecvResp, SyscallReturn, SyscallReturnType};
+use abi::{Cap, CapRef, Endpoint, RecvResp, SyscallReturn, TaskStats};
 use alloc::boxed::Box;
 use cordyceps::{list::Links, List};
 
 use crate::{
-    arch, regions::RegionAttr, task_ptr::TaskPtrMut, CapEntry, IPCMsg, IPCMsgBody, KernelError,
-    Task, TaskRef, ThreadState,
+    arch, regions::RegionAttr, req_queue::PerSenderQueue, task_ptr::TaskPtrMut, CapEntry,
+    IPCMsgBody, KernelError, Task, TaskRef, ThreadState,
 };
 
 #[repr(C)]
 pub(crate) struct Tcb {
     pub(crate) saved_state: arch::SavedThreadState,
     pub(crate) task: TaskRef, // Maybe use RC for this
-    pub(crate) req_queue: List<IPCMsg>,
+    pub(crate) req_queue: PerSenderQueue,
     pub(crate) state: ThreadState,
     pub(crate) priority: usize,
+    /// The priority of a higher priority thread blocked in [`crate::Kernel::call`] on this thread, which it
+    /// runs at until it replies
+    pub(crate) boosted_priority: Option<usize>,
     pub(crate) budget: usize,
     pub(crate) cooldown: usize,
     pub(crate) capabilities: List<CapEntry>,
     pub(crate) stack_pointer: usize,
+    /// Base address of the thread's thread-local storage, which sits above its stack
+    pub(crate) tls_base: usize,
     pub(crate) entrypoint: usize,
+    /// True for the first thread spawned in a task, which is respawned with the same settings if the task panics
+    pub(crate) is_init: bool,
     pub(crate) epoch: usize,
     pub(crate) rem_time: usize,
+    /// Notification bits set with [`abi::SyscallFn::Notify`] that the thread hasn't waited for yet
+    pub(crate) notify_bits: u32,
+    /// Counters returned by [`abi::SyscallFn::Stats`]
+    pub(crate) stats: TaskStats,
 }
 
+/// The largest a [`Tcb`] is allowed to grow to
+///
+/// Every context switch touches the TCB, so it should stay within a few cache lines. Roughly, on Cortex-M
+/// the saved state is 26 registers (104 bytes), the request queue and capability list headers add 28 bytes,
+/// the thread state holds a pending [`RecvReq`] and its deadline (32 bytes) and the remaining scalar fields
+/// add 48 bytes, plus 32 bytes of [`TaskStats`], for around 250 bytes. On rv64 the saved state alone is 33 registers
+/// (264 bytes), for around 460 bytes.
+pub(crate) const MAX_TCB_SIZE: usize = 512;
+
+const _: () = assert!(
+    core::mem::size_of::<Tcb>() <= MAX_TCB_SIZE,
+    "Tcb has grown past MAX_TCB_SIZE"
+);
+
 impl Tcb {
     // allowing too many args, because the alternative is ugly
     #[allow(clippy::too_many_arguments)]
@@ -41,30 +66,56 @@ impl Tcb {
         Self {
             task,
             //_pad: 0,
-            req_queue: List::new(),
+            req_queue: PerSenderQueue::default(),
             //reply_queue: List::new(),
             state: ThreadState::Ready,
             priority,
+            boosted_priority: None,
             budget,
             cooldown,
             capabilities: caps,
             stack_pointer,
+            tls_base: 0,
             entrypoint,
+            is_init: false,
             saved_state: Default::default(),
             epoch,
             rem_time: budget,
+            notify_bits: 0,
+            stats: TaskStats::default(),
         }
     }
 
+    /// Counts a message sent or received by the thread towards its [`TaskStats`]
+    pub(crate) fn record_ipc(&mut self, len: usize) {
+        self.stats.ipc_count = self.stats.ipc_count.wrapping_add(1);
+        self.stats.ipc_bytes = self.stats.ipc_bytes.wrapping_add(len as u64);
+    }
+
+    /// Returns the priority the thread is scheduled at, including any boost from [`Tcb::boosted_priority`]
+    pub(crate) fn effective_priority(&self) -> usize {
+        self.boosted_priority
+            .map_or(self.priority, |boosted| boosted.max(self.priority))
+    }
+
     #[inline]
     fn cap_entry(&self, cap_ref: CapRef) -> Result<&CapEntry, KernelError> {
+        if cap_ref.is_null() {
+            return Err(KernelError::InvalidCapRef(cap_ref));
+        }
         for c in self.capabilities.iter() {
-            let c_addr = (c as *const CapEntry).addr();
-            if c_addr == *cap_ref {
+            if *c.cap_ref() == *cap_ref {
                 return Ok(c);
             }
         }
-        Err(KernelError::InvalidCapRef)
+        Err(KernelError::InvalidCapRef(cap_ref))
+    }
+
+    /// Returns true if the thread holds a [`Cap::Admin`] capability
+    pub(crate) fn is_admin(&self) -> bool {
+        self.capabilities
+            .iter()
+            .any(|c| matches!(c.cap, Cap::Admin))
     }
 
     pub(crate) fn cap(&self, cap_ref: CapRef) -> Result<&Cap, KernelError> {
@@ -72,7 +123,10 @@ impl Tcb {
     }
 
     pub(crate) fn endpoint(&mut self, cap_ref: CapRef) -> Result<Endpoint, KernelError> {
-        let dest_cap = self.cap_entry(cap_ref)?;
+        // endpoints can be revoked out from under a thread, so a missing one is the caller's problem, not the kernel's
+        let dest_cap = self
+            .cap_entry(cap_ref)
+            .map_err(|_| KernelError::ABI(abi::Error::InvalidCap))?;
         let endpoint = if let Cap::Endpoint(endpoint) = dest_cap.cap {
             endpoint
         } else {
@@ -88,6 +142,22 @@ impl Tcb {
         Ok(endpoint)
     }
 
+    /// Removes every capability matching `f`
+    pub(crate) fn remove_caps(&mut self, mut f: impl FnMut(&Cap) -> bool) {
+        let mut cursor = self.capabilities.cursor_front_mut();
+        loop {
+            let remove = match cursor.current() {
+                Some(entry) => f(&entry.cap),
+                None => break,
+            };
+            if remove {
+                cursor.remove_current();
+            } else {
+                cursor.move_next();
+            }
+        }
+    }
+
     pub(crate) fn add_cap(&mut self, cap: Cap) {
         self.capabilities.push_back(Box::pin(CapEntry {
             _links: Links::default(),
@@ -102,15 +172,11 @@ impl Tcb {
     ) -> Result<RecvRes<'r>, KernelError> {
         match self.recv_inner(task, req) {
             res @ Ok(RecvRes::Copy) => {
-                self.saved_state.set_syscall_return(
-                    SyscallReturn::new().with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Copy),
-                );
+                self.saved_state.set_syscall_return(SyscallReturn::copy(0));
                 res
             }
             res @ Ok(RecvRes::Page) => {
-                self.saved_state.set_syscall_return(
-                    SyscallReturn::new().with(SyscallReturn::SYSCALL_TYPE, SyscallReturnType::Page),
-                );
+                self.saved_state.set_syscall_return(SyscallReturn::page(0));
                 res
             }
             Err(KernelError::ABI(err)) => {
@@ -127,24 +193,28 @@ impl Tcb {
         task: &mut Task,
         req: RecvReq<'r>,
     ) -> Result<RecvRes<'r>, KernelError> {
-        let mut cursor = self.req_queue.cursor_front_mut();
-        cursor.move_prev();
-        let mut found = false;
-        while let Some(msg) = {
-            cursor.move_next();
-            cursor.current()
-        } {
-            if msg.addr & req.mask == req.mask {
-                found = true;
-                break;
-            }
-        }
-        if !found {
+        let msg = if let Some(msg) = self.req_queue.pop(|addr| req.matches(addr)) {
+            msg
+        } else {
             return Ok(RecvRes::NotFound(req));
-        }
-        let msg = cursor.remove_current().unwrap();
+        };
+        self.record_ipc(msg.body.bytes().len());
         let (recv_res, mut resp) = match &msg.body {
-            IPCMsgBody::Buf(buf) => {
+            // delegated caps have an empty body, so the receive buffer is left alone
+            _ if msg.cap.is_some() => {
+                self.add_cap(msg.cap.clone().unwrap());
+                let cap = self.capabilities.back().unwrap().cap_ref();
+                (
+                    RecvRes::Copy,
+                    RecvResp {
+                        cap: None,
+                        addr: msg.addr,
+                        inner: abi::RecvRespInner::Cap { cap },
+                    },
+                )
+            }
+            IPCMsgBody::Short { .. } | IPCMsgBody::Buf(_) => {
+                let buf = msg.body.bytes();
                 let out = if let RecvReqInner::Buf { out } = req.inner {
                     out
                 } else {
@@ -160,6 +230,7 @@ impl Tcb {
                     RecvRes::Copy,
                     RecvResp {
                         cap: None,
+                        addr: msg.addr,
                         inner: abi::RecvRespInner::Copy(buf.len()),
                     },
                 )
@@ -176,6 +247,7 @@ impl Tcb {
                     RecvRes::Page,
                     RecvResp {
                         cap: None,
+                        addr: msg.addr,
                         inner: abi::RecvRespInner::Page {
                             addr,
                             len: slice.len(),
@@ -187,8 +259,7 @@ impl Tcb {
 
         if let Some(reply) = msg.reply_endpoint {
             self.add_cap(Cap::Endpoint(reply));
-            let cap_ptr = &*self.capabilities.back().unwrap() as *const CapEntry;
-            resp.cap = Some(CapRef(cap_ptr.addr()));
+            resp.cap = self.capabilities.back().map(|c| c.cap_ref());
         }
         let recv_resp = task
             .validate_mut_ptr(req.resp)
@@ -200,9 +271,22 @@ impl Tcb {
 
 pub(crate) struct RecvReq<'a> {
     pub(crate) mask: usize,
+    /// Whether a message sent to any of the addresses in `mask` matches, see [`abi::RECV_ANY`]
+    pub(crate) any: bool,
     pub(crate) resp: TaskPtrMut<'a, MaybeUninit<RecvResp>>,
     pub(crate) inner: RecvReqInner<'a>,
 }
+
+impl RecvReq<'_> {
+    /// Checks whether a message sent to `addr` satisfies this request
+    pub(crate) fn matches(&self, addr: usize) -> bool {
+        if self.any {
+            addr & self.mask != 0
+        } else {
+            addr & self.mask == self.mask
+        }
+    }
+}
 pub(crate) enum RecvReqInner<'a> {
     Page,
     Buf { out: TaskPtrMut<'a, [u8]> },
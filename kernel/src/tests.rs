@@ -78,6 +78,54 @@ fn test_simple_tick_schedule() {
     }
 }
 
+#[test]
+fn test_idle_boost() {
+    let mut kernel = test_kernel();
+    kernel.scheduler.idle_boost = true;
+    let a = Tcb::new(TaskRef(1), 0, 7, 2, 10, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    let next = kernel.scheduler.tick().unwrap();
+    assert_eq!(next.map(|r| *r), Some(1), "should switch to a");
+    assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    let next = kernel.scheduler.tick().unwrap();
+    assert_eq!(next.map(|r| *r), Some(0), "should switch to idle");
+    // a's cooldown of 10 is halved, since only idle is running
+    for _ in 0..4 {
+        assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    }
+    let next = kernel.scheduler.tick().unwrap();
+    assert_eq!(next.map(|r| *r), Some(1), "should requeue a early");
+}
+
+#[cfg(feature = "heap_defrag")]
+#[test]
+fn test_heap_defrag_runs_when_idle() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut kernel = test_kernel();
+    kernel.scheduler.heap_defrag = Some(scheduler::HeapDefrag {
+        interval: 3,
+        last_run: kernel.scheduler.ticks,
+        defrag: || {
+            RUNS.fetch_add(1, Ordering::Relaxed);
+        },
+    });
+    let a = Tcb::new(TaskRef(1), 0, 7, 4, 10, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    // a runs for its whole budget, so the defrag has to wait for idle
+    for _ in 0..5 {
+        kernel.scheduler.tick().unwrap();
+    }
+    assert_eq!(RUNS.load(Ordering::Relaxed), 0);
+    kernel.scheduler.tick().unwrap();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    for _ in 0..3 {
+        kernel.scheduler.tick().unwrap();
+    }
+    assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+}
+
 #[test]
 fn test_send_schedule() {
     let mut kernel = test_kernel();
@@ -87,9 +135,9 @@ fn test_send_schedule() {
         tcb_ref: ThreadRef(1),
         addr: 1,
         disposable: false,
+        max_queue_depth: 0,
     }));
-    let cap_ptr = &*b.capabilities.back().unwrap() as *const CapEntry;
-    let cap_ref = CapRef(cap_ptr.addr());
+    let cap_ref = b.capabilities.back().unwrap().cap_ref();
     kernel.scheduler.spawn(a).unwrap();
     kernel.scheduler.spawn(b).unwrap();
     let next = kernel
@@ -131,13 +179,10 @@ fn test_call_schedule() {
         tcb_ref: ThreadRef(1),
         addr: 1,
         disposable: false,
+        max_queue_depth: 0,
     }));
 
-    let cap_ref = unsafe {
-        CapRef(
-            (Pin::into_inner_unchecked(b.capabilities.back().unwrap()) as *const CapEntry).addr(),
-        )
-    };
+    let cap_ref = b.capabilities.back().unwrap().cap_ref();
     kernel.scheduler.spawn(a).unwrap();
     kernel.scheduler.spawn(b).unwrap();
     let next = kernel
@@ -168,6 +213,42 @@ fn test_call_schedule() {
     assert_eq!(*next, 1, "should switch to a");
 }
 
+#[test]
+fn test_invalid_thread_ref() {
+    let mut kernel = test_kernel();
+    kernel.scheduler.add_thread(7, ThreadRef(9)).unwrap();
+    assert!(matches!(
+        kernel.scheduler.next_thread(0),
+        Err(KernelError::InvalidThreadRef(ThreadRef(9)))
+    ));
+    assert!(matches!(
+        kernel
+            .scheduler
+            .switch_thread(DomainEntry::new(ThreadRef(9), None, 7)),
+        Err(KernelError::InvalidThreadRef(ThreadRef(9)))
+    ));
+}
+
+#[test]
+fn test_null_cap_ref() {
+    let mut tcb = Tcb::new(TaskRef(1), 0, 7, 5, 6, 0, 0, List::new());
+    tcb.add_cap(Cap::Admin);
+    assert!(matches!(
+        tcb.cap(CapRef::null()),
+        Err(KernelError::InvalidCapRef(_))
+    ));
+}
+
+#[test]
+fn test_short_msg_body() {
+    let short = IPCMsgBody::copy(&[1, 2, 3]);
+    assert!(matches!(short, IPCMsgBody::Short { len: 3, .. }));
+    assert_eq!(short.bytes(), [1, 2, 3]);
+    let long = IPCMsgBody::copy(&[0xA; 9]);
+    assert!(matches!(long, IPCMsgBody::Buf(_)));
+    assert_eq!(long.bytes(), [0xA; 9]);
+}
+
 #[test]
 fn test_alloc_stack() {
     let mut task = Task::new(
@@ -188,3 +269,692 @@ fn test_alloc_stack() {
     task.make_stack_available(40);
     assert_eq!(task.alloc_stack(), Some(50));
 }
+
+#[test]
+fn test_stack_free_list_merges() {
+    const STACKS: usize = 8;
+    // xorshift, so the test is random but reproducible
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize
+    };
+    let mut task = Task::new(
+        RegionTable::default(),
+        10,
+        0..10 * STACKS,
+        unsafe { TaskPtr::from_raw_parts(0, ()) },
+        false,
+    );
+    let mut allocated = alloc::vec::Vec::new();
+    for _ in 0..1000 {
+        if allocated.is_empty() || (allocated.len() < STACKS && rand() % 2 == 0) {
+            let stack = task.alloc_stack().expect("stack should be free");
+            allocated.push(stack - 10);
+        } else {
+            let stack = allocated.swap_remove(rand() % allocated.len());
+            task.make_stack_available(stack);
+        }
+        let free = &task.available_stack_ptr;
+        assert!(free.iter().all(|r| !r.is_empty()));
+        assert!(
+            free.windows(2).all(|w| w[0].end < w[1].start),
+            "free list should be sorted and merged: {:?}",
+            free
+        );
+        let free_len: usize = free.iter().map(|r| r.len()).sum();
+        assert_eq!(free_len + allocated.len() * 10, 10 * STACKS);
+    }
+}
+
+static SHUTDOWN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+fn shutdown() {
+    SHUTDOWN.store(true, core::sync::atomic::Ordering::SeqCst);
+}
+
+#[test]
+fn test_shutdown_after_last_thread_exits() {
+    let mut kernel = test_kernel();
+    kernel.scheduler.on_shutdown = Some(shutdown);
+    assert_eq!(kernel.scheduler.live_threads(), 0, "idle isn't counted");
+    let a = Tcb::new(TaskRef(1), 0, 7, 5, 6, 0, 0, List::new());
+    let a = kernel.scheduler.spawn(a).unwrap();
+    assert_eq!(kernel.scheduler.live_threads(), 1);
+    kernel.scheduler.exit_thread(a).unwrap();
+    assert_eq!(kernel.scheduler.live_threads(), 0);
+    assert!(!SHUTDOWN.load(core::sync::atomic::Ordering::SeqCst));
+    assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    assert!(SHUTDOWN.load(core::sync::atomic::Ordering::SeqCst));
+}
+
+#[test]
+fn test_yield_keeps_remaining_budget() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 7, 5, 6, 0, 0, List::new());
+    let b = Tcb::new(TaskRef(2), 0, 7, 3, 3, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 1);
+    assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    let next = kernel.scheduler.yield_thread().unwrap();
+    assert_eq!(next.map(|t| *t), Some(2), "a should yield to b");
+    let next = kernel.scheduler.yield_thread().unwrap();
+    assert_eq!(next.map(|t| *t), Some(1), "b should yield back to a");
+    assert_eq!(kernel.scheduler.current_thread.time, 4);
+}
+
+#[test]
+fn test_recv_deadline_expires() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 7, 5, 6, 0, 0, List::new());
+    let b = Tcb::new(TaskRef(2), 0, 3, 10, 10, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 1);
+    let recv_req = RecvReq {
+        mask: 0x1,
+        any: false,
+        resp: unsafe { TaskPtrMut::from_raw_parts(0, ()) },
+        inner: RecvReqInner::Page,
+    };
+    let deadline = kernel.scheduler.ticks + 2;
+    let next = kernel
+        .scheduler
+        .wait(recv_req, Some(deadline), false)
+        .unwrap();
+    assert_eq!(*next, 2, "should switch to b");
+    assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("a should time out and preempt b");
+    assert_eq!(*next, 1);
+    let a = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert!(matches!(a.state, ThreadState::Ready));
+}
+
+#[test]
+fn test_call_inherits_priority() {
+    let mut kernel = test_kernel();
+    let server = Tcb::new(TaskRef(1), 0, 1, 5, 5, 0, 0, List::new());
+    let background = Tcb::new(TaskRef(1), 0, 4, 5, 5, 0, 0, List::new());
+    let mut client = Tcb::new(TaskRef(1), 0, 7, 10, 10, 0, 0, List::new());
+    client.add_cap(Cap::Endpoint(Endpoint {
+        tcb_ref: ThreadRef(1),
+        addr: 1,
+        disposable: false,
+        max_queue_depth: 0,
+    }));
+    let cap_ref = client.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(server).unwrap();
+    kernel.scheduler.spawn(background).unwrap();
+    kernel.scheduler.spawn(client).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to the client");
+    assert_eq!(*next, 3);
+    let recv_req = RecvReq {
+        mask: 0,
+        any: false,
+        resp: unsafe { TaskPtrMut::from_raw_parts(0, ()) },
+        inner: RecvReqInner::Page,
+    };
+    let next = kernel
+        .call(cap_ref, IPCMsgBody::copy(&[1, 2, 3]), recv_req)
+        .unwrap();
+    assert_eq!(*next, 1, "should switch to the server");
+    let server = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert_eq!(server.effective_priority(), 7);
+    assert_eq!(kernel.scheduler.current_thread.priority, 7);
+    for _ in 0..3 {
+        assert_eq!(
+            kernel.scheduler.tick().unwrap(),
+            None,
+            "the background thread shouldn't preempt the boosted server"
+        );
+    }
+}
+
+#[test]
+fn test_call_boost_two_callers() {
+    fn client(prio: usize) -> Tcb {
+        let mut client = Tcb::new(TaskRef(1), 0, prio, 10, 10, 0, 0, List::new());
+        client.add_cap(Cap::Endpoint(Endpoint {
+            tcb_ref: ThreadRef(1),
+            addr: 1,
+            disposable: false,
+            max_queue_depth: 0,
+        }));
+        client
+    }
+    fn recv_req() -> RecvReq<'static> {
+        RecvReq {
+            mask: 0,
+            any: false,
+            resp: unsafe { TaskPtrMut::from_raw_parts(0, ()) },
+            inner: RecvReqInner::Page,
+        }
+    }
+    let mut kernel = test_kernel();
+    let server = Tcb::new(TaskRef(1), 0, 1, 10, 10, 0, 0, List::new());
+    let background = Tcb::new(TaskRef(1), 0, 4, 10, 10, 0, 0, List::new());
+    let b = client(6);
+    let cap_ref = b.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(server).unwrap();
+    kernel.scheduler.spawn(background).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to b");
+    assert_eq!(*next, 3);
+    let next = kernel
+        .call(cap_ref, IPCMsgBody::copy(&[1]), recv_req())
+        .unwrap();
+    assert_eq!(*next, 1, "should switch to the server");
+
+    kernel.scheduler.spawn(client(7)).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 4);
+    let next = kernel
+        .call(cap_ref, IPCMsgBody::copy(&[2]), recv_req())
+        .unwrap();
+    assert_eq!(*next, 1, "should switch to the server");
+    assert_eq!(kernel.scheduler.current_thread.priority, 7);
+
+    // receive both calls, which hands the server a reply endpoint for each caller
+    let server = kernel.scheduler.get_tcb_mut(ThreadRef(1)).unwrap();
+    let mut replies = alloc::vec::Vec::new();
+    while let Some(msg) = server.req_queue.pop(|_| true) {
+        server.add_cap(Cap::Endpoint(msg.reply_endpoint.unwrap()));
+        replies.push(server.capabilities.back().unwrap().cap_ref());
+    }
+    assert_eq!(replies.len(), 2);
+
+    kernel.send(replies[1], IPCMsgBody::copy(&[])).unwrap();
+    let server = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert_eq!(
+        server.effective_priority(),
+        6,
+        "b is still blocked on the server"
+    );
+    assert_eq!(kernel.scheduler.current_thread.priority, 6);
+    let next = kernel.scheduler.tick().unwrap().expect("a should preempt");
+    assert_eq!(*next, 4);
+    let next = kernel.scheduler.sleep(100).unwrap();
+    assert_eq!(
+        *next, 1,
+        "the server should run ahead of the background thread"
+    );
+    for _ in 0..3 {
+        assert_eq!(
+            kernel.scheduler.tick().unwrap(),
+            None,
+            "the background thread shouldn't preempt the boosted server"
+        );
+    }
+
+    kernel.send(replies[0], IPCMsgBody::copy(&[])).unwrap();
+    let server = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert_eq!(server.effective_priority(), 1);
+    assert_eq!(kernel.scheduler.current_thread.priority, 1);
+    let next = kernel.scheduler.tick().unwrap().expect("b should preempt");
+    assert_eq!(*next, 3);
+    let next = kernel.scheduler.sleep(100).unwrap();
+    assert_eq!(
+        *next, 2,
+        "the server shouldn't be left queued at its boosted priority"
+    );
+}
+
+#[test]
+fn test_sleep() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 7, 10, 10, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 1);
+    let next = kernel.scheduler.sleep(3).unwrap();
+    assert_eq!(next, ThreadRef::idle(), "nothing else is ready");
+    for _ in 0..2 {
+        assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    }
+    let next = kernel.scheduler.tick().unwrap().expect("a should wake up");
+    assert_eq!(*next, 1);
+}
+
+#[test]
+fn test_revoked_endpoint() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    let mut b = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    let endpoint = Endpoint {
+        tcb_ref: ThreadRef(1),
+        addr: 1,
+        disposable: false,
+        max_queue_depth: 0,
+    };
+    b.add_cap(Cap::Endpoint(endpoint));
+    b.add_cap(Cap::Admin);
+    let cap_ref = b.capabilities.front().unwrap().cap_ref();
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to b");
+    assert_eq!(*next, 2);
+    kernel
+        .send(cap_ref, IPCMsgBody::copy(&[1]))
+        .expect("send failed");
+    kernel.revoke_endpoint(endpoint);
+    assert!(matches!(
+        kernel.send(cap_ref, IPCMsgBody::copy(&[2])),
+        Err(KernelError::ABI(abi::Error::InvalidCap))
+    ));
+    let b = kernel.scheduler.get_tcb(ThreadRef(2)).unwrap();
+    assert!(b.is_admin(), "other caps should be left alone");
+}
+
+#[test]
+fn test_server_revokes_client() {
+    let mut kernel = test_kernel();
+    let mut server = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    let mut client = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    // the server answers the client on the same address the client sends to
+    server.add_cap(Cap::Endpoint(Endpoint {
+        tcb_ref: ThreadRef(2),
+        addr: 3,
+        disposable: false,
+        max_queue_depth: 0,
+    }));
+    let answer = server.capabilities.back().unwrap().cap_ref();
+    for addr in [3, 4] {
+        client.add_cap(Cap::Endpoint(Endpoint {
+            tcb_ref: ThreadRef(1),
+            addr,
+            disposable: false,
+            max_queue_depth: 0,
+        }));
+    }
+    let kicked = client.capabilities.front().unwrap().cap_ref();
+    let other = client.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(server).unwrap();
+    kernel.scheduler.spawn(client).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to the server");
+    assert_eq!(*next, 1);
+    let args = SyscallArgs {
+        arg1: *answer,
+        ..Default::default()
+    };
+    RevokeCall::from_args(&args)
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .expect("the server should be able to revoke endpoints to itself");
+    let server = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert!(
+        server.cap(answer).is_ok(),
+        "the server's own endpoint to the client should be kept"
+    );
+    let client = kernel.scheduler.get_tcb(ThreadRef(2)).unwrap();
+    assert!(client.cap(kicked).is_err());
+    assert!(
+        client.cap(other).is_ok(),
+        "other ports should be left alone"
+    );
+}
+
+#[test]
+fn test_queue_depth() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    let mut b = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    b.add_cap(Cap::Endpoint(Endpoint {
+        tcb_ref: ThreadRef(1),
+        addr: 1,
+        disposable: false,
+        max_queue_depth: 2,
+    }));
+    let cap_ref = b.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to b");
+    assert_eq!(*next, 2);
+    for i in 0..2 {
+        kernel.check_queue_depth(cap_ref).unwrap();
+        kernel
+            .send(cap_ref, IPCMsgBody::copy(&[i]))
+            .expect("send failed");
+    }
+    assert!(matches!(
+        kernel.check_queue_depth(cap_ref),
+        Err(KernelError::ABI(abi::Error::QueueFull))
+    ));
+    assert!(matches!(
+        kernel.send(cap_ref, IPCMsgBody::copy(&[2])),
+        Err(KernelError::ABI(abi::Error::QueueFull))
+    ));
+    let a = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert_eq!(a.req_queue.depth(1), 2);
+}
+
+#[test]
+fn test_send_cap() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    let mut b = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    b.add_cap(Cap::Endpoint(Endpoint {
+        tcb_ref: ThreadRef(1),
+        addr: 1,
+        disposable: false,
+        max_queue_depth: 0,
+    }));
+    let dest = b.capabilities.back().unwrap().cap_ref();
+    b.add_cap(Cap::Semaphore(4));
+    let semaphore = b.capabilities.back().unwrap().cap_ref();
+    b.add_cap(Cap::Endpoint(Endpoint {
+        tcb_ref: ThreadRef(1),
+        addr: 2,
+        disposable: true,
+        max_queue_depth: 0,
+    }));
+    let reply = b.capabilities.back().unwrap().cap_ref();
+    b.add_cap(Cap::Heap {
+        base: 0x1000,
+        size: 0x400,
+    });
+    let heap = b.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to b");
+    assert_eq!(*next, 2);
+    assert!(matches!(
+        kernel.send_cap(dest, reply),
+        Err(KernelError::ABI(abi::Error::NotSupported))
+    ));
+    assert!(matches!(
+        kernel.send_cap(dest, heap),
+        Err(KernelError::ABI(abi::Error::NotSupported))
+    ));
+    kernel.send_cap(dest, semaphore).expect("send failed");
+
+    let a = kernel.scheduler.get_tcb_mut(ThreadRef(1)).unwrap();
+    let msg = a
+        .req_queue
+        .pop(|addr| addr == 1)
+        .expect("the cap should be queued");
+    assert!(matches!(msg.cap, Some(Cap::Semaphore(4))));
+    assert!(msg.body.bytes().is_empty());
+}
+
+#[test]
+fn test_notify_wakes_waiter() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    let mut b = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    b.add_cap(Cap::Notify(ThreadRef(1)));
+    let cap_ref = b.capabilities.back().unwrap().cap_ref();
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 1);
+    let next = kernel.scheduler.wait_notify(0b110).unwrap();
+    assert_eq!(*next, 2, "should switch to b");
+
+    let mut args = SyscallArgs {
+        arg1: *cap_ref,
+        arg2: 0b001,
+        ..Default::default()
+    };
+    let ret = NotifyCall::from_args(&args)
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .unwrap();
+    assert!(
+        matches!(ret, CallReturn::Return { .. }),
+        "a isn't waiting for bit 0"
+    );
+    args.arg2 = 0b011;
+    let ret = NotifyCall::from_args(&args)
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .unwrap();
+    assert!(matches!(
+        ret,
+        CallReturn::Switch {
+            next_thread: ThreadRef(1),
+            ..
+        }
+    ));
+    let a = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert!(matches!(a.state, ThreadState::Ready));
+    assert_eq!(
+        a.notify_bits, 0b001,
+        "the bit a didn't wait for should stay pending"
+    );
+}
+
+#[test]
+fn test_stats_tick_accounting() {
+    let mut kernel = test_kernel();
+    let a = Tcb::new(TaskRef(1), 0, 7, 5, 6, 0, 0, List::new());
+    let b = Tcb::new(TaskRef(1), 0, 7, 3, 3, 0, 0, List::new());
+    kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(*next, 1);
+    for _ in 0..4 {
+        assert_eq!(kernel.scheduler.tick().unwrap(), None);
+    }
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("a should exhaust its budget");
+    assert_eq!(*next, 2, "should switch to b");
+    let a = kernel.scheduler.get_tcb(ThreadRef(1)).unwrap();
+    assert_eq!(a.stats.cpu_ticks, 5, "a ran for its whole budget");
+    assert_eq!(a.stats.context_switches, 1);
+    let b = kernel.scheduler.get_tcb(ThreadRef(2)).unwrap();
+    assert_eq!(b.stats.cpu_ticks, 0);
+    assert_eq!(b.stats.context_switches, 1);
+}
+
+#[test]
+fn test_reset_heaps() {
+    #[repr(align(32))]
+    struct Mem([u8; 512]);
+    let mut kernel = test_kernel();
+    let mut mem = Box::new(Mem([0; 512]));
+    let base = mem.0.as_ptr() as usize;
+    let range = base..base + mem.0.len();
+    kernel.tasks[1].heap = Some(range.clone());
+    // what `clear_mem` leaves behind with `ClearPattern::Poison`
+    mem.0.fill(0xAD);
+    kernel.tasks[1].reset_heaps(core::iter::empty());
+    let mut heap = unsafe { crate::heap::Heap::new(range) }.unwrap();
+    assert_eq!(
+        heap.alloc(1, 1),
+        Some(base + HEAP_BLOCK_LEN),
+        "the heap should be empty after being reset"
+    );
+}
+
+#[test]
+fn test_stack_usage_above_guard() {
+    let mut kernel = test_kernel();
+    let task = &mut kernel.tasks[1];
+    task.region_table
+        .push(regions::Region {
+            range: 0..200,
+            attr: RegionAttr::Read | RegionAttr::Write,
+        })
+        .unwrap();
+    // the first 100 byte stack is 0..100, with its guard at the bottom
+    assert!(task.region_table.push_guard(0..32));
+    let tcb = Tcb::new(TaskRef(1), 100, 7, 5, 5, 0, 0, List::new());
+    kernel.scheduler.spawn(tcb).unwrap();
+    kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to the thread");
+    let ret = StackUsageCall {}
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .expect("the guard shouldn't stop the stack being read");
+    // the dummy backend reads unpainted memory, so everything the scan covers counts as used
+    match ret {
+        CallReturn::Return { ret } => assert_eq!(ret.get(SyscallReturn::SYSCALL_PTR), 100 - 32),
+        _ => panic!("stack usage should return"),
+    }
+}
+
+#[test]
+fn test_spawn_zero_budget() {
+    let mut kernel = test_kernel();
+    let tcb = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    kernel.scheduler.spawn(tcb).unwrap();
+    kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to the thread");
+    for (budget, cooldown) in [(0, 5), (5, 0)] {
+        let args = SyscallArgs {
+            arg1: 1,
+            arg2: 7,
+            arg3: budget,
+            arg4: cooldown,
+            ..Default::default()
+        };
+        assert!(matches!(
+            SpawnCall::from_args(&args).exec(abi::SyscallDataType::Short, &mut kernel),
+            Err(KernelError::ABI(abi::Error::NotSupported))
+        ));
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let mut kernel = test_kernel();
+    let tcb = Tcb::new(TaskRef(1), 0, 3, 10, 10, 0, 0, List::new());
+    kernel.scheduler.spawn(tcb).unwrap();
+    kernel
+        .registry
+        .listen(
+            abi::Listen { port: [1; 16] },
+            abi::Endpoint {
+                tcb_ref: ThreadRef(1),
+                addr: 0,
+                disposable: false,
+                max_queue_depth: 0,
+            },
+        )
+        .unwrap();
+    kernel.registry.create_semaphore(1, 1).unwrap();
+    assert_eq!(
+        kernel.diagnostics(),
+        KernelDiagnostics {
+            tcb_used: 2,
+            tcb_capacity: TCB_CAPACITY,
+            task_count: 2,
+            task_capacity: TASK_CAPACITY,
+            registry_entries: 2,
+            registry_capacity: 16,
+        }
+    );
+}
+
+#[test]
+fn test_irq_cap_delivery() {
+    let mut kernel = test_kernel();
+    let mut a = Tcb::new(TaskRef(1), 0, 7, 5, 5, 0, 0, List::new());
+    a.add_cap(Cap::Irq { irq_number: 42 });
+    let cap_ref = a.capabilities.back().unwrap().cap_ref();
+    let b = Tcb::new(TaskRef(1), 0, 3, 5, 5, 0, 0, List::new());
+    let a = kernel.scheduler.spawn(a).unwrap();
+    kernel.scheduler.spawn(b).unwrap();
+    kernel.grant_irq(a, 42).unwrap();
+    assert!(matches!(
+        kernel.grant_irq(ThreadRef(2), 42),
+        Err(KernelError::InterruptClaimed)
+    ));
+    let next = kernel
+        .scheduler
+        .tick()
+        .unwrap()
+        .expect("should switch to a");
+    assert_eq!(next, a);
+
+    let args = SyscallArgs {
+        arg1: *cap_ref,
+        ..Default::default()
+    };
+    let ret = WaitIrqCall::from_args(&args)
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .unwrap();
+    assert!(matches!(
+        ret,
+        CallReturn::Replace {
+            next_thread: ThreadRef(2)
+        }
+    ));
+    let next = kernel.interrupt(42).unwrap();
+    assert_eq!(next, Some(a), "a should preempt b");
+    assert!(matches!(
+        kernel.scheduler.get_tcb(a).unwrap().state,
+        ThreadState::Ready
+    ));
+
+    // fires again before a waits, so the next wait returns straight away
+    assert_eq!(kernel.interrupt(42).unwrap(), None);
+    assert!(kernel.irq_caps[&42].pending);
+    let ret = WaitIrqCall::from_args(&args)
+        .exec(abi::SyscallDataType::Short, &mut kernel)
+        .unwrap();
+    assert!(matches!(ret, CallReturn::Return { .. }));
+    assert!(!kernel.irq_caps[&42].pending);
+}
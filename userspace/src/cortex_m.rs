@@ -69,6 +69,19 @@ pub unsafe extern "C" fn _start() -> ! {
     )
 }
 
+/// Returns the base of the calling thread's thread-local storage, which the kernel passes in `r9`
+///
+/// The storage is `tls_size` bytes, as configured in the app's `app.toml`, and is zeroed when the thread starts.
+/// `k5 build` builds every crate in the task with `+reserve-r9`, otherwise the compiler would be free to use `r9` as
+/// a general-purpose register. Tasks built some other way have to pass `-C target-feature=+reserve-r9` themselves.
+#[inline]
+pub fn thread_local_ptr() -> *mut u8 {
+    let r9: usize;
+    // Safety: reading r9 has no side effects
+    unsafe { asm!("mov {}, r9", out(reg) r9) };
+    core::ptr::from_exposed_addr_mut(r9)
+}
+
 #[naked]
 pub(crate) unsafe extern "C" fn syscall(
     index: SyscallIndex,
@@ -1,32 +1,84 @@
+//! Routes defmt frames through the kernel's `Log` syscall
+//!
+//! The kernel tags every log buffer with the calling task's ID, so the CLI can pick the correct
+//! defmt table when decoding. This works identically on every architecture, since it only relies on
+//! [`crate::log`].
+use crate::MAX_LOG_LEN;
+use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::global_logger;
 
 #[global_logger]
-struct KernelLogger;
+struct DefmtSink;
 
+static TAKEN: AtomicBool = AtomicBool::new(false);
 static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut BUF: FrameBuf = FrameBuf::new();
 
 defmt::timestamp!("{=u32:us}", 0);
 
-unsafe impl defmt::Logger for KernelLogger {
+// Safety: `acquire` guards against re-entrancy with `TAKEN`, so only a single
+// thread of execution writes to `ENCODER` and `BUF` at once
+unsafe impl defmt::Logger for DefmtSink {
     fn acquire() {
-        unsafe {
-            ENCODER.start_frame(|b| {
-                let _ = crate::log(b);
-            })
-        };
+        if TAKEN.swap(true, Ordering::Acquire) {
+            panic!("defmt logger taken reentrantly")
+        }
+        // Safety: we hold `TAKEN`, so we have exclusive access to `ENCODER` and `BUF`
+        unsafe { ENCODER.start_frame(|b| BUF.write(b)) };
     }
 
-    unsafe fn flush() {}
+    unsafe fn flush() {
+        BUF.flush();
+    }
 
     unsafe fn release() {
-        ENCODER.end_frame(|b| {
-            let _ = crate::log(b);
-        });
+        ENCODER.end_frame(|b| BUF.write(b));
+        BUF.flush();
+        TAKEN.store(false, Ordering::Release);
     }
 
     unsafe fn write(bytes: &[u8]) {
-        ENCODER.write(bytes, |b| {
-            let _ = crate::log(b);
-        });
+        ENCODER.write(bytes, |b| BUF.write(b));
+    }
+}
+
+/// Buffers encoded bytes so a defmt frame is usually sent with a single syscall
+///
+/// Frames larger than the buffer are sent with [`crate::log_chunked`], so they are split rather than truncated
+struct FrameBuf {
+    buf: [u8; MAX_LOG_LEN],
+    len: usize,
+}
+
+impl FrameBuf {
+    const fn new() -> Self {
+        Self {
+            buf: [0; MAX_LOG_LEN],
+            len: 0,
+        }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        if self.len == 0 && bytes.len() > self.buf.len() {
+            let _ = crate::log_chunked(bytes);
+            return;
+        }
+        while !bytes.is_empty() {
+            let n = bytes.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+            if self.len == self.buf.len() {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let _ = crate::log_chunked(&self.buf[..self.len]);
+        self.len = 0;
     }
 }
@@ -0,0 +1,9 @@
+//! A stand-in for the arch modules on the host, so the crate's unit tests build without a target
+use abi::{SyscallArgs, SyscallIndex, SyscallReturn};
+
+pub(crate) unsafe extern "C" fn syscall(
+    _index: SyscallIndex,
+    _args: &mut SyscallArgs,
+) -> SyscallReturn {
+    unimplemented!("syscalls need a kernel, build with an arch feature")
+}
@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(naked_functions)]
 #![feature(strict_provenance)]
 #![feature(ptr_metadata)]
@@ -9,12 +9,19 @@ mod cortex_m;
 #[cfg(feature = "cortex_m")]
 pub use cortex_m::*;
 
-#[cfg(feature = "rv64")]
-mod rv64;
-#[cfg(feature = "rv64")]
-pub use rv64::*;
+#[cfg(any(feature = "rv32", feature = "rv64"))]
+mod rv;
+#[cfg(any(feature = "rv32", feature = "rv64"))]
+pub use rv::*;
+
+#[cfg(not(any(feature = "cortex_m", feature = "rv32", feature = "rv64")))]
+mod host;
+#[cfg(not(any(feature = "cortex_m", feature = "rv32", feature = "rv64")))]
+use host::*;
 
 pub use abi;
+pub use k5_derive::{k5_client, k5_task};
+pub use k5_ipc as ipc;
 
 mod defmt_logger;
 
@@ -26,6 +33,7 @@ use abi::{
     CapListEntry, CapRef, Error, SyscallArgs, SyscallDataType, SyscallFn, SyscallIndex,
     SyscallReturn, SyscallReturnType,
 };
+use core::alloc::{GlobalAlloc, Layout};
 use core::fmt::Write;
 use core::mem;
 use core::{
@@ -33,6 +41,18 @@ use core::{
     ops::{Deref, DerefMut},
 };
 
+/// Turns an error returned by the kernel into an [`Error`], passing any other return through
+#[inline]
+fn check_error(res: SyscallReturn) -> Result<SyscallReturn, Error> {
+    match res.get(SyscallReturn::SYSCALL_TYPE) {
+        SyscallReturnType::Error => {
+            let code = res.get(SyscallReturn::SYSCALL_LEN);
+            Err(abi::Error::from(code as u8))
+        }
+        _ => Ok(res),
+    }
+}
+
 #[inline]
 fn send_inner<T: ?Sized>(ty: SyscallDataType, capability: CapRef, r: &mut T) -> Result<(), Error> {
     let size = core::mem::size_of_val(r);
@@ -48,13 +68,7 @@ fn send_inner<T: ?Sized>(ty: SyscallDataType, capability: CapRef, r: &mut T) ->
         ..Default::default()
     };
     let res = unsafe { syscall(index, &mut args) };
-    match res.get(SyscallReturn::SYSCALL_TYPE) {
-        SyscallReturnType::Error => {
-            let code = res.get(SyscallReturn::SYSCALL_LEN);
-            return Err(abi::Error::from(code as u8));
-        }
-        _ => {}
-    }
+    check_error(res)?;
     Ok(())
 }
 
@@ -88,13 +102,8 @@ fn call_innner<T: ?Sized>(
         arg6: out_size,
     };
     let res = unsafe { syscall(index, &mut args) };
-    match res.get(SyscallReturn::SYSCALL_TYPE) {
-        SyscallReturnType::Error => {
-            let code = res.get(SyscallReturn::SYSCALL_LEN);
-            Err(abi::Error::from(code as u8))
-        }
-        _ => Ok(unsafe { resp.assume_init() }),
-    }
+    check_error(res)?;
+    Ok(unsafe { resp.assume_init() })
 }
 
 #[inline]
@@ -110,12 +119,26 @@ pub fn log(data: &[u8]) -> Result<(), Error> {
         ..Default::default()
     };
     let res = unsafe { syscall(index, &mut args) };
-    match res.get(SyscallReturn::SYSCALL_TYPE) {
-        SyscallReturnType::Error => {
-            let code = res.get(SyscallReturn::SYSCALL_LEN);
-            return Err(abi::Error::from(code as u8));
-        }
-        _ => {}
+    check_error(res)?;
+    Ok(())
+}
+
+/// The largest buffer the kernel can forward in a single log frame
+///
+/// The kernel prefixes each log buffer with a length byte that also counts the task ID and the task's name, which
+/// it truncates to [`abi::MAX_LOG_NAME_LEN`] bytes, so this is what is left of 255 after the longest header
+pub const MAX_LOG_LEN: usize = u8::MAX as usize - 2 - 1 - abi::MAX_LOG_NAME_LEN;
+
+/// Logs a string directly, without any formatting
+#[inline]
+pub fn log_str(s: &str) -> Result<(), Error> {
+    log(s.as_bytes())
+}
+
+/// Logs `data`, splitting it into as many log syscalls as needed so none are truncated
+pub fn log_chunked(data: &[u8]) -> Result<(), Error> {
+    for chunk in data.chunks(MAX_LOG_LEN) {
+        log(chunk)?;
     }
     Ok(())
 }
@@ -124,6 +147,18 @@ pub trait CapExt {
     /// Sends a request to the capability and waits for a reply
     fn call<T: ?Sized>(&self, request: &mut T, out_buf: &mut T) -> Result<RecvResp<T>, Error>;
 
+    /// Sends a typed request for `method` to a server generated by [`k5_task`], and decodes its reply
+    ///
+    /// `req_buf` must fit the method index and the encoded request, and `out_buf` must fit the status byte and the
+    /// encoded reply. Usually this is called by the client generated by [`k5_task`], rather than directly.
+    fn call_msg<Req: ipc::Message, Rep: ipc::Message>(
+        &self,
+        method: u8,
+        req: &Req,
+        req_buf: &mut [u8],
+        out_buf: &mut [u8],
+    ) -> Result<Rep, Error>;
+
     /// Sends a request to the capability loaning the data in the io buf and waits for a reply
     fn call_io<'a, A: Aligned + 'a>(&self, io: &'a mut A) -> Result<(), Error>;
 
@@ -133,10 +168,36 @@ pub trait CapExt {
     /// Sends a request to the capability, and returns ASAP
     fn send_page<A: Aligned + 'static>(&self, request: A) -> Result<(), Error>;
 
+    /// Sends up to [`abi::MAX_SEND_VEC`] buffers to the capability as a single message, and returns ASAP
+    fn send_vec(&self, bufs: &[&[u8]]) -> Result<(), Error>;
+
+    /// Sends a copy of `cap_to_delegate` to the capability, and returns ASAP
+    ///
+    /// The receiver gets the copy as [`RecvRespBody::Cap`]. Reply endpoints and interrupts can't be delegated, and
+    /// fail with [`Error::NotSupported`].
+    fn send_cap(&self, cap_to_delegate: CapRef) -> Result<(), Error>;
+
     /// Listens to the port on the specified capability
     fn listen(&self) -> Result<(), Error>;
     /// Connects to the port, and returns an endpoint one can second messages to
     fn connect(&self) -> Result<CapRef, Error>;
+
+    /// Takes a unit from the semaphore, blocking until another thread posts if none are available
+    fn sem_wait(&self) -> Result<(), Error>;
+    /// Returns a unit to the semaphore, waking the highest priority thread waiting on it
+    fn sem_post(&self) -> Result<(), Error>;
+
+    /// Removes every thread's endpoint to this thread's port with the same address as this endpoint
+    ///
+    /// A server that answers each client on the address the client sends to kicks a client by revoking the endpoint
+    /// it answers that client with. A thread holding [`abi::Cap::Admin`] instead revokes every endpoint to this
+    /// endpoint's port, including this one.
+    fn revoke(&self) -> Result<(), Error>;
+
+    /// Maps the flash range of a [`abi::Cap::SharedFlash`] into this task, and returns a pointer to its start
+    ///
+    /// The range is read and execute only. Mapping it again returns the same pointer.
+    fn map_shared(&self) -> Result<*const u8, Error>;
 }
 
 impl CapExt for CapRef {
@@ -152,11 +213,30 @@ impl CapExt for CapRef {
         }
     }
 
+    fn call_msg<Req: ipc::Message, Rep: ipc::Message>(
+        &self,
+        method: u8,
+        req: &Req,
+        req_buf: &mut [u8],
+        out_buf: &mut [u8],
+    ) -> Result<Rep, Error> {
+        let (method_buf, body_buf) = req_buf.split_first_mut().ok_or(Error::BufferOverflow)?;
+        *method_buf = method;
+        let len = 1 + ipc::encode(req, body_buf).map_err(ipc_error)?;
+        let resp = self.call(&mut req_buf[..len], out_buf)?;
+        let len = match resp.body {
+            RecvRespBody::Copy(len) => len,
+            _ => return Err(Error::ReturnTypeMismatch),
+        };
+        match out_buf.get(..len).ok_or(Error::BufferOverflow)? {
+            [0, reply @ ..] => ipc::decode(reply).map_err(ipc_error),
+            _ => Err(Error::InvalidMessage),
+        }
+    }
+
     fn call_io<'a, A: Aligned + 'a>(&self, io: &'a mut A) -> Result<(), Error> {
+        let () = A::ALIGN_CHECK;
         match call_innner(SyscallDataType::Page, *self, io, None)?.inner {
-            abi::RecvRespInner::Copy(_) => {
-                return Err(Error::ReturnTypeMismatch);
-            }
             abi::RecvRespInner::Page { addr, len } => {
                 // Since we are taking a mutable borrow over just the course of the syscall, we must guarentee
                 // that we are getting back the same memory
@@ -165,9 +245,11 @@ impl CapExt for CapRef {
                     defmt::error!("addr mismatch");
                     return Err(Error::ReturnTypeMismatch);
                 }
+                Ok(())
             }
+            // a loaned page always comes back as a page, so copies, notifications, and delegated caps are all wrong
+            _ => Err(Error::ReturnTypeMismatch),
         }
-        Ok(())
     }
 
     fn send<T: ?Sized>(&self, r: &mut T) -> Result<(), Error> {
@@ -175,9 +257,45 @@ impl CapExt for CapRef {
     }
 
     fn send_page<A: Aligned + 'static>(&self, mut request: A) -> Result<(), Error> {
+        let () = A::ALIGN_CHECK;
         send_inner::<A::Target>(SyscallDataType::Page, *self, request.deref_mut())
     }
 
+    fn send_vec(&self, bufs: &[&[u8]]) -> Result<(), Error> {
+        if bufs.len() > abi::MAX_SEND_VEC {
+            return Err(Error::BufferOverflow);
+        }
+        let mut list = [abi::IoSlice { addr: 0, len: 0 }; abi::MAX_SEND_VEC];
+        for (slice, buf) in list.iter_mut().zip(bufs) {
+            let (ptr, _) = buf.as_ptr().to_raw_parts();
+            slice.addr = ptr.addr();
+            slice.len = buf.len();
+        }
+        let (ptr, _) = list.as_ptr().to_raw_parts();
+        let index = SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::SendVec);
+        let mut args = SyscallArgs {
+            arg1: ptr.addr(),
+            arg2: bufs.len(),
+            arg3: self.0,
+            ..Default::default()
+        };
+        let res = unsafe { syscall(index, &mut args) };
+        check_error(res)?;
+        Ok(())
+    }
+
+    fn send_cap(&self, cap_to_delegate: CapRef) -> Result<(), Error> {
+        let index = SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::SendCap);
+        let mut args = SyscallArgs {
+            arg1: self.0,
+            arg2: cap_to_delegate.0,
+            ..Default::default()
+        };
+        let res = unsafe { syscall(index, &mut args) };
+        check_error(res)?;
+        Ok(())
+    }
+
     fn listen(&self) -> Result<(), Error> {
         let index = SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::Listen);
         let mut args = SyscallArgs {
@@ -185,11 +303,7 @@ impl CapExt for CapRef {
             ..Default::default()
         };
         let res = unsafe { syscall(index, &mut args) };
-        match res.get(SyscallReturn::SYSCALL_TYPE) {
-            SyscallReturnType::Error => {
-                let code = res.get(SyscallReturn::SYSCALL_LEN);
-                Err(abi::Error::from(code as u8))
-            }
+        match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
             SyscallReturnType::Copy => Ok(()),
             _ => Err(abi::Error::ReturnTypeMismatch),
         }
@@ -202,26 +316,67 @@ impl CapExt for CapRef {
             ..Default::default()
         };
         let res = unsafe { syscall(index, &mut args) };
-        match res.get(SyscallReturn::SYSCALL_TYPE) {
-            SyscallReturnType::Error => {
-                let code = res.get(SyscallReturn::SYSCALL_LEN);
-                Err(abi::Error::from(code as u8))
-            }
+        match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
             SyscallReturnType::Copy => Ok(CapRef(res.get(SyscallReturn::SYSCALL_PTR) as usize)),
             _ => Err(abi::Error::ReturnTypeMismatch),
         }
     }
+
+    fn sem_wait(&self) -> Result<(), Error> {
+        cap_syscall(SyscallFn::SemWait, *self)
+    }
+
+    fn sem_post(&self) -> Result<(), Error> {
+        cap_syscall(SyscallFn::SemPost, *self)
+    }
+
+    fn revoke(&self) -> Result<(), Error> {
+        cap_syscall(SyscallFn::Revoke, *self)
+    }
+
+    fn map_shared(&self) -> Result<*const u8, Error> {
+        let index = SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::MapShared);
+        let mut args = SyscallArgs {
+            arg1: **self,
+            ..Default::default()
+        };
+        let res = unsafe { syscall(index, &mut args) };
+        match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+            SyscallReturnType::Short => {
+                Ok(res.get(SyscallReturn::SYSCALL_PTR) as usize as *const u8)
+            }
+            _ => Err(abi::Error::ReturnTypeMismatch),
+        }
+    }
 }
 
-/// Receives requests from other threads
-///
-/// This function will block until until another thread sends a request to
-/// the current thread
-pub fn recv<T: ?Sized, R: Sized>(mask: u32, r: &mut T) -> Result<RecvResp<R>, Error> {
+/// Runs a syscall whose only argument is `capability`, and which returns nothing
+#[inline]
+fn cap_syscall(f: SyscallFn, capability: CapRef) -> Result<(), Error> {
+    let index = SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, f);
+    let mut args = SyscallArgs {
+        arg1: *capability,
+        ..Default::default()
+    };
+    let res = unsafe { syscall(index, &mut args) };
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+        SyscallReturnType::Copy => Ok(()),
+        _ => Err(abi::Error::ReturnTypeMismatch),
+    }
+}
+
+#[inline]
+fn recv_inner<T: ?Sized>(
+    ty: SyscallDataType,
+    mask: u32,
+    r: &mut T,
+    timeout: usize,
+    flags: usize,
+) -> Result<abi::RecvResp, Error> {
     let size = core::mem::size_of_val(r);
     let (ptr, _) = (r as *mut T).to_raw_parts();
     let index = SyscallIndex::new()
-        .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy)
+        .with(SyscallIndex::SYSCALL_ARG_TYPE, ty)
         .with(SyscallIndex::SYSCALL_FN, SyscallFn::Recv);
     let mut resp: MaybeUninit<abi::RecvResp> = MaybeUninit::uninit();
     let mut args = SyscallArgs {
@@ -229,32 +384,218 @@ pub fn recv<T: ?Sized, R: Sized>(mask: u32, r: &mut T) -> Result<RecvResp<R>, Er
         arg2: size,
         arg3: mask as usize,
         arg4: resp.as_mut_ptr().addr(),
-        ..Default::default()
+        arg5: timeout,
+        arg6: flags,
     };
     let res = unsafe { syscall(index, &mut args) };
-    match res.get(SyscallReturn::SYSCALL_TYPE) {
-        SyscallReturnType::Error => {
-            let code = res.get(SyscallReturn::SYSCALL_LEN);
-            Err(abi::Error::from(code as u8))
+    check_error(res)?;
+    Ok(unsafe { resp.assume_init() })
+}
+
+/// Receives requests from other threads, copying the request into `r`
+///
+/// This function will block until another thread sends a request to
+/// the current thread. If the sender loaned a page instead, it must be exactly the size of `R`.
+pub fn recv_copy<T: ?Sized, R: Sized>(mask: u32, r: &mut T) -> Result<RecvResp<R>, Error> {
+    copy_resp(recv_inner(SyscallDataType::Copy, mask, r, 0, 0)?)
+}
+
+/// Receives a request like [`recv_copy`], but returns [`Error::WouldBlock`] instead of blocking if none is queued
+pub fn try_recv<T: ?Sized, R: Sized>(mask: u32, r: &mut T) -> Result<RecvResp<R>, Error> {
+    let resp = recv_inner(SyscallDataType::Copy, mask, r, abi::RECV_NO_WAIT, 0)?;
+    copy_resp(resp)
+}
+
+/// Receives a request like [`recv_copy`], but gives up with [`Error::Timeout`] after `ticks` kernel ticks
+///
+/// A `ticks` of 0 doesn't wait at all, just like [`try_recv`].
+pub fn recv_timeout<T: ?Sized, R: Sized>(
+    mask: u32,
+    r: &mut T,
+    ticks: usize,
+) -> Result<RecvResp<R>, Error> {
+    let timeout = if ticks == 0 { abi::RECV_NO_WAIT } else { ticks };
+    copy_resp(recv_inner(SyscallDataType::Copy, mask, r, timeout, 0)?)
+}
+
+/// Receives a request sent to any of the endpoints in `ports`, and returns the one it was sent to
+///
+/// Each port is resolved to its endpoint address, and the kernel matches messages sent to any of them.
+/// Since an address matches if it shares any bit with the others, the ports' addresses should be distinct bits,
+/// for instance by giving each its own power of two with `KernelBuilder::endpoint`.
+/// Ports that aren't endpoints, or whose address is 0, return [`Error::InvalidCap`].
+pub fn recv_any<T: ?Sized, R: Sized>(
+    ports: &[CapRef],
+    r: &mut T,
+) -> Result<(CapRef, RecvResp<R>), Error> {
+    let caps = caps()?;
+    let mut addrs = heapless::Vec::<(CapRef, usize), MAX_CAPS>::new();
+    for port in ports {
+        let addr = caps
+            .iter()
+            .find_map(|entry| match entry.desc {
+                abi::Cap::Endpoint(endpoint) if entry.cap_ref == *port => Some(endpoint.addr),
+                _ => None,
+            })
+            .filter(|&addr| addr != 0)
+            .ok_or(Error::InvalidCap)?;
+        addrs
+            .push((*port, addr))
+            .map_err(|_| Error::BufferOverflow)?;
+    }
+    let mask = addrs.iter().fold(0, |mask, (_, addr)| mask | addr);
+    let resp = recv_inner(SyscallDataType::Copy, mask as u32, r, 0, abi::RECV_ANY)?;
+    let port = addrs
+        .iter()
+        .find(|(_, addr)| resp.addr & addr != 0)
+        .map(|(port, _)| *port)
+        .ok_or(Error::InvalidCap)?;
+    Ok((port, copy_resp(resp)?))
+}
+
+fn copy_resp<R: Sized>(resp: abi::RecvResp) -> Result<RecvResp<R>, Error> {
+    Ok(RecvResp {
+        cap: resp.cap,
+        body: match resp.inner {
+            abi::RecvRespInner::Copy(len) => RecvRespBody::Copy(len),
+            abi::RecvRespInner::Page { addr, len } => {
+                if len != mem::size_of::<R>() {
+                    return Err(Error::ReturnTypeMismatch);
+                }
+                RecvRespBody::Page(PageRefMut(unsafe { core::mem::transmute(addr) }))
+            }
+            abi::RecvRespInner::Notification { bits } => RecvRespBody::Notification(bits),
+            abi::RecvRespInner::Cap { cap } => RecvRespBody::Cap(cap),
+        },
+    })
+}
+
+fn ipc_error(err: ipc::Error) -> Error {
+    match err {
+        ipc::Error::BufferOverflow => Error::BufferOverflow,
+        ipc::Error::Truncated | ipc::Error::Invalid => Error::InvalidMessage,
+    }
+}
+
+/// Serves typed requests sent to the endpoints in `mask` forever, this is the loop behind a [`k5_task`]'s `serve_loop`
+///
+/// Each request is split into its method index and encoded body and passed to `handler`, which encodes its reply
+/// after the status byte in `out_buf` and returns its length. If the handler fails, the reply is just a failed
+/// status, so the client returns [`Error::InvalidMessage`] rather than waiting forever.
+pub fn serve<F>(mask: u32, in_buf: &mut [u8], out_buf: &mut [u8], mut handler: F) -> !
+where
+    F: FnMut(u8, &[u8], &mut [u8]) -> Result<usize, ipc::Error>,
+{
+    loop {
+        let resp = match recv_copy::<[u8], u8>(mask, in_buf) {
+            Ok(resp) => resp,
+            Err(err) => {
+                defmt::error!("recv err: {:?}", err);
+                continue;
+            }
+        };
+        let (cap, len) = match (resp.cap, resp.body) {
+            (Some(cap), RecvRespBody::Copy(len)) => (cap, len),
+            _ => continue,
+        };
+        let len = match in_buf.get(..len) {
+            Some([method, req @ ..]) => {
+                let (status, reply) = out_buf.split_first_mut().expect("out_buf is empty");
+                match handler(*method, req, reply) {
+                    Ok(len) => {
+                        *status = 0;
+                        1 + len
+                    }
+                    Err(_) => {
+                        defmt::error!("invalid request for method {}", method);
+                        *status = 1;
+                        1
+                    }
+                }
+            }
+            _ => {
+                out_buf[0] = 1;
+                1
+            }
+        };
+        if let Err(err) = cap.send(&mut out_buf[..len]) {
+            defmt::error!("reply err: {:?}", err);
         }
-        _ => {
-            let resp = unsafe { resp.assume_init() };
+    }
+}
+
+/// Receives a loaned page from other threads
+///
+/// This function will block until another thread sends a page to
+/// the current thread. The page must match both the size and alignment of `P`.
+pub fn recv_page<T: ?Sized, P: Aligned>(mask: u32, r: &mut T) -> Result<RecvResp<P::Target>, Error>
+where
+    P::Target: Sized + 'static,
+{
+    let () = P::ALIGN_CHECK;
+    let resp = recv_inner(SyscallDataType::Page, mask, r, 0, 0)?;
+    match resp.inner {
+        abi::RecvRespInner::Copy(_) => Err(Error::ReturnTypeMismatch),
+        abi::RecvRespInner::Page { addr, len } => {
+            if len != mem::size_of::<P::Target>() || addr % mem::align_of::<P::Target>() != 0 {
+                return Err(Error::ReturnTypeMismatch);
+            }
             Ok(RecvResp {
                 cap: resp.cap,
-                body: match resp.inner {
-                    abi::RecvRespInner::Copy(len) => RecvRespBody::Copy(len),
-                    abi::RecvRespInner::Page { addr, len } => {
-                        if len != mem::size_of::<R>() {
-                            return Err(Error::ReturnTypeMismatch);
-                        }
-                        RecvRespBody::Page(PageRefMut(unsafe { core::mem::transmute(addr) }))
-                    }
-                },
+                body: RecvRespBody::Page(PageRefMut(unsafe { &mut *(addr as *mut P::Target) })),
             })
         }
+        abi::RecvRespInner::Notification { bits } => Ok(RecvResp {
+            cap: resp.cap,
+            body: RecvRespBody::Notification(bits),
+        }),
+        abi::RecvRespInner::Cap { cap } => Ok(RecvResp {
+            cap: resp.cap,
+            body: RecvRespBody::Cap(cap),
+        }),
     }
 }
 
+/// Waits for the next interrupt claimed by this thread, and returns its IRQ number
+///
+/// Interrupts are claimed with `ThreadBuilder::interrupt`. Calling this again re-enables the previous interrupt,
+/// so make sure the interrupt source has been cleared first.
+pub fn wait_interrupt() -> Result<u8, Error> {
+    let mut irq = [0u8; 1];
+    recv_copy::<_, [u8; 1]>(abi::INTERRUPT_ADDR as u32, &mut irq)?;
+    Ok(irq[0])
+}
+
+/// Waits for the interrupt of an IRQ cap to fire
+///
+/// The interrupt is masked each time it fires, clear it at its source and then call [`ack_irq`] to receive the next
+/// one. Returns straight away if the interrupt fired since the last call.
+pub fn wait_irq(cap: CapRef) -> Result<(), Error> {
+    irq_syscall(SyscallFn::WaitIrq, cap)
+}
+
+/// Acknowledges the interrupt of an IRQ cap, unmasking it so it can fire again
+pub fn ack_irq(cap: CapRef) -> Result<(), Error> {
+    irq_syscall(SyscallFn::AckIrq, cap)
+}
+
+fn irq_syscall(f: SyscallFn, cap: CapRef) -> Result<(), Error> {
+    let mut args = SyscallArgs {
+        arg1: *cap,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, f)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
 #[derive(Format, Debug)]
 pub struct RecvResp<T: ?Sized + 'static> {
     pub cap: Option<CapRef>,
@@ -265,37 +606,52 @@ pub struct RecvResp<T: ?Sized + 'static> {
 pub enum RecvRespBody<T: ?Sized + 'static> {
     Copy(usize),
     Page(PageRefMut<'static, T>),
+    Notification(u32),
+    /// A capability delegated by the sender with [`CapExt::send_cap`], which this thread now holds
+    Cap(CapRef),
 }
 
+/// The most capabilities [`caps`] can return
+pub const MAX_CAPS: usize = 10;
+
 /// Retrieves the tasks current capabilities
+///
+/// Only the first [`MAX_CAPS`] capabilities are returned.
 pub fn caps() -> Result<CapList, Error> {
-    const ELEM: MaybeUninit<CapListEntry> = MaybeUninit::uninit();
-
     let index = SyscallIndex::new()
         .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short)
         .with(SyscallIndex::SYSCALL_FN, SyscallFn::Caps);
-    let mut buf = [ELEM; 10];
-    let ptr = buf.as_mut_ptr();
+    let mut buf = heapless::Vec::<CapListEntry, MAX_CAPS>::new();
     let mut args = SyscallArgs {
-        arg1: ptr.addr(),
-        arg2: buf.len(),
+        arg1: buf.as_mut_ptr().addr(),
+        arg2: buf.capacity(),
         ..Default::default()
     };
     let res = unsafe { syscall(index, &mut args) };
-    match res.get(SyscallReturn::SYSCALL_TYPE) {
-        SyscallReturnType::Error => {
-            let code = res.get(SyscallReturn::SYSCALL_LEN);
-            Err(abi::Error::from(code as u8))
-        }
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
         SyscallReturnType::Copy => {
             let len = res.get(SyscallReturn::SYSCALL_LEN) as usize;
-            Ok(CapList { buf, len })
+            if len > buf.capacity() {
+                return Err(abi::Error::BufferOverflow);
+            }
+            // Safety: the kernel writes its capabilities contiguously from the start of the buffer, and returns
+            // how many it wrote, which we have checked fits in the buffer
+            unsafe { buf.set_len(len) };
+            Ok(CapList { buf })
         }
         _ => Err(abi::Error::ReturnTypeMismatch),
     }
 }
 
 pub fn panik(buf: &mut [u8]) -> ! {
+    let len = buf.len();
+    panik_with_len(buf, len)
+}
+
+/// Panics the current task, `full_len` is the length of the message before it was truncated to fit in `buf`
+///
+/// The kernel marks the message as truncated in its log if `full_len` is longer than `buf`
+pub fn panik_with_len(buf: &mut [u8], full_len: usize) -> ! {
     unsafe {
         syscall(
             SyscallIndex::new()
@@ -304,6 +660,7 @@ pub fn panik(buf: &mut [u8]) -> ! {
             &mut SyscallArgs {
                 arg1: buf.as_mut_ptr().addr(),
                 arg2: buf.len(),
+                arg3: full_len,
                 ..Default::default()
             },
         )
@@ -311,19 +668,375 @@ pub fn panik(buf: &mut [u8]) -> ! {
     loop {} // will never be called sicne we paniked
 }
 
-pub struct CapList {
-    buf: [MaybeUninit<abi::CapListEntry>; 10],
+/// Resets the system, this requires the calling thread to hold an admin cap
+///
+/// Unlike [`panik`], this is an intentional restart of the whole system, not just the calling task.
+pub fn reboot() -> ! {
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Reboot)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut SyscallArgs::default(),
+        )
+    };
+    // reboot only returns if the kernel refused the request
+    let code = res.get(SyscallReturn::SYSCALL_LEN);
+    panic!("reboot failed: {:?}", abi::Error::from(code as u8))
+}
+
+/// Gives up the rest of this thread's turn to another ready thread of the same or higher priority
+///
+/// The thread is requeued straight away and keeps the rest of its budget, so this returns once the scheduler picks
+/// it again, or immediately if nothing else is ready.
+pub fn yield_now() -> Result<(), Error> {
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Yield)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut SyscallArgs::default(),
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Suspends this thread for `ticks` kernel ticks
+///
+/// The thread doesn't run at all while asleep, and is requeued at its priority once the ticks have passed. Sleeping
+/// for 0 ticks returns immediately, and the idle thread can't sleep, so it gets [`Error::NotSupported`].
+pub fn sleep(ticks: usize) -> Result<(), Error> {
+    let mut args = SyscallArgs {
+        arg1: ticks,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Sleep)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Spawns a new thread in this task, starting at `entry`
+///
+/// The thread gets its own stack from the task's stack space, but no capabilities. `priority` can't be higher than
+/// this thread's own priority, or it fails with [`Error::PermissionDenied`]. A `budget` or `cooldown` of 0 fails with
+/// [`Error::NotSupported`]. Returns [`Error::BufferOverflow`] if the kernel is out of threads or the task is out of
+/// stack space.
+pub fn spawn_thread(
+    entry: fn() -> !,
+    priority: usize,
+    budget: usize,
+    cooldown: usize,
+) -> Result<abi::ThreadRef, Error> {
+    let mut args = SyscallArgs {
+        arg1: entry as usize,
+        arg2: priority,
+        arg3: budget,
+        arg4: cooldown,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Spawn)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(abi::ThreadRef(res.get(SyscallReturn::SYSCALL_PTR) as usize))
+}
+
+/// Sets `bits` in the notification word of the thread `cap` points to, which must be a [`abi::Cap::Notify`]
+///
+/// This is much cheaper than sending a message, so it suits simple signals like a transfer finishing.
+pub fn notify(cap: CapRef, bits: u32) -> Result<(), Error> {
+    let mut args = SyscallArgs {
+        arg1: *cap,
+        arg2: bits as usize,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::Notify),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Waits until any of the notification bits in `mask` are set, and returns them
+///
+/// The returned bits are cleared, while bits outside of `mask` stay pending for a later call. An empty `mask`
+/// would never return, so it fails with [`Error::WouldBlock`].
+pub fn wait_notify(mask: u32) -> Result<u32, Error> {
+    let mut args = SyscallArgs {
+        arg1: mask as usize,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::WaitNotify),
+            &mut args,
+        )
+    };
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+        SyscallReturnType::Short => Ok(res.get(SyscallReturn::SYSCALL_PTR) as u32),
+        _ => Err(abi::Error::ReturnTypeMismatch),
+    }
+}
+
+/// Returns the number of kernel ticks since boot
+///
+/// The resolution is one kernel tick, whose length is set by `KernelBuilder::cycles_per_tick`.
+/// The count wraps at 2^54 ticks, which is roughly 570,000 years at 1kHz, so in practice it is monotonic.
+pub fn ticks() -> u64 {
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Timestamp)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut SyscallArgs::default(),
+        )
+    };
+    (res.get(SyscallReturn::SYSCALL_LEN) << 32) | res.get(SyscallReturn::SYSCALL_PTR)
+}
+
+/// Maps the peripheral at `addr..addr + len` into the calling task as readable and writable device memory
+///
+/// This requires an admin cap, and the range must be inside a device window registered with
+/// `KernelBuilder::device_space`. Each peripheral can only be mapped by one task.
+pub fn map_device(addr: usize, len: usize) -> Result<(), Error> {
+    let mut args = SyscallArgs {
+        arg1: addr,
+        arg2: len,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::MapDevice)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Removes the region at `addr..addr + len` from the calling task
+///
+/// This is used to release pages received with [`recv_page`] that won't be sent onward, or devices mapped with
+/// [`map_device`]. The range must exactly match the region that was added.
+pub fn unmap(addr: usize, len: usize) -> Result<(), Error> {
+    let mut args = SyscallArgs {
+        arg1: addr,
+        arg2: len,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Unmap)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut args,
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Blocks until a thread listens on one of `ports`, returning the port that opened
+///
+/// Up to [`abi::MAX_POLL_PORTS`] ports can be waited on at once. This returns immediately if one is already open,
+/// so it can be called before [`CapExt::connect`] rather than retrying the connect in a loop.
+pub fn wait_for_port(ports: &[abi::PortId]) -> Result<abi::PortId, Error> {
+    let (ptr, _) = ports.as_ptr().to_raw_parts();
+    let mut args = SyscallArgs {
+        arg1: ptr.addr(),
+        arg2: ports.len(),
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::PollCaps)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut args,
+        )
+    };
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+        SyscallReturnType::Copy => {
+            let index = res.get(SyscallReturn::SYSCALL_LEN) as usize;
+            ports.get(index).copied().ok_or(Error::ReturnTypeMismatch)
+        }
+        _ => Err(abi::Error::ReturnTypeMismatch),
+    }
+}
+
+/// Returns the memory layout and thread count of the task at `index`
+///
+/// Tasks are indexed in the order they appear in the kernel's task list, with `Error::BadAccess` returned past the end.
+pub fn task_info(index: usize) -> Result<abi::TaskInfo, Error> {
+    let mut info: MaybeUninit<abi::TaskInfo> = MaybeUninit::uninit();
+    let mut args = SyscallArgs {
+        arg1: index,
+        arg2: info.as_mut_ptr().addr(),
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::GetTaskInfo)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut args,
+        )
+    };
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+        // Safety: the kernel writes a full `TaskInfo` before returning successfully
+        SyscallReturnType::Copy => Ok(unsafe { info.assume_init() }),
+        _ => Err(abi::Error::ReturnTypeMismatch),
+    }
+}
+
+/// Returns this thread's scheduling statistics
+pub fn stats() -> Result<abi::TaskStats, Error> {
+    let mut stats: MaybeUninit<abi::TaskStats> = MaybeUninit::uninit();
+    let mut args = SyscallArgs {
+        arg1: stats.as_mut_ptr().addr(),
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Stats)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Copy),
+            &mut args,
+        )
+    };
+    match check_error(res)?.get(SyscallReturn::SYSCALL_TYPE) {
+        // Safety: the kernel writes a full `TaskStats` before returning successfully
+        SyscallReturnType::Copy => Ok(unsafe { stats.assume_init() }),
+        _ => Err(abi::Error::ReturnTypeMismatch),
+    }
+}
+
+/// Returns the most stack this thread has used so far, in bytes
+///
+/// The kernel paints stacks before a task starts, so this is a high-water mark, it never goes down.
+pub fn stack_usage() -> Result<usize, Error> {
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::StackUsage)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut SyscallArgs::default(),
+        )
+    };
+    check_error(res)?;
+    Ok(res.get(SyscallReturn::SYSCALL_PTR) as usize)
+}
+
+/// Writes this thread's scheduling statistics to the log
+///
+/// `k5 logs --stats` shows them alongside the task's logs.
+pub fn log_stats() -> Result<(), Error> {
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new()
+                .with(SyscallIndex::SYSCALL_FN, SyscallFn::Stats)
+                .with(SyscallIndex::SYSCALL_ARG_TYPE, SyscallDataType::Short),
+            &mut SyscallArgs::default(),
+        )
+    };
+    check_error(res)?;
+    Ok(())
+}
+
+#[inline]
+fn memory_syscall(
+    f: SyscallFn,
+    ty: SyscallDataType,
+    addr: usize,
     len: usize,
+    buf_addr: usize,
+    buf_len: usize,
+) -> Result<(), Error> {
+    let index = SyscallIndex::new()
+        .with(SyscallIndex::SYSCALL_ARG_TYPE, ty)
+        .with(SyscallIndex::SYSCALL_FN, f);
+    let mut args = SyscallArgs {
+        arg1: addr,
+        arg2: len,
+        arg3: buf_addr,
+        arg4: buf_len,
+        ..Default::default()
+    };
+    let res = unsafe { syscall(index, &mut args) };
+    check_error(res)?;
+    Ok(())
+}
+
+/// Copies `out.len()` bytes starting at `addr` into `out`
+///
+/// This is a debugging aid, it requires an admin cap and is only supported by debug builds of the kernel.
+/// `addr` must be within the calling task's memory.
+pub fn read_memory(addr: usize, out: &mut [u8]) -> Result<(), Error> {
+    let (ptr, _) = out.as_mut_ptr().to_raw_parts();
+    memory_syscall(
+        SyscallFn::ReadMemory,
+        SyscallDataType::Copy,
+        addr,
+        out.len(),
+        ptr.addr(),
+        out.len(),
+    )
+}
+
+/// Copies `data` to `addr`, see [`read_memory`] for the requirements
+pub fn write_memory(addr: usize, data: &[u8]) -> Result<(), Error> {
+    let (ptr, _) = data.as_ptr().to_raw_parts();
+    memory_syscall(
+        SyscallFn::WriteMemory,
+        SyscallDataType::Copy,
+        addr,
+        data.len(),
+        ptr.addr(),
+        data.len(),
+    )
+}
+
+/// Has the kernel write `len` bytes starting at `addr` to the log, where `k5 memory-dumps` will display them
+///
+/// See [`read_memory`] for the requirements
+pub fn dump_memory(addr: usize, len: usize) -> Result<(), Error> {
+    memory_syscall(
+        SyscallFn::ReadMemory,
+        SyscallDataType::Short,
+        addr,
+        len,
+        0,
+        0,
+    )
+}
+
+pub struct CapList {
+    buf: heapless::Vec<abi::CapListEntry, MAX_CAPS>,
 }
 
 impl Deref for CapList {
     type Target = [abi::CapListEntry];
 
     fn deref(&self) -> &Self::Target {
-        // Safety:
-        // The core invarient here is that when you create `CapList`
-        // you ensure that 0..len items are initialized
-        unsafe { core::mem::transmute(&self.buf[0..self.len]) }
+        &self.buf
     }
 }
 
@@ -332,15 +1045,23 @@ impl Deref for CapList {
 fn panic(info: &core::panic::PanicInfo) -> ! {
     let mut buf = LenWrite::default();
     let _ = write!(&mut buf, "{}", info); // our impl is infaillible
-    panik(buf.buf())
+    let full_len = buf.full_len;
+    panik_with_len(buf.buf(), full_len)
 }
 
+/// Appended to messages that didn't fit in a [`LenWrite`]
+const TRUNCATED_TRAILER: &[u8] = b"...\0";
+
 pub(crate) struct LenWrite {
     buf: [u8; 512],
     pos: usize,
+    /// The length of everything written, including anything that didn't fit
+    full_len: usize,
+    truncated: bool,
 }
 
 impl LenWrite {
+    /// Returns the written message, ending with [`TRUNCATED_TRAILER`] if it was truncated
     pub(crate) fn buf(&mut self) -> &mut [u8] {
         &mut self.buf[0..self.pos]
     }
@@ -351,34 +1072,52 @@ impl Default for LenWrite {
         Self {
             buf: [0; 512],
             pos: 0,
+            full_len: 0,
+            truncated: false,
         }
     }
 }
 
 impl Write for LenWrite {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let bytes = s.as_bytes();
-        let start = self.pos;
-        let (end, overflow) = self.pos.overflowing_add(bytes.len());
-        if end >= 512 || overflow {
+        self.full_len = self.full_len.saturating_add(s.len());
+        if self.truncated {
             return Ok(());
         }
-        unsafe {
-            core::ptr::copy_nonoverlapping(
-                bytes.as_ptr(),
-                self.buf.get_unchecked_mut(start..end).as_mut_ptr(),
-                bytes.len(),
-            );
+        let space = self.buf.len() - TRUNCATED_TRAILER.len() - self.pos;
+        let mut len = s.len().min(space);
+        // only cut on a char boundary so the message stays valid utf-8
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.pos..self.pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        if len < s.len() {
+            self.truncated = true;
+            self.buf[self.pos..self.pos + TRUNCATED_TRAILER.len()]
+                .copy_from_slice(TRUNCATED_TRAILER);
+            self.pos += TRUNCATED_TRAILER.len();
         }
-        self.pos = end;
         Ok(())
     }
 }
 
+/// The alignment of a [`Page`], which is the MPU's region granularity on cortex-m
+pub const PAGE_ALIGN: usize = 32;
+
 #[derive(defmt::Format)]
 #[repr(C, align(32))]
 pub struct Page<T: ?Sized>(pub T);
 
+const _: () = assert!(mem::align_of::<Page<()>>() == PAGE_ALIGN);
+
+impl<T> Page<T> {
+    const ALIGN_CHECK: () = assert!(
+        mem::align_of::<T>() <= PAGE_ALIGN,
+        "page contents must not be aligned to more than PAGE_ALIGN"
+    );
+}
+
 impl<T> Deref for Page<T> {
     type Target = T;
 
@@ -409,7 +1148,114 @@ impl<T> DerefMut for PageRefMut<'static, T> {
     }
 }
 
-pub trait Aligned: Deref + DerefMut {}
+pub trait Aligned: Deref + DerefMut {
+    /// Fails to compile when referenced if the type can't be loaned as a page
+    #[doc(hidden)]
+    const ALIGN_CHECK: () = ();
+}
 
-impl<T> Aligned for Page<T> {}
+impl<T> Aligned for Page<T> {
+    const ALIGN_CHECK: () = Page::<T>::ALIGN_CHECK;
+}
 impl<T> Aligned for PageRefMut<'static, T> {}
+
+/// Allocates `layout` from the heap behind the [`abi::Cap::Heap`] `cap`
+///
+/// A null `cap` allocates from the calling task's heap, which every thread in the task shares.
+///
+/// Fails with [`Error::BufferOverflow`] if the heap doesn't have a free range large enough.
+pub fn heap_alloc(cap: CapRef, layout: Layout) -> Result<*mut u8, Error> {
+    let res = heap_syscall(cap, layout, 0)?;
+    Ok(res.get(SyscallReturn::SYSCALL_PTR) as usize as *mut u8)
+}
+
+/// Frees `ptr`, which must have been returned by [`heap_alloc`] on the same heap with the same `layout`
+pub fn heap_free(cap: CapRef, ptr: *mut u8, layout: Layout) -> Result<(), Error> {
+    heap_syscall(cap, layout, ptr.addr()).map(|_| ())
+}
+
+fn heap_syscall(cap: CapRef, layout: Layout, ptr: usize) -> Result<SyscallReturn, Error> {
+    let mut args = SyscallArgs {
+        arg1: cap.0,
+        arg2: layout.size(),
+        arg3: layout.align(),
+        arg4: ptr,
+        ..Default::default()
+    };
+    let res = unsafe {
+        syscall(
+            SyscallIndex::new().with(SyscallIndex::SYSCALL_FN, SyscallFn::Alloc),
+            &mut args,
+        )
+    };
+    check_error(res)
+}
+
+/// A [`GlobalAlloc`] backed by the task's heap, usually declared with [`task_heap`]
+///
+/// The heap is shared by every thread in the task, including those started with [`spawn_thread`], so allocations
+/// pass a null cap rather than one of the thread's [`abi::Cap::Heap`]s. Allocations fail, returning null, if the
+/// task has no heap or the heap is full.
+pub struct TaskHeap;
+
+impl TaskHeap {
+    pub const fn new() -> Self {
+        TaskHeap
+    }
+}
+
+impl Default for TaskHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for TaskHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        heap_alloc(CapRef::null(), layout).unwrap_or(core::ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(err) = heap_free(CapRef::null(), ptr, layout) {
+            defmt::error!("failed to free {=usize:#x}: {}", ptr.addr(), err);
+        }
+    }
+}
+
+/// Declares a [`TaskHeap`] as the task's `#[global_allocator]`, so it can use `alloc` with the heap given to it by
+/// `KernelBuilder::heap`
+#[macro_export]
+macro_rules! task_heap {
+    () => {
+        #[global_allocator]
+        static TASK_HEAP: $crate::TaskHeap = $crate::TaskHeap::new();
+    };
+}
+
+/// Includes the IPC stubs generated by `codegen::gen_interfaces` from the crate's `interfaces/` directory
+#[macro_export]
+macro_rules! include_interfaces {
+    () => {
+        include!(concat!(env!("OUT_DIR"), "/interfaces.rs"));
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LenWrite;
+    use core::fmt::Write;
+
+    #[test]
+    fn test_len_write_truncates() {
+        let mut buf = LenWrite::default();
+        for _ in 0..100 {
+            write!(&mut buf, "overflow ").unwrap();
+        }
+        assert!(buf.truncated);
+        assert_eq!(buf.full_len, 900);
+        let msg = buf.buf();
+        assert_eq!(msg.len(), 512);
+        assert!(msg.ends_with(b"...\0"));
+        assert!(core::str::from_utf8(msg).is_ok());
+    }
+}
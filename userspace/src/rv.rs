@@ -0,0 +1,187 @@
+use abi::{SyscallArgs, SyscallIndex, SyscallReturn};
+use core::arch::asm;
+
+#[cfg(feature = "rv64")]
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".text.start"]
+#[naked]
+pub unsafe extern "C" fn _start() -> ! {
+    // Provided by the user program:
+    extern "Rust" {
+        fn main() -> !;
+    }
+
+    asm!("
+        # Copy data initialization image into data section.
+        la t0, _edata       # upper bound in t0
+        la t1, _sidata      # source in t1
+        la t2, _sdata       # dest in t2
+        j 1f
+    2:  ld s3, (t1)
+        add t1, t1, 4
+        sd s3, (t2)
+        add t2, t2, 4
+    1:  bne t2, t0, 2b
+        # Zero BSS
+        la t0, _ebss        # upper bound in t0
+        la t1, _sbss        # base in t1
+        j 1f
+    2:  sd zero, (t1)
+        add t1, t1, 4
+    1:  bne t1, t0, 2b
+        j {main}
+        ",
+        main = sym main,
+        options(noreturn),
+    )
+}
+
+#[cfg(feature = "rv32")]
+#[doc(hidden)]
+#[no_mangle]
+#[link_section = ".text.start"]
+#[naked]
+pub unsafe extern "C" fn _start() -> ! {
+    // Provided by the user program:
+    extern "Rust" {
+        fn main() -> !;
+    }
+
+    asm!("
+        # Copy data initialization image into data section.
+        la t0, _edata       # upper bound in t0
+        la t1, _sidata      # source in t1
+        la t2, _sdata       # dest in t2
+        j 1f
+    2:  lw s3, (t1)
+        add t1, t1, 4
+        sw s3, (t2)
+        add t2, t2, 4
+    1:  bne t2, t0, 2b
+        # Zero BSS
+        la t0, _ebss        # upper bound in t0
+        la t1, _sbss        # base in t1
+        j 1f
+    2:  sw zero, (t1)
+        add t1, t1, 4
+    1:  bne t1, t0, 2b
+        j {main}
+        ",
+        main = sym main,
+        options(noreturn),
+    )
+}
+
+/// Returns the base of the calling thread's thread-local storage, which the kernel passes in `tp`
+///
+/// The storage is `tls_size` bytes, as configured in the app's `app.toml`, and is zeroed when the thread starts
+#[inline]
+pub fn thread_local_ptr() -> *mut u8 {
+    let tp: usize;
+    // Safety: reading tp has no side effects
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    core::ptr::from_exposed_addr_mut(tp)
+}
+
+#[cfg(feature = "rv64")]
+#[naked]
+pub(crate) unsafe extern "C" fn syscall(
+    index: SyscallIndex,
+    args: &mut SyscallArgs,
+) -> SyscallReturn {
+    asm!(
+        "
+          ld a2, 0*8(a1)
+          ld a3, 1*8(a1)
+          ld a4, 2*8(a1)
+          ld a5, 3*8(a1)
+          ld a6, 4*8(a1)
+          ld a7, 5*8(a1)
+          ld a1, 0*8(a1)
+
+          addi    sp,sp,-8 * 12
+          sd s0,  0*8(sp)
+          sd s1,  1*8(sp)
+          sd s2,  2*8(sp)
+          sd s3,  3*8(sp)
+          sd s4,  4*8(sp)
+          sd s5,  5*8(sp)
+          sd s6,  6*8(sp)
+          sd s7,  7*8(sp)
+          sd s8,  8*8(sp)
+          sd s9,  9*8(sp)
+          sd s10, 10*8(sp)
+          sd s11, 11*8(sp)
+
+          ecall
+
+          ld s0,  0*8(sp)
+          ld s1,  1*8(sp)
+          ld s2,  2*8(sp)
+          ld s3,  3*8(sp)
+          ld s4,  4*8(sp)
+          ld s5,  5*8(sp)
+          ld s6,  6*8(sp)
+          ld s7,  7*8(sp)
+          ld s8,  8*8(sp)
+          ld s9,  9*8(sp)
+          ld s10, 10*8(sp)
+          ld s11, 11*8(sp)
+          addi    sp,sp, 8 * 12
+          ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Makes a syscall, the kernel returns the 64 bit [`SyscallReturn`] split across a0 and a1
+#[cfg(feature = "rv32")]
+#[naked]
+pub(crate) unsafe extern "C" fn syscall(
+    index: SyscallIndex,
+    args: &mut SyscallArgs,
+) -> SyscallReturn {
+    asm!(
+        "
+          lw a2, 0*4(a1)
+          lw a3, 1*4(a1)
+          lw a4, 2*4(a1)
+          lw a5, 3*4(a1)
+          lw a6, 4*4(a1)
+          lw a7, 5*4(a1)
+
+          addi    sp,sp,-4 * 12
+          sw s0,  0*4(sp)
+          sw s1,  1*4(sp)
+          sw s2,  2*4(sp)
+          sw s3,  3*4(sp)
+          sw s4,  4*4(sp)
+          sw s5,  5*4(sp)
+          sw s6,  6*4(sp)
+          sw s7,  7*4(sp)
+          sw s8,  8*4(sp)
+          sw s9,  9*4(sp)
+          sw s10, 10*4(sp)
+          sw s11, 11*4(sp)
+
+          ecall
+
+          lw s0,  0*4(sp)
+          lw s1,  1*4(sp)
+          lw s2,  2*4(sp)
+          lw s3,  3*4(sp)
+          lw s4,  4*4(sp)
+          lw s5,  5*4(sp)
+          lw s6,  6*4(sp)
+          lw s7,  7*4(sp)
+          lw s8,  8*4(sp)
+          lw s9,  9*4(sp)
+          lw s10, 10*4(sp)
+          lw s11, 11*4(sp)
+          addi    sp,sp, 4 * 12
+          ret
+        ",
+        options(noreturn)
+    )
+}